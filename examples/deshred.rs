@@ -3,81 +3,94 @@
 
 use {
     // itertools::Itertools,
-    // ReedSolomonCache
-    solana_ledger::shred::{Shred, ShredType, Shredder},
-    solana_sdk::clock::Slot,
-    std::collections::HashMap,
+    solana_ledger::shred::{ReedSolomonCache, Shred, ShredType, Shredder},
+    solana_sdk::{clock::Slot, packet::PACKET_DATA_SIZE},
+    std::{
+        collections::{BTreeMap, HashMap},
+        mem,
+        sync::RwLock,
+    },
 };
 
 const MAX_DATA_SHREDS_PER_SLOT: usize = 32768;
 
-#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
-enum ShredStatus {
-    #[default]
-    Unknown,
-    NotDataComplete,
-    DataComplete,
+/// slots tracked by DeshredManager::new()
+const DEFAULT_SLOT_WINDOW: Slot = 128;
+
+/// which slot DeshredManager drops when a new slot arrives and the window is full
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// lowest slot number
+    OldestFirst,
+    /// slot that least recently received a shred
+    LeastRecentlyUsed,
+    /// slot holding the most shreds (SlotShreds::memory_bytes)
+    SizeWeighted,
 }
 
 /// tracks per-slot shred information for data shreds
 pub struct SlotShreds {
     pub slot: Slot,
-    /// compact status of each data shred
-    data_status: Vec<ShredStatus>,
-    /// data shreds received
-    data_shreds: Vec<Option<Shred>>,
-    /// code shreds for FEC recovery
-    code_shreds: Vec<Shred>,
+    /// data shreds received and not deshredded yet, keyed by shred index. a slot rarely gets
+    /// anywhere near MAX_DATA_SHREDS_PER_SLOT so only what arrived is stored
+    data_shreds: BTreeMap<u32, Shred>,
+    /// index of the first shred of the next segment, everything before it was deshredded
+    next_segment_start: u32,
+    /// code shreds for FEC recovery, keyed by fec_set_index
+    code_shreds: BTreeMap<u32, Vec<Shred>>,
+    /// number of shreds in code_shreds
+    code_shred_count: usize,
+    /// fec sets already recovered, no point trying again
+    recovered_fec_sets: Vec<u32>,
+    /// DeshredManager tick of the last shred added, for LRU eviction
+    last_used: u64,
 }
 
 impl SlotShreds {
     pub fn new(slot: Slot) -> Self {
         Self {
             slot,
-            data_status: vec![ShredStatus::Unknown; MAX_DATA_SHREDS_PER_SLOT],
-            data_shreds: vec![None; MAX_DATA_SHREDS_PER_SLOT],
-            code_shreds: Vec::new(),
+            data_shreds: BTreeMap::new(),
+            next_segment_start: 0,
+            code_shreds: BTreeMap::new(),
+            code_shred_count: 0,
+            recovered_fec_sets: Vec::new(),
+            last_used: 0,
         }
     }
 
     /// add a shred to the slot
     /// returns true if this is a new shred
     pub fn add_shred(&mut self, shred: Shred) -> bool {
-        let index = shred.index() as usize;
+        let index = shred.index();
 
         match shred.shred_type() {
             ShredType::Data => {
-                if index >= MAX_DATA_SHREDS_PER_SLOT {
+                // late copies of shreds that were already deshredded
+                if index as usize >= MAX_DATA_SHREDS_PER_SLOT || index < self.next_segment_start {
                     return false;
                 }
 
-                if self.data_shreds[index].is_some() {
+                if self.data_shreds.contains_key(&index) {
                     return false; // already have this shred
                 }
 
-                // check if this is a data complete shred using public methods
-                let is_data_complete = shred.data_complete() || shred.last_in_slot();
-
                 // debug: track DATA_COMPLETE markers
-                if is_data_complete {
+                if is_data_complete(&shred) {
                     eprintln!("debug deshred: slot:{} idx:{} DATA_COMPLETE=true", self.slot, index);
                 }
 
-                self.data_status[index] = if is_data_complete {
-                    ShredStatus::DataComplete
-                } else {
-                    ShredStatus::NotDataComplete
-                };
-
-                self.data_shreds[index] = Some(shred);
+                self.data_shreds.insert(index, shred);
                 true
             }
             ShredType::Code => {
                 // check if we already have this code shred
-                if self.code_shreds.iter().any(|s| s.index() == shred.index()) {
+                let fec_set = self.code_shreds.entry(shred.fec_set_index()).or_default();
+                if fec_set.iter().any(|s| s.index() == shred.index()) {
                     return false;
                 }
-                self.code_shreds.push(shred);
+                fec_set.push(shred);
+                self.code_shred_count += 1;
                 true
             }
         }
@@ -86,26 +99,32 @@ impl SlotShreds {
     /// try to reconstruct entries from available shreds
     /// returns (entries, deshredded_payload) if successful
     /// note: this function consumes the segment to prevent re-processing
-    pub fn try_deshred(&mut self) -> Option<(Vec<solana_entry::entry::Entry>, Vec<u8>)> {
+    pub fn try_deshred(
+        &mut self,
+        rs_cache: &ReedSolomonCache,
+    ) -> Option<(Vec<solana_entry::entry::Entry>, Vec<u8>)> {
         // find a complete segment [NotDataComplete*, DataComplete]
         let (start, end) = self.find_complete_segment()?;
 
-        // get shreds for this segment
-        let shreds = &self.data_shreds[start..=end];
-
         // check all shreds are present
-        if shreds.iter().any(|s| s.is_none()) {
-            let missing: Vec<usize> = (start..=end)
-                .filter(|&i| self.data_shreds[i].is_none())
-                .collect();
+        let missing: Vec<u32> = (start..=end)
+            .filter(|i| !self.data_shreds.contains_key(i))
+            .collect();
+        if !missing.is_empty() {
             eprintln!("debug_deshred: slot:{} range:{}..={} missing_indices:{:?}",
                       self.slot, start, end, missing);
+
+            // rebuild the missing shreds from the code shreds, recovered shreds can move the
+            // segment boundaries so start over
+            if self.try_recover(start, end, rs_cache) > 0 {
+                return self.try_deshred(rs_cache);
+            }
             return None;
         }
 
         // deshred the payload
         let deshredded_payload = match Shredder::deshred(
-            shreds.iter().map(|s| s.as_ref().unwrap().payload()),
+            (start..=end).map(|i| self.data_shreds[&i].payload()),
         ) {
             Ok(payload) => payload,
             Err(_) => return None,
@@ -116,58 +135,170 @@ impl SlotShreds {
             bincode::deserialize(&deshredded_payload).ok()?;
 
         // clear the processed segment to prevent re-deshredding
-        // just array updates
         for i in start..=end {
-            self.data_shreds[i] = None;
-            self.data_status[i] = ShredStatus::Unknown;
+            self.data_shreds.remove(&i);
         }
+        self.next_segment_start = end + 1;
 
         Some((entries, deshredded_payload))
     }
 
-    /// find first complete segment: [0+ NotDataComplete, DataComplete]
-    fn find_complete_segment(&self) -> Option<(usize, usize)> {
-        // find first DataComplete
-        let end = self.data_status.iter().position(|s| *s == ShredStatus::DataComplete)?;
-        eprintln!("debug_deshred_segment: found DataComplete at index:{} for slot:{}", end, self.slot);
+    /// true if this shred was already added
+    pub fn has_shred(&self, shred: &Shred) -> bool {
+        match shred.shred_type() {
+            ShredType::Data => {
+                shred.index() < self.next_segment_start
+                    || self.data_shreds.contains_key(&shred.index())
+            }
+            ShredType::Code => self
+                .code_shreds
+                .get(&shred.fec_set_index())
+                .is_some_and(|set| set.iter().any(|s| s.index() == shred.index())),
+        }
+    }
+
+    /// approximate memory held by buffered data and code shreds, the shreds themselves plus
+    /// their payloads
+    pub fn memory_bytes(&self) -> usize {
+        (self.data_shreds.len() + self.code_shred_count)
+            * (mem::size_of::<Shred>() + PACKET_DATA_SIZE)
+    }
+
+    /// run erasure recovery on every fec set starting at or before `end` that has code shreds
+    /// returns the number of data shreds recovered
+    /// shreds before `start` belong to segments that were already deshredded and are dropped
+    fn try_recover(&mut self, start: u32, end: u32, rs_cache: &ReedSolomonCache) -> usize {
+        let fec_sets: Vec<u32> = self
+            .code_shreds
+            .range(..=end)
+            .map(|(fec_set, _)| *fec_set)
+            .filter(|fec_set| !self.recovered_fec_sets.contains(fec_set))
+            .collect();
+
+        let mut recovered_count = 0;
+        for fec_set in fec_sets {
+            let code_shreds = &self.code_shreds[&fec_set];
+            let Some(num_data_shreds) = code_shreds.first().and_then(num_data_shreds) else {
+                continue;
+            };
+            // the set's data shreds are fec_set..fec_set + num_data_shreds
+            let data_shreds = (fec_set..fec_set.saturating_add(num_data_shreds as u32))
+                .filter_map(|i| self.data_shreds.get(&i));
 
-        // find start (after previous DataComplete or beginning)
-        let start = if end == 0 {
-            0
-        } else {
-            // scan backwards
-            let mut s = end;
-            while s > 0 {
-                s -= 1;
-                match self.data_status[s] {
-                    ShredStatus::DataComplete => return Some((s + 1, end)),
-                    ShredStatus::Unknown => return None, // gap
-                    ShredStatus::NotDataComplete => continue,
+            // reed-solomon needs num_data_shreds shreds of the set, don't bother before that
+            if data_shreds.clone().count() + code_shreds.len() < num_data_shreds {
+                continue;
+            }
+
+            let shreds: Vec<Shred> = data_shreds.chain(code_shreds).cloned().collect();
+
+            // fails until enough shreds of the set have arrived
+            let Ok(recovered) = Shredder::try_recovery(shreds, rs_cache) else {
+                continue;
+            };
+            self.recovered_fec_sets.push(fec_set);
+            for shred in recovered {
+                if shred.shred_type() == ShredType::Data
+                    && shred.index() >= start
+                    && self.add_shred(shred)
+                {
+                    recovered_count += 1;
                 }
             }
-            0
-        };
+        }
 
-        Some((start, end))
+        if recovered_count > 0 {
+            eprintln!("debug_deshred: slot:{} recovered {} data shreds", self.slot, recovered_count);
+        }
+        recovered_count
+    }
+
+    /// find the next segment: the shreds after the last deshredded one up to the first
+    /// DataComplete shred. the segment may still have gaps
+    fn find_complete_segment(&self) -> Option<(u32, u32)> {
+        // find first DataComplete
+        let (&end, _) = self
+            .data_shreds
+            .iter()
+            .find(|(_, shred)| is_data_complete(shred))?;
+        eprintln!("debug_deshred_segment: found DataComplete at index:{} for slot:{}", end, self.slot);
+
+        Some((self.next_segment_start, end))
     }
 }
 
+/// true for the last data shred of an entry batch
+fn is_data_complete(shred: &Shred) -> bool {
+    shred.data_complete() || shred.last_in_slot()
+}
+
+/// number of data shreds in a code shred's fec set, from the coding header right after the
+/// 83 byte common header
+fn num_data_shreds(code_shred: &Shred) -> Option<usize> {
+    let bytes = code_shred.payload().get(83..85)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
 /// manages shreds across multiple slots
 pub struct DeshredManager {
     slots: HashMap<Slot, SlotShreds>,
-    // rs_cache: ReedSolomonCache,
+    rs_cache: ReedSolomonCache,
+    /// max number of slots tracked at once
+    window_size: Slot,
+    eviction: EvictionPolicy,
+    /// bumped on every shred, orders slots for LeastRecentlyUsed
+    tick: u64,
+    /// called with every deshredded segment instead of returning it from add_shred
+    on_complete: Option<Box<SlotCompleteCallback>>,
+    /// sum of SlotShreds::memory_bytes over all tracked slots
+    memory_bytes: usize,
+    memory_limit: Option<usize>,
 }
 
+pub type SlotCompleteCallback = dyn Fn(Slot, Vec<solana_entry::entry::Entry>, Vec<u8>) + Send;
+
 impl DeshredManager {
     pub fn new() -> Self {
+        Self::with_config(DEFAULT_SLOT_WINDOW, EvictionPolicy::OldestFirst)
+    }
+
+    /// track at most `window_size` slots, evicting one according to `eviction` when a shred
+    /// for a new slot arrives and the window is full
+    pub fn with_config(window_size: Slot, eviction: EvictionPolicy) -> Self {
         Self {
             slots: HashMap::new(),
-            // rs_cache: ReedSolomonCache::default(),
+            rs_cache: ReedSolomonCache::default(),
+            window_size: window_size.max(1),
+            eviction,
+            tick: 0,
+            on_complete: None,
+            memory_bytes: 0,
+            memory_limit: None,
         }
     }
 
+    /// once the tracked slots hold more than `bytes` (SlotShreds::memory_bytes), evict the
+    /// largest slots until they fit again. applies on top of the slot window
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = Some(bytes);
+    }
+
+    /// estimated memory held by all tracked slots
+    pub fn memory_bytes(&self) -> usize {
+        self.memory_bytes
+    }
+
+    /// call `cb` synchronously from add_shred with every deshredded segment. once set,
+    /// add_shred always returns None
+    pub fn on_slot_complete(
+        &mut self,
+        cb: impl Fn(Slot, Vec<solana_entry::entry::Entry>, Vec<u8>) + Send + 'static,
+    ) {
+        self.on_complete = Some(Box::new(cb));
+    }
+
     /// add a shred and try to deshred if complete
-    /// returns (slot, entries, payload) if successful
+    /// returns (slot, entries, payload) if successful and no on_slot_complete callback is set
     pub fn add_shred(
         &mut self,
         shred: Shred,
@@ -181,19 +312,147 @@ impl DeshredManager {
             eprintln!("debug_deshred_slot: processing slot:{} (total slots tracked:{})", slot, self.slots.len());
         }
 
+        if !self.slots.contains_key(&slot) && self.slots.len() as Slot >= self.window_size {
+            self.evict_one();
+        }
+
+        self.tick += 1;
         let slot_shreds = self.slots.entry(slot).or_insert_with(|| SlotShreds::new(slot));
+        slot_shreds.last_used = self.tick;
 
+        let before = slot_shreds.memory_bytes();
         if !slot_shreds.add_shred(shred) {
             return None; // duplicate shred
         }
 
         // try to deshred
-        slot_shreds.try_deshred().map(|(entries, payload)| (slot, entries, payload))
+        let deshredded = slot_shreds.try_deshred(&self.rs_cache);
+        self.memory_bytes = self.memory_bytes - before + slot_shreds.memory_bytes();
+        self.enforce_memory_limit();
+
+        let (entries, payload) = deshredded?;
+        match &self.on_complete {
+            Some(cb) => {
+                cb(slot, entries, payload);
+                None
+            }
+            None => Some((slot, entries, payload)),
+        }
+    }
+
+    /// drop one slot according to the eviction policy
+    fn evict_one(&mut self) {
+        let victim = match self.eviction {
+            EvictionPolicy::OldestFirst => self.slots.keys().min().copied(),
+            EvictionPolicy::LeastRecentlyUsed => self
+                .slots
+                .values()
+                .min_by_key(|s| s.last_used)
+                .map(|s| s.slot),
+            EvictionPolicy::SizeWeighted => self.largest_slot(),
+        };
+        if let Some(slot) = victim {
+            eprintln!("debug_deshred_slot: evicting slot:{} ({:?})", slot, self.eviction);
+            self.remove_slot(slot);
+        }
+    }
+
+    /// evict the slots holding the most memory until the total is back under the limit
+    fn enforce_memory_limit(&mut self) {
+        let Some(limit) = self.memory_limit else {
+            return;
+        };
+        while self.memory_bytes > limit {
+            let Some(slot) = self.largest_slot() else {
+                break;
+            };
+            eprintln!(
+                "debug_deshred_slot: evicting slot:{} (memory {} > {})",
+                slot, self.memory_bytes, limit
+            );
+            self.remove_slot(slot);
+        }
+    }
+
+    /// slot with the highest memory_bytes, the oldest one on a tie
+    fn largest_slot(&self) -> Option<Slot> {
+        self.slots
+            .values()
+            .max_by_key(|s| (s.memory_bytes(), std::cmp::Reverse(s.slot)))
+            .map(|s| s.slot)
+    }
+
+    fn remove_slot(&mut self, slot: Slot) {
+        if let Some(slot_shreds) = self.slots.remove(&slot) {
+            self.memory_bytes -= slot_shreds.memory_bytes();
+        }
     }
 
     /// clean up old slots
     pub fn cleanup_old_slots(&mut self, current_slot: Slot, lookback: Slot) {
         let threshold = current_slot.saturating_sub(lookback);
-        self.slots.retain(|slot, _| *slot >= threshold);
+        let memory_bytes = &mut self.memory_bytes;
+        self.slots.retain(|slot, slot_shreds| {
+            let keep = *slot >= threshold;
+            if !keep {
+                *memory_bytes -= slot_shreds.memory_bytes();
+            }
+            keep
+        });
+    }
+}
+
+/// manages shreds for slots arriving on multiple threads, e.g. one per RSS queue
+///
+/// slots live in SHARD_COUNT shards indexed by slot % SHARD_COUNT, each behind its own RwLock,
+/// so threads working on different slots never contend. a shard holds one slot at a time, a
+/// newer slot mapping to the same shard replaces it.
+pub struct DeshredManagerConcurrent<const SHARD_COUNT: usize> {
+    shards: [RwLock<Option<SlotShreds>>; SHARD_COUNT],
+    rs_cache: ReedSolomonCache,
+}
+
+impl<const SHARD_COUNT: usize> DeshredManagerConcurrent<SHARD_COUNT> {
+    pub fn new() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| RwLock::new(None)),
+            rs_cache: ReedSolomonCache::default(),
+        }
+    }
+
+    /// add a shred and try to deshred if complete
+    /// returns (slot, entries, payload) if successful
+    pub fn add_shred(
+        &self,
+        shred: Shred,
+    ) -> Option<(Slot, Vec<solana_entry::entry::Entry>, Vec<u8>)> {
+        let slot = shred.slot();
+        let shard = &self.shards[(slot as usize) % SHARD_COUNT];
+
+        // duplicates are the common case, check them without blocking other readers
+        match &*shard.read().unwrap() {
+            Some(slot_shreds) if slot_shreds.slot == slot && slot_shreds.has_shred(&shred) => {
+                return None;
+            }
+            // shred for a slot that was already replaced
+            Some(slot_shreds) if slot_shreds.slot > slot => return None,
+            _ => {}
+        }
+
+        let mut shard = shard.write().unwrap();
+        // another thread may have replaced the slot since we dropped the read lock
+        let slot_shreds = match &mut *shard {
+            Some(slot_shreds) if slot_shreds.slot == slot => slot_shreds,
+            Some(slot_shreds) if slot_shreds.slot > slot => return None,
+            entry => entry.insert(SlotShreds::new(slot)),
+        };
+
+        if !slot_shreds.add_shred(shred) {
+            return None;
+        }
+
+        slot_shreds
+            .try_deshred(&self.rs_cache)
+            .map(|(entries, payload)| (slot, entries, payload))
     }
 }
@@ -12,7 +12,7 @@ use {
 };
 
 // use smaller arrays for better cache locality
-const SLOT_WINDOW_SIZE: usize = 128;  // track 128 slots
+const SLOT_WINDOW_SIZE: usize = 128;  // default, track 128 slots
 const MAX_SHREDS_PER_SLOT: usize = 512;  // most slots use <100 shreds
 
 /// compact shred tracking with better cache locality
@@ -123,15 +123,20 @@ impl SlotShrdsCompact {
 
 /// per-thread deshred manager - no locks needed
 pub struct DeshredManagerLocal {
-    // use fixed-size array indexed by slot % WINDOW_SIZE
-    slots: [Option<SlotShrdsCompact>; SLOT_WINDOW_SIZE],
+    // fixed-size window indexed by slot % window size
+    slots: Vec<Option<SlotShrdsCompact>>,
     current_slot: AtomicU64,
 }
 
 impl DeshredManagerLocal {
     pub fn new() -> Self {
+        Self::with_window_size(SLOT_WINDOW_SIZE)
+    }
+
+    /// track `window_size` slots, a slot is replaced by the one window_size slots after it
+    pub fn with_window_size(window_size: usize) -> Self {
         Self {
-            slots: std::array::from_fn(|_| None),
+            slots: (0..window_size.max(1)).map(|_| None).collect(),
             current_slot: AtomicU64::new(0),
         }
     }
@@ -140,7 +145,7 @@ impl DeshredManagerLocal {
     #[inline]
     pub fn add_shred(&mut self, shred: Shred) -> Option<(Slot, Vec<solana_entry::entry::Entry>, Vec<u8>)> {
         let slot = shred.slot();
-        let slot_idx = (slot as usize) % SLOT_WINDOW_SIZE;
+        let slot_idx = (slot as usize) % self.slots.len();
 
         // update current slot
         self.current_slot.store(slot, Ordering::Relaxed);
@@ -167,7 +172,7 @@ impl DeshredManagerLocal {
     /// cleanup old slots (using slot window)
     #[inline]
     pub fn cleanup_old_slots(&mut self, current_slot: Slot) {
-        let threshold = current_slot.saturating_sub(SLOT_WINDOW_SIZE as u64);
+        let threshold = current_slot.saturating_sub(self.slots.len() as u64);
 
         for slot_opt in &mut self.slots {
             if let Some(slot_shreds) = slot_opt {
@@ -195,9 +200,13 @@ pub struct DeshredManagerSharded {
 
 impl DeshredManagerSharded {
     pub fn new(num_threads: usize) -> Self {
+        Self::with_window_size(num_threads, SLOT_WINDOW_SIZE)
+    }
+
+    pub fn with_window_size(num_threads: usize, window_size: usize) -> Self {
         Self {
             managers: (0..num_threads)
-                .map(|_| DeshredManagerLocal::new())
+                .map(|_| DeshredManagerLocal::with_window_size(window_size))
                 .collect(),
         }
     }
@@ -0,0 +1,84 @@
+// prometheus exporter for shred stats
+// minimal http server on a TcpListener, serves GET /metrics in the text exposition format
+
+use {
+    crate::shred_processor::ShredStats,
+    std::{
+        fmt::Write as _,
+        io::{self, BufRead, BufReader, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{atomic::Ordering, Arc},
+        thread::{self, JoinHandle},
+        time::Duration,
+    },
+};
+
+/// serves ShredStats counters at GET /metrics
+pub struct PrometheusExporter {
+    stats: Arc<ShredStats>,
+}
+
+impl PrometheusExporter {
+    pub fn new(stats: Arc<ShredStats>) -> Self {
+        Self { stats }
+    }
+
+    /// bind to `addr` and serve requests on a new thread
+    pub fn spawn(self, addr: SocketAddr) -> io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        thread::Builder::new()
+            .name("metricsExporter".to_string())
+            .spawn(move || self.serve(listener))
+    }
+
+    fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| self.handle(stream));
+            if let Err(e) = result {
+                eprintln!("metrics request failed: {}", e);
+            }
+        }
+    }
+
+    /// one request per connection, scrapers don't need keep-alive
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+
+    fn render(&self) -> String {
+        let stats = &self.stats;
+        let mut out = String::new();
+        for (name, help, value) in [
+            ("shred_received_total", "packets handed to the shred processor", &stats.received),
+            ("shred_decoded_total", "shreds successfully parsed", &stats.decoded),
+            ("shred_errors_total", "packets that failed to parse as shreds", &stats.errors),
+            ("shred_data_total", "data shreds parsed", &stats.data_shreds),
+            ("shred_code_total", "code shreds parsed", &stats.code_shreds),
+        ] {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
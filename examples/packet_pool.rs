@@ -1,26 +1,35 @@
 // zero-copy packet pool
 // pre-allocates packets to eliminate heap allocations in hot path
 
-use std::{
-    cell::UnsafeCell,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::SystemTime,
+use {
+    agave_xdp::umem::PageAlignedMemory,
+    std::{
+        cell::{RefCell, UnsafeCell},
+        collections::VecDeque,
+        io, mem, ptr,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+        time::SystemTime,
+    },
 };
 
 /// maximum packet size (jumbo frames)
 const MAX_PACKET_SIZE: usize = 9000;
 
+/// buffer sizes of the TieredPacketPool tiers
+const SMALL_PACKET_SIZE: usize = 1500;
+const MEDIUM_PACKET_SIZE: usize = 4096;
+
 /// number of pre-allocated packets in the pool
 const POOL_SIZE: usize = 65536;
 
 /// pre-allocated packet buffer
 #[repr(align(64))] // cache line aligned
-pub struct PacketBuffer {
-    data: [u8; MAX_PACKET_SIZE],
+pub struct PacketBuffer<const SIZE: usize = MAX_PACKET_SIZE> {
+    data: [u8; SIZE],
     len: usize,
 }
 
-impl PacketBuffer {
+impl<const SIZE: usize> PacketBuffer<SIZE> {
     #[inline]
     pub fn as_slice(&self) -> &[u8] {
         &self.data[..self.len]
@@ -28,7 +37,7 @@ impl PacketBuffer {
 
     #[inline]
     pub fn set_data(&mut self, data: &[u8]) {
-        let len = data.len().min(MAX_PACKET_SIZE);
+        let len = data.len().min(SIZE);
         self.data[..len].copy_from_slice(&data[..len]);
         self.len = len;
     }
@@ -49,44 +58,128 @@ pub struct PacketMeta {
     pub timestamp: SystemTime,
 }
 
+/// returns a packet slot to the pool it came from, lets PacketRef point into any pool size
+pub trait PacketRelease: Sync {
+    fn release(&self, index: usize);
+}
+
 /// reference to a packet in the pool
-pub struct PacketRef {
-    pub buffer: &'static PacketBuffer,
+pub struct PacketRef<'a> {
+    /// the packet's bytes, owned exclusively until the ref is dropped
+    data: &'a mut [u8],
     pub meta: PacketMeta,
-    pool: &'static PacketPool,
+    pool: &'a dyn PacketRelease,
     index: usize,
 }
 
-impl PacketRef {
+impl PacketRef<'_> {
     #[inline]
     pub fn payload(&self) -> &[u8] {
-        self.buffer.as_slice()
+        self.data
+    }
+
+    #[inline]
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        self.data
     }
 }
 
-impl Drop for PacketRef {
+impl Drop for PacketRef<'_> {
     fn drop(&mut self) {
         // return packet to pool when dropped
         self.pool.release(self.index);
     }
 }
 
+/// snapshot of PacketPool counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PacketPoolStats {
+    pub allocations: u64,
+    pub releases: u64,
+    /// acquire() calls that found the pool empty
+    pub exhaustions: u64,
+    pub in_use: u64,
+    pub peak_in_use: u64,
+}
+
+/// where the POOL_SIZE packet buffers of a PacketPool live
+enum PacketStorage<const SIZE: usize> {
+    Heap(Box<[UnsafeCell<PacketBuffer<SIZE>>]>),
+    /// bound to a NUMA node, unmapped when the pool is dropped
+    Mapped(PageAlignedMemory),
+}
+
+impl<const SIZE: usize> PacketStorage<SIZE> {
+    #[inline]
+    fn get(&self, index: usize) -> &UnsafeCell<PacketBuffer<SIZE>> {
+        match self {
+            Self::Heap(packets) => &packets[index],
+            Self::Mapped(memory) => {
+                assert!(index < POOL_SIZE);
+                // Safety: new_on_node() sized the page aligned mapping for POOL_SIZE buffers
+                // and zeroed memory is a valid empty PacketBuffer
+                unsafe { &*(memory.as_ptr() as *const UnsafeCell<PacketBuffer<SIZE>>).add(index) }
+            }
+        }
+    }
+}
+
 /// lock-free packet pool using atomics
-pub struct PacketPool {
-    packets: Box<[UnsafeCell<PacketBuffer>; POOL_SIZE]>,
+pub struct PacketPool<const SIZE: usize = MAX_PACKET_SIZE> {
+    packets: PacketStorage<SIZE>,
     meta: Box<[UnsafeCell<PacketMeta>; POOL_SIZE]>,
     // bitset for free packets (1 = free, 0 = in use)
     free_mask: Box<[AtomicUsize; POOL_SIZE / 64]>,
     next_search: AtomicUsize,
+    allocations: AtomicU64,
+    releases: AtomicU64,
+    exhaustions: AtomicU64,
+    peak_in_use: AtomicU64,
 }
 
-impl PacketPool {
+impl<const SIZE: usize> PacketPool<SIZE> {
     pub fn new() -> &'static Self {
         let packets = Box::new([(); POOL_SIZE].map(|_| UnsafeCell::new(PacketBuffer {
-            data: [0u8; MAX_PACKET_SIZE],
+            data: [0u8; SIZE],
             len: 0,
         })));
 
+        Box::leak(Box::new(Self::with_packets(PacketStorage::Heap(packets))))
+    }
+
+    /// allocate the packet buffers on NUMA node `node`, e.g. NetworkDevice::numa_node() so
+    /// copies out of the UMEM stay on the NIC's node. the buffers are mmapped, bound to the
+    /// node with mbind and unmapped when the pool is dropped
+    pub fn new_on_node(node: u32) -> io::Result<Self> {
+        // PageAlignedMemory wants power of two sizes, round up to whole pages
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let bytes = mem::size_of::<PacketBuffer<SIZE>>() * POOL_SIZE;
+        let pages = bytes.div_ceil(page_size).next_power_of_two();
+        let memory = PageAlignedMemory::alloc_on_numa_node(page_size, pages, node)?;
+
+        Ok(Self::with_packets(PacketStorage::Mapped(memory)))
+    }
+
+    /// new_on_node() on the node of the CPU the calling thread runs on, pin the thread first
+    pub fn new_local() -> io::Result<Self> {
+        let mut cpu = 0u32;
+        let mut node = 0u32;
+        // Safety: getcpu only writes the two out params, the cache argument is unused
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_getcpu,
+                &mut cpu as *mut u32,
+                &mut node as *mut u32,
+                ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Self::new_on_node(node)
+    }
+
+    fn with_packets(packets: PacketStorage<SIZE>) -> Self {
         let meta = Box::new([(); POOL_SIZE].map(|_| UnsafeCell::new(PacketMeta {
             src_ip: [0; 4],
             src_port: 0,
@@ -97,17 +190,34 @@ impl PacketPool {
 
         let free_mask = Box::new([(); POOL_SIZE / 64].map(|_| AtomicUsize::new(!0)));
 
-        Box::leak(Box::new(Self {
+        Self {
             packets,
             meta,
             free_mask,
             next_search: AtomicUsize::new(0),
-        }))
+            allocations: AtomicU64::new(0),
+            releases: AtomicU64::new(0),
+            exhaustions: AtomicU64::new(0),
+            peak_in_use: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> PacketPoolStats {
+        // releases first so in_use can't underflow while other threads are racing us
+        let releases = self.releases.load(Ordering::Relaxed);
+        let allocations = self.allocations.load(Ordering::Relaxed);
+        PacketPoolStats {
+            allocations,
+            releases,
+            exhaustions: self.exhaustions.load(Ordering::Relaxed),
+            in_use: allocations.saturating_sub(releases),
+            peak_in_use: self.peak_in_use.load(Ordering::Relaxed),
+        }
     }
 
     /// acquire a packet from the pool (lock-free)
     #[inline]
-    pub fn acquire(&'static self) -> Option<(&'static mut PacketBuffer, &'static mut PacketMeta, usize)> {
+    pub fn acquire(&self) -> Option<(&mut PacketBuffer<SIZE>, &mut PacketMeta, usize)> {
         let start_idx = self.next_search.load(Ordering::Relaxed) % (POOL_SIZE / 64);
 
         for offset in 0..POOL_SIZE / 64 {
@@ -138,9 +248,13 @@ impl PacketPool {
                     let packet_idx = idx * 64 + bit_pos;
                     self.next_search.store((idx + 1) % (POOL_SIZE / 64), Ordering::Relaxed);
 
+                    let allocations = self.allocations.fetch_add(1, Ordering::Relaxed) + 1;
+                    let in_use = allocations.saturating_sub(self.releases.load(Ordering::Relaxed));
+                    self.peak_in_use.fetch_max(in_use, Ordering::Relaxed);
+
                     // safe because we have exclusive access via atomic bit
                     unsafe {
-                        let packet = &mut *self.packets[packet_idx].get();
+                        let packet = &mut *self.packets.get(packet_idx).get();
                         let meta = &mut *self.meta[packet_idx].get();
                         return Some((packet, meta, packet_idx));
                     }
@@ -148,38 +262,291 @@ impl PacketPool {
             }
         }
 
-        None // pool exhausted
+        // pool exhausted
+        self.exhaustions.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// acquire with automatic return on drop
+    #[inline]
+    pub fn acquire_ref(&self, data: &[u8], meta: PacketMeta) -> Option<PacketRef<'_>> {
+        let mut packet = self.acquire_len(data.len(), meta)?;
+        let len = packet.data.len();
+        packet.payload_mut().copy_from_slice(&data[..len]);
+        Some(packet)
+    }
+
+    /// acquire a `len` byte packet (truncated to SIZE) for the caller to fill in through
+    /// PacketRef::payload_mut()
+    #[inline]
+    pub fn acquire_len(&self, len: usize, meta: PacketMeta) -> Option<PacketRef<'_>> {
+        let (_, _, index) = self.acquire()?;
+        // Safety: acquire() just handed us the slot
+        Some(unsafe { self.packet_ref(index, len, meta, self) })
+    }
+
+    /// claim up to `count` free slots into `out`, taking as many as possible from a bitset word
+    /// with a single compare-exchange. returns how many were claimed
+    pub fn acquire_batch(&self, count: usize, out: &mut VecDeque<usize>) -> usize {
+        let words = POOL_SIZE / 64;
+        let start_idx = self.next_search.load(Ordering::Relaxed) % words;
+        let mut claimed = 0;
+
+        for offset in 0..words {
+            if claimed == count {
+                break;
+            }
+            let idx = (start_idx + offset) % words;
+            let mask = &self.free_mask[idx];
+
+            let mut current = mask.load(Ordering::Acquire);
+            while current != 0 {
+                // lowest count - claimed set bits
+                let mut take = 0usize;
+                let mut bits = current;
+                for _ in claimed..count {
+                    if bits == 0 {
+                        break;
+                    }
+                    take |= bits & bits.wrapping_neg();
+                    bits &= bits - 1;
+                }
+
+                match mask.compare_exchange_weak(
+                    current,
+                    current & !take,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let mut take = take;
+                        while take != 0 {
+                            out.push_back(idx * 64 + take.trailing_zeros() as usize);
+                            take &= take - 1;
+                            claimed += 1;
+                        }
+                        self.next_search.store((idx + 1) % words, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+
+        if claimed < count {
+            self.exhaustions.fetch_add(1, Ordering::Relaxed);
+        }
+        let allocations =
+            self.allocations.fetch_add(claimed as u64, Ordering::Relaxed) + claimed as u64;
+        let in_use = allocations.saturating_sub(self.releases.load(Ordering::Relaxed));
+        self.peak_in_use.fetch_max(in_use, Ordering::Relaxed);
+        claimed
     }
 
+    /// return slots claimed with acquire_batch(), one atomic per bitset word touched
+    pub fn release_batch(&self, indices: impl IntoIterator<Item = usize>) {
+        let mut word = usize::MAX;
+        let mut bits = 0usize;
+        let mut released = 0;
+        for index in indices {
+            if index / 64 != word {
+                if bits != 0 {
+                    self.free_mask[word].fetch_or(bits, Ordering::Release);
+                }
+                word = index / 64;
+                bits = 0;
+            }
+            bits |= 1 << (index % 64);
+            released += 1;
+        }
+        if bits != 0 {
+            self.free_mask[word].fetch_or(bits, Ordering::Release);
+        }
+        self.releases.fetch_add(released, Ordering::Relaxed);
+    }
+
+    /// # safety
+    /// the caller must own slot `index`, `releaser` gets it back when the ref is dropped
+    unsafe fn packet_ref<'a>(
+        &'a self,
+        index: usize,
+        len: usize,
+        meta: PacketMeta,
+        releaser: &'a dyn PacketRelease,
+    ) -> PacketRef<'a> {
+        let buffer = unsafe { &mut *self.packets.get(index).get() };
+        buffer.len = len.min(SIZE);
+        unsafe { *self.meta[index].get() = meta };
+
+        PacketRef {
+            data: &mut buffer.data[..buffer.len],
+            meta,
+            pool: releaser,
+            index,
+        }
+    }
+}
+
+impl<const SIZE: usize> PacketRelease for PacketPool<SIZE> {
     /// release a packet back to the pool
     #[inline]
     fn release(&self, index: usize) {
         let word_idx = index / 64;
         let bit_idx = index % 64;
         self.free_mask[word_idx].fetch_or(1 << bit_idx, Ordering::Release);
+        self.releases.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    /// per thread free slots of every PacketPool used through a ThreadLocalPool
+    static LOCAL_CACHES: RefCell<Vec<LocalCache>> = const { RefCell::new(Vec::new()) };
+}
+
+/// one thread's free slots of `pool`, handed back to it when the thread exits
+struct LocalCache {
+    pool: &'static PacketPool,
+    free: VecDeque<usize>,
+}
+
+impl Drop for LocalCache {
+    fn drop(&mut self) {
+        self.pool.release_batch(self.free.drain(..));
+    }
+}
+
+/// per thread cache of free slots in front of a PacketPool
+///
+/// acquire() and drop are plain VecDeque operations on the calling thread's cache, the shared
+/// bitset is only touched to move THREAD_BATCH slots at a time. slots sitting in a cache count
+/// as in use in PacketPool::stats() until their thread exits and returns them.
+pub struct ThreadLocalPool {
+    pool: &'static PacketPool,
+}
+
+impl ThreadLocalPool {
+    /// slots moved between the cache and the global pool at a time
+    const THREAD_BATCH: usize = 32;
+    /// cache size kept after handing slots back
+    const THREAD_CACHE: usize = 64;
+    /// cache size that triggers handing slots back
+    const THREAD_CACHE_MAX: usize = 128;
+
+    pub fn new(pool: &'static PacketPool) -> Self {
+        Self { pool }
+    }
+
+    /// run `f` on the calling thread's cache, None once the thread's caches were torn down
+    fn with_cache<R>(&self, f: impl FnOnce(&mut VecDeque<usize>) -> R) -> Option<R> {
+        LOCAL_CACHES
+            .try_with(|caches| {
+                let mut caches = caches.borrow_mut();
+                let position = match caches.iter().position(|c| ptr::eq(c.pool, self.pool)) {
+                    Some(position) => position,
+                    None => {
+                        caches.push(LocalCache {
+                            pool: self.pool,
+                            free: VecDeque::with_capacity(Self::THREAD_CACHE_MAX + 1),
+                        });
+                        caches.len() - 1
+                    }
+                };
+                f(&mut caches[position].free)
+            })
+            .ok()
     }
 
-    /// acquire with automatic return on drop
     #[inline]
-    pub fn acquire_ref(&'static self, data: &[u8], meta: PacketMeta) -> Option<PacketRef> {
-        let (buffer, meta_slot, index) = self.acquire()?;
-        buffer.set_data(data);
-        *meta_slot = meta;
-
-        Some(PacketRef {
-            buffer,
-            meta: *meta_slot,
-            pool: self,
-            index,
-        })
+    pub fn acquire_len(&self, len: usize, meta: PacketMeta) -> Option<PacketRef<'_>> {
+        let cached = self.with_cache(|cache| {
+            if cache.is_empty() {
+                self.pool.acquire_batch(Self::THREAD_BATCH, cache);
+            }
+            cache.pop_front()
+        });
+        let index = match cached {
+            Some(index) => index?,
+            // the thread is exiting, go straight to the pool
+            None => self.pool.acquire()?.2,
+        };
+        // Safety: the slot came out of our cache or the pool, nobody else has it
+        Some(unsafe { self.pool.packet_ref(index, len, meta, self) })
+    }
+
+    #[inline]
+    pub fn acquire_ref(&self, data: &[u8], meta: PacketMeta) -> Option<PacketRef<'_>> {
+        let mut packet = self.acquire_len(data.len(), meta)?;
+        let len = packet.data.len();
+        packet.payload_mut().copy_from_slice(&data[..len]);
+        Some(packet)
+    }
+}
+
+impl PacketRelease for ThreadLocalPool {
+    #[inline]
+    fn release(&self, index: usize) {
+        let cached = self.with_cache(|cache| {
+            cache.push_back(index);
+            if cache.len() > Self::THREAD_CACHE_MAX {
+                let excess = cache.len() - Self::THREAD_CACHE;
+                self.pool.release_batch(cache.drain(..excess));
+            }
+        });
+        if cached.is_none() {
+            self.pool.release(index);
+        }
+    }
+}
+
+/// small, medium and large PacketPools so typical ~1245 byte shreds don't each take a 9000
+/// byte jumbo buffer
+pub struct TieredPacketPool {
+    small: &'static PacketPool<SMALL_PACKET_SIZE>,
+    medium: &'static PacketPool<MEDIUM_PACKET_SIZE>,
+    large: &'static PacketPool<MAX_PACKET_SIZE>,
+}
+
+impl TieredPacketPool {
+    pub fn new() -> Self {
+        Self {
+            small: PacketPool::new(),
+            medium: PacketPool::new(),
+            large: PacketPool::new(),
+        }
+    }
+
+    /// acquire a `len` byte packet from the smallest tier that fits, moving up a tier when one
+    /// is exhausted. the packet goes back to its tier on drop
+    #[inline]
+    pub fn acquire_for_size(&self, len: usize, meta: PacketMeta) -> Option<PacketRef<'_>> {
+        if len > MAX_PACKET_SIZE {
+            return None;
+        }
+        (len <= SMALL_PACKET_SIZE)
+            .then(|| self.small.acquire_len(len, meta))
+            .flatten()
+            .or_else(|| {
+                (len <= MEDIUM_PACKET_SIZE)
+                    .then(|| self.medium.acquire_len(len, meta))
+                    .flatten()
+            })
+            .or_else(|| self.large.acquire_len(len, meta))
+    }
+
+    /// acquire_for_size() and copy `data` in
+    #[inline]
+    pub fn acquire_ref(&self, data: &[u8], meta: PacketMeta) -> Option<PacketRef<'_>> {
+        let mut packet = self.acquire_for_size(data.len(), meta)?;
+        packet.payload_mut().copy_from_slice(data);
+        Some(packet)
     }
 }
 
 // safety: PacketPool is Send + Sync because:
 // - we only access packets when we have exclusive ownership via atomic bit
 // - the atomic bitset ensures only one thread can access a packet at a time
-unsafe impl Send for PacketPool {}
-unsafe impl Sync for PacketPool {}
+unsafe impl<const SIZE: usize> Send for PacketPool<SIZE> {}
+unsafe impl<const SIZE: usize> Sync for PacketPool<SIZE> {}
 
 // global packet pool instance
 // note: to use this, call PacketPool::new() once at startup and store the reference
@@ -1,19 +1,55 @@
 extern crate agave_xdp;
 extern crate clap;
 extern crate caps;
+extern crate ctrlc;
+
+mod deshred;
+mod metrics;
+mod packet_pool;
+mod shred_processor;
 
 use {
     agave_xdp::{
-        device::{NetworkDevice, QueueId},
-        netlink::MacAddress,
-        relay_loop::relay_loop,
+        device::{enter_netns, open_netns, NetworkDevice, QueueId},
+        flow::FlowTable,
+        netlink::{MacAddress, NetworkMonitor},
+        packet_filter::{
+            DeduplicatorFilter, MinPayloadLengthFilter, PacketFilter, PacketFilterChain,
+            SlotWindowFilter, SourceValidator, StrictRpfFilter, UdpOnlyFilter,
+        },
+        disruptor::Disruptor,
+        packet_event::PacketEventZeroCopy,
+        relay_loop::{
+            relay_loop, MultiDestRelayConfig, OverflowPolicy, RateLimit, RelayConfig, SchedPolicy,
+            PACKET_EVENT_RING_SIZE,
+        },
+        relay_watchdog::RelayWatchdog,
+        route::Router,
+        rx_loop::RxStats,
+        tx_loop::TxStats,
         set_cpu_affinity,
     },
     caps::{CapSet, Capability},
+    metrics::PrometheusExporter,
+    shred_processor::{decoder_worker, ShredStats, ValidatorFilter},
     clap::Parser,
-    std::net::Ipv4Addr,
+    solana_client::rpc_client::RpcClient,
+    solana_sdk::pubkey::Pubkey,
+    std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+        os::fd::AsRawFd,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
 };
 
+// slots of leader schedule loaded with --rpc-url, the most getSlotLeaders returns
+const LEADER_SCHEDULE_SLOTS: u64 = 5000;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about = "relay", long_about = None)]
 struct Opt {
@@ -29,15 +65,141 @@ struct Opt {
     #[arg(long)]
     dest_mac: Option<String>,
 
+    /// forward a copy of every packet to IP:PORT@MAC, can be given multiple times
+    #[arg(long, value_name = "IP:PORT@MAC")]
+    fanout: Vec<String>,
+
+    /// when fanout copies overflow the tx ring drop the oldest queued ones instead of the newest
+    #[arg(long)]
+    fanout_drop_oldest: bool,
+
+    /// limit each fanout destination to this many bytes per second
+    #[arg(long)]
+    fanout_rate_limit: Option<u64>,
+
+    /// burst allowed per fanout destination on top of --fanout-rate-limit, in bytes
+    #[arg(long, default_value = "1048576")]
+    fanout_burst: u64,
+
+    /// drop packets with less L4 payload than this, 0 disables the check
+    #[arg(long, default_value = "400")]
+    min_payload_len: usize,
+
+    /// only relay UDP, drop TCP
+    #[arg(long)]
+    udp_only: bool,
+
+    /// only relay packets from this validator IP, can be given multiple times
+    #[arg(long)]
+    validator: Vec<Ipv4Addr>,
+
+    /// only relay packets from the validator IPs listed in this file, one per line
+    #[arg(long)]
+    validators_file: Option<PathBuf>,
+
+    /// drop packets whose source IP isn't routed back out of --interface
+    #[arg(long)]
+    strict_rpf: bool,
+
+    /// drop shreds more than this many slots away from the newest deshredded slot, needs
+    /// --decoder-threads and --leader
+    #[arg(long)]
+    slot_window: Option<u64>,
+
+    /// only decode shreds signed by this leader, may be given several times
+    #[arg(long)]
+    leader: Vec<Pubkey>,
+
+    /// load the leader schedule from this RPC node so --leader costs one signature check per
+    /// shred
+    #[arg(long)]
+    rpc_url: Option<String>,
+
+    /// drop shreds already received within this many slots
+    #[arg(long)]
+    dedup_slot_window: Option<u64>,
+
+    /// decapsulate VXLAN (UDP port 4789) and relay the inner packet
+    #[arg(long)]
+    vxlan_strip: bool,
+
+    /// write received frames to this pcap file, or pcapng if it ends in .pcapng
+    #[arg(long)]
+    pcap_output: Option<PathBuf>,
+
+    /// stop capturing after this many packets
+    #[arg(long, default_value = "100000")]
+    pcap_max_packets: usize,
+
     #[arg(short, long)]
     zero_copy: bool,
 
     #[arg(long, default_value = "0")]
     queue: u64,
 
+    /// interrupt on every received packet right away, no coalescing or adaptive moderation
+    #[arg(long)]
+    low_latency: bool,
+
+    /// resize the NIC's rx ring before binding, defaults to the current size
+    #[arg(long)]
+    rx_ring_size: Option<u32>,
+
+    /// resize the NIC's tx ring before binding, defaults to the current size
+    #[arg(long)]
+    tx_ring_size: Option<u32>,
+
     #[arg(long, default_value = "2")]
     cpu: usize,
 
+    /// warn if the queue's interrupt isn't pinned to --cpu
+    #[arg(long)]
+    check_irq_affinity: bool,
+
+    /// NUMA node for UMEM, defaults to the NIC's node
+    #[arg(long)]
+    numa_node: Option<u32>,
+
+    /// decode shreds from the relayed packets on this many threads, 0 disables decoding
+    #[arg(long, default_value = "0")]
+    decoder_threads: usize,
+
+    /// busy-poll the NIC for up to N microseconds per syscall, 0 disables busy-polling
+    #[arg(long)]
+    busy_poll_micros: Option<u32>,
+
+    /// refill the fill ring mid-poll when the kernel has fewer frames than this, defaults to a
+    /// quarter of the rx ring
+    #[arg(long)]
+    fill_low_watermark: Option<usize>,
+
+    /// restart the relay when a tx frame hasn't completed after this many milliseconds, defaults
+    /// to 1000
+    #[arg(long)]
+    tx_timeout_ms: Option<u64>,
+
+    /// run the relay thread under SCHED_DEADLINE instead of SCHED_FIFO, needs the relay CPU in an
+    /// exclusive cpuset
+    #[arg(long, value_name = "RUNTIME_NS:DEADLINE_NS:PERIOD_NS")]
+    sched_deadline: Option<String>,
+
+    /// serve shred metrics for prometheus on this port
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// exit when the relay loop makes no progress for this many seconds, an idle link alone
+    /// doesn't count
+    #[arg(long)]
+    stall_timeout_secs: Option<u64>,
+
+    /// print the busiest flows every this many seconds, flows idle for longer are forgotten
+    #[arg(long)]
+    flow_stats_secs: Option<u64>,
+
+    /// run inside this network namespace, e.g. /var/run/netns/NAME or /proc/PID/ns/net
+    #[arg(long)]
+    netns: Option<PathBuf>,
+
     // #[arg(long)]
     // decoder_cpu: Option<usize>,
 }
@@ -57,12 +219,66 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // everything below, the threads we spawn included, runs inside the namespace
+    if let Some(path) = &opt.netns {
+        if let Err(e) = caps::raise(None, CapSet::Effective, Capability::CAP_SYS_ADMIN) {
+            eprintln!("failed to raise capability CAP_SYS_ADMIN for --netns: {}", e);
+            std::process::exit(1);
+        }
+        let netns = open_netns(path)?;
+        enter_netns(netns.as_raw_fd())?;
+        println!("entered network namespace {}", path.display());
+    }
+
     set_cpu_affinity([opt.cpu]).unwrap();
 
     let dev = NetworkDevice::new(&opt.interface)?;
 
+    // catch a bad --queue before we try to bind to it
+    match dev.rx_queue_count() {
+        Ok(count) if opt.queue >= count as u64 => {
+            eprintln!("error: --queue {} but {} only has {} rx queues", opt.queue, opt.interface, count);
+            std::process::exit(1);
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("could not query rx queue count for {}: {}", opt.interface, e),
+    }
+
+    // the driver usually resets the interface for this, do it before anything binds to it
+    if opt.rx_ring_size.is_some() || opt.tx_ring_size.is_some() {
+        let current = NetworkDevice::ring_sizes(dev.name())?;
+        let rx = opt.rx_ring_size.unwrap_or(current.rx as u32);
+        let tx = opt.tx_ring_size.unwrap_or(current.tx as u32);
+        if let Err(e) = dev.set_ring_sizes(rx, tx) {
+            eprintln!(
+                "failed to set ring sizes of {} to rx {} tx {}: {}",
+                opt.interface, rx, tx, e
+            );
+            if let Ok(max) = dev.max_ring_sizes() {
+                eprintln!("{} supports up to rx {} tx {}", opt.interface, max.rx, max.tx);
+            }
+            std::process::exit(1);
+        }
+        println!("ring sizes of {} set to rx {} tx {}", opt.interface, rx, tx);
+    }
+
+    if opt.low_latency {
+        let params = match dev.coalesce() {
+            Ok(params) => params.low_latency(),
+            Err(e) => {
+                eprintln!("failed to read interrupt coalescing of {}: {}", opt.interface, e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = dev.set_coalesce(params) {
+            eprintln!("failed to set interrupt coalescing of {}: {}", opt.interface, e);
+            std::process::exit(1);
+        }
+        println!("interrupt coalescing of {}: {:?}", opt.interface, params);
+    }
+
     let (dest_ip, dest_port) = match (opt.dest_ip, opt.dest_port) {
-        (Some(ip), Some(port)) => (Some(ip.parse::<Ipv4Addr>()?), Some(port)),
+        (Some(ip), Some(port)) => (Some(ip.parse::<IpAddr>()?), Some(port)),
         (None, None) => (None, None),
         _ => {
             eprintln!("error: both --dest-ip and --dest-port must be specified together, or neither");
@@ -70,38 +286,152 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let dest_mac = if let Some(mac_str) = opt.dest_mac {
-        let parts: Vec<&str> = mac_str.split(':').collect();
-        if parts.len() != 6 {
-            eprintln!("invalid MAC address format. use: aa:bb:cc:dd:ee:ff");
+    let dest_mac = match opt.dest_mac.as_deref().map(str::parse::<MacAddress>) {
+        Some(Ok(mac)) => Some(mac),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
             std::process::exit(1);
         }
-        let mac_bytes: Result<Vec<u8>, _> = parts
-            .iter()
-            .map(|p| u8::from_str_radix(p, 16))
-            .collect();
-        match mac_bytes {
-            Ok(bytes) => Some(MacAddress([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]])),
-            Err(_) => {
-                eprintln!("invalid MAC address format. use hex: aa:bb:cc:dd:ee:ff");
+        None => None,
+    };
+
+    let multi_dest = if opt.fanout.is_empty() {
+        None
+    } else {
+        let mut destinations = Vec::with_capacity(opt.fanout.len());
+        for dest in &opt.fanout {
+            let Some((addr, mac)) = dest.split_once('@') else {
+                eprintln!("error: --fanout {} must be IP:PORT@MAC", dest);
                 std::process::exit(1);
-            }
+            };
+            let addr = addr.parse::<SocketAddrV4>()?;
+            destinations.push((*addr.ip(), addr.port(), mac.parse::<MacAddress>()?));
         }
-    } else {
-        None
+        let overflow = if opt.fanout_drop_oldest {
+            OverflowPolicy::DropOldest
+        } else {
+            OverflowPolicy::DropNewest
+        };
+        let rate_limit = opt.fanout_rate_limit.map(|bytes_per_sec| RateLimit {
+            bytes_per_sec,
+            burst_bytes: opt.fanout_burst,
+        });
+        Some(MultiDestRelayConfig {
+            destinations,
+            overflow,
+            rate_limit,
+        })
     };
 
-    if let (Some(ip), Some(port)) = (dest_ip, dest_port) {
-        println!("starting on {} forwarding to {}:{}", opt.interface, ip, port);
+    let sched_policy = match opt.sched_deadline.as_deref() {
+        Some(budget) => {
+            let parts = budget
+                .split(':')
+                .map(str::parse::<u64>)
+                .collect::<Result<Vec<_>, _>>()?;
+            let [runtime_ns, deadline_ns, period_ns] = parts[..] else {
+                eprintln!("error: --sched-deadline {} must be RUNTIME_NS:DEADLINE_NS:PERIOD_NS", budget);
+                std::process::exit(1);
+            };
+            Some(SchedPolicy::Deadline {
+                runtime_ns,
+                deadline_ns,
+                period_ns,
+            })
+        }
+        None => None,
+    };
+
+    // newest slot the decoders deshredded
+    let current_slot = Arc::new(AtomicU64::new(0));
+    let mut filter = PacketFilterChain::new();
+    if let Some(window) = opt.slot_window {
+        // the slot only advances from shreds the decoders verified
+        if opt.decoder_threads == 0 || opt.leader.is_empty() {
+            eprintln!(
+                "error: --slot-window needs --decoder-threads and --leader to track the slot"
+            );
+            std::process::exit(1);
+        }
+        filter.push(SlotWindowFilter::new(Arc::clone(&current_slot), window, window));
+    }
+    if opt.udp_only {
+        filter.push(UdpOnlyFilter);
+    }
+    if opt.min_payload_len > 0 {
+        filter.push(MinPayloadLengthFilter(opt.min_payload_len));
+    }
+    if opt.validators_file.is_some() || !opt.validator.is_empty() {
+        let mut validators = match &opt.validators_file {
+            Some(path) => SourceValidator::from_file(path)?,
+            None => SourceValidator::default(),
+        };
+        for ip in &opt.validator {
+            validators.insert(*ip);
+        }
+        println!("accepting packets from {} validators", validators.len());
+        filter.push(validators);
+    }
+    let stats = Arc::new(RxStats::new());
+    if opt.strict_rpf {
+        filter.push(StrictRpfFilter::new(
+            Router::new()?,
+            dev.if_index(),
+            Arc::clone(&stats),
+        ));
+    }
+    // last, so only shreds that passed every other filter take up table space
+    if let Some(slot_window) = opt.dedup_slot_window {
+        filter.push(DeduplicatorFilter::new(slot_window, Arc::clone(&stats)));
+    }
+    let filter = (!filter.is_empty()).then(|| Box::new(filter) as Box<dyn PacketFilter + Send>);
+
+    let pcap = opt
+        .pcap_output
+        .clone()
+        .map(|path| (path, opt.pcap_max_packets));
+
+    if let Some(multi) = &multi_dest {
+        println!("starting on {} fanning out to:", opt.interface);
+        for (ip, port, mac) in &multi.destinations {
+            println!("  {}:{} ({})", ip, port, mac);
+        }
+    } else if let (Some(ip), Some(port)) = (dest_ip, dest_port) {
+        println!(
+            "starting on {} forwarding to {}",
+            opt.interface,
+            SocketAddr::new(ip, port)
+        );
         if let Some(ref mac) = dest_mac {
-            println!("destination MAC: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-                mac.0[0], mac.0[1], mac.0[2], mac.0[3], mac.0[4], mac.0[5]);
+            println!("destination MAC: {}", mac);
         }
     } else {
         println!("starting on {}", opt.interface);
     }
     println!("running on CPU {}", opt.cpu);
-    println!("zero-copy mode: {}", opt.zero_copy);
+    if opt.check_irq_affinity {
+        match dev.queue_irq_affinities() {
+            Ok(affinities) => {
+                for (queue, cpus) in affinities.iter().filter(|(q, _)| *q as u64 == opt.queue) {
+                    if !cpus.contains(opt.cpu) {
+                        eprintln!(
+                            "warning: queue {} interrupt is pinned to CPUs {:?}, relay runs on CPU {}",
+                            queue,
+                            cpus.iter().collect::<Vec<_>>(),
+                            opt.cpu
+                        );
+                    }
+                }
+            }
+            Err(e) => eprintln!("failed to read IRQ affinities: {}", e),
+        }
+    }
+    // the mode in use is logged once the socket is created, drivers without zero-copy support
+    // fall back to copy mode
+    println!("zero-copy requested: {}", opt.zero_copy);
+    if let Some(node) = opt.numa_node.or_else(|| dev.numa_node()) {
+        println!("UMEM on NUMA node {}", node);
+    }
 
     // if let Some(decoder_cpu) = opt.decoder_cpu {
     //     println!("data shred worker on CPU {}", decoder_cpu);
@@ -109,6 +439,122 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     println!("no shred processing worker");
     // }
 
+    let shred_stats = Arc::new(ShredStats::new());
+    if let Some(port) = opt.metrics_port {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        PrometheusExporter::new(Arc::clone(&shred_stats)).spawn(addr)?;
+        println!("serving metrics on http://{}/metrics", addr);
+    }
+
+    let validator_filter = if opt.leader.is_empty() {
+        None
+    } else {
+        let mut validator_filter =
+            ValidatorFilter::new(opt.leader.iter().map(|pubkey| pubkey.to_bytes()));
+        // slots past the loaded schedule fall back to trying every --leader
+        if let Some(rpc_url) = &opt.rpc_url {
+            let rpc = RpcClient::new(rpc_url);
+            let slot = rpc.get_slot()?;
+            let leaders = rpc.get_slot_leaders(slot, LEADER_SCHEDULE_SLOTS)?;
+            println!("loaded the leaders of slots {slot}..{}", slot + leaders.len() as u64);
+            validator_filter.set_leaders(slot, leaders.iter().map(|pubkey| pubkey.to_bytes()));
+        }
+        Some(Arc::new(validator_filter))
+    };
+
+    // every decoder sees every packet event and decodes its share of the slots
+    let events = if opt.decoder_threads > 0 {
+        let (producer, consumers) = Disruptor::<_, PACKET_EVENT_RING_SIZE>::new(
+            opt.decoder_threads,
+            PacketEventZeroCopy::factory,
+        );
+        let decoder_threads = opt.decoder_threads;
+        for (i, consumer) in consumers.into_iter().enumerate() {
+            let stats = Arc::clone(&shred_stats);
+            let validator_filter = validator_filter.clone();
+            let current_slot = Arc::clone(&current_slot);
+            std::thread::Builder::new()
+                .name(format!("shredDecoder{i}"))
+                .spawn(move || {
+                    decoder_worker(
+                        consumer,
+                        stats,
+                        None,
+                        validator_filter,
+                        None,
+                        Some(current_slot),
+                        i,
+                        decoder_threads,
+                    )
+                })?;
+        }
+        Some(producer)
+    } else {
+        None
+    };
+
+    let exit = Arc::new(AtomicBool::new(false));
+    {
+        let exit = Arc::clone(&exit);
+        ctrlc::set_handler(move || exit.store(true, Ordering::Relaxed))?;
+    }
+
+    // the relay tears down and sets itself up again when the interface goes down and comes back
+    let monitor = NetworkMonitor::spawn(dev.if_index())?;
+
+    let flows = opt.flow_stats_secs.map(|secs| {
+        let flows = Arc::new(Mutex::new(FlowTable::new()));
+        let interval = Duration::from_secs(secs);
+        let exit = Arc::clone(&exit);
+        let table = Arc::clone(&flows);
+        std::thread::Builder::new()
+            .name("flowStats".to_string())
+            .spawn(move || {
+                while !exit.load(Ordering::Relaxed) {
+                    std::thread::sleep(interval);
+                    // don't hold up the relay loop while printing
+                    let (active, evicted, overflow, top) = {
+                        let mut table = table.lock().unwrap();
+                        let evicted = table.evict_idle(interval);
+                        (table.len(), evicted, table.overflow_packets(), table.top_flows(10))
+                    };
+                    println!(
+                        "{} active flows, {} idle evicted, {} packets of untracked flows",
+                        active, evicted, overflow
+                    );
+                    for (key, stats) in top {
+                        println!(
+                            "  {}:{} -> {}:{} proto {}: {} packets {} bytes",
+                            Ipv4Addr::from(key.src_ip),
+                            key.src_port,
+                            Ipv4Addr::from(key.dst_ip),
+                            key.dst_port,
+                            key.proto,
+                            stats.packets,
+                            stats.bytes
+                        );
+                    }
+                }
+            })
+            .map(|_| flows)
+    })
+    .transpose()?;
+
+    let _watchdog = opt
+        .stall_timeout_secs
+        .map(|secs| {
+            let exit = Arc::clone(&exit);
+            RelayWatchdog::spawn(
+                Arc::clone(&stats),
+                Duration::from_secs(secs),
+                Box::new(move || {
+                    eprintln!("relay loop stalled, exiting");
+                    exit.store(true, Ordering::Relaxed);
+                }),
+            )
+        })
+        .transpose()?;
+
     relay_loop(
         opt.cpu,
         &dev,
@@ -117,8 +563,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         dest_ip,
         dest_port,
         dest_mac,
+        &RelayConfig {
+            sched_policy,
+            numa_node: opt.numa_node,
+            busy_poll_micros: opt.busy_poll_micros,
+            fill_low_watermark: opt.fill_low_watermark,
+            tx_timeout: opt.tx_timeout_ms.map(Duration::from_millis),
+            vxlan_strip: opt.vxlan_strip,
+            multi_dest,
+            filter,
+            pcap,
+            flows,
+        },
+        events,
+        stats,
+        Arc::new(TxStats::new()),
+        Some(monitor.interface_down()),
+        exit,
         // opt.decoder_cpu
-    );
+    )?;
 
     Ok(())
 }
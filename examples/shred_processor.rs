@@ -10,15 +10,21 @@
 //   0x4f ( 4B): fec_set_index
 
 use {
-    crate::deshred::DeshredManager,
-    solana_ledger::shred::{Shred, ShredType},
-    solana_sdk::clock::Slot,
+    crate::{deshred::DeshredManager, packet_pool::PacketPool},
+    agave_xdp::{latency::LatencyHistogram, relay_loop::PacketEventConsumer},
+    solana_ledger::shred::{wire, Shred, ShredType},
+    solana_sdk::{
+        clock::Slot, hash::Hash, signature::Signature, transaction::VersionedTransaction,
+    },
     std::{
-        sync::{atomic::{AtomicUsize, Ordering}, Mutex},
-        time::SystemTime,
+        collections::{HashMap, HashSet, VecDeque},
+        sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Mutex},
+        time::{Duration, Instant, SystemTime},
     },
 };
 
+pub use agave_xdp::packet_filter::extract_slot_fast;
+
 /// trait for deshred managers (allows both locked and lock-free implementations)
 pub trait DeshredTrait {
     fn add_shred(&mut self, shred: Shred) -> Option<(Slot, Vec<solana_entry::entry::Entry>, Vec<u8>)>;
@@ -31,17 +37,6 @@ impl DeshredTrait for Mutex<DeshredManager> {
     }
 }
 
-/// extract slot number from raw shred bytes (offset 65-72, little-endian)
-/// just 8-byte read, no parsing. low latency for filtering future slots
-#[inline]
-pub fn extract_slot_fast(payload: &[u8]) -> Option<Slot> {
-    if payload.len() < 73 {
-        return None;
-    }
-    let bytes: [u8; 8] = payload[65..73].try_into().ok()?;
-    Some(u64::from_le_bytes(bytes))
-}
-
 /// shred type detection without full deserialization
 /// parses just the variant byte at offset 0x40
 ///
@@ -80,6 +75,179 @@ pub fn parse_shred_type(data: &[u8]) -> Option<ShredType> {
     }
 }
 
+/// borrowed view of a shred's headers, read straight from the packet (e.g. a UMEM frame)
+///
+/// Shred::new_from_serialized_shred needs an owned payload, so every shred costs a 1.2KB copy.
+/// ShredRef answers the questions the filtering and routing path asks without that copy, only
+/// shreds that make it to the deshredder pay for it through to_owned().
+///
+/// data shred header (5 bytes, after the common header):
+///   0x53 ( 2B): parent_offset
+///   0x55 ( 1B): flags
+///   0x56 ( 2B): size
+#[derive(Clone, Copy, Debug)]
+pub struct ShredRef<'a> {
+    payload: &'a [u8],
+    shred_type: ShredType,
+}
+
+impl<'a> ShredRef<'a> {
+    const SIGNATURE_SIZE: usize = 64;
+    const SLOT_OFFSET: usize = 0x41;
+    const INDEX_OFFSET: usize = 0x49;
+    const FEC_SET_INDEX_OFFSET: usize = 0x4f;
+    const DATA_FLAGS_OFFSET: usize = 0x55;
+    const DATA_HEADER_END: usize = 0x58;
+    // ShredFlags::DATA_COMPLETE_SHRED, also set by LAST_SHRED_IN_SLOT
+    const DATA_COMPLETE_SHRED: u8 = 0b0100_0000;
+    // ShredFlags::LAST_SHRED_IN_SLOT
+    const LAST_SHRED_IN_SLOT: u8 = 0b1100_0000;
+
+    /// None if `payload` is too short or doesn't carry a merkle shred variant
+    #[inline]
+    pub fn new(payload: &'a [u8]) -> Option<Self> {
+        let shred_type = parse_shred_type(payload)?;
+        if shred_type == ShredType::Data && payload.len() < Self::DATA_HEADER_END {
+            return None;
+        }
+        Some(Self {
+            payload,
+            shred_type,
+        })
+    }
+
+    #[inline]
+    pub fn slot(&self) -> Slot {
+        let bytes = &self.payload[Self::SLOT_OFFSET..Self::SLOT_OFFSET + 8];
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[inline]
+    pub fn index(&self) -> u32 {
+        let bytes = &self.payload[Self::INDEX_OFFSET..Self::INDEX_OFFSET + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[inline]
+    pub fn fec_set_index(&self) -> u32 {
+        let bytes = &self.payload[Self::FEC_SET_INDEX_OFFSET..Self::FEC_SET_INDEX_OFFSET + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    #[inline]
+    pub fn signature(&self) -> &'a [u8; 64] {
+        self.payload[..Self::SIGNATURE_SIZE].try_into().unwrap()
+    }
+
+    #[inline]
+    pub fn shred_type(&self) -> ShredType {
+        self.shred_type
+    }
+
+    /// last data shred of an entry batch, always false for code shreds
+    #[inline]
+    pub fn data_complete(&self) -> bool {
+        self.shred_type == ShredType::Data
+            && self.payload[Self::DATA_FLAGS_OFFSET] & Self::DATA_COMPLETE_SHRED != 0
+    }
+
+    /// last data shred of the slot, always false for code shreds
+    #[inline]
+    pub fn last_in_slot(&self) -> bool {
+        self.shred_type == ShredType::Data
+            && self.payload[Self::DATA_FLAGS_OFFSET] & Self::LAST_SHRED_IN_SLOT
+                == Self::LAST_SHRED_IN_SLOT
+    }
+
+    /// copy the payload into a full Shred, this is the one allocation per shred
+    pub fn to_owned(self) -> Result<Shred, solana_ledger::shred::Error> {
+        Shred::new_from_serialized_shred(self.payload.to_vec())
+    }
+
+    #[inline]
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+
+/// borrowed shred view over a UDP payload, None if it isn't a merkle shred
+#[inline]
+pub fn decode_shred_from_bytes(bytes: &[u8]) -> Option<ShredRef<'_>> {
+    ShredRef::new(bytes)
+}
+
+/// drops shreds that weren't signed by one of the allowed validators
+///
+/// the signature at 0x00 can't be mapped back to a pubkey (ed25519 keys aren't recoverable
+/// from signatures). with the leader schedule loaded through set_leaders() each shred is
+/// verified once against its slot's leader, for slots the schedule doesn't cover every allowed
+/// key is tried, so keep the set small. every shred of a FEC set signs the same merkle root, once
+/// a root verified the rest of the set only pays for recomputing it. works on the borrowed
+/// payload, a rejected shred is never copied.
+pub struct ValidatorFilter {
+    allowed: HashSet<[u8; 32]>,
+    leaders: HashMap<Slot, [u8; 32]>,
+    // merkle roots that verified and their signature, evicted oldest first
+    verified: Mutex<(HashMap<Hash, [u8; 64]>, VecDeque<Hash>)>,
+}
+
+impl ValidatorFilter {
+    // FEC sets remembered as verified, a slot has a few dozen
+    const VERIFIED_ROOTS: usize = 4096;
+
+    pub fn new(allowed: impl IntoIterator<Item = [u8; 32]>) -> Self {
+        Self {
+            allowed: allowed.into_iter().collect(),
+            leaders: HashMap::new(),
+            verified: Mutex::default(),
+        }
+    }
+
+    pub fn insert(&mut self, pubkey: [u8; 32]) {
+        self.allowed.insert(pubkey);
+    }
+
+    pub fn remove(&mut self, pubkey: &[u8; 32]) {
+        self.allowed.remove(pubkey);
+    }
+
+    /// leaders of consecutive slots starting at `first_slot`, as returned by getSlotLeaders
+    pub fn set_leaders(&mut self, first_slot: Slot, leaders: impl IntoIterator<Item = [u8; 32]>) {
+        self.leaders.extend((first_slot..).zip(leaders));
+    }
+
+    /// true if the shred's signature verifies against one of the allowed pubkeys
+    pub fn allows(&self, shred: &ShredRef) -> bool {
+        // merkle shreds sign the root of their FEC set's merkle tree
+        let Some(merkle_root) = wire::get_merkle_root(shred.payload()) else {
+            return false;
+        };
+        let signature = shred.signature();
+        if self.verified.lock().unwrap().0.get(&merkle_root) == Some(signature) {
+            return true;
+        }
+
+        let verifies =
+            |pubkey: &[u8; 32]| Signature::from(*signature).verify(pubkey, merkle_root.as_ref());
+        let allowed = match self.leaders.get(&shred.slot()) {
+            Some(leader) => self.allowed.contains(leader) && verifies(leader),
+            None => self.allowed.iter().any(verifies),
+        };
+        if allowed {
+            let (roots, order) = &mut *self.verified.lock().unwrap();
+            if roots.len() >= Self::VERIFIED_ROOTS {
+                if let Some(oldest) = order.pop_front() {
+                    roots.remove(&oldest);
+                }
+            }
+            if roots.insert(merkle_root, *signature).is_none() {
+                order.push_back(merkle_root);
+            }
+        }
+        allowed
+    }
+}
+
 /// packet data sent from relay loop to decoder thread
 pub struct PacketDataRef<'a> {
     pub payload: &'a [u8],
@@ -103,6 +271,79 @@ pub struct PacketData {
     pub timestamp: SystemTime,
 }
 
+/// per second counts for the last 60 seconds, advanced by tick() once a second
+pub struct RollingStats {
+    /// (received, decoded, errors) per bucket
+    buckets: [(usize, usize, usize); Self::WINDOW],
+    /// bucket the next tick writes to
+    next: usize,
+    /// number of buckets written so far, up to WINDOW
+    filled: usize,
+    /// cumulative totals at the last tick
+    last: (usize, usize, usize),
+}
+
+impl RollingStats {
+    const WINDOW: usize = 60;
+    /// seconds averaged by the rate_* methods
+    const RATE_SECONDS: usize = 10;
+
+    pub fn new() -> Self {
+        Self {
+            buckets: [(0, 0, 0); Self::WINDOW],
+            next: 0,
+            filled: 0,
+            last: (0, 0, 0),
+        }
+    }
+
+    /// close the current second with the cumulative ShredStats counters
+    pub fn tick(&mut self, received: usize, decoded: usize, errors: usize) {
+        self.buckets[self.next] = (
+            received.saturating_sub(self.last.0),
+            decoded.saturating_sub(self.last.1),
+            errors.saturating_sub(self.last.2),
+        );
+        self.last = (received, decoded, errors);
+        self.next = (self.next + 1) % Self::WINDOW;
+        self.filled = (self.filled + 1).min(Self::WINDOW);
+    }
+
+    pub fn rate_received(&self) -> f64 {
+        self.rate(|b| b.0)
+    }
+
+    pub fn rate_decoded(&self) -> f64 {
+        self.rate(|b| b.1)
+    }
+
+    pub fn rate_errors(&self) -> f64 {
+        self.rate(|b| b.2)
+    }
+
+    /// per second average over the last RATE_SECONDS buckets
+    fn rate(&self, field: impl Fn(&(usize, usize, usize)) -> usize) -> f64 {
+        let seconds = self.filled.min(Self::RATE_SECONDS);
+        if seconds == 0 {
+            return 0.0;
+        }
+        let sum: usize = (1..=seconds)
+            .map(|age| field(&self.buckets[(self.next + Self::WINDOW - age) % Self::WINDOW]))
+            .sum();
+        sum as f64 / seconds as f64
+    }
+
+    pub fn print_stats(&self) {
+        println!(
+            "shred rates ({}s) - received: {:.0}/s, decoded: {:.0}/s, errors: {:.0}/s",
+            self.filled.min(Self::RATE_SECONDS),
+            self.rate_received(),
+            self.rate_decoded(),
+            self.rate_errors()
+        );
+    }
+}
+
 /// statistics for shred processing
 pub struct ShredStats {
     pub received: AtomicUsize,
@@ -111,6 +352,8 @@ pub struct ShredStats {
     pub data_shreds: AtomicUsize,
     pub code_shreds: AtomicUsize,
     pub code_drops: AtomicUsize, // code shreds dropped due to channel overflow
+    pub rejected: AtomicUsize, // shreds from validators not in the ValidatorFilter
+    pub latency: LatencyHistogram, // receive to decode
 }
 
 impl ShredStats {
@@ -122,9 +365,23 @@ impl ShredStats {
             data_shreds: AtomicUsize::new(0),
             code_shreds: AtomicUsize::new(0),
             code_drops: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            latency: LatencyHistogram::new(),
         }
     }
 
+    pub fn latency_p50(&self) -> Duration {
+        self.latency.percentile(0.5)
+    }
+
+    pub fn latency_p99(&self) -> Duration {
+        self.latency.percentile(0.99)
+    }
+
+    pub fn latency_p999(&self) -> Duration {
+        self.latency.percentile(0.999)
+    }
+
     pub fn print_stats(&self) {
         let received = self.received.load(Ordering::Relaxed);
         let decoded = self.decoded.load(Ordering::Relaxed);
@@ -132,14 +389,155 @@ impl ShredStats {
         let data = self.data_shreds.load(Ordering::Relaxed);
         let code = self.code_shreds.load(Ordering::Relaxed);
         let drops = self.code_drops.load(Ordering::Relaxed);
+        let rejected = self.rejected.load(Ordering::Relaxed);
 
         println!(
-            "shred stats - received: {}, decoded: {}, errors: {}, data: {}, code: {}, code drops: {}",
-            received, decoded, errors, data, code, drops
+            "shred stats - received: {}, decoded: {}, errors: {}, data: {}, code: {}, code drops: {}, rejected: {}, latency p50: {:?} p99: {:?} p999: {:?}",
+            received, decoded, errors, data, code, drops, rejected,
+            self.latency_p50(), self.latency_p99(), self.latency_p999()
         );
     }
 }
 
+// ComputeBudget111111111111111111111111111111
+const COMPUTE_BUDGET_PROGRAM_ID: [u8; 32] = [
+    3, 6, 70, 111, 229, 33, 23, 50, 255, 236, 173, 186, 114, 195, 155, 231, 188, 140, 229, 187,
+    197, 247, 18, 107, 44, 67, 155, 58, 64, 0, 0, 0,
+];
+// ComputeBudgetInstruction::SetComputeUnitPrice discriminant, followed by a u64 in micro-lamports
+const SET_COMPUTE_UNIT_PRICE: u8 = 3;
+
+// Vote111111111111111111111111111111111111111
+const VOTE_PROGRAM_ID: [u8; 32] = [
+    7, 97, 72, 29, 53, 116, 116, 187, 124, 77, 118, 36, 235, 211, 189, 179, 216, 53, 94, 115, 209,
+    16, 67, 252, 13, 163, 83, 128, 0, 0, 0, 0,
+];
+// serialized size of vote transactions observed on mainnet
+const VOTE_TXN_SIZE_RANGE: std::ops::RangeInclusive<u64> = 295..=380;
+
+/// true if any instruction invokes the vote program
+/// only looks at program id indexes and static keys, instruction data is never decoded
+pub fn is_vote_transaction_fast(tx: &VersionedTransaction) -> bool {
+    let keys = tx.message.static_account_keys();
+    tx.message.instructions().iter().any(|instruction| {
+        keys.get(instruction.program_id_index as usize)
+            .is_some_and(|program_id| keys_eq(&program_id.to_bytes(), &VOTE_PROGRAM_ID))
+    })
+}
+
+/// size heuristic for votes that weren't caught by is_vote_transaction_fast
+#[inline]
+pub fn is_likely_vote_by_size(serialized_size: u64) -> bool {
+    VOTE_TXN_SIZE_RANGE.contains(&serialized_size)
+}
+
+/// constant-time pubkey comparison, no early exit on the first differing byte
+#[inline]
+fn keys_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// the 8 jito tip payment accounts
+const JITO_TIP_ACCOUNTS: [[u8; 32]; 8] = [
+    // 96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5
+    [
+        120, 82, 28, 177, 121, 206, 187, 133, 137, 181, 86, 162, 213, 236, 148, 210,
+        73, 134, 130, 253, 249, 187, 42, 245, 173, 100, 228, 145, 204, 65, 83, 218,
+    ],
+    // HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe
+    [
+        241, 135, 236, 135, 209, 247, 69, 203, 58, 3, 56, 74, 38, 166, 158, 218,
+        12, 162, 209, 170, 15, 65, 228, 36, 22, 55, 126, 145, 255, 91, 93, 49,
+    ],
+    // Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY
+    [
+        177, 78, 13, 229, 94, 159, 186, 134, 57, 110, 191, 213, 72, 207, 248, 201,
+        32, 17, 234, 199, 183, 91, 170, 155, 45, 156, 106, 134, 245, 161, 113, 65,
+    ],
+    // ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49
+    [
+        136, 241, 255, 163, 162, 223, 230, 23, 189, 196, 227, 87, 50, 81, 163, 34,
+        227, 252, 174, 129, 229, 164, 87, 57, 14, 100, 117, 28, 0, 164, 101, 226,
+    ],
+    // DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh
+    [
+        188, 43, 87, 6, 94, 241, 221, 102, 84, 48, 190, 96, 107, 166, 89, 108,
+        2, 149, 48, 27, 173, 239, 139, 90, 252, 65, 1, 65, 80, 244, 18, 116,
+    ],
+    // ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt
+    [
+        137, 7, 125, 85, 165, 187, 19, 48, 118, 62, 183, 103, 245, 94, 192, 119,
+        180, 26, 13, 7, 95, 125, 225, 215, 63, 186, 202, 60, 99, 213, 84, 113,
+    ],
+    // DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL
+    [
+        191, 151, 27, 89, 16, 139, 91, 133, 160, 79, 176, 147, 241, 226, 27, 78,
+        63, 212, 196, 200, 244, 135, 221, 9, 185, 87, 82, 118, 159, 13, 216, 195,
+    ],
+    // 3AVi9Tg9Uo68tJfuvoKvqKNWKkC5wPdSSdeBnizKZ6jT
+    [
+        32, 38, 16, 30, 194, 3, 40, 150, 74, 50, 171, 171, 19, 108, 84, 5,
+        185, 31, 58, 227, 142, 228, 246, 76, 182, 189, 232, 121, 184, 104, 56, 210,
+    ],
+];
+
+// SystemInstruction::Transfer, u32 discriminant followed by the u64 lamports
+const SYSTEM_PROGRAM_ID: [u8; 32] = [0; 32];
+const SYSTEM_TRANSFER: u32 = 2;
+
+/// true if the transaction references one of the jito tip accounts
+/// only static keys are checked, a tip account loaded through a lookup table is missed
+pub fn is_jito_tip_transaction(tx: &VersionedTransaction) -> bool {
+    tx.message
+        .static_account_keys()
+        .iter()
+        .any(|key| JITO_TIP_ACCOUNTS.contains(&key.to_bytes()))
+}
+
+/// lamports sent to a jito tip account by the first system transfer paying one
+pub fn extract_tip_amount(tx: &VersionedTransaction) -> Option<u64> {
+    let keys = tx.message.static_account_keys();
+    let key = |index: u8| keys.get(index as usize).map(|key| key.to_bytes());
+    tx.message.instructions().iter().find_map(|instruction| {
+        if key(instruction.program_id_index)? != SYSTEM_PROGRAM_ID {
+            return None;
+        }
+        // accounts are [from, to]
+        let to = key(*instruction.accounts.get(1)?)?;
+        if !JITO_TIP_ACCOUNTS.contains(&to) {
+            return None;
+        }
+        let data = &instruction.data;
+        if data.len() != 12 || data[..4] != SYSTEM_TRANSFER.to_le_bytes() {
+            return None;
+        }
+        Some(u64::from_le_bytes(data[4..].try_into().ok()?))
+    })
+}
+
+/// micro-lamports per compute unit if `data` is a SetComputeUnitPrice instruction
+fn parse_compute_unit_price(data: &[u8]) -> Option<u64> {
+    match data {
+        [SET_COMPUTE_UNIT_PRICE, price @ ..] if price.len() == 8 => {
+            Some(u64::from_le_bytes(price.try_into().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// priority fee (compute unit price in micro-lamports) of a decoded transaction, None if it sets
+/// no price
+pub fn transaction_priority_fee(transaction: &VersionedTransaction) -> Option<u64> {
+    let keys = transaction.message.static_account_keys();
+    transaction.message.instructions().iter().find_map(|instruction| {
+        let program_id = keys.get(instruction.program_id_index as usize)?;
+        if program_id.to_bytes() != COMPUTE_BUDGET_PROGRAM_ID {
+            return None;
+        }
+        parse_compute_unit_price(&instruction.data)
+    })
+}
+
 /// parse and decode a shred from UDP payload
 /// the public Shred::new_from_serialized_shred internally:
 /// 1. checks variant byte to determine Merkle vs Legacy
@@ -182,39 +580,67 @@ fn format_timestamp(timestamp: SystemTime) -> String {
 
 /// processes shred without allocations
 /// uses pre-parsed shred type to avoid double parsing
+/// shreds not allowed by `filter` are dropped before they are copied, transactions paying less
+/// than `min_priority_fee` micro-lamports per CU aren't logged. `current_slot` is raised to every
+/// slot that gets deshredded from shreds `filter` verified, without a filter it's left alone
 #[inline]
-pub fn process_shred_ref<T>(packet: &PacketDataRef, stats: &ShredStats, deshred_mgr: &mut T)
-where
+pub fn process_shred_ref<T>(
+    packet: &PacketDataRef,
+    stats: &ShredStats,
+    deshred_mgr: &mut T,
+    filter: Option<&ValidatorFilter>,
+    min_priority_fee: Option<u64>,
+    current_slot: Option<&AtomicU64>,
+) where
     T: DeshredTrait,
 {
     // #[cfg(feature = "debug")]
     stats.received.fetch_add(1, Ordering::Relaxed);
 
     // skip full parsing if we already know its not a shred
-    if packet.shred_type.is_none() {
+    let Some(shred_ref) = packet.shred_type.and(decode_shred_from_bytes(packet.payload)) else {
         // #[cfg(feature = "debug")]
         stats.errors.fetch_add(1, Ordering::Relaxed);
         return;
+    };
+
+    if filter.is_some_and(|filter| !filter.allows(&shred_ref)) {
+        stats.rejected.fetch_add(1, Ordering::Relaxed);
+        return;
     }
 
-    // use pre-parsed type to avoid double parsing
-    match Shred::new_from_serialized_shred(packet.payload.to_vec()) {
+    // the copy into an owned Shred is unavoidable from here on, everything above works on the
+    // borrowed payload
+    match shred_ref.to_owned() {
         Ok(shred) => {
+            // clock can go backwards, skip those samples
+            if let Ok(latency) = SystemTime::now().duration_since(packet.timestamp) {
+                stats.latency.record(latency);
+            }
+
             // #[cfg(feature = "debug")]
             {
-                eprintln!("debug_shred_processor_parse: parsed shred slot:{} index:{}", shred.slot(), shred.index());
+                eprintln!(
+                    "debug_shred_processor_parse: parsed shred slot:{} index:{} data_complete:{}",
+                    shred_ref.slot(),
+                    shred_ref.index(),
+                    shred_ref.data_complete()
+                );
                 stats.decoded.fetch_add(1, Ordering::Relaxed);
 
-                match packet.shred_type.unwrap() {
+                match shred_ref.shred_type() {
                     ShredType::Data => stats.data_shreds.fetch_add(1, Ordering::Relaxed),
                     ShredType::Code => stats.code_shreds.fetch_add(1, Ordering::Relaxed),
                 };
             }
 
-            let _slot = shred.slot();
-
             // try to deshred
             if let Some((slot, entries, _payload)) = deshred_mgr.add_shred(shred) {
+                // several decoders race here, never move the slot back. unverified shreds could
+                // carry any slot and push the window away from the cluster
+                if let (Some(current_slot), Some(_)) = (current_slot, filter) {
+                    current_slot.fetch_max(slot, Ordering::Relaxed);
+                }
                 let txn_count: usize = entries.iter().map(|e| e.transactions.len()).sum();
 
                 // only format timestamp when actually printing
@@ -236,13 +662,36 @@ where
                 // extract and log transaction signatures (filter out votes)
                 for (entry_idx, entry) in entries.iter().enumerate() {
                     for transaction in &entry.transactions {
-                        // check transaction size first. replace with wincode -> https://crates.io/crates/wincode
-                        let txn_size = bincode::serialized_size(transaction).unwrap_or(0);
+                        // MEV: bundles pay a tip to one of the jito tip accounts
+                        if is_jito_tip_transaction(transaction) {
+                            if let Some(sig) = transaction.signatures.first() {
+                                eprintln!(
+                                    "mev [{}] slot:{} entry:{} tip:{} sig: https://solscan.io/tx/{}",
+                                    ts,
+                                    slot,
+                                    entry_idx,
+                                    extract_tip_amount(transaction)
+                                        .map_or("unknown".to_string(), |tip| tip.to_string()),
+                                    sig
+                                );
+                            }
+                        }
 
-                        // FILTER: skip small transactions (votes are ~352 bytes)
-                        const VOTE_TXN_SIZE_THRESHOLD: u64 = 400;
-                        if txn_size < VOTE_TXN_SIZE_THRESHOLD {
-                            continue; // Skip votes
+                        // cheapest check first, only reads the compute budget instructions
+                        if min_priority_fee.is_some_and(|min_fee| {
+                            transaction_priority_fee(transaction).unwrap_or(0) < min_fee
+                        }) {
+                            continue;
+                        }
+
+                        // FILTER: skip votes, by program id first and by size for the rest
+                        if is_vote_transaction_fast(transaction) {
+                            continue;
+                        }
+                        // replace with wincode -> https://crates.io/crates/wincode
+                        let txn_size = bincode::serialized_size(transaction).unwrap_or(0);
+                        if is_likely_vote_by_size(txn_size) {
+                            continue;
                         }
 
                         if !transaction.signatures.is_empty() {
@@ -369,21 +818,88 @@ pub fn process_shred(packet: &PacketData, stats: &ShredStats, deshred_mgr: &Mute
 }
 
 /// decoder thread worker
-/// continuously processes packets from the channel
+/// consumes the packet events the relay loop publishes and decodes the shreds straight from UMEM
+/// until the relay loop exits
+/// every decoder sees every event, this one only decodes the slots where
+/// `slot % shards == shard` so each shred is decoded once and a slot's shreds meet in one
+/// DeshredManager
+/// `pool` is a PacketPool the shreds are copied into upstream, if any, and is checked for
+/// exhaustion every 10,000 packets
 pub fn decoder_worker(
-    rx: crossbeam_channel::Receiver<PacketData>,
+    mut events: PacketEventConsumer,
     stats: std::sync::Arc<ShredStats>,
+    pool: Option<&'static PacketPool>,
+    filter: Option<std::sync::Arc<ValidatorFilter>>,
+    min_priority_fee: Option<u64>,
+    current_slot: Option<std::sync::Arc<AtomicU64>>,
+    shard: usize,
+    shards: usize,
 ) {
-    let deshred_mgr = Mutex::new(DeshredManager::new());
-
-    loop {
-        match rx.recv() {
-            Ok(packet) => {
-                process_shred(&packet, &stats, &deshred_mgr);
+    let mut deshred_mgr = Mutex::new(DeshredManager::new());
+    let mut rolling_stats = RollingStats::new();
+    let mut last_tick = Instant::now();
+    let mut ticks = 0usize;
+    let mut packets = 0usize;
+    let mut last_exhaustions = 0;
+
+    while !events.is_closed() {
+        let consumed = events.consume(|event| {
+            let (true, Some(frame)) = (event.valid, &event.frame) else {
+                return;
+            };
+            // safety: the event's frame reference keeps the relay loop from recycling the frame
+            // and it waits for consumers to catch up before it unmaps the UMEM
+            let payload = unsafe { event.payload_slice(frame.umem_base()) };
+            // anything without a slot goes to the first decoder
+            if extract_slot_fast(payload).unwrap_or(0) % shards as u64 != shard as u64 {
+                return;
+            }
+            let packet = PacketDataRef {
+                payload,
+                packet_len: event.packet_len,
+                src_ip: event.src_ipv4().map_or([0; 4], |ip| ip.octets()),
+                src_port: event.src_port,
+                dst_ip: event.dst_ipv4().map_or([0; 4], |ip| ip.octets()),
+                dst_port: event.dst_port,
+                timestamp: event.timestamp,
+                shred_type: event.shred_type.or_else(|| parse_shred_type(payload)),
+            };
+            process_shred_ref(
+                &packet,
+                &stats,
+                &mut deshred_mgr,
+                filter.as_deref(),
+                min_priority_fee,
+                current_slot.as_deref(),
+            );
+
+            packets += 1;
+            if let Some(pool) = pool.filter(|_| packets % 10_000 == 0) {
+                let pool_stats = pool.stats();
+                if pool_stats.exhaustions > last_exhaustions {
+                    eprintln!(
+                        "warning: packet pool exhausted {} times since last check (peak in use {}), increase POOL_SIZE",
+                        pool_stats.exhaustions - last_exhaustions,
+                        pool_stats.peak_in_use
+                    );
+                    last_exhaustions = pool_stats.exhaustions;
+                }
             }
-            Err(_) => {
-                // channel closed, exit thread
-                break;
+        });
+        if consumed == 0 {
+            std::thread::sleep(Duration::from_micros(50));
+        }
+
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick += Duration::from_secs(1);
+            rolling_stats.tick(
+                stats.received.load(Ordering::Relaxed),
+                stats.decoded.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed),
+            );
+            ticks += 1;
+            if ticks % 5 == 0 {
+                rolling_stats.print_stats();
             }
         }
     }
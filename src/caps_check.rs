@@ -0,0 +1,88 @@
+use {
+    caps::{
+        CapSet,
+        Capability::{
+            self, CAP_BPF, CAP_IPC_LOCK, CAP_NET_ADMIN, CAP_NET_RAW, CAP_SYS_ADMIN, CAP_SYS_NICE,
+        },
+    },
+    std::{error::Error, fmt},
+};
+
+/// capabilities the rx and relay loops use
+pub const REQUIRED_CAPABILITIES: [Capability; 5] = [
+    CAP_NET_ADMIN,
+    CAP_NET_RAW,
+    CAP_BPF,
+    CAP_SYS_NICE,
+    CAP_IPC_LOCK,
+];
+
+/// capabilities that are neither effective nor permitted, so they can't be raised
+#[derive(Debug, PartialEq, Eq)]
+pub struct MissingCapabilities(pub Vec<Capability>);
+
+impl fmt::Display for MissingCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "missing capabilities:")?;
+        for cap in &self.0 {
+            write!(f, " {cap}")?;
+        }
+        write!(
+            f,
+            ", run with: sudo -E <command> or grant them with setcap {}+ep <binary>",
+            self.0
+                .iter()
+                .map(|cap| cap.to_string().to_lowercase())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+impl Error for MissingCapabilities {}
+
+/// check REQUIRED_CAPABILITIES before touching sockets, so we fail with a list of what's
+/// missing instead of an EPERM halfway through setup
+///
+/// a capability counts as present if it's effective, or permitted so it can be raised.
+/// CAP_BPF only exists since Linux 5.8, before that loading programs needs CAP_SYS_ADMIN so
+/// either one will do.
+pub fn capability_preflight() -> Result<(), MissingCapabilities> {
+    let has = |set, cap| caps::has_cap(None, set, cap).unwrap_or(false);
+    let missing =
+        missing_capabilities(|cap| has(CapSet::Effective, cap) || has(CapSet::Permitted, cap));
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(MissingCapabilities(missing))
+    }
+}
+
+fn missing_capabilities(present: impl Fn(Capability) -> bool) -> Vec<Capability> {
+    REQUIRED_CAPABILITIES
+        .into_iter()
+        .filter(|&cap| !(present(cap) || cap == CAP_BPF && present(CAP_SYS_ADMIN)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_capabilities() {
+        assert_eq!(missing_capabilities(|_| true), vec![]);
+        assert_eq!(
+            missing_capabilities(|_| false),
+            REQUIRED_CAPABILITIES.to_vec()
+        );
+
+        // CAP_SYS_ADMIN stands in for CAP_BPF on kernels older than 5.8
+        assert_eq!(missing_capabilities(|cap| cap != CAP_BPF), vec![]);
+        assert_eq!(
+            missing_capabilities(|cap| cap != CAP_BPF && cap != CAP_SYS_ADMIN),
+            vec![CAP_BPF]
+        );
+    }
+}
@@ -0,0 +1,306 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// spreads traffic over CPUs with a CPUMAP instead of the NIC's RSS queues
+
+use {
+    crate::{
+        cpu_ids_for_numa_node,
+        device::{NetworkDevice, QueueId, RingSizes, RxFillRing},
+        program::{insert_socket_into_xskmap, load_xdp_cpumap_program, XdpProgram},
+        rx_loop::RxStats,
+        set_cpu_affinity,
+        socket::{RxRing, Socket},
+        umem::{FrameOffset, PageAlignedMemory, PageSize, SliceUmem, SliceUmemFrame, Umem as _},
+        Error,
+    },
+    libc::{sysconf, _SC_PAGESIZE},
+    std::{
+        hint, io,
+        os::fd::{AsFd, AsRawFd},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    },
+};
+
+// empty polls a worker spins through, then yields for as many before it starts sleeping
+const IDLE_SPINS: u32 = 64;
+const IDLE_SLEEP: Duration = Duration::from_micros(50);
+
+// Safety: the rings are mmaps owned by sockets that CpuMapRelay keeps alive until all the
+// workers have been joined, and each ring is only used by one thread at a time.
+struct SendRing<T>(T);
+unsafe impl<T> Send for SendRing<T> {}
+
+impl<T> SendRing<T> {
+    // a method call moves the whole wrapper into a closure, destructuring it in there would only
+    // capture the non Send field
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// receive on one AF_XDP socket per CPU, fed by the cpumap XDP program
+///
+/// the XDP program hashes each flow onto one of the relay's CPUs, and the kernel runs the
+/// second stage on that CPU which hands the packet to the worker pinned there. this doesn't
+/// depend on rx_queue_index so it works on single queue NICs. packets go through the cpumap as
+/// xdp_frames and are always copied into the UMEM, so the sockets run in copy mode.
+///
+/// the caller must hold CAP_NET_ADMIN, CAP_NET_RAW and CAP_BPF.
+pub struct CpuMapRelay {
+    exit: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+    cpus: Vec<usize>,
+    stats: Vec<Arc<RxStats>>,
+    _sockets: Vec<Socket<SliceUmem<'static>>>,
+    _ebpf: XdpProgram,
+}
+
+impl CpuMapRelay {
+    /// spawn `num_cpus` workers, on the NIC's NUMA node when it has enough CPUs and on CPUs
+    /// 0..num_cpus otherwise. see spawn_on_cpus() to pick the CPUs.
+    pub fn spawn<H>(dev: &NetworkDevice, num_cpus: usize, handler: H) -> Result<Self, Error>
+    where
+        H: Fn(usize, &[u8]) + Send + Sync + 'static,
+    {
+        Self::spawn_on_cpus(dev, relay_cpus(dev, num_cpus), handler)
+    }
+
+    /// spawn one worker pinned to each of `cpus`, which must be distinct and below 64.
+    /// `handler` is called on the worker's thread with the CPU id and every packet received on
+    /// it.
+    pub fn spawn_on_cpus<H>(
+        dev: &NetworkDevice,
+        cpus: Vec<usize>,
+        handler: H,
+    ) -> Result<Self, Error>
+    where
+        H: Fn(usize, &[u8]) + Send + Sync + 'static,
+    {
+        if let Some((index, &cpu)) = cpus
+            .iter()
+            .enumerate()
+            .find(|(index, cpu)| cpus[..*index].contains(cpu))
+        {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CPU {cpu} is listed twice, at {index}"),
+            )));
+        }
+        let num_cpus = cpus.len();
+        let cpu_ids = cpus.iter().map(|&cpu| cpu as u32).collect::<Vec<_>>();
+        let mut ebpf = load_xdp_cpumap_program(dev.if_index(), &cpu_ids)?;
+
+        let frame_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+
+        // all cpumap packets are reported on queue 0
+        let queue = dev.open_queue(QueueId(0))?;
+        let RingSizes { rx: rx_size, .. } = queue.ring_sizes().unwrap_or_default();
+
+        // frames only circulate between the fill ring and the rx rings, so there are never more
+        // than rx_size of them in flight
+        let frame_count = rx_size * 2;
+        let memory = Arc::new(
+            PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, PageSize::Huge2M)
+                .or_else(|_| PageAlignedMemory::alloc_prefaulted(frame_size, frame_count))
+                .map_err(Error::UmemAlloc)?,
+        );
+        let umem = SliceUmem::new_shared(Arc::clone(&memory), frame_size as u32)?;
+
+        // the rings can't be empty, the tx side is unused
+        let (mut primary, rx, _tx) = Socket::new(queue, umem, false, rx_size, rx_size, 64, 64)
+            .map_err(Error::SocketCreate)?;
+        let mut fill = rx.fill;
+        let rx_ring = rx.ring.ok_or_else(|| {
+            Error::SocketCreate(io::Error::other("primary socket has no rx ring"))
+        })?;
+        let mut rings = vec![(cpus[0], rx_ring)];
+
+        // the same queue's fill ring is shared by all the sockets, fill it once
+        fill.sync(false);
+        while fill.available() > 0 {
+            let Some(frame) = primary.umem().reserve() else {
+                break;
+            };
+            fill.write(frame)?;
+        }
+        fill.commit();
+
+        let mut sockets = Vec::with_capacity(num_cpus);
+        for &cpu in &cpus[1..] {
+            let umem = primary
+                .umem()
+                .split_off(0)
+                .ok_or_else(|| Error::UmemAlloc(io::Error::other("failed to split UMEM")))?;
+            let (socket, ring) =
+                Socket::new_shared_queue(&primary, umem, rx_size).map_err(Error::SocketCreate)?;
+            insert_socket_into_xskmap(&mut ebpf, cpu as u32, socket.as_fd().as_raw_fd())?;
+            sockets.push(socket);
+            rings.push((cpu, ring));
+        }
+        insert_socket_into_xskmap(&mut ebpf, cpus[0] as u32, primary.as_fd().as_raw_fd())?;
+        sockets.push(primary);
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let fill = Arc::new(Mutex::new(SendRing(fill)));
+        let handler = Arc::new(handler);
+        let mut stats = Vec::with_capacity(num_cpus);
+        let mut workers = Vec::with_capacity(num_cpus);
+        for (cpu, ring) in rings {
+            let worker_stats = Arc::new(RxStats::new());
+            stats.push(Arc::clone(&worker_stats));
+
+            let ring = SendRing(ring);
+            let fill = Arc::clone(&fill);
+            let memory = Arc::clone(&memory);
+            let handler = Arc::clone(&handler);
+            let exit = Arc::clone(&exit);
+            workers.push(
+                thread::Builder::new()
+                    .name(format!("cpumapRelay{cpu:02}"))
+                    .spawn(move || {
+                        worker(
+                            cpu,
+                            ring.into_inner(),
+                            &fill,
+                            &memory,
+                            frame_size,
+                            &*handler,
+                            &worker_stats,
+                            &exit,
+                        )
+                    })?,
+            );
+        }
+
+        Ok(Self {
+            exit,
+            workers,
+            cpus,
+            stats,
+            _sockets: sockets,
+            _ebpf: ebpf,
+        })
+    }
+
+    /// the CPUs the workers are pinned to
+    pub fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+
+    /// per worker stats, in the same order as cpus()
+    pub fn stats(&self) -> &[Arc<RxStats>] {
+        &self.stats
+    }
+
+    /// stop and join the workers, then detach the XDP program and close the sockets. returns
+    /// the panic of a worker that panicked
+    pub fn stop(mut self) -> thread::Result<()> {
+        self.join_workers()
+    }
+
+    fn join_workers(&mut self) -> thread::Result<()> {
+        self.exit.store(true, Ordering::Relaxed);
+        let mut result = Ok(());
+        for worker in self.workers.drain(..) {
+            let joined = worker.join();
+            if result.is_ok() {
+                result = joined;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for CpuMapRelay {
+    fn drop(&mut self) {
+        // the workers use the sockets' rings, they must be gone before the sockets are closed
+        let _ = self.join_workers();
+    }
+}
+
+/// the first `num_cpus` CPUs of the NIC's NUMA node, so the workers and the second XDP stage
+/// run next to the NIC. falls back to CPUs 0..num_cpus when the node is unknown or too small.
+fn relay_cpus(dev: &NetworkDevice, num_cpus: usize) -> Vec<usize> {
+    let local = match dev.numa_node().map(cpu_ids_for_numa_node) {
+        Some(Ok(cpus)) => cpus,
+        Some(Err(e)) => {
+            log::warn!(
+                "failed to read CPUs of the NUMA node of {}: {e}",
+                dev.name()
+            );
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    // CPU ids index XSKS_MAP which has 64 entries
+    let local = local
+        .into_iter()
+        .filter(|&cpu| cpu < 64)
+        .take(num_cpus)
+        .collect::<Vec<_>>();
+    if local.len() == num_cpus {
+        local
+    } else {
+        (0..num_cpus).collect()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn worker(
+    cpu: usize,
+    mut ring: RxRing,
+    fill: &Mutex<SendRing<RxFillRing<SliceUmemFrame<'static>>>>,
+    memory: &PageAlignedMemory,
+    frame_size: usize,
+    handler: &dyn Fn(usize, &[u8]),
+    stats: &RxStats,
+    exit: &AtomicBool,
+) {
+    if let Err(e) = set_cpu_affinity([cpu]) {
+        // still works, but the kernel runs the second stage on `cpu` so we'd be competing with
+        // whatever else is pinned there
+        log::warn!("failed to pin cpumap worker to cpu {cpu}: {e}");
+    }
+
+    let mut done = Vec::with_capacity(ring.capacity());
+    let mut idle = 0;
+    while !exit.load(Ordering::Relaxed) {
+        ring.sync(false);
+        while let Some(desc) = ring.read() {
+            let packet = &memory[desc.addr as usize..][..desc.len as usize];
+            stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+            stats.rx_bytes.fetch_add(packet.len(), Ordering::Relaxed);
+            handler(cpu, packet);
+            done.push(desc.addr as usize & !(frame_size - 1));
+        }
+        if done.is_empty() {
+            // back off so idle workers don't hold their CPUs at 100%
+            idle += 1;
+            if idle < IDLE_SPINS {
+                hint::spin_loop();
+            } else if idle < 2 * IDLE_SPINS {
+                thread::yield_now();
+            } else {
+                thread::sleep(IDLE_SLEEP);
+            }
+            continue;
+        }
+        idle = 0;
+        ring.commit();
+
+        // hand the frames back to the kernel. there are never more frames than fill ring slots
+        // so this can't fail
+        let mut fill = fill.lock().unwrap();
+        let SendRing(fill) = &mut *fill;
+        fill.sync(false);
+        for offset in done.drain(..) {
+            let _ = fill.write(SliceUmemFrame::from_offset(FrameOffset(offset), 0));
+        }
+        fill.commit();
+    }
+}
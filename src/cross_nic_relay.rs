@@ -0,0 +1,192 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// receives on one NIC and transmits on another without copying, both sockets share one UMEM
+
+use {
+    crate::{
+        device::{NetworkDevice, QueueId, RingSizes, RxFillRing, TxCompletionRing},
+        netlink::MacAddress,
+        program::{insert_socket_into_xskmap, load_xdp_program, XdpProgram},
+        rx_loop::RxStats,
+        socket::{RxRing, Socket, TxRing},
+        umem::{FrameOffset, SliceUmem, SliceUmemFrame, Umem as _},
+        Error,
+    },
+    std::{
+        io,
+        os::fd::{AsFd, AsRawFd},
+        sync::atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// forward every packet received on `rx_dev` out of `tx_dev`
+///
+/// the rx socket is the primary one, the tx socket is bound to the other NIC with
+/// XDP_SHARED_UMEM so a received frame is handed to the tx ring as is. only the ethernet header
+/// is rewritten: the source MAC becomes tx_dev's and the destination MAC the next hop set with
+/// set_dest_mac() (the original one is kept when unset). frames only circulate between the fill,
+/// rx, tx and completion rings, so the UMEM needs at least as many frames as the rx queue's ring.
+/// the sockets run in copy mode since the UMEM would have to be DMA mapped by both NICs for zero
+/// copy.
+///
+/// the XDP program is only attached to rx_dev. the tx socket has no rx ring, traffic arriving on
+/// tx_dev that got redirected to it would be dropped by the kernel.
+///
+/// the caller must hold CAP_NET_ADMIN, CAP_NET_RAW and CAP_BPF.
+pub struct CrossNicRelay {
+    rx_ring: RxRing,
+    fill: RxFillRing<SliceUmemFrame<'static>>,
+    tx_ring: TxRing<SliceUmemFrame<'static>>,
+    completion: TxCompletionRing,
+    src_mac: MacAddress,
+    dst_mac: Option<MacAddress>,
+    frame_size: usize,
+    tx_socket: Socket<SliceUmem<'static>>,
+    rx_socket: Socket<SliceUmem<'static>>,
+    ebpf: XdpProgram,
+}
+
+impl CrossNicRelay {
+    /// `umem` must be splittable (SliceUmem::new_shared) so the tx socket can get its own handle
+    pub fn new(
+        rx_dev: &NetworkDevice,
+        rx_queue: QueueId,
+        tx_dev: &NetworkDevice,
+        tx_queue: QueueId,
+        umem: SliceUmem<'static>,
+    ) -> Result<Self, Error> {
+        let src_mac = tx_dev.mac_addr()?;
+        let frame_size = umem.frame_size();
+
+        let rx_queue_id = rx_queue.0 as u32;
+        let queue = rx_dev.open_queue(rx_queue)?;
+        let RingSizes { rx: rx_size, .. } = queue.ring_sizes().unwrap_or_default();
+        let tx_queue = tx_dev.open_queue(tx_queue)?;
+        let RingSizes { tx: tx_size, .. } = tx_queue.ring_sizes().unwrap_or_default();
+
+        // the rings can't be empty, the rx socket's tx side is unused
+        let (mut rx_socket, rx, _tx) = Socket::new(queue, umem, false, rx_size, rx_size, 64, 64)
+            .map_err(Error::SocketCreate)?;
+        let mut fill = rx.fill;
+        let rx_ring = rx
+            .ring
+            .ok_or_else(|| Error::SocketCreate(io::Error::other("rx socket has no rx ring")))?;
+
+        let tx_umem = rx_socket
+            .umem()
+            .split_off(0)
+            .ok_or_else(|| Error::UmemAlloc(io::Error::other("failed to split UMEM")))?;
+        // no RX fill ring needed for TX only sockets
+        let (tx_socket, _rx, tx) =
+            Socket::new_shared_umem(&rx_socket, tx_queue, tx_umem, false, 1, 0, tx_size, tx_size)
+                .map_err(Error::SocketCreate)?;
+        let completion = tx.completion;
+        let tx_ring = tx
+            .ring
+            .ok_or_else(|| Error::SocketCreate(io::Error::other("tx socket has no tx ring")))?;
+
+        // every frame starts out in the fill ring, the loop then passes them around
+        fill.sync(false);
+        while fill.available() > 0 {
+            let Some(frame) = rx_socket.umem().reserve() else {
+                break;
+            };
+            fill.write(frame)?;
+        }
+        fill.commit();
+
+        let mut ebpf = load_xdp_program(rx_dev.if_index())?;
+        insert_socket_into_xskmap(&mut ebpf, rx_queue_id, rx_socket.as_fd().as_raw_fd())?;
+
+        Ok(Self {
+            rx_ring,
+            fill,
+            tx_ring,
+            completion,
+            src_mac,
+            dst_mac: None,
+            frame_size,
+            tx_socket,
+            rx_socket,
+            ebpf,
+        })
+    }
+
+    /// destination MAC written into every forwarded frame, usually the next hop's on tx_dev
+    pub fn set_dest_mac(&mut self, mac: MacAddress) {
+        self.dst_mac = Some(mac);
+    }
+
+    /// the XDP program attached to rx_dev, to configure filters
+    pub fn ebpf_mut(&mut self) -> &mut XdpProgram {
+        &mut self.ebpf
+    }
+
+    pub fn rx_socket(&self) -> &Socket<SliceUmem<'static>> {
+        &self.rx_socket
+    }
+
+    pub fn tx_socket(&self) -> &Socket<SliceUmem<'static>> {
+        &self.tx_socket
+    }
+
+    /// forward packets until `exit` is set
+    pub fn run(&mut self, stats: &RxStats, exit: &AtomicBool) {
+        let frame_mask = !(self.frame_size - 1);
+        while !exit.load(Ordering::Relaxed) {
+            stats.loop_iterations.fetch_add(1, Ordering::Relaxed);
+            self.fill.sync(false);
+            self.completion.sync(false);
+            self.rx_ring.sync(false);
+            self.tx_ring.sync(false);
+
+            // sent frames go straight back to the kernel for receiving. there are never more
+            // frames than fill ring slots so this can't fail
+            while let Some(FrameOffset(offset)) = self.completion.read() {
+                let frame = SliceUmemFrame::from_offset(FrameOffset(offset & frame_mask), 0);
+                let _ = self.fill.write(frame);
+            }
+            self.completion.commit();
+
+            let mut forwarded = false;
+            while let Some(desc) = self.rx_ring.read() {
+                let len = desc.len as usize;
+                stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+                stats.rx_bytes.fetch_add(len, Ordering::Relaxed);
+
+                let frame = SliceUmemFrame::from_offset(FrameOffset(desc.addr as usize), len);
+                let packet = self.rx_socket.umem().map_frame_mut(&frame);
+                if packet.len() >= 14 {
+                    if let Some(dst_mac) = &self.dst_mac {
+                        packet[0..6].copy_from_slice(&dst_mac.0);
+                    }
+                    packet[6..12].copy_from_slice(&self.src_mac.0);
+                }
+                match self.tx_ring.write(frame, 0) {
+                    Ok(()) => forwarded = true,
+                    Err(_) => {
+                        // tx ring full, drop the packet and receive into the frame again
+                        let frame = SliceUmemFrame::from_offset(
+                            FrameOffset(desc.addr as usize & frame_mask),
+                            0,
+                        );
+                        let _ = self.fill.write(frame);
+                    }
+                }
+            }
+            self.rx_ring.commit();
+            self.fill.commit();
+            stats.fill_ring_frames.store(
+                self.fill.capacity() - self.fill.available(),
+                Ordering::Relaxed,
+            );
+
+            if forwarded {
+                self.tx_ring.commit();
+                if self.tx_ring.needs_wakeup() {
+                    let _ = self.tx_ring.wake();
+                }
+            }
+        }
+    }
+}
@@ -1,20 +1,23 @@
 use {
     crate::{
-        netlink::MacAddress,
+        netlink::{netlink_get_addrs, MacAddress},
         route::Router,
         umem::{Frame, FrameOffset},
     },
     libc::{
         ifreq, mmap, munmap, socket, syscall, xdp_ring_offset, SYS_ioctl, AF_INET, IF_NAMESIZE,
-        SIOCETHTOOL, SIOCGIFADDR, SIOCGIFHWADDR, SOCK_DGRAM,
+        AF_INET6, IFF_RUNNING, IFF_UP, SIOCETHTOOL, SIOCGIFADDR, SIOCGIFFLAGS, SIOCGIFHWADDR,
+        SIOCGIFMTU, SIOCSIFMTU, SOCK_DGRAM,
     },
     std::{
+        collections::HashMap,
         ffi::{c_char, CStr, CString},
         io::{self, ErrorKind},
         marker::PhantomData,
         mem,
-        net::Ipv4Addr,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
         os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+        path::{Path, PathBuf},
         ptr, slice,
         sync::atomic::{AtomicU32, Ordering},
         convert::TryInto,
@@ -44,6 +47,22 @@ impl NetworkDevice {
         Ok(Self { if_index, if_name })
     }
 
+    /// look up `name` inside the network namespace `netns_fd`, see open_netns()
+    ///
+    /// the calling thread switches into the namespace for the lookup and back afterwards. the
+    /// if_index only means something inside that namespace and the ioctl based methods
+    /// (mac_addr(), ring_sizes(), ...) open their sockets in the thread's current namespace, so
+    /// the thread must enter_netns() before using the device. AF_XDP sockets and XDP programs
+    /// stay bound to the device once created, wherever the thread goes afterwards.
+    pub fn from_netns_fd(name: &str, netns_fd: RawFd) -> Result<Self, io::Error> {
+        let current = open_netns(Path::new("/proc/thread-self/ns/net"))?;
+        enter_netns(netns_fd)?;
+        let dev = Self::new(name);
+        // staying behind in the other namespace would be worse than failing the lookup
+        enter_netns(current.as_raw_fd())?;
+        dev
+    }
+
     pub fn new_from_index(if_index: u32) -> Result<Self, io::Error> {
         let mut buf = [0u8; 1024];
         let ret = unsafe { libc::if_indextoname(if_index, buf.as_mut_ptr() as *mut c_char) };
@@ -138,58 +157,582 @@ impl NetworkDevice {
         Ok(addr)
     }
 
-    pub fn open_queue(&self, queue_id: QueueId) -> Result<DeviceQueue, io::Error> {
-        let ring_sizes = Self::ring_sizes(&self.if_name).ok();
-        Ok(DeviceQueue::new(self.if_index, queue_id, ring_sizes))
+    /// all unicast IPv6 addresses configured on the device
+    pub fn ipv6_addrs(&self) -> Result<Vec<Ipv6Addr>, io::Error> {
+        Ok(netlink_get_addrs(Some(self.if_index), AF_INET6 as u8)?
+            .into_iter()
+            .filter_map(|entry| match entry.address {
+                IpAddr::V6(addr) if !addr.is_multicast() && !addr.is_unspecified() => Some(addr),
+                _ => None,
+            })
+            .collect())
     }
 
-    pub fn ring_sizes(if_name: &str) -> Result<RingSizes, io::Error> {
-        const ETHTOOL_GRINGPARAM: u32 = 0x00000010;
+    /// the device's link-local (fe80::/10) IPv6 address, if any
+    pub fn link_local_ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6_addrs()
+            .ok()?
+            .into_iter()
+            .find(|addr| addr.segments()[0] & 0xffc0 == 0xfe80)
+    }
 
-        #[repr(C)]
-        #[derive(Debug)]
-        struct EthtoolRingParam {
-            cmd: u32,
-            rx_max_pending: u32,
-            rx_mini_max_pending: u32,
-            rx_jumbo_max_pending: u32,
-            tx_max_pending: u32,
-            rx_pending: u32,
-            rx_mini_pending: u32,
-            rx_jumbo_pending: u32,
-            tx_pending: u32,
-        }
+    pub fn mtu(&self) -> Result<u32, io::Error> {
+        let mut req = self.ifreq();
+        ifreq_ioctl(SIOCGIFMTU, &mut req)?;
+        Ok(unsafe { req.ifr_ifru.ifru_mtu } as u32)
+    }
 
-        let fd = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
-        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    /// whether the interface is up and has carrier (IFF_UP and IFF_RUNNING)
+    pub fn is_running(&self) -> Result<bool, io::Error> {
+        let mut req = self.ifreq();
+        ifreq_ioctl(SIOCGIFFLAGS, &mut req)?;
+        let flags = unsafe { req.ifr_ifru.ifru_flags } as i32;
+        let running = IFF_UP | IFF_RUNNING;
+        Ok(flags & running == running)
+    }
 
-        let mut rp: EthtoolRingParam = unsafe { mem::zeroed() };
-        rp.cmd = ETHTOOL_GRINGPARAM;
+    /// requires CAP_NET_ADMIN
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), io::Error> {
+        let mut req = self.ifreq();
+        req.ifr_ifru.ifru_mtu = i32::try_from(mtu)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid MTU"))?;
+        ifreq_ioctl(SIOCSIFMTU, &mut req)
+    }
 
-        let mut ifr: ifreq = unsafe { mem::zeroed() };
+    fn ifreq(&self) -> ifreq {
+        let mut req: ifreq = unsafe { mem::zeroed() };
+        let if_name = self.if_name.as_bytes();
+        let len = if_name.len().min(IF_NAMESIZE - 1);
         unsafe {
             ptr::copy_nonoverlapping(
                 if_name.as_ptr() as *const c_char,
-                ifr.ifr_name.as_mut_ptr(),
-                if_name.len().min(IF_NAMESIZE),
+                req.ifr_name.as_mut_ptr(),
+                len,
             );
         }
-        ifr.ifr_name[IF_NAMESIZE - 1] = 0;
-        ifr.ifr_ifru.ifru_data = &mut rp as *mut _ as *mut c_char;
+        req
+    }
+
+    /// kernel NIC counters from /sys/class/net/<name>/statistics
+    pub fn nic_stats(&self) -> Result<HashMap<String, u64>, io::Error> {
+        let mut stats = HashMap::new();
+        for entry in std::fs::read_dir(self.statistics_path())? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            // some drivers expose counters that fail to read, skip them
+            if let Ok(value) = read_counter(&entry.path()) {
+                stats.insert(name, value);
+            }
+        }
+        Ok(stats)
+    }
 
-        let res = unsafe { syscall(SYS_ioctl, fd.as_raw_fd(), SIOCETHTOOL, &ifr) };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+    /// packets dropped by the kernel/driver before they reached us
+    pub fn rx_dropped(&self) -> Result<u64, io::Error> {
+        read_counter(&self.statistics_path().join("rx_dropped"))
+    }
+
+    pub fn tx_dropped(&self) -> Result<u64, io::Error> {
+        read_counter(&self.statistics_path().join("tx_dropped"))
+    }
+
+    fn statistics_path(&self) -> PathBuf {
+        Path::new("/sys/class/net")
+            .join(&self.if_name)
+            .join("statistics")
+    }
+
+    /// (queue id, CPU affinity) for each of the device's queue interrupts, found by matching
+    /// <iface>-rx-<queue> and <iface>-TxRx-<queue> lines in /proc/interrupts
+    pub fn queue_irq_affinities(&self) -> Result<Vec<(u32, CpuSet)>, io::Error> {
+        let interrupts = std::fs::read_to_string("/proc/interrupts")?;
+        let mut affinities = Vec::new();
+        for line in interrupts.lines() {
+            let Some((irq, rest)) = line.trim_start().split_once(':') else {
+                continue;
+            };
+            let Ok(irq) = irq.parse::<u32>() else {
+                continue;
+            };
+            let Some(queue) = rest
+                .split_whitespace()
+                .last()
+                .and_then(|name| parse_queue_irq_name(name, &self.if_name))
+            else {
+                continue;
+            };
+            let mask = std::fs::read_to_string(format!("/proc/irq/{irq}/smp_affinity"))?;
+            let cpus = CpuSet::from_hex_mask(&mask).ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, format!("invalid affinity mask {mask}"))
+            })?;
+            affinities.push((queue, cpus));
         }
+        Ok(affinities)
+    }
+
+    /// NUMA node the NIC is attached to, None for virtual devices or single node systems where
+    /// the kernel reports -1
+    pub fn numa_node(&self) -> Option<u32> {
+        std::fs::read_to_string(format!("/sys/class/net/{}/device/numa_node", self.if_name))
+            .ok()?
+            .trim()
+            .parse::<i32>()
+            .ok()
+            .and_then(|node| u32::try_from(node).ok())
+    }
 
+    pub fn open_queue(&self, queue_id: QueueId) -> Result<DeviceQueue, io::Error> {
+        let ring_sizes = Self::ring_sizes(&self.if_name).ok();
+        Ok(DeviceQueue::new(self.if_index, queue_id, ring_sizes))
+    }
+
+    pub fn ring_sizes(if_name: &str) -> Result<RingSizes, io::Error> {
+        let rp = ring_param(if_name)?;
         Ok(RingSizes {
             rx: rp.rx_pending as usize,
             tx: rp.tx_pending as usize,
         })
     }
+
+    /// the largest rx and tx rings the driver supports
+    pub fn max_ring_sizes(&self) -> Result<RingSizes, io::Error> {
+        let rp = ring_param(&self.if_name).map_err(unsupported)?;
+        Ok(RingSizes {
+            rx: rp.rx_max_pending as usize,
+            tx: rp.tx_max_pending as usize,
+        })
+    }
+
+    /// resize the NIC's rx and tx rings, like `ethtool -G`. requires CAP_NET_ADMIN
+    ///
+    /// most drivers reset the interface to apply this, so it must happen before any socket is
+    /// bound to it.
+    pub fn set_ring_sizes(&self, rx: u32, tx: u32) -> Result<(), io::Error> {
+        const ETHTOOL_SRINGPARAM: u32 = 0x00000011;
+
+        // the mini and jumbo rings are left as they are
+        let mut rp = ring_param(&self.if_name).map_err(unsupported)?;
+        if rx == 0 || tx == 0 || rx > rp.rx_max_pending || tx > rp.tx_max_pending {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "ring sizes rx {rx} tx {tx} out of range, {} supports up to rx {} tx {}",
+                    self.if_name, rp.rx_max_pending, rp.tx_max_pending
+                ),
+            ));
+        }
+        rp.cmd = ETHTOOL_SRINGPARAM;
+        rp.rx_pending = rx;
+        rp.tx_pending = tx;
+        ethtool_ioctl(&self.if_name, &mut rp).map_err(unsupported)
+    }
+
+    /// current interrupt coalescing settings, like `ethtool -c`
+    pub fn coalesce(&self) -> Result<CoalesceParams, io::Error> {
+        let ec = coalesce_param(&self.if_name).map_err(unsupported)?;
+        Ok(CoalesceParams {
+            rx_usecs: ec.rx_coalesce_usecs,
+            rx_max_frames: ec.rx_max_coalesced_frames,
+            tx_usecs: ec.tx_coalesce_usecs,
+            use_adaptive_rx: ec.use_adaptive_rx_coalesce != 0,
+        })
+    }
+
+    /// change interrupt coalescing, like `ethtool -C`. requires CAP_NET_ADMIN
+    ///
+    /// the settings CoalesceParams doesn't cover are left as they are. drivers reject values for
+    /// parameters they don't support with EOPNOTSUPP.
+    pub fn set_coalesce(&self, params: CoalesceParams) -> Result<(), io::Error> {
+        const ETHTOOL_SCOALESCE: u32 = 0x0000000f;
+
+        let mut ec = coalesce_param(&self.if_name).map_err(unsupported)?;
+        ec.cmd = ETHTOOL_SCOALESCE;
+        ec.rx_coalesce_usecs = params.rx_usecs;
+        ec.rx_max_coalesced_frames = params.rx_max_frames;
+        ec.tx_coalesce_usecs = params.tx_usecs;
+        ec.use_adaptive_rx_coalesce = params.use_adaptive_rx as u32;
+        ethtool_ioctl(&self.if_name, &mut ec).map_err(unsupported)
+    }
+
+    /// number of rx queues, returns ErrorKind::Unsupported if the driver doesn't implement
+    /// ETHTOOL_GRXRINGS
+    pub fn rx_queue_count(&self) -> io::Result<u32> {
+        const ETHTOOL_GRXRINGS: u32 = 0x0000002d;
+
+        let mut nfc: EthtoolRxnfc = unsafe { mem::zeroed() };
+        nfc.cmd = ETHTOOL_GRXRINGS;
+        ethtool_ioctl(&self.if_name, &mut nfc).map_err(unsupported)?;
+
+        Ok(nfc.data as u32)
+    }
+
+    /// the header fields the NIC's RSS hash covers for `flow`, like
+    /// `ethtool -n IFACE rx-flow-hash`
+    pub fn get_rss_hash_fields(&self, flow: RssFlowType) -> io::Result<RssHashFields> {
+        const ETHTOOL_GRXFH: u32 = 0x00000029;
+
+        let mut nfc: EthtoolRxnfc = unsafe { mem::zeroed() };
+        nfc.cmd = ETHTOOL_GRXFH;
+        nfc.flow_type = flow as u32;
+        ethtool_ioctl(&self.if_name, &mut nfc).map_err(unsupported)?;
+
+        Ok(RssHashFields(nfc.data))
+    }
+
+    /// choose the header fields the NIC's RSS hash covers for `flow`, like `ethtool -N IFACE
+    /// rx-flow-hash`. requires CAP_NET_ADMIN
+    ///
+    /// with every sender talking to the same UDP port, hashing on the addresses and the source
+    /// port alone spreads flows just as well. drivers only accept the combinations their hardware
+    /// supports and return EINVAL for the others.
+    pub fn set_rss_hash_fields(&self, flow: RssFlowType, fields: RssHashFields) -> io::Result<()> {
+        const ETHTOOL_SRXFH: u32 = 0x0000002a;
+
+        let mut nfc: EthtoolRxnfc = unsafe { mem::zeroed() };
+        nfc.cmd = ETHTOOL_SRXFH;
+        nfc.flow_type = flow as u32;
+        nfc.data = fields.0;
+        ethtool_ioctl(&self.if_name, &mut nfc).map_err(unsupported)
+    }
+
+    /// number of tx queues, counting both dedicated tx and combined channels. Returns
+    /// ErrorKind::Unsupported if the driver doesn't implement ETHTOOL_GCHANNELS.
+    pub fn tx_queue_count(&self) -> io::Result<u32> {
+        const ETHTOOL_GCHANNELS: u32 = 0x0000003c;
+
+        #[repr(C)]
+        #[allow(dead_code)]
+        struct EthtoolChannels {
+            cmd: u32,
+            max_rx: u32,
+            max_tx: u32,
+            max_other: u32,
+            max_combined: u32,
+            rx_count: u32,
+            tx_count: u32,
+            other_count: u32,
+            combined_count: u32,
+        }
+
+        let mut channels: EthtoolChannels = unsafe { mem::zeroed() };
+        channels.cmd = ETHTOOL_GCHANNELS;
+        ethtool_ioctl(&self.if_name, &mut channels).map_err(unsupported)?;
+
+        Ok(channels.tx_count + channels.combined_count)
+    }
+}
+
+/// open a network namespace, e.g. /var/run/netns/NAME or /proc/PID/ns/net
+pub fn open_netns(path: &Path) -> Result<OwnedFd, io::Error> {
+    Ok(std::fs::File::open(path)?.into())
+}
+
+/// move the calling thread into the network namespace `netns_fd`, threads it spawns afterwards
+/// start out in it too. needs CAP_SYS_ADMIN
+pub fn enter_netns(netns_fd: RawFd) -> Result<(), io::Error> {
+    if unsafe { libc::setns(netns_fd, libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// struct ethtool_ringparam
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+struct EthtoolRingParam {
+    cmd: u32,
+    rx_max_pending: u32,
+    rx_mini_max_pending: u32,
+    rx_jumbo_max_pending: u32,
+    tx_max_pending: u32,
+    rx_pending: u32,
+    rx_mini_pending: u32,
+    rx_jumbo_pending: u32,
+    tx_pending: u32,
+}
+
+fn ring_param(if_name: &str) -> Result<EthtoolRingParam, io::Error> {
+    const ETHTOOL_GRINGPARAM: u32 = 0x00000010;
+
+    let mut rp: EthtoolRingParam = unsafe { mem::zeroed() };
+    rp.cmd = ETHTOOL_GRINGPARAM;
+    ethtool_ioctl(if_name, &mut rp)?;
+    Ok(rp)
+}
+
+// struct ethtool_rxnfc, we only care about flow_type and data. The kernel copies in the whole
+// struct for some commands so pad it out to cover the flow spec and rule count.
+#[repr(C)]
+#[allow(dead_code)]
+struct EthtoolRxnfc {
+    cmd: u32,
+    flow_type: u32,
+    data: u64,
+    _rest: [u8; 256],
+}
+
+// struct ethtool_coalesce
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+struct EthtoolCoalesce {
+    cmd: u32,
+    rx_coalesce_usecs: u32,
+    rx_max_coalesced_frames: u32,
+    rx_coalesce_usecs_irq: u32,
+    rx_max_coalesced_frames_irq: u32,
+    tx_coalesce_usecs: u32,
+    tx_max_coalesced_frames: u32,
+    tx_coalesce_usecs_irq: u32,
+    tx_max_coalesced_frames_irq: u32,
+    stats_block_coalesce_usecs: u32,
+    use_adaptive_rx_coalesce: u32,
+    use_adaptive_tx_coalesce: u32,
+    pkt_rate_low: u32,
+    rx_coalesce_usecs_low: u32,
+    rx_max_coalesced_frames_low: u32,
+    tx_coalesce_usecs_low: u32,
+    tx_max_coalesced_frames_low: u32,
+    pkt_rate_high: u32,
+    rx_coalesce_usecs_high: u32,
+    rx_max_coalesced_frames_high: u32,
+    tx_coalesce_usecs_high: u32,
+    tx_max_coalesced_frames_high: u32,
+    rate_sample_interval: u32,
+}
+
+fn coalesce_param(if_name: &str) -> Result<EthtoolCoalesce, io::Error> {
+    const ETHTOOL_GCOALESCE: u32 = 0x0000000e;
+
+    let mut ec: EthtoolCoalesce = unsafe { mem::zeroed() };
+    ec.cmd = ETHTOOL_GCOALESCE;
+    ethtool_ioctl(if_name, &mut ec)?;
+    Ok(ec)
+}
+
+// issue a SIOCETHTOOL ioctl, `cmd` must be an ethtool struct starting with the command id
+fn ethtool_ioctl<T>(if_name: &str, cmd: &mut T) -> Result<(), io::Error> {
+    let fd = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut ifr: ifreq = unsafe { mem::zeroed() };
+    unsafe {
+        ptr::copy_nonoverlapping(
+            if_name.as_ptr() as *const c_char,
+            ifr.ifr_name.as_mut_ptr(),
+            if_name.len().min(IF_NAMESIZE),
+        );
+    }
+    ifr.ifr_name[IF_NAMESIZE - 1] = 0;
+    ifr.ifr_ifru.ifru_data = cmd as *mut T as *mut c_char;
+
+    let res = unsafe { syscall(SYS_ioctl, fd.as_raw_fd(), SIOCETHTOOL, &ifr) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn read_counter(path: &Path) -> Result<u64, io::Error> {
+    std::fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn ifreq_ioctl(request: libc::c_ulong, req: &mut ifreq) -> Result<(), io::Error> {
+    let fd = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let res = unsafe { syscall(SYS_ioctl, fd.as_raw_fd(), request, req as *mut ifreq) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// drivers without the ethtool op return EOPNOTSUPP
+fn unsupported(e: io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => io::Error::from(ErrorKind::Unsupported),
+        _ => e,
+    }
+}
+
+/// set of CPU ids
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuSet {
+    // sorted
+    cpus: Vec<usize>,
+}
+
+impl CpuSet {
+    /// parse a kernel cpumask like "00000000,0000000c", most significant word first
+    pub fn from_hex_mask(mask: &str) -> Option<Self> {
+        let mut cpus = Vec::new();
+        let digits = mask.trim().bytes().filter(|b| *b != b',').rev();
+        for (i, digit) in digits.enumerate() {
+            let nibble = (digit as char).to_digit(16)?;
+            for bit in 0..4 {
+                if nibble & (1 << bit) != 0 {
+                    cpus.push(i * 4 + bit);
+                }
+            }
+        }
+        Some(Self { cpus })
+    }
+
+    /// parse a kernel cpu list like "0-3,8,10-11"
+    pub fn from_cpu_list(list: &str) -> Option<Self> {
+        let mut cpus = Vec::new();
+        for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+            match range.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (first.parse::<usize>().ok()?, last.parse().ok()?);
+                    if first > last {
+                        return None;
+                    }
+                    cpus.extend(first..=last);
+                }
+                None => cpus.push(range.parse().ok()?),
+            }
+        }
+        Some(Self::from_iter(cpus))
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.cpus.binary_search(&cpu).is_ok()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cpus.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpus.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_empty()
+    }
+}
+
+impl FromIterator<usize> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut cpus = Vec::from_iter(iter);
+        cpus.sort_unstable();
+        cpus.dedup();
+        Self { cpus }
+    }
+}
+
+// "eth0-TxRx-3" -> 3
+fn parse_queue_irq_name(name: &str, if_name: &str) -> Option<u32> {
+    let suffix = name.strip_prefix(if_name)?.strip_prefix('-')?;
+    let queue = suffix
+        .strip_prefix("rx-")
+        .or_else(|| suffix.strip_prefix("TxRx-"))?;
+    queue.parse().ok()
+}
+
+/// traffic class an RSS hash configuration applies to, the kernel's *_FLOW values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RssFlowType {
+    TcpV4 = 0x01,
+    UdpV4 = 0x02,
+    TcpV6 = 0x05,
+    UdpV6 = 0x06,
+}
+
+/// a header field the RSS hash can cover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RssHashField {
+    SrcIp,
+    DstIp,
+    /// first two bytes of the L4 header, the source port for TCP and UDP
+    SrcPort,
+    /// bytes 2 and 3 of the L4 header, the destination port for TCP and UDP
+    DstPort,
+    /// the protocol field of the IP header
+    L3Proto,
+}
+
+impl RssHashField {
+    // the kernel's RXH_* bits
+    fn bit(self) -> u64 {
+        match self {
+            Self::L3Proto => 1 << 3,
+            Self::SrcIp => 1 << 4,
+            Self::DstIp => 1 << 5,
+            Self::SrcPort => 1 << 6,
+            Self::DstPort => 1 << 7,
+        }
+    }
+}
+
+/// set of header fields the RSS hash covers. bits without an RssHashField (e.g. the VLAN tag)
+/// are kept as read from the driver
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RssHashFields(u64);
+
+impl RssHashFields {
+    pub fn new(fields: &[RssHashField]) -> Self {
+        Self(fields.iter().fold(0, |bits, field| bits | field.bit()))
+    }
+
+    pub fn contains(&self, field: RssHashField) -> bool {
+        self.0 & field.bit() != 0
+    }
+
+    pub fn insert(&mut self, field: RssHashField) {
+        self.0 |= field.bit();
+    }
+
+    pub fn remove(&mut self, field: RssHashField) {
+        self.0 &= !field.bit();
+    }
+
+    /// the raw RXH_* bits
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+}
+
+/// interrupt coalescing settings of a NIC, see NetworkDevice::set_coalesce()
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoalesceParams {
+    /// how long to delay an rx interrupt after a packet arrives
+    pub rx_usecs: u32,
+    /// raise the rx interrupt once this many packets arrived, whatever rx_usecs says
+    pub rx_max_frames: u32,
+    /// how long to delay a tx completion interrupt
+    pub tx_usecs: u32,
+    /// let the driver tune rx coalescing to the packet rate, overrides the rx settings
+    pub use_adaptive_rx: bool,
+}
+
+impl CoalesceParams {
+    /// an interrupt per packet, as soon as it arrives. `tx_usecs` is kept as given
+    pub fn low_latency(self) -> Self {
+        Self {
+            rx_usecs: 0,
+            rx_max_frames: 1,
+            use_adaptive_rx: false,
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -271,6 +814,14 @@ impl RingConsumer {
         Some(index)
     }
 
+    /// the index consume() would return next, without consuming it
+    pub fn peek(&self) -> Option<u32> {
+        if self.cached_consumer == self.cached_producer {
+            return None;
+        }
+        Some(self.cached_consumer)
+    }
+
     pub fn commit(&mut self) {
         unsafe { (*self.consumer).store(self.cached_consumer, Ordering::Release) };
     }
@@ -319,6 +870,15 @@ impl RingProducer {
         Some(index)
     }
 
+    /// reserve up to `count` slots, returns the first index and how many were reserved
+    #[inline]
+    pub fn produce_batch(&mut self, count: u32) -> (u32, u32) {
+        let count = count.min(self.available());
+        let index = self.cached_producer;
+        self.cached_producer = self.cached_producer.wrapping_add(count);
+        (index, count)
+    }
+
     #[inline]
     pub fn commit(&mut self) {
         unsafe { (*self.producer).store(self.cached_producer, Ordering::Release) };
@@ -396,14 +956,34 @@ impl<F: Frame> RxFillRing<F> {
         let Some(index) = self.producer.produce() else {
             return Err(ErrorKind::StorageFull.into());
         };
+        self.write_desc(index, frame.offset());
+        Ok(())
+    }
+
+    /// write as many of `frames` as fit, returns how many were written. the frames after that
+    /// are still owned by the caller.
+    pub fn write_batch(&mut self, frames: &[F]) -> usize {
+        let (start, count) = self
+            .producer
+            .produce_batch(frames.len().min(u32::MAX as usize) as u32);
+        for (i, frame) in frames[..count as usize].iter().enumerate() {
+            self.write_desc(start.wrapping_add(i as u32), frame.offset());
+        }
+        count as usize
+    }
+
+    /// fill in the descriptor at producer `index`, which must have been produced
+    #[inline]
+    fn write_desc(&mut self, index: u32, offset: FrameOffset) {
         let index = index & self.size.saturating_sub(1);
-        let desc = unsafe { self.mmap.desc.add(index as usize) };
         // Safety: index is within the ring so the pointer is valid
         unsafe {
-            desc.write(frame.offset().0 as u64);
+            self.mmap.desc.add(index as usize).write(offset.0 as u64);
         }
+    }
 
-        Ok(())
+    pub fn capacity(&self) -> usize {
+        self.size as usize
     }
 
     pub fn available(&self) -> usize {
@@ -508,6 +1088,26 @@ mod test {
         assert_eq!(ring.produce(), Some(17));
     }
 
+    #[test]
+    fn test_ring_producer_batch() {
+        let mut producer = AtomicU32::new(0);
+        let mut consumer = AtomicU32::new(0);
+        let size = 16;
+        let mut ring = RingProducer::new(&mut producer as *mut _, &mut consumer as *mut _, size);
+
+        assert_eq!(ring.produce_batch(10), (0, 10));
+        assert_eq!(ring.available(), 6);
+        // only what fits is reserved
+        assert_eq!(ring.produce_batch(10), (10, 6));
+        assert_eq!(ring.produce_batch(1), (16, 0));
+
+        consumer.store(4, Ordering::Release);
+        ring.sync(true);
+        assert_eq!(producer.load(Ordering::Acquire), 16);
+        assert_eq!(ring.produce_batch(8), (16, 4));
+        assert_eq!(ring.produce(), None);
+    }
+
     #[test]
     fn test_ring_producer_wrap_around() {
         let size = 16;
@@ -553,6 +1153,28 @@ mod test {
         assert_eq!(ring.consume(), None);
     }
 
+    #[test]
+    fn test_ring_consumer_peek() {
+        let mut producer = AtomicU32::new(0);
+        let mut consumer = AtomicU32::new(0);
+        let mut ring = RingConsumer::new(&mut producer as *mut _, &mut consumer as *mut _);
+        assert_eq!(ring.peek(), None);
+
+        producer.store(2, Ordering::Release);
+        ring.sync(true);
+        assert_eq!(ring.peek(), Some(0));
+        assert_eq!(ring.peek(), Some(0));
+        assert_eq!(ring.available(), 2);
+
+        assert_eq!(ring.consume(), Some(0));
+        assert_eq!(ring.peek(), Some(1));
+        ring.commit();
+        // only consumed entries are released to the producer
+        assert_eq!(consumer.load(Ordering::Acquire), 1);
+        assert_eq!(ring.consume(), Some(1));
+        assert_eq!(ring.peek(), None);
+    }
+
     #[test]
     fn test_ring_consumer_wrap_around() {
         let mut producer = AtomicU32::new(u32::MAX - 1);
@@ -578,4 +1200,64 @@ mod test {
         ring.sync(true);
         assert_eq!(ring.consume(), Some(1));
     }
+
+    #[test]
+    fn test_irq_affinity_parsing() {
+        let cpus = CpuSet::from_hex_mask("00000001,0000000c\n").unwrap();
+        assert_eq!(cpus.iter().collect::<Vec<_>>(), vec![2, 3, 32]);
+        assert!(cpus.contains(32));
+        assert!(!cpus.contains(0));
+        assert!(CpuSet::from_hex_mask("zz").is_none());
+
+        let cpus = CpuSet::from_cpu_list("0-3,8,10-11\n").unwrap();
+        assert_eq!(cpus.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert!(CpuSet::from_cpu_list("").unwrap().is_empty());
+        assert!(CpuSet::from_cpu_list("3-1").is_none());
+        assert!(CpuSet::from_cpu_list("a").is_none());
+
+        assert_eq!(parse_queue_irq_name("eth0-TxRx-3", "eth0"), Some(3));
+        assert_eq!(parse_queue_irq_name("eth0-rx-12", "eth0"), Some(12));
+        assert_eq!(parse_queue_irq_name("eth0-tx-1", "eth0"), None);
+        assert_eq!(parse_queue_irq_name("eth10-rx-1", "eth1"), None);
+    }
+
+    #[test]
+    fn test_ethtool_struct_sizes() {
+        // the kernel copies exactly sizeof(struct ethtool_*) in and out
+        assert_eq!(mem::size_of::<EthtoolRingParam>(), 9 * 4);
+        assert_eq!(mem::size_of::<EthtoolCoalesce>(), 23 * 4);
+
+        let params = CoalesceParams {
+            rx_usecs: 50,
+            rx_max_frames: 64,
+            tx_usecs: 100,
+            use_adaptive_rx: true,
+        };
+        assert_eq!(
+            params.low_latency(),
+            CoalesceParams {
+                rx_usecs: 0,
+                rx_max_frames: 1,
+                tx_usecs: 100,
+                use_adaptive_rx: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rss_hash_fields() {
+        use RssHashField::*;
+
+        // RXH_IP_SRC | RXH_IP_DST | RXH_L4_B_0_1 | RXH_L4_B_2_3, the usual 4-tuple
+        let mut fields = RssHashFields::new(&[SrcIp, DstIp, SrcPort, DstPort]);
+        assert_eq!(fields.bits(), 0xf0);
+        fields.remove(DstPort);
+        assert!(!fields.contains(DstPort));
+        assert!(fields.contains(SrcPort));
+
+        // bits we have no name for survive a round trip, here RXH_VLAN
+        let mut fields = RssHashFields(1 << 2);
+        fields.insert(L3Proto);
+        assert_eq!(fields.bits(), 0x0c);
+    }
 }
@@ -0,0 +1,312 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// single producer, multi consumer ring buffer in the style of the LMAX disruptor. every consumer
+// sees every event, the producer reuses a slot once all consumers are past it. slots are
+// allocated once up front and written in place, so publishing never allocates.
+
+use {
+    crate::rx_loop::CachePadded,
+    std::{
+        cell::UnsafeCell,
+        hint,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+};
+
+/// ring shared between a Producer and its Consumers, N must be a power of two
+pub struct Disruptor<T, const N: usize> {
+    slots: Box<[UnsafeCell<T>; N]>,
+    // number of events published
+    published: CachePadded<AtomicUsize>,
+    // number of events each consumer is done with, usize::MAX once a consumer is dropped
+    consumed: Box<[CachePadded<AtomicUsize>]>,
+    closed: AtomicBool,
+}
+
+// Safety: a slot is only written by the producer while no consumer can read it and only read by
+// consumers after it's published, the sequence atomics order the two.
+unsafe impl<T: Send + Sync, const N: usize> Sync for Disruptor<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Disruptor<T, N> {}
+
+impl<T, const N: usize> Disruptor<T, N> {
+    const MASK: usize = N - 1;
+
+    /// allocate the ring with every slot initialized by `factory`, returns the producer and
+    /// `consumers` consumers
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        consumers: usize,
+        mut factory: impl FnMut() -> T,
+    ) -> (Producer<T, N>, Vec<Consumer<T, N>>) {
+        assert!(N.is_power_of_two(), "ring size must be a power of two");
+        assert!(consumers > 0, "a disruptor needs at least one consumer");
+
+        let slots: Box<[UnsafeCell<T>]> = (0..N).map(|_| UnsafeCell::new(factory())).collect();
+        let Ok(slots) = slots.try_into() else {
+            unreachable!("exactly N slots were allocated");
+        };
+        let ring = Arc::new(Self {
+            slots,
+            published: CachePadded(AtomicUsize::new(0)),
+            consumed: (0..consumers)
+                .map(|_| CachePadded(AtomicUsize::new(0)))
+                .collect(),
+            closed: AtomicBool::new(false),
+        });
+
+        let consumers = (0..consumers)
+            .map(|id| Consumer {
+                ring: Arc::clone(&ring),
+                id,
+                next: 0,
+            })
+            .collect();
+        let producer = Producer {
+            ring,
+            next: 0,
+            cached_min_consumed: 0,
+            reclaimed: 0,
+        };
+        (producer, consumers)
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    fn min_consumed(&self) -> usize {
+        self.consumed
+            .iter()
+            .map(|consumed| consumed.load(Ordering::Acquire))
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+}
+
+pub struct Producer<T, const N: usize> {
+    ring: Arc<Disruptor<T, N>>,
+    next: usize,
+    // the slowest consumer's position when last checked, avoids touching the consumer cache
+    // lines on every publish
+    cached_min_consumed: usize,
+    // events passed to reclaim() so far
+    reclaimed: usize,
+}
+
+impl<T, const N: usize> Producer<T, N> {
+    /// fill the next slot with `f` and publish it, spins while the slowest consumer is a full
+    /// ring behind
+    #[inline]
+    pub fn publish(&mut self, f: impl FnOnce(&mut T)) {
+        while !self.has_room() {
+            hint::spin_loop();
+        }
+        self.write(f);
+    }
+
+    /// like publish but returns false instead of waiting when the ring is full
+    #[inline]
+    pub fn try_publish(&mut self, f: impl FnOnce(&mut T)) -> bool {
+        if !self.has_room() {
+            return false;
+        }
+        self.write(f);
+        true
+    }
+
+    /// events published so far
+    pub fn published(&self) -> usize {
+        self.next
+    }
+
+    /// spin until every consumer is done with every published event, after this no consumer
+    /// reads a slot again until the next publish
+    pub fn wait_until_consumed(&mut self) {
+        while self.ring.min_consumed() < self.next {
+            hint::spin_loop();
+        }
+        self.cached_min_consumed = self.next;
+    }
+
+    /// run `f` on every slot all consumers are done with since the last call, lets the producer
+    /// release what an event holds without waiting for its slot to be reused. returns how many
+    /// slots were visited
+    pub fn reclaim(&mut self, mut f: impl FnMut(&mut T)) -> usize {
+        self.cached_min_consumed = self.ring.min_consumed();
+        let done = self.cached_min_consumed.min(self.next);
+        // slots more than a ring behind were overwritten by later events
+        let start = self.reclaimed.max(self.next.saturating_sub(N));
+        for sequence in start..done {
+            let slot = &self.ring.slots[sequence & Disruptor::<T, N>::MASK];
+            // Safety: every consumer is past this slot and the producer is the only writer
+            f(unsafe { &mut *slot.get() });
+        }
+        self.reclaimed = self.reclaimed.max(done);
+        done.saturating_sub(start)
+    }
+
+    #[inline]
+    fn has_room(&mut self) -> bool {
+        if self.next - self.cached_min_consumed.min(self.next) < N {
+            return true;
+        }
+        self.cached_min_consumed = self.ring.min_consumed();
+        self.next - self.cached_min_consumed.min(self.next) < N
+    }
+
+    #[inline]
+    fn write(&mut self, f: impl FnOnce(&mut T)) {
+        let slot = &self.ring.slots[self.next & Disruptor::<T, N>::MASK];
+        // Safety: has_room() saw every consumer past this slot and they only read published
+        // slots, so nobody else holds a reference to it
+        f(unsafe { &mut *slot.get() });
+        self.next += 1;
+        self.ring.published.store(self.next, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> Drop for Producer<T, N> {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+    }
+}
+
+pub struct Consumer<T, const N: usize> {
+    ring: Arc<Disruptor<T, N>>,
+    id: usize,
+    next: usize,
+}
+
+impl<T, const N: usize> Consumer<T, N> {
+    /// run `f` on every event published since the last call, returns how many there were
+    #[inline]
+    pub fn consume(&mut self, mut f: impl FnMut(&T)) -> usize {
+        let published = self.ring.published.load(Ordering::Acquire);
+        let count = published - self.next;
+        for sequence in self.next..published {
+            let slot = &self.ring.slots[sequence & Disruptor::<T, N>::MASK];
+            // Safety: the slot is published and the producer won't reuse it until we store a
+            // consumed position past it below
+            f(unsafe { &*slot.get() });
+        }
+        self.next = published;
+        self.ring.consumed[self.id].store(published, Ordering::Release);
+        count
+    }
+
+    /// true once the producer is gone and every event it published has been consumed
+    pub fn is_closed(&self) -> bool {
+        self.ring.closed.load(Ordering::Acquire)
+            && self.ring.published.load(Ordering::Acquire) == self.next
+    }
+}
+
+impl<T, const N: usize> Drop for Consumer<T, N> {
+    fn drop(&mut self) {
+        // stop gating the producer on us
+        self.ring.consumed[self.id].store(usize::MAX, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::thread};
+
+    #[test]
+    fn test_disruptor_wraps_and_gates_on_slowest_consumer() {
+        let (mut producer, mut consumers) = Disruptor::<u64, 4>::new(2, || 0);
+
+        for i in 0..4 {
+            assert!(producer.try_publish(|slot| *slot = i));
+        }
+        // full until both consumers have caught up
+        assert!(!producer.try_publish(|_| unreachable!()));
+        let mut seen = Vec::new();
+        assert_eq!(consumers[0].consume(|&event| seen.push(event)), 4);
+        assert_eq!(seen, [0, 1, 2, 3]);
+        assert!(!producer.try_publish(|_| unreachable!()));
+        assert_eq!(consumers[1].consume(|_| ()), 4);
+
+        // slots are reused in place
+        assert!(producer.try_publish(|slot| *slot += 10));
+        seen.clear();
+        assert_eq!(consumers[0].consume(|&event| seen.push(event)), 1);
+        assert_eq!(seen, [10]);
+
+        // a dropped consumer no longer holds the producer back
+        drop(consumers.pop());
+        for i in 0..4 {
+            assert!(producer.try_publish(|slot| *slot = i));
+        }
+        assert!(!consumers[0].is_closed());
+        drop(producer);
+        assert!(!consumers[0].is_closed());
+        assert_eq!(consumers[0].consume(|_| ()), 4);
+        assert!(consumers[0].is_closed());
+    }
+
+    #[test]
+    fn test_disruptor_reclaim() {
+        let (mut producer, mut consumers) = Disruptor::<Option<u64>, 4>::new(1, || None);
+
+        for i in 0..3 {
+            assert!(producer.try_publish(|slot| *slot = Some(i)));
+        }
+        assert_eq!(producer.reclaim(|_| unreachable!()), 0);
+        assert_eq!(consumers[0].consume(|_| ()), 3);
+        assert_eq!(producer.reclaim(|slot| *slot = None), 3);
+        assert_eq!(producer.reclaim(|_| unreachable!()), 0);
+
+        // slots overwritten before being reclaimed aren't visited twice
+        for i in 3..9 {
+            while !producer.try_publish(|slot| *slot = Some(i)) {
+                consumers[0].consume(|_| ());
+            }
+        }
+        consumers[0].consume(|_| ());
+        let mut reclaimed = Vec::new();
+        assert_eq!(producer.reclaim(|slot| reclaimed.push(slot.take())), 4);
+        assert_eq!(reclaimed, [Some(5), Some(6), Some(7), Some(8)]);
+    }
+
+    #[test]
+    fn test_disruptor_threads() {
+        const EVENTS: u64 = 10_000;
+        let (mut producer, consumers) = Disruptor::<u64, 64>::new(3, || 0);
+
+        let handles: Vec<_> = consumers
+            .into_iter()
+            .map(|mut consumer| {
+                thread::spawn(move || {
+                    let mut sum = 0;
+                    let mut expected = 0;
+                    while !consumer.is_closed() {
+                        let consumed = consumer.consume(|&event| {
+                            assert_eq!(event, expected);
+                            expected += 1;
+                            sum += event;
+                        });
+                        if consumed == 0 {
+                            thread::yield_now();
+                        }
+                    }
+                    sum
+                })
+            })
+            .collect();
+
+        for i in 0..EVENTS {
+            while !producer.try_publish(|slot| *slot = i) {
+                thread::yield_now();
+            }
+        }
+        drop(producer);
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), EVENTS * (EVENTS - 1) / 2);
+        }
+    }
+}
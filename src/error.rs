@@ -0,0 +1,76 @@
+use {
+    crate::{caps_check::MissingCapabilities, umem::UmemError},
+    aya::{
+        maps::{xdp::XdpMapError, MapError},
+        programs::ProgramError,
+        EbpfError,
+    },
+    caps::errors::CapsError,
+    std::io,
+    thiserror::Error,
+};
+
+/// errors from loading the XDP program and setting up sockets around it
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to load eBPF object: {0}")]
+    XdpLoad(#[from] EbpfError),
+
+    #[error("invalid BTF in eBPF object, rebuild with xdp-ebpf/build_ebpf.sh: {0}")]
+    InvalidBtf(#[source] EbpfError),
+
+    #[error("BTF relocation failed, the kernel may lack CONFIG_DEBUG_INFO_BTF: {0}")]
+    BtfRelocation(#[source] EbpfError),
+
+    #[error("{0} not found in eBPF object")]
+    MissingProgram(&'static str),
+
+    #[error("{0} not found in XDP program")]
+    MissingMap(&'static str),
+
+    #[error("XDP program error: {0}")]
+    Program(#[from] ProgramError),
+
+    #[error("no XDP program attached to if_index {0}")]
+    NotAttached(u32),
+
+    #[error("invalid CPU count {0}, must be 1..=64")]
+    InvalidCpuCount(u32),
+
+    #[error("invalid CPU id {0}, must be below 64")]
+    InvalidCpuId(u32),
+
+    #[error("invalid XSKS_MAP index {0}, must be below 64")]
+    InvalidXskIndex(u32),
+
+    #[error("invalid STAGES slot {0}, must be below 8")]
+    InvalidStageSlot(u32),
+
+    #[error("XDP map error: {0}")]
+    Map(#[from] MapError),
+
+    /// CPUMAP and DEVMAP updates, which can also fail for lack of chained program support
+    #[error("XDP map error: {0}")]
+    XdpMap(#[from] XdpMapError),
+
+    #[error("failed to insert socket into XSKS_MAP: {0}")]
+    XskMapInsert(#[source] MapError),
+
+    #[error("failed to create AF_XDP socket: {0}")]
+    SocketCreate(#[source] io::Error),
+
+    #[error("failed to allocate UMEM: {0}")]
+    UmemAlloc(#[source] io::Error),
+
+    #[error(transparent)]
+    Umem(#[from] UmemError),
+
+    #[error("capability error: {0}")]
+    CapabilityError(#[from] CapsError),
+
+    #[error(transparent)]
+    MissingCapabilities(#[from] MissingCapabilities),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
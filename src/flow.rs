@@ -0,0 +1,227 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// per flow packet and byte counters, updated by the relay loop for every forwarded packet
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// IPv4 5-tuple identifying a flow
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src_ip: [u8; 4],
+    pub dst_ip: [u8; 4],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlowStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+/// flows a FlowTable tracks unless told otherwise
+pub const DEFAULT_MAX_FLOWS: usize = 65536;
+
+/// flows seen by the relay loop
+///
+/// flows are only removed by evict_idle(), whoever owns the table has to call it periodically.
+/// once `max_flows` are tracked packets of new flows are only counted in overflow_packets(), so
+/// a flood of spoofed 5-tuples can't grow the table without bound.
+#[derive(Debug)]
+pub struct FlowTable {
+    flows: HashMap<FlowKey, FlowStats>,
+    max_flows: usize,
+    overflow_packets: u64,
+}
+
+impl Default for FlowTable {
+    fn default() -> Self {
+        Self::with_max_flows(DEFAULT_MAX_FLOWS)
+    }
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_flows(max_flows: usize) -> Self {
+        Self {
+            flows: HashMap::new(),
+            max_flows,
+            overflow_packets: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<&FlowStats> {
+        self.flows.get(key)
+    }
+
+    /// packets of flows that weren't tracked because the table was full
+    pub fn overflow_packets(&self) -> u64 {
+        self.overflow_packets
+    }
+
+    /// count a packet of `len` bytes seen at `now`, creating the flow on its first packet.
+    /// returns None if the flow is new and the table is full
+    #[inline]
+    pub fn record(&mut self, key: FlowKey, len: usize, now: Instant) -> Option<&FlowStats> {
+        self.add(
+            key,
+            FlowStats {
+                packets: 1,
+                bytes: len as u64,
+                first_seen: now,
+                last_seen: now,
+            },
+        )
+    }
+
+    /// move the flows of `other` into this table, summing the counters of flows both have.
+    /// `other` is left empty
+    ///
+    /// lets the relay loop count into a table of its own and only take the shared table's lock
+    /// now and then.
+    pub fn merge(&mut self, other: &mut FlowTable) {
+        self.overflow_packets += std::mem::take(&mut other.overflow_packets);
+        for (key, stats) in other.flows.drain() {
+            self.add(key, stats);
+        }
+    }
+
+    fn add(&mut self, key: FlowKey, add: FlowStats) -> Option<&FlowStats> {
+        if self.flows.len() >= self.max_flows && !self.flows.contains_key(&key) {
+            self.overflow_packets += add.packets;
+            return None;
+        }
+        let stats = self.flows.entry(key).or_insert(FlowStats {
+            packets: 0,
+            bytes: 0,
+            ..add
+        });
+        stats.packets += add.packets;
+        stats.bytes += add.bytes;
+        stats.first_seen = stats.first_seen.min(add.first_seen);
+        stats.last_seen = stats.last_seen.max(add.last_seen);
+        Some(stats)
+    }
+
+    /// remove the flows that haven't seen a packet for `idle_timeout`, returns how many
+    pub fn evict_idle(&mut self, idle_timeout: Duration) -> usize {
+        self.evict_idle_at(idle_timeout, Instant::now())
+    }
+
+    pub fn evict_idle_at(&mut self, idle_timeout: Duration, now: Instant) -> usize {
+        let before = self.flows.len();
+        self.flows
+            .retain(|_, stats| now.saturating_duration_since(stats.last_seen) < idle_timeout);
+        before - self.flows.len()
+    }
+
+    /// the `n` flows with the most packets, busiest first
+    pub fn top_flows(&self, n: usize) -> Vec<(FlowKey, FlowStats)> {
+        let mut flows = self
+            .flows
+            .iter()
+            .map(|(key, stats)| (*key, *stats))
+            .collect::<Vec<_>>();
+        // only the top n need sorting
+        if n < flows.len() {
+            flows.select_nth_unstable_by_key(n, |(_, stats)| Reverse(stats.packets));
+            flows.truncate(n);
+        }
+        flows.sort_unstable_by_key(|(_, stats)| Reverse(stats.packets));
+        flows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_table() {
+        let key = |src_port| FlowKey {
+            src_ip: [10, 0, 0, 1],
+            dst_ip: [10, 0, 0, 2],
+            src_port,
+            dst_port: 8001,
+            proto: 17,
+        };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut table = FlowTable::new();
+        for _ in 0..3 {
+            table.record(key(1), 100, at(0));
+        }
+        table.record(key(2), 1000, at(10));
+        table.record(key(3), 50, at(20));
+        table.record(key(3), 50, at(500));
+
+        let stats = table.get(&key(3)).unwrap();
+        assert_eq!((stats.packets, stats.bytes), (2, 100));
+        assert_eq!((stats.first_seen, stats.last_seen), (at(20), at(500)));
+
+        let top = table.top_flows(2);
+        assert_eq!(
+            top.iter().map(|(key, _)| key.src_port).collect::<Vec<_>>(),
+            [1, 3]
+        );
+        assert_eq!(table.top_flows(10).len(), 3);
+
+        // only the flow seen at 500ms is still active
+        assert_eq!(table.evict_idle_at(Duration::from_millis(400), at(600)), 2);
+        assert_eq!(table.len(), 1);
+        assert!(table.get(&key(3)).is_some());
+    }
+
+    #[test]
+    fn test_flow_table_merge_max_flows() {
+        let key = |src_port| FlowKey {
+            src_ip: [10, 0, 0, 1],
+            dst_ip: [10, 0, 0, 2],
+            src_port,
+            dst_port: 8001,
+            proto: 17,
+        };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut shared = FlowTable::with_max_flows(2);
+        shared.record(key(1), 100, at(0));
+        shared.record(key(2), 100, at(0));
+        // full, known flows are still counted
+        assert!(shared.record(key(3), 100, at(0)).is_none());
+        assert!(shared.record(key(1), 100, at(5)).is_some());
+        assert_eq!(shared.overflow_packets(), 1);
+
+        let mut local = FlowTable::new();
+        local.record(key(1), 50, at(10));
+        local.record(key(4), 50, at(10));
+        local.record(key(4), 50, at(20));
+        shared.merge(&mut local);
+        assert!(local.is_empty());
+
+        let stats = shared.get(&key(1)).unwrap();
+        assert_eq!((stats.packets, stats.bytes), (3, 250));
+        assert_eq!((stats.first_seen, stats.last_seen), (at(0), at(10)));
+        assert!(shared.get(&key(4)).is_none());
+        assert_eq!(shared.overflow_packets(), 3);
+    }
+}
@@ -0,0 +1,242 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// latency histograms shared by the relay loop and the shred decoders
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// lock-free latency histogram with log-linear buckets from 1ns to 10s
+///
+/// every power of two is split into 16 buckets, so percentiles are accurate to about 6%.
+/// anything slower than 10s lands in the last bucket and is reported through max. recording
+/// takes &self so threads can share one histogram, concurrent records and reads are only
+/// approximately consistent with each other.
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+    sum_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+/// latency percentiles of the samples recorded since the last reset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyReport {
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    const SUB_BUCKET_BITS: u32 = 4;
+    const SUB_BUCKETS: u64 = 1 << Self::SUB_BUCKET_BITS;
+    const MAX_NS: u64 = 10_000_000_000;
+
+    pub fn new() -> Self {
+        let len = Self::bucket_index(Self::MAX_NS) + 1;
+        Self {
+            buckets: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            sum_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn record(&self, latency: Duration) {
+        let ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = Self::bucket_index(ns.min(Self::MAX_NS));
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// upper bound of the bucket holding the `quantile` (0.0..=1.0) sample, capped at the
+    /// largest sample
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        Duration::from_nanos(self.percentile_ns(&counts, quantile))
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let count = counts.iter().sum();
+        if count == 0 {
+            return LatencyReport::default();
+        }
+        LatencyReport {
+            count,
+            p50: Duration::from_nanos(self.percentile_ns(&counts, 0.5)),
+            p99: Duration::from_nanos(self.percentile_ns(&counts, 0.99)),
+            p999: Duration::from_nanos(self.percentile_ns(&counts, 0.999)),
+            mean: Duration::from_nanos(self.sum_ns.load(Ordering::Relaxed) / count),
+            max: Duration::from_nanos(self.max_ns.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn reset(&self) {
+        for count in self.buckets.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.sum_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+    }
+
+    fn percentile_ns(&self, counts: &[u64], quantile: f64) -> u64 {
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let max_ns = self.max_ns.load(Ordering::Relaxed);
+        let target = ((total as f64 * quantile).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                // the last bucket also holds everything slower than MAX_NS
+                if index == counts.len() - 1 {
+                    return max_ns;
+                }
+                return Self::bucket_upper_bound(index).min(max_ns);
+            }
+        }
+        max_ns
+    }
+
+    #[inline]
+    fn bucket_index(ns: u64) -> usize {
+        if ns < Self::SUB_BUCKETS {
+            return ns as usize;
+        }
+        let exp = 63 - ns.leading_zeros();
+        let sub = (ns >> (exp - Self::SUB_BUCKET_BITS)) & (Self::SUB_BUCKETS - 1);
+        ((exp - Self::SUB_BUCKET_BITS + 1) as u64 * Self::SUB_BUCKETS + sub) as usize
+    }
+
+    /// smallest value in bucket `index`
+    fn bucket_value(index: usize) -> u64 {
+        let index = index as u64;
+        if index < Self::SUB_BUCKETS {
+            return index;
+        }
+        let exp = (index / Self::SUB_BUCKETS) as u32 + Self::SUB_BUCKET_BITS - 1;
+        (Self::SUB_BUCKETS + index % Self::SUB_BUCKETS) << (exp - Self::SUB_BUCKET_BITS)
+    }
+
+    /// largest value in bucket `index`
+    fn bucket_upper_bound(index: usize) -> u64 {
+        Self::bucket_value(index + 1) - 1
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} p50={:?} p99={:?} p999={:?} mean={:?} max={:?}",
+            self.count, self.p50, self.p99, self.p999, self.mean, self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram_percentiles() {
+        let latency = LatencyHistogram::new();
+        assert_eq!(latency.report(), LatencyReport::default());
+
+        // 990 fast packets, 9 slow ones and a single outlier
+        for _ in 0..990 {
+            latency.record(Duration::from_micros(5));
+        }
+        for _ in 0..9 {
+            latency.record(Duration::from_micros(500));
+        }
+        latency.record(Duration::from_millis(50));
+
+        let report = latency.report();
+        assert_eq!(report.count, 1000);
+        // bucket bounds are within 1/16 of the sample
+        assert!(report.p50 >= Duration::from_micros(5) && report.p50 < Duration::from_nanos(5400));
+        assert!(report.p99 >= Duration::from_micros(5) && report.p99 < Duration::from_nanos(5400));
+        assert!(
+            report.p999 >= Duration::from_micros(500) && report.p999 < Duration::from_micros(540)
+        );
+        assert_eq!(report.max, Duration::from_millis(50));
+        assert_eq!(
+            report.mean.as_nanos(),
+            (990 * 5_000 + 9 * 500_000 + 50_000_000) / 1000
+        );
+        assert_eq!(latency.percentile(1.0), Duration::from_millis(50));
+
+        latency.reset();
+        assert_eq!(latency.count(), 0);
+        assert_eq!(latency.report(), LatencyReport::default());
+    }
+
+    #[test]
+    fn test_latency_histogram_buckets() {
+        let len = LatencyHistogram::bucket_index(LatencyHistogram::MAX_NS) + 1;
+        for index in 0..len {
+            // every bucket starts right after the previous one and maps back to itself
+            let value = LatencyHistogram::bucket_value(index);
+            assert_eq!(LatencyHistogram::bucket_index(value), index);
+            assert_eq!(
+                LatencyHistogram::bucket_index(LatencyHistogram::bucket_upper_bound(index)),
+                index
+            );
+            if index > 0 {
+                assert_eq!(LatencyHistogram::bucket_upper_bound(index - 1) + 1, value);
+            }
+            // buckets are at most 1/16 of their lower bound wide
+            let width = LatencyHistogram::bucket_upper_bound(index) - value + 1;
+            assert!(width <= (value / LatencyHistogram::SUB_BUCKETS).max(1));
+        }
+
+        let latency = LatencyHistogram::new();
+        assert_eq!(latency.percentile(0.5), Duration::ZERO);
+
+        // a single sample is every percentile, capped at the sample itself
+        latency.record(Duration::from_nanos(1_000_001));
+        assert_eq!(latency.percentile(0.0), Duration::from_nanos(1_000_001));
+        assert_eq!(latency.percentile(1.0), Duration::from_nanos(1_000_001));
+
+        // samples past the last bucket are counted there and still reported through max
+        latency.record(Duration::from_secs(60));
+        assert_eq!(latency.count(), 2);
+        let bucket = LatencyHistogram::bucket_index(1_000_001);
+        assert_eq!(
+            latency.percentile(0.5),
+            Duration::from_nanos(LatencyHistogram::bucket_upper_bound(bucket))
+        );
+        assert_eq!(latency.percentile(1.0), Duration::from_secs(60));
+        assert_eq!(latency.report().max, Duration::from_secs(60));
+    }
+}
@@ -8,29 +8,66 @@
 #![warn(unsafe_attr_outside_unsafe)]
 #![warn(unsafe_op_in_unsafe_fn)]
 
+#[cfg(target_os = "linux")]
+pub mod caps_check;
+#[cfg(target_os = "linux")]
+pub mod cpumap_relay;
+#[cfg(target_os = "linux")]
+pub mod cross_nic_relay;
 #[cfg(target_os = "linux")]
 pub mod device;
 #[cfg(target_os = "linux")]
+pub mod disruptor;
+#[cfg(target_os = "linux")]
+mod error;
+pub mod flow;
+pub mod latency;
+#[cfg(target_os = "linux")]
 pub mod netlink;
 #[cfg(target_os = "linux")]
 pub mod packet;
+pub mod packet_event;
+#[cfg(target_os = "linux")]
+pub mod packet_filter;
+pub mod pcap;
 #[cfg(target_os = "linux")]
 mod program;
 #[cfg(target_os = "linux")]
+pub mod raw_socket;
+#[cfg(target_os = "linux")]
 pub mod route;
 #[cfg(target_os = "linux")]
 pub mod socket;
 #[cfg(target_os = "linux")]
+pub mod sys;
+#[cfg(all(
+    target_os = "linux",
+    any(test, feature = "dev-context-only-utils")
+))]
+pub mod test_utils;
+#[cfg(target_os = "linux")]
 pub mod tx_loop;
 #[cfg(target_os = "linux")]
 pub mod relay_loop;
 #[cfg(target_os = "linux")]
+pub mod relay_watchdog;
+#[cfg(target_os = "linux")]
 pub mod rx_loop;
 #[cfg(target_os = "linux")]
 pub mod umem;
 
 #[cfg(target_os = "linux")]
-pub use program::{load_xdp_program, insert_socket_into_xskmap};
+pub use error::Error;
+#[cfg(target_os = "linux")]
+pub use program::{
+    add_ip_to_whitelist, add_route, add_udp_port, insert_socket_into_xskmap,
+    insert_tail_program, load_xdp_cpumap_program, load_xdp_devmap_forwarder,
+    load_xdp_dispatcher, load_xdp_program, load_xdp_routing_program, load_xdp_sample_program,
+    open_packet_event_stream, read_flow_hash, read_xdp_timestamp, read_xdp_stats,
+    remove_ip_from_whitelist, remove_route, remove_tail_program, replace_xdp_program,
+    set_local_ipv4, set_packet_sample_rate, set_rate_limit, PacketEvent, PacketEventStream,
+    RouteAction, XdpProgram, XdpQueueStats, STAGE_FILTER, STAGE_RATE_LIMIT, STAGE_REDIRECT,
+};
 use std::io;
 extern crate libc;
 extern crate aya;
@@ -64,3 +101,41 @@ pub fn set_cpu_affinity(cpus: impl IntoIterator<Item = usize>) -> Result<(), io:
 pub fn set_cpu_affinity(_cpus: impl IntoIterator<Item = usize>) -> Result<(), io::Error> {
     unimplemented!()
 }
+
+/// CPUs on NUMA node `node`, from /sys/devices/system/node/node<N>/cpulist
+#[cfg(target_os = "linux")]
+pub fn cpu_ids_for_numa_node(node: u32) -> Result<Vec<usize>, io::Error> {
+    let path = format!("/sys/devices/system/node/node{node}/cpulist");
+    let list = std::fs::read_to_string(&path)?;
+    let cpus = device::CpuSet::from_cpu_list(&list).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid cpu list in {path}: {list:?}"),
+        )
+    })?;
+    Ok(cpus.iter().collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cpu_ids_for_numa_node(_node: u32) -> Result<Vec<usize>, io::Error> {
+    unimplemented!()
+}
+
+/// pin the calling thread to all the CPUs on NUMA node `node`
+#[cfg(target_os = "linux")]
+pub fn set_cpu_affinity_numa_node(node: u32) -> Result<(), io::Error> {
+    let cpus = cpu_ids_for_numa_node(node)?;
+    if cpus.is_empty() {
+        // memory-only node
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("NUMA node {node} has no CPUs"),
+        ));
+    }
+    set_cpu_affinity(cpus)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_cpu_affinity_numa_node(_node: u32) -> Result<(), io::Error> {
+    unimplemented!()
+}
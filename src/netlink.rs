@@ -1,20 +1,30 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 use {
+    crate::socket::XdpSocketStats,
     libc::{
-        getsockname, nlattr, nlmsgerr, nlmsghdr, recv, send, setsockopt, sockaddr_nl, socket,
-        AF_INET, AF_INET6, AF_NETLINK, NDA_DST, NDA_LLADDR, NETLINK_EXT_ACK, NETLINK_ROUTE,
+        bind, getsockname, nlattr, nlmsgerr, nlmsghdr, recv, send, setsockopt, sockaddr_nl, socket,
+        timeval, AF_INET, AF_INET6, AF_NETLINK, AF_XDP, IFA_ADDRESS, IFA_LOCAL, IFF_RUNNING,
+        IFF_UP, NDA_DST, NDA_LLADDR, NETLINK_EXT_ACK, NETLINK_ROUTE, NETLINK_SOCK_DIAG,
         NLA_ALIGNTO, NLA_TYPE_MASK, NLMSG_DONE, NLMSG_ERROR, NLM_F_DUMP, NLM_F_MULTI,
         NLM_F_REQUEST, NUD_PERMANENT, NUD_REACHABLE, NUD_STALE, RTA_DST, RTA_GATEWAY, RTA_IIF,
-        RTA_OIF, RTA_PREFSRC, RTA_PRIORITY, RTA_TABLE, RTM_GETNEIGH, RTM_GETROUTE, RTM_NEWNEIGH,
-        RTM_NEWROUTE, RT_TABLE_MAIN, SOCK_RAW, SOL_NETLINK,
+        RTA_OIF, RTA_PREFSRC, RTA_PRIORITY, RTA_TABLE, RTMGRP_IPV4_IFADDR, RTMGRP_IPV6_IFADDR,
+        RTMGRP_LINK, RTM_DELADDR, RTM_DELLINK, RTM_GETADDR, RTM_GETNEIGH, RTM_GETROUTE,
+        RTM_NEWADDR, RTM_NEWLINK, RTM_NEWNEIGH, RTM_NEWROUTE, RT_TABLE_MAIN, SOCK_RAW, SOL_NETLINK,
+        SOL_SOCKET, SO_RCVTIMEO,
     },
     std::{
-        collections::HashMap,
+        collections::{HashMap, VecDeque},
         io, mem,
         net::{IpAddr, Ipv4Addr, Ipv6Addr},
         os::fd::{AsRawFd, FromRawFd, OwnedFd},
         ptr, slice,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
     },
     thiserror::Error,
 };
@@ -28,8 +38,12 @@ pub struct NetlinkSocket {
 
 impl NetlinkSocket {
     fn open() -> Result<Self, io::Error> {
+        Self::open_protocol(NETLINK_ROUTE)
+    }
+
+    fn open_protocol(protocol: i32) -> Result<Self, io::Error> {
         // Safety: libc wrapper
-        let sock = unsafe { socket(AF_NETLINK, SOCK_RAW, NETLINK_ROUTE) };
+        let sock = unsafe { socket(AF_NETLINK, SOCK_RAW, protocol) };
         if sock < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -273,7 +287,7 @@ fn bytes_of<T>(val: &T) -> &[u8] {
 
 const NLMSG_ALIGNTO: u32 = 4;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct MacAddress(pub [u8; 6]);
 
 impl MacAddress {
@@ -284,6 +298,50 @@ impl MacAddress {
     pub fn as_bytes(&self) -> &[u8; 6] {
         &self.0
     }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.0 == [0xff; 6]
+    }
+
+    /// group bit set, includes broadcast
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 6]
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid MAC address `{0}`, expected aa:bb:cc:dd:ee:ff")]
+pub struct ParseMacAddressError(String);
+
+impl std::str::FromStr for MacAddress {
+    type Err = ParseMacAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in mac.iter_mut() {
+            *byte = parts
+                .next()
+                // from_str_radix takes a sign, "+a" would pass as 0a
+                .filter(|part| part.len() == 2 && part.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .ok_or_else(|| ParseMacAddressError(s.to_string()))?;
+        }
+        if parts.next().is_some() {
+            return Err(ParseMacAddressError(s.to_string()));
+        }
+        Ok(MacAddress(mac))
+    }
+}
+
+impl std::fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
 }
 
 impl std::fmt::Display for MacAddress {
@@ -572,3 +630,594 @@ pub fn netlink_get_default_gateway(family: u8) -> Result<Option<RouteEntry>, io:
 
     Ok(None)
 }
+
+#[derive(Debug, Clone)]
+pub struct AddrEntry {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+    pub scope: u8,
+    pub if_index: u32,
+}
+
+#[repr(C)]
+struct ifaddrmsg {
+    ifa_family: u8,
+    ifa_prefixlen: u8,
+    ifa_flags: u8,
+    ifa_scope: u8,
+    ifa_index: u32,
+}
+
+#[repr(C)]
+struct AddrRequest {
+    header: nlmsghdr,
+    ifa: ifaddrmsg,
+}
+
+/// fetch the addresses configured on an interface, or on all interfaces if if_index is None
+pub fn netlink_get_addrs(if_index: Option<u32>, family: u8) -> Result<Vec<AddrEntry>, io::Error> {
+    let sock = NetlinkSocket::open()?;
+
+    // Safety: AddrRequest is POD
+    let mut req = unsafe { mem::zeroed::<AddrRequest>() };
+
+    let nlmsg_len = mem::size_of::<nlmsghdr>() + mem::size_of::<ifaddrmsg>();
+    req.header = nlmsghdr {
+        nlmsg_len: nlmsg_len as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_DUMP) as u16,
+        nlmsg_type: RTM_GETADDR,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+
+    req.ifa.ifa_family = family;
+
+    sock.send(&bytes_of(&req)[..req.header.nlmsg_len as usize])?;
+
+    let mut addrs = Vec::new();
+
+    for msg in sock.recv()? {
+        if msg.header.nlmsg_type != RTM_NEWADDR {
+            continue;
+        }
+
+        if msg.data.len() < mem::size_of::<ifaddrmsg>() {
+            continue;
+        }
+
+        let Some(addr) = parse_rtm_newaddr(msg, if_index) else {
+            continue;
+        };
+
+        addrs.push(addr);
+    }
+
+    Ok(addrs)
+}
+
+pub fn parse_rtm_newaddr(msg: NetlinkMessage, if_index: Option<u32>) -> Option<AddrEntry> {
+    let ifa_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const ifaddrmsg) };
+    if let Some(idx) = if_index {
+        if ifa_msg.ifa_index != idx {
+            return None;
+        }
+    }
+    let Ok(attrs) = parse_attrs(&msg.data[mem::size_of::<ifaddrmsg>()..]) else {
+        return None;
+    };
+    // IFA_LOCAL is the local address on point-to-point links where IFA_ADDRESS is the peer
+    let addr_attr = attrs.get(&IFA_LOCAL).or_else(|| attrs.get(&IFA_ADDRESS))?;
+    Some(AddrEntry {
+        address: parse_ip_address(addr_attr.data, ifa_msg.ifa_family)?,
+        prefix_len: ifa_msg.ifa_prefixlen,
+        scope: ifa_msg.ifa_scope,
+        if_index: ifa_msg.ifa_index,
+    })
+}
+
+// sock_diag, see linux/sock_diag.h and linux/xdp_diag.h
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const XDP_SHOW_INFO: u32 = 1 << 0;
+const XDP_SHOW_RING_CFG: u32 = 1 << 1;
+const XDP_SHOW_UMEM: u32 = 1 << 2;
+const XDP_SHOW_STATS: u32 = 1 << 4;
+const XDP_DIAG_INFO: u16 = 1;
+const XDP_DIAG_RX_RING: u16 = 3;
+const XDP_DIAG_TX_RING: u16 = 4;
+const XDP_DIAG_UMEM: u16 = 5;
+const XDP_DIAG_UMEM_FILL_RING: u16 = 6;
+const XDP_DIAG_UMEM_COMPLETION_RING: u16 = 7;
+const XDP_DIAG_STATS: u16 = 9;
+const XDP_DU_F_ZEROCOPY: u32 = 1 << 0;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct xdp_diag_req {
+    sdiag_family: u8,
+    sdiag_protocol: u8,
+    pad: u16,
+    xdiag_ino: u32,
+    xdiag_show: u32,
+    xdiag_cookie: [u32; 2],
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct xdp_diag_msg {
+    xdiag_family: u8,
+    xdiag_type: u8,
+    pad: u16,
+    xdiag_ino: u32,
+    xdiag_cookie: [u32; 2],
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct xdp_diag_umem {
+    size: u64,
+    id: u32,
+    num_pages: u32,
+    chunk_size: u32,
+    headroom: u32,
+    ifindex: u32,
+    queue_id: u32,
+    flags: u32,
+    refs: u32,
+}
+
+#[repr(C)]
+struct XdpDiagRequest {
+    header: nlmsghdr,
+    req: xdp_diag_req,
+}
+
+/// UMEM registered by an AF_XDP socket, as reported by sock_diag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XdpUmemInfo {
+    pub id: u32,
+    /// size in bytes
+    pub size: u64,
+    pub pages: u32,
+    pub frame_size: u32,
+    pub headroom: u32,
+    /// sockets sharing the UMEM
+    pub refs: u32,
+    pub zero_copy: bool,
+}
+
+/// an AF_XDP socket as seen by the kernel
+///
+/// the ring sizes are None for rings the socket didn't create. fill and completion rings are the
+/// UMEM's, sockets created with XDP_SHARED_UMEM report the ones of the socket owning the UMEM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XdpSocketEntry {
+    pub inode: u32,
+    /// None until the socket is bound
+    pub if_index: Option<u32>,
+    pub queue_id: Option<u32>,
+    pub rx_ring: Option<u32>,
+    pub tx_ring: Option<u32>,
+    pub fill_ring: Option<u32>,
+    pub completion_ring: Option<u32>,
+    pub umem: Option<XdpUmemInfo>,
+    /// the same counters as XDP_STATISTICS
+    pub stats: Option<XdpSocketStats>,
+}
+
+/// dump all the AF_XDP sockets in the current network namespace
+///
+/// needs the xsk_diag module (CONFIG_XDP_SOCKETS_DIAG), fails with ENOENT without it. the
+/// counters are only reported to CAP_NET_ADMIN.
+pub fn netlink_get_xdp_sockets() -> Result<Vec<XdpSocketEntry>, io::Error> {
+    let sock = NetlinkSocket::open_protocol(NETLINK_SOCK_DIAG)?;
+
+    // Safety: XdpDiagRequest is POD
+    let mut req = unsafe { mem::zeroed::<XdpDiagRequest>() };
+
+    let nlmsg_len = mem::size_of::<nlmsghdr>() + mem::size_of::<xdp_diag_req>();
+    req.header = nlmsghdr {
+        nlmsg_len: nlmsg_len as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_DUMP) as u16,
+        nlmsg_type: SOCK_DIAG_BY_FAMILY,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+
+    req.req.sdiag_family = AF_XDP as u8;
+    req.req.xdiag_show = XDP_SHOW_INFO | XDP_SHOW_RING_CFG | XDP_SHOW_UMEM | XDP_SHOW_STATS;
+
+    sock.send(&bytes_of(&req)[..req.header.nlmsg_len as usize])?;
+
+    let mut sockets = Vec::new();
+
+    for msg in sock.recv()? {
+        if msg.header.nlmsg_type != SOCK_DIAG_BY_FAMILY {
+            continue;
+        }
+
+        if msg.data.len() < mem::size_of::<xdp_diag_msg>() {
+            continue;
+        }
+
+        let Some(socket) = parse_xdp_diag_msg(msg) else {
+            continue;
+        };
+
+        sockets.push(socket);
+    }
+
+    Ok(sockets)
+}
+
+/// the sock_diag entry of the AF_XDP socket with inode `inode`, see Socket::inode()
+pub fn netlink_get_xdp_socket(inode: u32) -> Result<Option<XdpSocketEntry>, io::Error> {
+    Ok(netlink_get_xdp_sockets()?
+        .into_iter()
+        .find(|socket| socket.inode == inode))
+}
+
+pub fn parse_xdp_diag_msg(msg: NetlinkMessage) -> Option<XdpSocketEntry> {
+    let diag_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const xdp_diag_msg) };
+    if diag_msg.xdiag_family != AF_XDP as u8 {
+        return None;
+    }
+    let Ok(attrs) = parse_attrs(&msg.data[mem::size_of::<xdp_diag_msg>()..]) else {
+        return None;
+    };
+    let read_u32 = |data: &[u8], offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+    };
+    let read_u64 = |data: &[u8], offset: usize| {
+        data.get(offset..offset + 8)
+            .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
+    };
+    let ring = |attr_type| {
+        attrs
+            .get(&attr_type)
+            .and_then(|attr| read_u32(attr.data, 0))
+    };
+
+    let info = attrs.get(&XDP_DIAG_INFO);
+    let umem = attrs
+        .get(&XDP_DIAG_UMEM)
+        .filter(|attr| attr.data.len() >= mem::size_of::<xdp_diag_umem>())
+        .map(|attr| {
+            let umem = unsafe { ptr::read_unaligned(attr.data.as_ptr() as *const xdp_diag_umem) };
+            XdpUmemInfo {
+                id: umem.id,
+                size: umem.size,
+                pages: umem.num_pages,
+                frame_size: umem.chunk_size,
+                headroom: umem.headroom,
+                refs: umem.refs,
+                zero_copy: umem.flags & XDP_DU_F_ZEROCOPY != 0,
+            }
+        });
+    // struct xdp_diag_stats, six u64 counters
+    let stats = attrs.get(&XDP_DIAG_STATS).and_then(|attr| {
+        let counter = |i: usize| read_u64(attr.data, i * 8);
+        Some(XdpSocketStats {
+            rx_dropped: counter(0)?,
+            rx_invalid_descs: counter(1)?,
+            rx_ring_full: counter(2)?,
+            rx_fill_ring_empty_descs: counter(3)?,
+            tx_invalid_descs: counter(4)?,
+            tx_ring_empty_descs: counter(5)?,
+        })
+    });
+
+    Some(XdpSocketEntry {
+        inode: diag_msg.xdiag_ino,
+        if_index: info.and_then(|attr| read_u32(attr.data, 0)),
+        queue_id: info.and_then(|attr| read_u32(attr.data, 4)),
+        rx_ring: ring(XDP_DIAG_RX_RING),
+        tx_ring: ring(XDP_DIAG_TX_RING),
+        fill_ring: ring(XDP_DIAG_UMEM_FILL_RING),
+        completion_ring: ring(XDP_DIAG_UMEM_COMPLETION_RING),
+        umem,
+        stats,
+    })
+}
+
+// how long NetlinkMonitor::poll() blocks waiting for an event
+const MONITOR_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// change to a network interface reported by NetlinkMonitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// the interface is up and has carrier
+    Up { if_index: u32 },
+    /// the interface was brought down, lost carrier or was removed
+    Down { if_index: u32 },
+    /// an address was added to or removed from the interface
+    AddressChanged { if_index: u32 },
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct ifinfomsg {
+    ifi_family: u8,
+    ifi_pad: u8,
+    ifi_type: u16,
+    ifi_index: i32,
+    ifi_flags: u32,
+    ifi_change: u32,
+}
+
+/// listens for link and address changes on all interfaces
+pub struct NetlinkMonitor {
+    sock: OwnedFd,
+    // last reported state per interface, RTM_NEWLINK is sent for all sorts of changes and we
+    // only report up/down transitions
+    link_up: HashMap<u32, bool>,
+    pending: VecDeque<LinkEvent>,
+}
+
+impl NetlinkMonitor {
+    pub fn new() -> Result<Self, io::Error> {
+        // Safety: libc wrapper
+        let sock = unsafe { socket(AF_NETLINK, SOCK_RAW, NETLINK_ROUTE) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `socket` returns a file descriptor.
+        let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+
+        // Safety: sockaddr_nl is POD so this is safe
+        let mut addr = unsafe { mem::zeroed::<sockaddr_nl>() };
+        addr.nl_family = AF_NETLINK as u16;
+        addr.nl_groups = (RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR) as u32;
+        // Safety: libc wrapper
+        if unsafe {
+            bind(
+                sock.as_raw_fd(),
+                &addr as *const _ as *const _,
+                mem::size_of::<sockaddr_nl>() as u32,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // poll() must return now and then so callers can check their exit flag
+        let timeout = timeval {
+            tv_sec: MONITOR_POLL_TIMEOUT.as_secs() as _,
+            tv_usec: MONITOR_POLL_TIMEOUT.subsec_micros() as _,
+        };
+        // Safety: libc wrapper
+        if unsafe {
+            setsockopt(
+                sock.as_raw_fd(),
+                SOL_SOCKET,
+                SO_RCVTIMEO,
+                &timeout as *const _ as *const _,
+                mem::size_of::<timeval>() as u32,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            sock,
+            link_up: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// next event, waits up to MONITOR_POLL_TIMEOUT for one to arrive
+    pub fn poll(&mut self) -> Option<LinkEvent> {
+        if self.pending.is_empty() {
+            self.read_events();
+        }
+        self.pending.pop_front()
+    }
+
+    fn read_events(&mut self) {
+        let mut buf = [0u8; 8192];
+        // Safety: libc wrapper
+        let len = unsafe {
+            recv(
+                self.sock.as_raw_fd(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+                0,
+            )
+        };
+        // timeouts, and ENOBUFS if we fell behind. either way there's nothing to parse
+        if len <= 0 {
+            return;
+        }
+
+        let len = len as usize;
+        let mut offset = 0;
+        while offset < len {
+            let Ok(message) = NetlinkMessage::read(&buf[offset..len]) else {
+                break;
+            };
+            offset += align_to(message.header.nlmsg_len as usize, NLMSG_ALIGNTO as usize);
+            if let Some(event) = self.parse_event(&message) {
+                self.pending.push_back(event);
+            }
+        }
+    }
+
+    fn parse_event(&mut self, msg: &NetlinkMessage) -> Option<LinkEvent> {
+        match msg.header.nlmsg_type {
+            RTM_NEWLINK | RTM_DELLINK => {
+                if msg.data.len() < mem::size_of::<ifinfomsg>() {
+                    return None;
+                }
+                let ifi_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const ifinfomsg) };
+                let if_index = ifi_msg.ifi_index as u32;
+                let running = (IFF_UP | IFF_RUNNING) as u32;
+                let up =
+                    msg.header.nlmsg_type == RTM_NEWLINK && ifi_msg.ifi_flags & running == running;
+                if self.link_up.insert(if_index, up) == Some(up) {
+                    return None;
+                }
+                Some(if up {
+                    LinkEvent::Up { if_index }
+                } else {
+                    LinkEvent::Down { if_index }
+                })
+            }
+            RTM_NEWADDR | RTM_DELADDR => {
+                if msg.data.len() < mem::size_of::<ifaddrmsg>() {
+                    return None;
+                }
+                let ifa_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const ifaddrmsg) };
+                Some(LinkEvent::AddressChanged {
+                    if_index: ifa_msg.ifa_index,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// tracks whether one interface is running from a background thread
+///
+/// interface_down() is raised when the interface goes down or loses carrier and cleared when it
+/// comes back, pass it to relay_loop to have the relay restart itself. the thread stops when the
+/// monitor is dropped.
+pub struct NetworkMonitor {
+    interface_down: Arc<AtomicBool>,
+    exit: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NetworkMonitor {
+    pub fn spawn(if_index: u32) -> Result<Self, io::Error> {
+        // subscribe before the thread starts so no event is missed
+        let mut monitor = NetlinkMonitor::new()?;
+        let interface_down = Arc::new(AtomicBool::new(false));
+        let exit = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("netMonitor".to_string())
+            .spawn({
+                let interface_down = Arc::clone(&interface_down);
+                let exit = Arc::clone(&exit);
+                move || {
+                    while !exit.load(Ordering::Relaxed) {
+                        match monitor.poll() {
+                            Some(LinkEvent::Down { if_index: down }) if down == if_index => {
+                                log::warn!("interface {if_index} went down");
+                                interface_down.store(true, Ordering::Relaxed);
+                            }
+                            Some(LinkEvent::Up { if_index: up }) if up == if_index => {
+                                log::info!("interface {if_index} is up");
+                                interface_down.store(false, Ordering::Relaxed);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            interface_down,
+            exit,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn interface_down(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interface_down)
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.interface_down.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for NetworkMonitor {
+    fn drop(&mut self) {
+        // poll() returns within MONITOR_POLL_TIMEOUT
+        self.exit.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_address_round_trip() {
+        let mac: MacAddress = "aa:bb:cc:0d:0e:ff".parse().unwrap();
+        assert_eq!(mac, MacAddress([0xaa, 0xbb, 0xcc, 0x0d, 0x0e, 0xff]));
+        assert_eq!(mac.to_string(), "aa:bb:cc:0d:0e:ff");
+        assert_eq!(format!("{mac:?}"), "aa:bb:cc:0d:0e:ff");
+        assert!(!mac.is_multicast());
+
+        for bad in [
+            "",
+            "aa:bb:cc:dd:ee",
+            "aa:bb:cc:dd:ee:ff:00",
+            "aa:bb:cc:dd:ee:gg",
+            "a:bb:cc:dd:ee:ff",
+            "+a:bb:cc:dd:ee:ff",
+        ] {
+            assert!(bad.parse::<MacAddress>().is_err(), "{bad}");
+        }
+
+        assert!(MacAddress([0xff; 6]).is_broadcast());
+        assert!(MacAddress([0; 6]).is_zero());
+        assert!(MacAddress([0x01, 0x00, 0x5e, 0, 0, 1]).is_multicast());
+    }
+
+    #[test]
+    fn test_parse_xdp_diag_msg() {
+        let attr = |buf: &mut Vec<u8>, nla_type: u16, data: &[u8]| {
+            buf.extend_from_slice(&((NLA_HDR_LEN + data.len()) as u16).to_ne_bytes());
+            buf.extend_from_slice(&nla_type.to_ne_bytes());
+            buf.extend_from_slice(data);
+            buf.resize(align_to(buf.len(), NLA_ALIGNTO as usize), 0);
+        };
+        let u32s = |vals: &[u32]| {
+            vals.iter()
+                .flat_map(|v| v.to_ne_bytes())
+                .collect::<Vec<_>>()
+        };
+
+        let mut buf = vec![0u8; mem::size_of::<nlmsghdr>()];
+        buf.extend_from_slice(&[AF_XDP as u8, SOCK_RAW as u8, 0, 0]);
+        buf.extend_from_slice(&u32s(&[1234, 0, 0]));
+        attr(&mut buf, XDP_DIAG_INFO, &u32s(&[3, 5]));
+        attr(&mut buf, XDP_DIAG_RX_RING, &u32s(&[2048]));
+        attr(&mut buf, XDP_DIAG_UMEM_FILL_RING, &u32s(&[4096]));
+        let mut umem = (1u64 << 24).to_ne_bytes().to_vec();
+        umem.extend(u32s(&[7, 4096, 4096, 0, 3, 5, XDP_DU_F_ZEROCOPY, 2]));
+        attr(&mut buf, XDP_DIAG_UMEM, &umem);
+        let counters = (1..=6u64).flat_map(|v| v.to_ne_bytes()).collect::<Vec<_>>();
+        attr(&mut buf, XDP_DIAG_STATS, &counters);
+        let len = buf.len() as u32;
+        buf[..4].copy_from_slice(&len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+
+        let msg = NetlinkMessage::read(&buf).unwrap();
+        let entry = parse_xdp_diag_msg(msg).unwrap();
+        assert_eq!(entry.inode, 1234);
+        assert_eq!((entry.if_index, entry.queue_id), (Some(3), Some(5)));
+        assert_eq!((entry.rx_ring, entry.tx_ring), (Some(2048), None));
+        assert_eq!((entry.fill_ring, entry.completion_ring), (Some(4096), None));
+        assert_eq!(
+            entry.umem,
+            Some(XdpUmemInfo {
+                id: 7,
+                size: 1 << 24,
+                pages: 4096,
+                frame_size: 4096,
+                headroom: 0,
+                refs: 2,
+                zero_copy: true,
+            })
+        );
+        let stats = entry.stats.unwrap();
+        assert_eq!((stats.rx_dropped, stats.rx_ring_full), (1, 3));
+        assert_eq!(stats.tx_ring_empty_descs, 6);
+    }
+}
@@ -1,19 +1,224 @@
 #![allow(clippy::arithmetic_side_effects)]
 
-use {libc::ETH_P_IP, std::net::Ipv4Addr};
+use {
+    crate::{netlink::MacAddress, raw_socket::RawSocket},
+    libc::{ETH_P_ARP, ETH_P_IP, ETH_P_IPV6},
+    std::{
+        io,
+        net::{Ipv4Addr, Ipv6Addr},
+    },
+};
 
 pub const ETH_HEADER_SIZE: usize = 14;
+/// ethernet header with one 802.1Q tag
+pub const VLAN_HEADER_SIZE: usize = 18;
 pub const IP_HEADER_SIZE: usize = 20;
+pub const IPV6_HEADER_SIZE: usize = 40;
 pub const UDP_HEADER_SIZE: usize = 8;
+pub const TCP_HEADER_SIZE: usize = 20;
+pub const VXLAN_HEADER_SIZE: usize = 8;
+/// IANA assigned VXLAN UDP port
+pub const VXLAN_PORT: u16 = 4789;
+/// ethernet header plus an IPv4 over ethernet ARP packet
+pub const ARP_PACKET_SIZE: usize = ETH_HEADER_SIZE + 28;
+
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+pub const ETH_P_8021Q: u16 = 0x8100;
+/// 802.1ad service tag, the outer tag of QinQ frames
+pub const ETH_P_8021AD: u16 = 0x88a8;
+
+// one 802.1Q/802.1ad tag: TCI and the ethertype that follows it
+const VLAN_TAG_SIZE: usize = 4;
+// QinQ, deeper stacks aren't parsed
+const MAX_VLAN_TAGS: usize = 2;
+
+// the I flag, the VNI field is valid
+const VXLAN_FLAG_VNI: u8 = 0x08;
+
+// TCP flags
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+// IPv6 extension header types we know how to skip
+const IPV6_EXT_HOP_BY_HOP: u8 = 0;
+const IPV6_EXT_ROUTING: u8 = 43;
+const IPV6_EXT_FRAGMENT: u8 = 44;
+const IPV6_EXT_AUTH: u8 = 51;
+const IPV6_EXT_DEST_OPTS: u8 = 60;
+
+// upper bound on chained extension headers, protects against crafted packets
+const IPV6_MAX_EXT_HEADERS: usize = 8;
 
 pub fn write_eth_header(packet: &mut [u8], src_mac: &[u8; 6], dst_mac: &[u8; 6]) {
+    write_eth_header_with_ethertype(packet, src_mac, dst_mac, ETH_P_IP as u16);
+}
+
+pub fn write_eth_header_with_ethertype(
+    packet: &mut [u8],
+    src_mac: &[u8; 6],
+    dst_mac: &[u8; 6],
+    ethertype: u16,
+) {
     packet[0..6].copy_from_slice(dst_mac);
     packet[6..12].copy_from_slice(src_mac);
-    packet[12..14].copy_from_slice(&(ETH_P_IP as u16).to_be_bytes());
+    packet[12..14].copy_from_slice(&ethertype.to_be_bytes());
+}
+
+/// read the ethertype from an untagged ethernet header
+#[inline]
+pub fn ethertype(packet: &[u8]) -> Option<u16> {
+    let bytes = packet.get(12..14)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// parse an 802.1Q tag, returns the VLAN id, the ethertype of the tagged frame and the offset
+/// of its L3 header. None for untagged frames
+#[inline]
+pub fn parse_vlan_tag(packet: &[u8]) -> Option<(u16, u16, usize)> {
+    if ethertype(packet)? != ETH_P_8021Q {
+        return None;
+    }
+    let tag = packet.get(ETH_HEADER_SIZE..VLAN_HEADER_SIZE)?;
+    // the top 4 bits are priority and drop eligibility
+    let vlan_id = u16::from_be_bytes([tag[0], tag[1]]) & 0x0fff;
+    let inner_ethertype = u16::from_be_bytes([tag[2], tag[3]]);
+    Some((vlan_id, inner_ethertype, VLAN_HEADER_SIZE))
+}
+
+/// the ethertype of the L3 header and its offset from the start of the frame, looking through
+/// up to two 802.1Q/802.1ad tags. a frame with more tags reports the innermost tag's ethertype
+#[inline]
+pub fn parse_ethertype(packet: &[u8]) -> Option<(u16, usize)> {
+    let mut l3_ethertype = ethertype(packet)?;
+    let mut offset = ETH_HEADER_SIZE;
+    for _ in 0..MAX_VLAN_TAGS {
+        if l3_ethertype != ETH_P_8021Q && l3_ethertype != ETH_P_8021AD {
+            break;
+        }
+        let tag = packet.get(offset..offset + VLAN_TAG_SIZE)?;
+        l3_ethertype = u16::from_be_bytes([tag[2], tag[3]]);
+        offset += VLAN_TAG_SIZE;
+    }
+    Some((l3_ethertype, offset))
+}
+
+/// write an ethernet header with an 802.1Q tag for `vlan_id`, priority 0
+pub fn write_vlan_header(
+    packet: &mut [u8],
+    src_mac: &[u8; 6],
+    dst_mac: &[u8; 6],
+    vlan_id: u16,
+    inner_ethertype: u16,
+) {
+    write_eth_header_with_ethertype(packet, src_mac, dst_mac, ETH_P_8021Q);
+    packet[14..16].copy_from_slice(&(vlan_id & 0x0fff).to_be_bytes());
+    packet[16..18].copy_from_slice(&inner_ethertype.to_be_bytes());
+}
+
+/// parse the VXLAN header of a frame sent to UDP port 4789 over IPv4 or IPv6, returns the VNI
+/// and the offset of the encapsulated ethernet frame
+pub fn parse_vxlan(packet: &[u8]) -> Option<(u32, usize)> {
+    let (l3_ethertype, l3_offset) = parse_ethertype(packet)?;
+    let ip_header = packet.get(l3_offset..)?;
+    let udp_offset = match i32::from(l3_ethertype) {
+        ETH_P_IP => {
+            let header_len = (*ip_header.first()? & 0x0f) as usize * 4;
+            if header_len < IP_HEADER_SIZE || *ip_header.get(9)? != IPPROTO_UDP {
+                return None;
+            }
+            l3_offset + header_len
+        }
+        ETH_P_IPV6 => {
+            let ipv6 = parse_ipv6_header(ip_header)?;
+            if ipv6.next_header != IPPROTO_UDP {
+                return None;
+            }
+            l3_offset + ipv6.payload_offset
+        }
+        _ => return None,
+    };
+
+    let udp = packet.get(udp_offset..udp_offset + UDP_HEADER_SIZE + VXLAN_HEADER_SIZE)?;
+    if u16::from_be_bytes([udp[2], udp[3]]) != VXLAN_PORT {
+        return None;
+    }
+    let vxlan = &udp[UDP_HEADER_SIZE..];
+    if vxlan[0] & VXLAN_FLAG_VNI == 0 {
+        return None;
+    }
+    let vni = u32::from_be_bytes([0, vxlan[4], vxlan[5], vxlan[6]]);
+
+    // the encapsulated frame needs at least its ethernet header
+    let inner_offset = udp_offset + UDP_HEADER_SIZE + VXLAN_HEADER_SIZE;
+    (packet.len() >= inner_offset + ETH_HEADER_SIZE).then_some((vni, inner_offset))
+}
+
+/// write an 8 byte VXLAN header for `vni`, only the low 24 bits are used
+pub fn write_vxlan_header(packet: &mut [u8], vni: u32) {
+    packet[0] = VXLAN_FLAG_VNI;
+    packet[1..4].fill(0);
+    packet[4..7].copy_from_slice(&vni.to_be_bytes()[1..]);
+    packet[7] = 0;
+}
+
+/// write a broadcast ARP request asking for the MAC of `target_ip`
+pub fn write_arp_request(
+    packet: &mut [u8],
+    src_mac: &[u8; 6],
+    src_ip: &Ipv4Addr,
+    target_ip: &Ipv4Addr,
+) {
+    write_eth_header_with_ethertype(packet, src_mac, &[0xff; 6], ETH_P_ARP as u16);
+    let arp = &mut packet[ETH_HEADER_SIZE..ARP_PACKET_SIZE];
+    // ethernet hardware, IPv4 protocol, 6 and 4 byte addresses, request
+    arp[0..2].copy_from_slice(&1u16.to_be_bytes());
+    arp[2..4].copy_from_slice(&(ETH_P_IP as u16).to_be_bytes());
+    arp[4] = 6;
+    arp[5] = 4;
+    arp[6..8].copy_from_slice(&1u16.to_be_bytes());
+    arp[8..14].copy_from_slice(src_mac);
+    arp[14..18].copy_from_slice(&src_ip.octets());
+    // target MAC is what we're asking for
+    arp[18..24].fill(0);
+    arp[24..28].copy_from_slice(&target_ip.octets());
+}
+
+/// broadcast an ARP request for `target_ip` from the interface of `socket`, which can be send
+/// only (RawSocket::new_send_only)
+///
+/// the reply doesn't go into the kernel's neighbor table on its own (arp_accept=0 ignores
+/// replies to requests the kernel didn't send), it wakes up a target that is slow to answer so
+/// the kernel's own resolution, e.g. Router::resolve_mac, succeeds quickly.
+pub fn send_arp_request(
+    socket: &RawSocket,
+    iface_ip: Ipv4Addr,
+    iface_mac: &MacAddress,
+    target_ip: Ipv4Addr,
+) -> io::Result<()> {
+    let mut frame = [0u8; ARP_PACKET_SIZE];
+    write_arp_request(&mut frame, &iface_mac.0, &iface_ip, &target_ip);
+    socket.send(&frame)?;
+    Ok(())
 }
 
 pub fn write_ip_header(packet: &mut [u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr, udp_len: u16) {
-    let total_len = IP_HEADER_SIZE + udp_len as usize;
+    write_ip_header_with_protocol(packet, src_ip, dst_ip, udp_len, IPPROTO_UDP);
+}
+
+pub fn write_ip_header_with_protocol(
+    packet: &mut [u8],
+    src_ip: &Ipv4Addr,
+    dst_ip: &Ipv4Addr,
+    l4_len: u16,
+    protocol: u8,
+) {
+    let total_len = IP_HEADER_SIZE + l4_len as usize;
 
     // version (4) and IHL (5)
     packet[0] = 0x45;
@@ -26,8 +231,8 @@ pub fn write_ip_header(packet: &mut [u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr,
     packet[6..8].copy_from_slice(&0u16.to_be_bytes());
     // TTL
     packet[8] = 64;
-    // protocol (UDP = 17)
-    packet[9] = 17;
+    // protocol
+    packet[9] = protocol;
     // checksum
     packet[10..12].copy_from_slice(&0u16.to_be_bytes());
     packet[12..16].copy_from_slice(&src_ip.octets());
@@ -37,6 +242,77 @@ pub fn write_ip_header(packet: &mut [u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr,
     packet[10..12].copy_from_slice(&checksum.to_be_bytes());
 }
 
+pub fn write_ipv6_header(
+    packet: &mut [u8],
+    src_ip: &Ipv6Addr,
+    dst_ip: &Ipv6Addr,
+    payload_len: u16,
+    next_header: u8,
+) {
+    // version (6), traffic class (0) and flow label (0)
+    packet[0..4].copy_from_slice(&(6u32 << 28).to_be_bytes());
+    packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    packet[6] = next_header;
+    // hop limit
+    packet[7] = 64;
+    packet[8..24].copy_from_slice(&src_ip.octets());
+    packet[24..40].copy_from_slice(&dst_ip.octets());
+}
+
+/// parsed IPv6 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Header {
+    pub src_ip: Ipv6Addr,
+    pub dst_ip: Ipv6Addr,
+    /// payload length as written in the fixed header (includes extension headers)
+    pub payload_len: u16,
+    /// upper layer protocol, after skipping extension headers
+    pub next_header: u8,
+    /// offset from the start of the IPv6 header to the upper layer header
+    pub payload_offset: usize,
+}
+
+/// parse an IPv6 header, walking any extension headers
+/// returns None if the buffer is too short or not IPv6
+pub fn parse_ipv6_header(packet: &[u8]) -> Option<Ipv6Header> {
+    if packet.len() < IPV6_HEADER_SIZE || packet[0] >> 4 != 6 {
+        return None;
+    }
+
+    let payload_len = u16::from_be_bytes([packet[4], packet[5]]);
+    let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+    let dst_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?);
+
+    let mut next_header = packet[6];
+    let mut offset = IPV6_HEADER_SIZE;
+    for _ in 0..IPV6_MAX_EXT_HEADERS {
+        let ext_len = match next_header {
+            IPV6_EXT_HOP_BY_HOP | IPV6_EXT_ROUTING | IPV6_EXT_DEST_OPTS => {
+                (*packet.get(offset + 1)? as usize + 1) * 8
+            }
+            IPV6_EXT_FRAGMENT => 8,
+            IPV6_EXT_AUTH => (*packet.get(offset + 1)? as usize + 2) * 4,
+            _ => {
+                return Some(Ipv6Header {
+                    src_ip,
+                    dst_ip,
+                    payload_len,
+                    next_header,
+                    payload_offset: offset,
+                })
+            }
+        };
+        next_header = *packet.get(offset)?;
+        offset += ext_len;
+        if offset > packet.len() {
+            return None;
+        }
+    }
+
+    // too many extension headers
+    None
+}
+
 pub fn write_udp_header(
     packet: &mut [u8],
     src_ip: &Ipv4Addr,
@@ -59,8 +335,174 @@ pub fn write_udp_header(
     }
 }
 
+/// check the header checksum of the IPv4 packet at the start of `packet`
+pub fn verify_ip_checksum(packet: &[u8]) -> bool {
+    let Some(&version_ihl) = packet.first() else {
+        return false;
+    };
+    let header_len = (version_ihl & 0x0f) as usize * 4;
+    if version_ihl >> 4 != 4 || header_len < IP_HEADER_SIZE {
+        return false;
+    }
+    // summing a header that includes its checksum gives 0xffff, which calculate_ip_checksum
+    // complements to 0
+    packet
+        .get(..header_len)
+        .is_some_and(|header| calculate_ip_checksum(header) == 0)
+}
+
+/// check the UDP checksum of the IPv4 packet at the start of `packet`, including the pseudo
+/// header. a zero checksum means the sender didn't compute one and always passes
+pub fn verify_udp_checksum(packet: &[u8]) -> bool {
+    let Some(&version_ihl) = packet.first() else {
+        return false;
+    };
+    let header_len = (version_ihl & 0x0f) as usize * 4;
+    if version_ihl >> 4 != 4 || header_len < IP_HEADER_SIZE || packet.len() < header_len {
+        return false;
+    }
+    let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst_ip = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+
+    let udp = &packet[header_len..];
+    let Some(header) = udp.get(..UDP_HEADER_SIZE) else {
+        return false;
+    };
+    let udp_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let checksum = u16::from_be_bytes([header[6], header[7]]);
+    if checksum == 0 {
+        return true;
+    }
+    let Some(segment) = udp.get(..udp_len).filter(|_| udp_len >= UDP_HEADER_SIZE) else {
+        return false;
+    };
+    // a computed checksum of 0 goes on the wire as 0xffff
+    match calculate_udp_checksum(segment, &src_ip, &dst_ip) {
+        0 => checksum == 0xffff,
+        expected => checksum == expected,
+    }
+}
+
+/// parsed TCP header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    /// TCP_* flags
+    pub flags: u8,
+    pub window: u16,
+    pub checksum: u16,
+    pub urgent_ptr: u16,
+    /// header length including options, offset from the start of the TCP header to the payload
+    pub payload_offset: usize,
+}
+
+/// write a 20 byte TCP header (no options)
+/// the payload must already be in place after the header as it's needed for the checksum
+#[allow(clippy::too_many_arguments)]
+pub fn write_tcp_header(
+    packet: &mut [u8],
+    src_ip: &Ipv4Addr,
+    src_port: u16,
+    dst_ip: &Ipv4Addr,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload_len: u16,
+) {
+    write_tcp_header_with_options(
+        packet,
+        TCP_HEADER_SIZE,
+        src_ip,
+        src_port,
+        dst_ip,
+        dst_port,
+        seq,
+        ack,
+        flags,
+        window,
+        payload_len,
+    );
+}
+
+/// write the fixed part of a TCP header that is `header_len` bytes long including options
+/// the options must already be in place after the first 20 bytes and the payload after them, as
+/// both are needed for the checksum
+#[allow(clippy::too_many_arguments)]
+pub fn write_tcp_header_with_options(
+    packet: &mut [u8],
+    header_len: usize,
+    src_ip: &Ipv4Addr,
+    src_port: u16,
+    dst_ip: &Ipv4Addr,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    payload_len: u16,
+) {
+    let tcp_len = header_len + payload_len as usize;
+
+    packet[0..2].copy_from_slice(&src_port.to_be_bytes());
+    packet[2..4].copy_from_slice(&dst_port.to_be_bytes());
+    packet[4..8].copy_from_slice(&seq.to_be_bytes());
+    packet[8..12].copy_from_slice(&ack.to_be_bytes());
+    // data offset in 32 bit words, including the options
+    packet[12] = ((header_len / 4) as u8) << 4;
+    packet[13] = flags;
+    packet[14..16].copy_from_slice(&window.to_be_bytes());
+    // checksum
+    packet[16..18].copy_from_slice(&0u16.to_be_bytes());
+    // urgent pointer
+    packet[18..20].copy_from_slice(&0u16.to_be_bytes());
+
+    let checksum = calculate_l4_checksum(&packet[..tcp_len], src_ip, dst_ip, IPPROTO_TCP, 16);
+    packet[16..18].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// parse a TCP header, returns None if the buffer is too short or the data offset is invalid
+pub fn parse_tcp_header(packet: &[u8]) -> Option<TcpHeader> {
+    if packet.len() < TCP_HEADER_SIZE {
+        return None;
+    }
+
+    let payload_offset = (packet[12] >> 4) as usize * 4;
+    if payload_offset < TCP_HEADER_SIZE || payload_offset > packet.len() {
+        return None;
+    }
+
+    Some(TcpHeader {
+        src_port: u16::from_be_bytes([packet[0], packet[1]]),
+        dst_port: u16::from_be_bytes([packet[2], packet[3]]),
+        seq: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        ack: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        flags: packet[13],
+        window: u16::from_be_bytes([packet[14], packet[15]]),
+        checksum: u16::from_be_bytes([packet[16], packet[17]]),
+        urgent_ptr: u16::from_be_bytes([packet[18], packet[19]]),
+        payload_offset,
+    })
+}
+
 fn calculate_udp_checksum(udp_packet: &[u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr) -> u16 {
-    let udp_len = udp_packet.len();
+    calculate_l4_checksum(udp_packet, src_ip, dst_ip, IPPROTO_UDP, 6)
+}
+
+/// ones' complement checksum over the IPv4 pseudo header and the L4 segment, skipping the
+/// checksum field at `csum_offset`
+fn calculate_l4_checksum(
+    segment: &[u8],
+    src_ip: &Ipv4Addr,
+    dst_ip: &Ipv4Addr,
+    protocol: u8,
+    csum_offset: usize,
+) -> u16 {
+    let len = segment.len();
 
     let mut sum: u32 = 0;
 
@@ -71,20 +513,20 @@ fn calculate_udp_checksum(udp_packet: &[u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Add
     sum += (u32::from(src_ip[2]) << 8) | u32::from(src_ip[3]);
     sum += (u32::from(dst_ip[0]) << 8) | u32::from(dst_ip[1]);
     sum += (u32::from(dst_ip[2]) << 8) | u32::from(dst_ip[3]);
-    sum += 17; // UDP
-    sum += udp_len as u32;
+    sum += protocol as u32;
+    sum += len as u32;
 
-    for i in 0..udp_len / 2 {
+    for i in 0..len / 2 {
         // skip the checksum field
-        if i * 2 == 6 {
+        if i * 2 == csum_offset {
             continue;
         }
-        let word = ((udp_packet[i * 2] as u32) << 8) | (udp_packet[i * 2 + 1] as u32);
+        let word = ((segment[i * 2] as u32) << 8) | (segment[i * 2 + 1] as u32);
         sum += word;
     }
 
-    if udp_len % 2 == 1 {
-        sum += (udp_packet[udp_len - 1] as u32) << 8;
+    if len % 2 == 1 {
+        sum += (segment[len - 1] as u32) << 8;
     }
 
     while sum >> 16 != 0 {
@@ -112,3 +554,256 @@ fn calculate_ip_checksum(header: &[u8]) -> u16 {
 
     !(sum as u16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_header_roundtrip() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        let payload = b"hello";
+        let mut buf = [0u8; TCP_HEADER_SIZE + 5];
+        buf[TCP_HEADER_SIZE..].copy_from_slice(payload);
+        write_tcp_header(
+            &mut buf,
+            &src,
+            8899,
+            &dst,
+            443,
+            1000,
+            2000,
+            TCP_PSH | TCP_ACK,
+            65535,
+            payload.len() as u16,
+        );
+
+        let tcp = parse_tcp_header(&buf).unwrap();
+        assert_eq!(tcp.src_port, 8899);
+        assert_eq!(tcp.dst_port, 443);
+        assert_eq!(tcp.seq, 1000);
+        assert_eq!(tcp.ack, 2000);
+        assert_eq!(tcp.flags, TCP_PSH | TCP_ACK);
+        assert_eq!(tcp.window, 65535);
+        assert_eq!(tcp.payload_offset, TCP_HEADER_SIZE);
+        assert_eq!(
+            tcp.checksum,
+            calculate_l4_checksum(&buf, &src, &dst, IPPROTO_TCP, 16)
+        );
+
+        // data offset smaller than the fixed header
+        buf[12] = 4 << 4;
+        assert_eq!(parse_tcp_header(&buf), None);
+    }
+
+    #[test]
+    fn test_tcp_header_with_options() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        // MSS 1460 and two NOPs
+        let options = [2, 4, 0x05, 0xb4, 1, 1, 1, 1];
+        let mut buf = [0u8; TCP_HEADER_SIZE + 8 + 5];
+        buf[TCP_HEADER_SIZE..TCP_HEADER_SIZE + 8].copy_from_slice(&options);
+        buf[TCP_HEADER_SIZE + 8..].copy_from_slice(b"hello");
+        write_tcp_header_with_options(
+            &mut buf,
+            TCP_HEADER_SIZE + 8,
+            &src,
+            8899,
+            &dst,
+            443,
+            1000,
+            2000,
+            TCP_ACK,
+            65535,
+            5,
+        );
+
+        let tcp = parse_tcp_header(&buf).unwrap();
+        assert_eq!(tcp.payload_offset, TCP_HEADER_SIZE + 8);
+        assert_eq!(&buf[TCP_HEADER_SIZE..TCP_HEADER_SIZE + 8], &options);
+        assert_eq!(
+            tcp.checksum,
+            calculate_l4_checksum(&buf, &src, &dst, IPPROTO_TCP, 16)
+        );
+    }
+
+    #[test]
+    fn test_ipv6_header_roundtrip() {
+        let src = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let dst = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+        let mut buf = [0u8; IPV6_HEADER_SIZE + UDP_HEADER_SIZE];
+        write_ipv6_header(&mut buf, &src, &dst, UDP_HEADER_SIZE as u16, 17);
+
+        let header = parse_ipv6_header(&buf).unwrap();
+        assert_eq!(header.src_ip, src);
+        assert_eq!(header.dst_ip, dst);
+        assert_eq!(header.payload_len, UDP_HEADER_SIZE as u16);
+        assert_eq!(header.next_header, 17);
+        assert_eq!(header.payload_offset, IPV6_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_ipv6_extension_headers() {
+        let src = Ipv6Addr::LOCALHOST;
+        let mut buf = [0u8; IPV6_HEADER_SIZE + 16 + 8 + UDP_HEADER_SIZE];
+        write_ipv6_header(&mut buf, &src, &src, 32, IPV6_EXT_HOP_BY_HOP);
+        // hop-by-hop, 16 bytes, next is fragment
+        buf[IPV6_HEADER_SIZE] = IPV6_EXT_FRAGMENT;
+        buf[IPV6_HEADER_SIZE + 1] = 1;
+        // fragment, next is UDP
+        buf[IPV6_HEADER_SIZE + 16] = 17;
+
+        let header = parse_ipv6_header(&buf).unwrap();
+        assert_eq!(header.next_header, 17);
+        assert_eq!(header.payload_offset, IPV6_HEADER_SIZE + 16 + 8);
+
+        // truncated extension header
+        assert_eq!(parse_ipv6_header(&buf[..IPV6_HEADER_SIZE + 8]), None);
+        // not IPv6
+        buf[0] = 0x45;
+        assert_eq!(parse_ipv6_header(&buf), None);
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        let payload = b"checksum";
+        let mut buf = [0u8; IP_HEADER_SIZE + UDP_HEADER_SIZE + 8];
+        buf[IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(payload);
+        write_ip_header(
+            &mut buf,
+            &src,
+            &dst,
+            (UDP_HEADER_SIZE + payload.len()) as u16,
+        );
+        write_udp_header(
+            &mut buf[IP_HEADER_SIZE..],
+            &src,
+            1234,
+            &dst,
+            5678,
+            payload.len() as u16,
+            true,
+        );
+        assert!(verify_ip_checksum(&buf));
+        assert!(verify_udp_checksum(&buf));
+
+        // a flipped payload bit breaks the UDP checksum but not the IP one
+        buf[IP_HEADER_SIZE + UDP_HEADER_SIZE] ^= 1;
+        assert!(verify_ip_checksum(&buf));
+        assert!(!verify_udp_checksum(&buf));
+
+        // no UDP checksum at all is fine
+        buf[IP_HEADER_SIZE + 6..IP_HEADER_SIZE + 8].fill(0);
+        assert!(verify_udp_checksum(&buf));
+
+        buf[8] = buf[8].wrapping_sub(1);
+        assert!(!verify_ip_checksum(&buf));
+        assert!(!verify_ip_checksum(&buf[..10]));
+        assert!(!verify_udp_checksum(&buf[..IP_HEADER_SIZE + 4]));
+    }
+
+    #[test]
+    fn test_vxlan_roundtrip() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        let inner_len = ETH_HEADER_SIZE + 4;
+        let udp_len = UDP_HEADER_SIZE + VXLAN_HEADER_SIZE + inner_len;
+        let mut buf = vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + udp_len];
+        write_eth_header(&mut buf, &[1; 6], &[2; 6]);
+        write_ip_header(&mut buf[ETH_HEADER_SIZE..], &src, &dst, udp_len as u16);
+        let udp_offset = ETH_HEADER_SIZE + IP_HEADER_SIZE;
+        write_udp_header(
+            &mut buf[udp_offset..],
+            &src,
+            50000,
+            &dst,
+            VXLAN_PORT,
+            (VXLAN_HEADER_SIZE + inner_len) as u16,
+            false,
+        );
+        write_vxlan_header(&mut buf[udp_offset + UDP_HEADER_SIZE..], 0x12_3456);
+
+        let inner_offset = udp_offset + UDP_HEADER_SIZE + VXLAN_HEADER_SIZE;
+        assert_eq!(parse_vxlan(&buf), Some((0x12_3456, inner_offset)));
+
+        // truncated inner frame
+        assert_eq!(parse_vxlan(&buf[..inner_offset + 4]), None);
+        // VNI flag missing
+        buf[udp_offset + UDP_HEADER_SIZE] = 0;
+        assert_eq!(parse_vxlan(&buf), None);
+        // other port
+        write_vxlan_header(&mut buf[udp_offset + UDP_HEADER_SIZE..], 1);
+        buf[udp_offset + 2..udp_offset + 4].copy_from_slice(&4790u16.to_be_bytes());
+        assert_eq!(parse_vxlan(&buf), None);
+    }
+
+    #[test]
+    fn test_arp_request() {
+        let mut buf = [0u8; ARP_PACKET_SIZE];
+        let src_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let target_ip = Ipv4Addr::new(10, 0, 0, 2);
+        write_arp_request(&mut buf, &[1, 2, 3, 4, 5, 6], &src_ip, &target_ip);
+
+        assert_eq!(&buf[0..6], &[0xff; 6]);
+        assert_eq!(ethertype(&buf), Some(ETH_P_ARP as u16));
+        let arp = &buf[ETH_HEADER_SIZE..];
+        assert_eq!(&arp[..8], &[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+        assert_eq!(&arp[8..14], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(&arp[14..18], &src_ip.octets());
+        assert_eq!(&arp[24..28], &target_ip.octets());
+    }
+
+    #[test]
+    fn test_vlan_header_roundtrip() {
+        let mut buf = [0u8; VLAN_HEADER_SIZE];
+        write_vlan_header(&mut buf, &[1; 6], &[2; 6], 100, ETH_P_IP as u16);
+        assert_eq!(&buf[0..6], &[2; 6]);
+        assert_eq!(ethertype(&buf), Some(ETH_P_8021Q));
+        assert_eq!(
+            parse_vlan_tag(&buf),
+            Some((100, ETH_P_IP as u16, VLAN_HEADER_SIZE))
+        );
+
+        // priority bits aren't part of the id
+        buf[14] |= 0xe0;
+        assert_eq!(parse_vlan_tag(&buf).map(|(id, _, _)| id), Some(100));
+
+        // untagged and truncated frames
+        assert_eq!(parse_vlan_tag(&buf[..16]), None);
+        write_eth_header(&mut buf, &[1; 6], &[2; 6]);
+        assert_eq!(parse_vlan_tag(&buf), None);
+    }
+
+    #[test]
+    fn test_parse_ethertype() {
+        let mut buf = [0u8; VLAN_HEADER_SIZE + VLAN_TAG_SIZE];
+        write_eth_header(&mut buf, &[1; 6], &[2; 6]);
+        assert_eq!(
+            parse_ethertype(&buf),
+            Some((ETH_P_IP as u16, ETH_HEADER_SIZE))
+        );
+
+        write_vlan_header(&mut buf, &[1; 6], &[2; 6], 100, ETH_P_IPV6 as u16);
+        assert_eq!(
+            parse_ethertype(&buf),
+            Some((ETH_P_IPV6 as u16, VLAN_HEADER_SIZE))
+        );
+
+        // QinQ, an 802.1ad tag in front of the 802.1Q one
+        buf[12..14].copy_from_slice(&ETH_P_8021AD.to_be_bytes());
+        buf[16..18].copy_from_slice(&ETH_P_8021Q.to_be_bytes());
+        buf[20..22].copy_from_slice(&(ETH_P_ARP as u16).to_be_bytes());
+        assert_eq!(
+            parse_ethertype(&buf),
+            Some((ETH_P_ARP as u16, VLAN_HEADER_SIZE + VLAN_TAG_SIZE))
+        );
+
+        // truncated tag and runt frames
+        assert_eq!(parse_ethertype(&buf[..VLAN_HEADER_SIZE + 2]), None);
+        assert_eq!(parse_ethertype(&buf[..12]), None);
+    }
+}
@@ -1,15 +1,23 @@
 // zero-copy event type for packet processing
-// stores UMEM offsets instead of copying packet data
+// stores UMEM offsets instead of copying packet data, published by the relay loop to the
+// decoder threads through a disruptor::Disruptor
 
 use {
+    crate::umem::UmemRc,
     solana_ledger::shred::ShredType,
-    std::time::SystemTime,
+    std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::SystemTime,
+    },
 };
 
 /// ring buffer
 /// contains only metadata and UMEM offsets
 #[repr(align(64))] // cache line alignment for optimal performance
 pub struct PacketEventZeroCopy {
+    /// reference to the UMEM frame holding the packet, keeps the relay loop from recycling the
+    /// frame. clone it to hold on to the packet after the event is consumed
+    pub frame: Option<UmemRc>,
     /// offset in UMEM where packet starts
     pub umem_offset: usize,
     /// offset within packet to UDP payload (skip ETH+IP+UDP headers)
@@ -18,16 +26,25 @@ pub struct PacketEventZeroCopy {
     pub payload_len: usize,
     /// total packet length (including all headers)
     pub packet_len: usize,
-    /// source IP address (4 bytes for IPv4)
-    pub src_ip: [u8; 4],
+    /// source IP address, IPv4 addresses use the first 4 bytes and the rest is zeroed
+    pub src_ip: [u8; 16],
     /// source port
     pub src_port: u16,
-    /// destination IP address
-    pub dst_ip: [u8; 4],
+    /// destination IP address, same layout as src_ip
+    pub dst_ip: [u8; 16],
     /// destination port
     pub dst_port: u16,
+    /// 4 or 6
+    pub ip_version: u8,
+    /// software flow hash from the XDP program's packet metadata (program::read_flow_hash), not
+    /// the NIC's RSS hash. 0 if missing
+    pub flow_hash: u32,
     /// packet receive timestamp
     pub timestamp: SystemTime,
+    /// CLOCK_MONOTONIC nanoseconds at which the XDP program redirected the packet, from the
+    /// packet metadata (program::read_xdp_timestamp). this is the kernel's clock when the program
+    /// ran, not a NIC hardware timestamp
+    pub xdp_timestamp: Option<u64>,
     /// pre-parsed shred type (avoid double parsing)
     pub shred_type: Option<ShredType>,
     /// validity flag (true = packet contains valid data)
@@ -38,15 +55,19 @@ impl PacketEventZeroCopy {
     /// factory function for initializing ring buffer events
     pub fn factory() -> Self {
         Self {
+            frame: None,
             umem_offset: 0,
             payload_offset: 0,
             payload_len: 0,
             packet_len: 0,
-            src_ip: [0; 4],
+            src_ip: [0; 16],
             src_port: 0,
-            dst_ip: [0; 4],
+            dst_ip: [0; 16],
             dst_port: 0,
+            ip_version: 4,
+            flow_hash: 0,
             timestamp: SystemTime::UNIX_EPOCH,
+            xdp_timestamp: None,
             shred_type: None,
             valid: false,
         }
@@ -55,44 +76,84 @@ impl PacketEventZeroCopy {
     /// reset event to initial state (for reuse)
     #[inline]
     pub fn reset(&mut self) {
+        self.frame = None;
         self.valid = false;
+        self.xdp_timestamp = None;
         self.shred_type = None;
     }
 
     /// set event data from UMEM without copying packet data
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     pub fn set_from_umem(
         &mut self,
+        frame: UmemRc,
         umem_offset: usize,
         payload_offset: usize,
         payload_len: usize,
         packet_len: usize,
-        src_ip: [u8; 4],
+        src_ip: IpAddr,
         src_port: u16,
-        dst_ip: [u8; 4],
+        dst_ip: IpAddr,
         dst_port: u16,
+        flow_hash: u32,
         timestamp: SystemTime,
+        xdp_timestamp: Option<u64>,
         shred_type: Option<ShredType>,
     ) {
+        self.frame = Some(frame);
         self.umem_offset = umem_offset;
         self.payload_offset = payload_offset;
         self.payload_len = payload_len;
         self.packet_len = packet_len;
-        self.src_ip = src_ip;
+        self.ip_version = if src_ip.is_ipv6() { 6 } else { 4 };
+        self.src_ip = ip_bytes(src_ip);
         self.src_port = src_port;
-        self.dst_ip = dst_ip;
+        self.dst_ip = ip_bytes(dst_ip);
         self.dst_port = dst_port;
+        self.flow_hash = flow_hash;
         self.timestamp = timestamp;
+        self.xdp_timestamp = xdp_timestamp;
         self.shred_type = shred_type;
         self.valid = true;
     }
 
+    #[inline]
+    pub fn src_ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4(&self.src_ip)
+    }
+
+    #[inline]
+    pub fn src_ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6(&self.src_ip)
+    }
+
+    #[inline]
+    pub fn dst_ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4(&self.dst_ip)
+    }
+
+    #[inline]
+    pub fn dst_ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6(&self.dst_ip)
+    }
+
+    fn ipv4(&self, addr: &[u8; 16]) -> Option<Ipv4Addr> {
+        (self.ip_version == 4).then(|| Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+    }
+
+    fn ipv6(&self, addr: &[u8; 16]) -> Option<Ipv6Addr> {
+        (self.ip_version == 6).then(|| Ipv6Addr::from(*addr))
+    }
+
     /// get payload slice from UMEM base pointer
-    /// # safety
+    /// # Safety
     /// caller must ensure:
     /// - umem_base is valid for the lifetime of the returned slice
     /// - umem_base points to the start of UMEM region
     /// - offsets are within valid UMEM bounds
+    /// - payload_offset matches ip_version, the IPv6 header is 40 bytes vs 20+ for IPv4 so an
+    ///   offset computed for the wrong family points into the headers or past the payload
     #[inline]
     pub unsafe fn payload_slice<'a>(&self, umem_base: *const u8) -> &'a [u8] {
         // safety: caller guarantees umem_base is valid and offsets are within bounds
@@ -103,8 +164,9 @@ impl PacketEventZeroCopy {
     }
 
     /// get full packet slice from UMEM base pointer
-    /// # safety
-    /// same safety requirements as payload_slice
+    /// # Safety
+    /// same safety requirements as payload_slice, packet_len must cover the full IPv4 or IPv6
+    /// header chain as well as the payload
     #[inline]
     pub unsafe fn packet_slice<'a>(&self, umem_base: *const u8) -> &'a [u8] {
         // safety: caller guarantees umem_base is valid and offset is within bounds
@@ -115,5 +177,18 @@ impl PacketEventZeroCopy {
     }
 }
 
+/// IPv4 addresses are right-padded with zeros
+#[inline]
+fn ip_bytes(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mut bytes = [0; 16];
+            bytes[..4].copy_from_slice(&addr.octets());
+            bytes
+        }
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
 // ensure struct fits in reasonable size (should be much smaller - 9KB buffer)
-const _: () = assert!(std::mem::size_of::<PacketEventZeroCopy>() <= 128);
\ No newline at end of file
+const _: () = assert!(std::mem::size_of::<PacketEventZeroCopy>() <= 128);
@@ -0,0 +1,741 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// filters the relay loop runs on every received packet before forwarding it
+
+use {
+    crate::{
+        packet::{
+            parse_ethertype, parse_ipv6_header, parse_tcp_header, IPPROTO_TCP, IPPROTO_UDP,
+            IP_HEADER_SIZE, UDP_HEADER_SIZE,
+        },
+        route::Router,
+        rx_loop::RxStats,
+    },
+    libc::{ETH_P_IP, ETH_P_IPV6},
+    std::{
+        cell::{Cell, RefCell},
+        collections::{HashMap, HashSet, VecDeque},
+        fs, io,
+        net::{IpAddr, Ipv4Addr},
+        path::Path,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    },
+};
+
+// sources StrictRpfFilter remembers the route of, a flood from random spoofed addresses would
+// grow the cache forever otherwise
+const RPF_CACHE_SIZE: usize = 65536;
+
+// DeduplicatorFilter's table, each bucket remembers the last DEDUP_BUCKET_SIZE shreds hashed to it
+const DEDUP_BUCKETS: usize = 65536;
+const DEDUP_BUCKET_SIZE: usize = 4;
+
+// a shred's signature, variant, slot and index. the signature alone doesn't identify a shred,
+// merkle shreds of one FEC set share it
+const SHRED_ID_SIZE: usize = 77;
+const SHRED_SLOT_OFFSET: usize = 65;
+const SHRED_SLOT_END: usize = SHRED_SLOT_OFFSET + 8;
+
+// nominal slot duration, SlotWindowFilter moves its window at this rate between decoder updates
+const SLOT_DURATION: Duration = Duration::from_millis(400);
+// how long SlotWindowFilter rejects every shred before it gives up on the current slot
+const SLOT_WINDOW_RESYNC: Duration = Duration::from_secs(2);
+
+/// decides whether a received ethernet frame gets forwarded
+pub trait PacketFilter {
+    fn accept(&self, packet: &[u8]) -> bool;
+}
+
+/// accepts a packet only if every filter in the chain accepts it, filters run in the order they
+/// were added and stop at the first rejection
+#[derive(Default)]
+pub struct PacketFilterChain {
+    filters: Vec<Box<dyn PacketFilter + Send>>,
+}
+
+impl PacketFilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, filter: impl PacketFilter + Send + 'static) -> Self {
+        self.push(filter);
+        self
+    }
+
+    pub fn push(&mut self, filter: impl PacketFilter + Send + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl PacketFilter for PacketFilterChain {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        self.filters.iter().all(|filter| filter.accept(packet))
+    }
+}
+
+/// UDP over IPv4 or IPv6
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UdpOnlyFilter;
+
+impl PacketFilter for UdpOnlyFilter {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        matches!(l4_header(packet), Some((IPPROTO_UDP, _)))
+    }
+}
+
+/// UDP or TCP packets carrying at least this many payload bytes
+#[derive(Clone, Copy, Debug)]
+pub struct MinPayloadLengthFilter(pub usize);
+
+impl PacketFilter for MinPayloadLengthFilter {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        let payload_offset = match l4_header(packet) {
+            Some((IPPROTO_UDP, l4_offset)) => l4_offset + UDP_HEADER_SIZE,
+            Some((IPPROTO_TCP, l4_offset)) => {
+                match packet.get(l4_offset..).and_then(parse_tcp_header) {
+                    Some(tcp) => l4_offset + tcp.payload_offset,
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+        packet.len().saturating_sub(payload_offset) >= self.0
+    }
+}
+
+/// IPv4 packets from one of these addresses
+#[derive(Clone, Debug)]
+pub struct SourceIpFilter(pub Vec<Ipv4Addr>);
+
+impl PacketFilter for SourceIpFilter {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        let Some((ETH_P_IP, l3_offset)) = l3_header(packet) else {
+            return false;
+        };
+        let Some(src) = packet.get(l3_offset + 12..l3_offset + 16) else {
+            return false;
+        };
+        let src = Ipv4Addr::new(src[0], src[1], src[2], src[3]);
+        self.0.contains(&src)
+    }
+}
+
+/// UDP or TCP packets sent to one of these ports
+#[derive(Clone, Debug)]
+pub struct DestinationPortFilter(pub Vec<u16>);
+
+impl PacketFilter for DestinationPortFilter {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        let Some((IPPROTO_UDP | IPPROTO_TCP, l4_offset)) = l4_header(packet) else {
+            return false;
+        };
+        let Some(port) = packet.get(l4_offset + 2..l4_offset + 4) else {
+            return false;
+        };
+        self.0.contains(&u16::from_be_bytes([port[0], port[1]]))
+    }
+}
+
+/// IPv4 packets from a known validator, anything else is assumed spoofed
+///
+/// same as SourceIpFilter but backed by a HashSet, made for the few thousand addresses of a
+/// cluster's gossip table.
+#[derive(Clone, Debug, Default)]
+pub struct SourceValidator {
+    validators: HashSet<Ipv4Addr>,
+}
+
+impl SourceValidator {
+    pub fn new(validators: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        Self {
+            validators: validators.into_iter().collect(),
+        }
+    }
+
+    /// read one address per line, blank lines and lines starting with # are skipped
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(list: &str) -> io::Result<Self> {
+        let mut validator = Self::default();
+        for (i, line) in list.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let ip = line.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: invalid IPv4 address {line:?}", i + 1),
+                )
+            })?;
+            validator.insert(ip);
+        }
+        Ok(validator)
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Addr) -> bool {
+        self.validators.insert(ip)
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        self.validators.contains(ip)
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+}
+
+impl PacketFilter for SourceValidator {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        matches!(source_ip(packet), Some(IpAddr::V4(src)) if self.validators.contains(&src))
+    }
+}
+
+/// strict reverse path filtering, like rp_filter=1: drops packets whose source the kernel would
+/// route out of another interface than the one they arrived on
+///
+/// the route to each source is looked up once with Router::out_if_index() and cached, so the
+/// verdicts are only as fresh as the router's snapshot of the routing table. the cache holds
+/// RPF_CACHE_SIZE sources and evicts the least recently used ones, approximated with a second
+/// chance queue. drops are counted in RxStats::rp_filter_drops.
+pub struct StrictRpfFilter {
+    router: Router,
+    if_index: u32,
+    stats: Arc<RxStats>,
+    cache: RefCell<RpfCache>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct RpfCache {
+    // verdict and whether it was used since it last went through the queue
+    verdicts: HashMap<IpAddr, (bool, bool)>,
+    // sources in the order they're checked for eviction
+    queue: VecDeque<IpAddr>,
+}
+
+impl StrictRpfFilter {
+    /// `if_index` is the interface the relay receives on
+    pub fn new(router: Router, if_index: u32, stats: Arc<RxStats>) -> Self {
+        Self {
+            router,
+            if_index,
+            stats,
+            cache: RefCell::default(),
+            capacity: RPF_CACHE_SIZE,
+        }
+    }
+
+    fn verdict(&self, src: IpAddr) -> bool {
+        let mut cache = self.cache.borrow_mut();
+        if let Some((accept, referenced)) = cache.verdicts.get_mut(&src) {
+            *referenced = true;
+            return *accept;
+        }
+        // no route back to the source fails the check too
+        let accept = self.router.out_if_index(src) == Some(self.if_index);
+        while cache.verdicts.len() >= self.capacity {
+            let Some(oldest) = cache.queue.pop_front() else {
+                break;
+            };
+            match cache.verdicts.get_mut(&oldest) {
+                Some((_, referenced)) if *referenced => {
+                    *referenced = false;
+                    cache.queue.push_back(oldest);
+                }
+                _ => {
+                    cache.verdicts.remove(&oldest);
+                }
+            }
+        }
+        cache.verdicts.insert(src, (accept, false));
+        cache.queue.push_back(src);
+        accept
+    }
+}
+
+impl PacketFilter for StrictRpfFilter {
+    fn accept(&self, packet: &[u8]) -> bool {
+        let Some(src) = source_ip(packet) else {
+            return false;
+        };
+        let accept = self.verdict(src);
+        if !accept {
+            self.stats.rp_filter_drops.fetch_add(1, Ordering::Relaxed);
+        }
+        accept
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct DedupEntry {
+    hash: u64,
+    slot: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct DedupBucket {
+    entries: [DedupEntry; DEDUP_BUCKET_SIZE],
+    // next entry to overwrite when none is stale
+    next: usize,
+}
+
+/// drops shreds that were already received, turbine and repair deliver the same shred from
+/// several peers
+///
+/// shreds are identified by a hash of their signature, variant, slot and index, kept in a fixed
+/// table of 65536 buckets of 4. entries more than `slot_window` slots older than the incoming
+/// shred are overwritten first, so a busy bucket can forget a shred and let a late duplicate
+/// through but never drops a new one. UDP packets too short to be a shred and anything that isn't
+/// UDP are accepted. drops are counted in RxStats::duplicate_drops.
+pub struct DeduplicatorFilter {
+    buckets: RefCell<Box<[DedupBucket]>>,
+    slot_window: u64,
+    stats: Arc<RxStats>,
+}
+
+impl DeduplicatorFilter {
+    pub fn new(slot_window: u64, stats: Arc<RxStats>) -> Self {
+        Self {
+            buckets: RefCell::new(vec![DedupBucket::default(); DEDUP_BUCKETS].into_boxed_slice()),
+            slot_window,
+            stats,
+        }
+    }
+}
+
+impl PacketFilter for DeduplicatorFilter {
+    fn accept(&self, packet: &[u8]) -> bool {
+        let Some((IPPROTO_UDP, l4_offset)) = l4_header(packet) else {
+            return true;
+        };
+        let Some(id) =
+            packet.get(l4_offset + UDP_HEADER_SIZE..l4_offset + UDP_HEADER_SIZE + SHRED_ID_SIZE)
+        else {
+            return true;
+        };
+        let Some(slot) = extract_slot_fast(id) else {
+            return true;
+        };
+        let hash = fnv1a(id);
+
+        let mut buckets = self.buckets.borrow_mut();
+        let bucket = &mut buckets[hash as usize % DEDUP_BUCKETS];
+        if bucket
+            .entries
+            .iter()
+            .any(|entry| entry.hash == hash && entry.slot == slot)
+        {
+            self.stats.duplicate_drops.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let stale = bucket
+            .entries
+            .iter()
+            .position(|entry| entry.slot.saturating_add(self.slot_window) < slot);
+        let i = stale.unwrap_or_else(|| {
+            let i = bucket.next;
+            bucket.next = (i + 1) % DEDUP_BUCKET_SIZE;
+            i
+        });
+        bucket.entries[i] = DedupEntry { hash, slot };
+        true
+    }
+}
+
+/// drops shreds whose slot is more than `lookback` slots behind or `lookahead` slots ahead of
+/// the current slot
+///
+/// `current_slot` is the newest slot the decoder put together from verified shreds. between
+/// updates the window keeps moving at the nominal slot rate, so it doesn't stall when the decoder
+/// falls behind or the link goes quiet. if nothing has been accepted for `SLOT_WINDOW_RESYNC` the
+/// slot is taken to be wrong, it's reset to 0 and everything is accepted until the decoder
+/// reports a slot again. while it's 0 nothing is dropped. put it first in the chain, it only
+/// reads the slot and saves the other filters the work for stale shreds. UDP packets too short to
+/// be a shred and anything that isn't UDP are accepted.
+#[derive(Clone, Debug)]
+pub struct SlotWindowFilter {
+    current_slot: Arc<AtomicU64>,
+    lookback: u64,
+    lookahead: u64,
+    // last value read from current_slot and when it changed to it
+    reported: Cell<(u64, Instant)>,
+    last_accept: Cell<Instant>,
+}
+
+impl SlotWindowFilter {
+    pub fn new(current_slot: Arc<AtomicU64>, lookback: u64, lookahead: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            current_slot,
+            lookback,
+            lookahead,
+            reported: Cell::new((0, now)),
+            last_accept: Cell::new(now),
+        }
+    }
+
+    fn accept_at(&self, packet: &[u8], now: Instant) -> bool {
+        let Some((IPPROTO_UDP, l4_offset)) = l4_header(packet) else {
+            return true;
+        };
+        let Some(slot) = packet
+            .get(l4_offset + UDP_HEADER_SIZE..)
+            .and_then(extract_slot_fast)
+        else {
+            return true;
+        };
+        let current_slot = self.current_slot.load(Ordering::Relaxed);
+        if current_slot == 0 {
+            self.last_accept.set(now);
+            return true;
+        }
+        let (reported, reported_at) = self.reported.get();
+        let reported_at = if reported == current_slot {
+            reported_at
+        } else {
+            self.reported.set((current_slot, now));
+            now
+        };
+        let elapsed = now.saturating_duration_since(reported_at);
+        let estimate =
+            current_slot.saturating_add((elapsed.as_millis() / SLOT_DURATION.as_millis()) as u64);
+        if (estimate.saturating_sub(self.lookback)..=estimate.saturating_add(self.lookahead))
+            .contains(&slot)
+        {
+            self.last_accept.set(now);
+            return true;
+        }
+        if now.saturating_duration_since(self.last_accept.get()) < SLOT_WINDOW_RESYNC {
+            return false;
+        }
+        // every shred has been rejected for a while, current_slot is off. a decoder that
+        // reported in the meantime wins the exchange
+        let _ = self.current_slot.compare_exchange(
+            current_slot,
+            0,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        self.last_accept.set(now);
+        true
+    }
+}
+
+impl PacketFilter for SlotWindowFilter {
+    #[inline]
+    fn accept(&self, packet: &[u8]) -> bool {
+        self.accept_at(packet, Instant::now())
+    }
+}
+
+/// slot of a shred, read from its common header without parsing anything else
+#[inline]
+pub fn extract_slot_fast(payload: &[u8]) -> Option<u64> {
+    let bytes = payload.get(SHRED_SLOT_OFFSET..SHRED_SLOT_END)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// 64 bit FNV-1a
+#[inline]
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// source address of an IPv4 or IPv6 packet
+fn source_ip(packet: &[u8]) -> Option<IpAddr> {
+    let (ethertype, l3_offset) = l3_header(packet)?;
+    match ethertype {
+        ETH_P_IP => {
+            let src: [u8; 4] = packet
+                .get(l3_offset + 12..l3_offset + 16)?
+                .try_into()
+                .ok()?;
+            Some(src.into())
+        }
+        ETH_P_IPV6 => {
+            let src: [u8; 16] = packet.get(l3_offset + 8..l3_offset + 24)?.try_into().ok()?;
+            Some(src.into())
+        }
+        _ => None,
+    }
+}
+
+/// ethertype of the L3 header and its offset from the start of the frame, looking through
+/// VLAN tags
+fn l3_header(packet: &[u8]) -> Option<(i32, usize)> {
+    parse_ethertype(packet).map(|(ethertype, offset)| (ethertype.into(), offset))
+}
+
+/// L4 protocol and the offset of the L4 header from the start of the frame
+fn l4_header(packet: &[u8]) -> Option<(u8, usize)> {
+    let (ethertype, l3_offset) = l3_header(packet)?;
+    let ip_header = packet.get(l3_offset..)?;
+    match ethertype {
+        ETH_P_IP => {
+            if ip_header.len() < IP_HEADER_SIZE {
+                return None;
+            }
+            let header_len = (ip_header[0] & 0x0f) as usize * 4;
+            (header_len >= IP_HEADER_SIZE).then_some((ip_header[9], l3_offset + header_len))
+        }
+        ETH_P_IPV6 => {
+            let ipv6 = parse_ipv6_header(ip_header)?;
+            Some((ipv6.next_header, l3_offset + ipv6.payload_offset))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            netlink::RouteEntry,
+            packet::{write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE},
+        },
+    };
+
+    fn udp_packet(src_ip: Ipv4Addr, dst_port: u16, payload_len: usize) -> Vec<u8> {
+        let dst_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let mut packet =
+            vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + payload_len];
+        write_eth_header(&mut packet, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut packet[ETH_HEADER_SIZE..],
+            &src_ip,
+            &dst_ip,
+            (UDP_HEADER_SIZE + payload_len) as u16,
+        );
+        write_udp_header(
+            &mut packet[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            1234,
+            &dst_ip,
+            dst_port,
+            payload_len as u16,
+            false,
+        );
+        packet
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let chain = PacketFilterChain::new()
+            .with(UdpOnlyFilter)
+            .with(MinPayloadLengthFilter(100))
+            .with(SourceIpFilter(vec![src]))
+            .with(DestinationPortFilter(vec![8001, 8002]));
+        assert_eq!(chain.len(), 4);
+
+        assert!(chain.accept(&udp_packet(src, 8001, 100)));
+        assert!(chain.accept(&udp_packet(src, 8002, 1200)));
+        // each filter rejects on its own
+        assert!(!chain.accept(&udp_packet(src, 8001, 99)));
+        assert!(!chain.accept(&udp_packet(Ipv4Addr::new(10, 0, 0, 3), 8001, 100)));
+        assert!(!chain.accept(&udp_packet(src, 9000, 100)));
+
+        let mut tcp = udp_packet(src, 8001, 100);
+        tcp[ETH_HEADER_SIZE + 9] = IPPROTO_TCP;
+        assert!(!UdpOnlyFilter.accept(&tcp));
+        assert!(!chain.accept(&tcp));
+
+        // truncated and empty frames never match
+        assert!(!chain.accept(&[]));
+        assert!(!UdpOnlyFilter.accept(&udp_packet(src, 8001, 0)[..ETH_HEADER_SIZE + 4]));
+
+        // filters look through an 802.1Q tag
+        let untagged = udp_packet(src, 8001, 100);
+        let mut tagged = untagged[..12].to_vec();
+        tagged.extend_from_slice(&[0x81, 0x00, 0x00, 0x64]);
+        tagged.extend_from_slice(&untagged[12..]);
+        assert!(chain.accept(&tagged));
+
+        // an empty chain accepts everything
+        assert!(PacketFilterChain::new().accept(&[]));
+    }
+
+    #[test]
+    fn test_source_validator() {
+        let list = "# validators\n10.0.0.1\n\n  10.0.0.7  \n";
+        let validator = SourceValidator::parse(list).unwrap();
+        assert_eq!(validator.len(), 2);
+        assert!(validator.accept(&udp_packet(Ipv4Addr::new(10, 0, 0, 1), 8001, 100)));
+        assert!(validator.accept(&udp_packet(Ipv4Addr::new(10, 0, 0, 7), 8001, 100)));
+        assert!(!validator.accept(&udp_packet(Ipv4Addr::new(10, 0, 0, 2), 8001, 100)));
+        assert!(!validator.accept(&[]));
+
+        let err = SourceValidator::parse("10.0.0.1\n10.0.0.256\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2:"));
+    }
+
+    #[test]
+    fn test_strict_rpf_filter() {
+        let route = |destination: Option<&str>, dst_len, out_if_index| RouteEntry {
+            destination: destination.map(|d| d.parse().unwrap()),
+            gateway: None,
+            pref_src: None,
+            out_if_index: Some(out_if_index),
+            in_if_index: None,
+            priority: None,
+            table: None,
+            protocol: 0,
+            scope: 0,
+            type_: 0,
+            family: libc::AF_INET as u8,
+            dst_len,
+        };
+        let router = Router::from_routes(vec![
+            route(None, 0, 1),
+            route(Some("10.0.0.0"), 8, 2),
+        ]);
+        let stats = Arc::new(RxStats::new());
+        let mut filter = StrictRpfFilter::new(router, 2, Arc::clone(&stats));
+        filter.capacity = 2;
+        let packet = |src: [u8; 4]| udp_packet(Ipv4Addr::from(src), 8001, 100);
+
+        assert!(filter.accept(&packet([10, 0, 0, 1])));
+        // routed out of the default route's interface
+        assert!(!filter.accept(&packet([192, 168, 1, 1])));
+        assert_eq!(stats.rp_filter_drops.load(Ordering::Relaxed), 1);
+
+        // 10.0.0.1 was used since it was cached, 192.168.1.1 goes first
+        assert!(filter.accept(&packet([10, 0, 0, 1])));
+        assert!(filter.accept(&packet([10, 0, 0, 2])));
+        let cache = filter.cache.borrow();
+        assert_eq!(cache.verdicts.len(), 2);
+        assert!(cache.verdicts.contains_key(&IpAddr::from([10, 0, 0, 1])));
+        assert!(cache.verdicts.contains_key(&IpAddr::from([10, 0, 0, 2])));
+    }
+
+    #[test]
+    fn test_deduplicator_filter() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let shred_offset = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+        let shred = |slot: u64, index: u32| {
+            let mut packet = udp_packet(src, 8001, 1228);
+            let shred = &mut packet[shred_offset..];
+            shred[..64].fill(0xab);
+            shred[64] = 0x86;
+            shred[65..73].copy_from_slice(&slot.to_le_bytes());
+            shred[73..77].copy_from_slice(&index.to_le_bytes());
+            packet
+        };
+
+        let stats = Arc::new(RxStats::new());
+        let dedup = DeduplicatorFilter::new(4, Arc::clone(&stats));
+        assert!(dedup.accept(&shred(100, 0)));
+        assert!(dedup.accept(&shred(100, 1)));
+        assert!(dedup.accept(&shred(101, 0)));
+        assert!(!dedup.accept(&shred(100, 0)));
+        assert!(!dedup.accept(&shred(101, 0)));
+        assert_eq!(stats.duplicate_drops.load(Ordering::Relaxed), 2);
+
+        // only the bytes identifying the shred count
+        let mut resent = shred(100, 1);
+        resent[shred_offset + 100] = 1;
+        assert!(!dedup.accept(&resent));
+
+        // not a shred
+        assert!(dedup.accept(&udp_packet(src, 8001, 10)));
+        assert!(dedup.accept(&udp_packet(src, 8001, 10)));
+        assert_eq!(stats.duplicate_drops.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_slot_window_filter() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let shred_offset = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+        let shred = |slot: u64| {
+            let mut packet = udp_packet(src, 8001, 1228);
+            packet[shred_offset + 65..shred_offset + 73].copy_from_slice(&slot.to_le_bytes());
+            packet
+        };
+
+        let current_slot = Arc::new(AtomicU64::new(0));
+        let filter = SlotWindowFilter::new(Arc::clone(&current_slot), 10, 5);
+        // no slot decoded yet
+        assert!(filter.accept(&shred(1)));
+
+        current_slot.store(1000, Ordering::Relaxed);
+        for (slot, accept) in [
+            (989, false),
+            (990, true),
+            (1000, true),
+            (1005, true),
+            (1006, false),
+        ] {
+            assert_eq!(filter.accept(&shred(slot)), accept, "{slot}");
+        }
+        assert!(filter.accept(&udp_packet(src, 8001, 72)));
+        assert_eq!(extract_slot_fast(&shred(42)[shred_offset..]), Some(42));
+    }
+
+    #[test]
+    fn test_slot_window_filter_stale_slot() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let shred_offset = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+        let shred = |slot: u64| {
+            let mut packet = udp_packet(src, 8001, 1228);
+            packet[shred_offset + 65..shred_offset + 73].copy_from_slice(&slot.to_le_bytes());
+            packet
+        };
+
+        let current_slot = Arc::new(AtomicU64::new(1000));
+        let filter = SlotWindowFilter::new(Arc::clone(&current_slot), 10, 5);
+        let start = Instant::now();
+        assert!(filter.accept_at(&shred(1000), start));
+
+        // the decoder stalled, the window keeps moving with the clock
+        let later = start + SLOT_DURATION * 100;
+        assert!(filter.accept_at(&shred(1100), later));
+        assert!(!filter.accept_at(&shred(1000), later));
+
+        // current_slot jumped far ahead of the cluster, every shred gets rejected until the
+        // filter gives up on it
+        current_slot.store(1_000_000, Ordering::Relaxed);
+        assert!(!filter.accept_at(&shred(1100), later));
+        assert!(!filter.accept_at(&shred(1100), later + SLOT_WINDOW_RESYNC / 2));
+        assert!(filter.accept_at(&shred(1100), later + SLOT_WINDOW_RESYNC));
+        assert_eq!(current_slot.load(Ordering::Relaxed), 0);
+        assert!(filter.accept_at(&shred(1101), later + SLOT_WINDOW_RESYNC));
+
+        // the decoder reports again and the window follows it
+        let resumed = later + SLOT_WINDOW_RESYNC * 2;
+        current_slot.store(1105, Ordering::Relaxed);
+        assert!(filter.accept_at(&shred(1105), resumed));
+        assert!(!filter.accept_at(&shred(1200), resumed));
+    }
+}
@@ -0,0 +1,764 @@
+// minimal pcap and pcapng writers for dumping relayed traffic, readable by wireshark and tcpdump
+
+use {
+    crossbeam_channel::{Receiver, Sender, TrySendError},
+    std::{
+        fs::File,
+        io::{self, BufReader, BufWriter, Read, Write},
+        mem,
+        path::Path,
+        thread::{self, JoinHandle},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+// nanosecond resolution variant of the classic pcap magic
+const PCAP_MAGIC_NS: u32 = 0xa1b2_3c4d;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+// largest frame the relay sees is a UMEM frame
+const SNAPLEN: u32 = 65535;
+
+pub const PCAP_GLOBAL_HEADER_SIZE: usize = 24;
+pub const PCAP_RECORD_HEADER_SIZE: usize = 16;
+
+const PCAPNG_SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const PCAPNG_ENHANCED_PACKET_BLOCK: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_COMMENT: u16 = 1;
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+// block type, block length and the trailing copy of the block length
+const PCAPNG_BLOCK_OVERHEAD: usize = 12;
+// interface id, timestamp high and low, captured and original length
+const PCAPNG_EPB_HEADER_SIZE: usize = 20;
+// longer records and blocks are treated as corrupt rather than allocated
+const MAX_RECORD_LEN: u32 = 256 * 1024;
+// FrameQueue buffers start out large enough for a full MTU frame
+const FRAME_BUFFER_SIZE: usize = 2048;
+
+/// writes ethernet frames to a pcap file
+///
+/// writes are buffered, the file is complete once the writer is flushed or dropped
+pub struct PcapWriter {
+    file: BufWriter<File>,
+    packets: usize,
+}
+
+impl PcapWriter {
+    /// create or truncate `path` and write the pcap global header
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let mut header = [0u8; PCAP_GLOBAL_HEADER_SIZE];
+        header[0..4].copy_from_slice(&PCAP_MAGIC_NS.to_le_bytes());
+        header[4..6].copy_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header[6..8].copy_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // thiszone and sigfigs stay 0
+        header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self { file, packets: 0 })
+    }
+
+    /// append a record for `data` captured at `ts_ns` nanoseconds since the unix epoch, frames
+    /// longer than the snaplen are truncated
+    pub fn write_packet(&mut self, data: &[u8], ts_ns: u64) -> io::Result<()> {
+        let captured = &data[..data.len().min(SNAPLEN as usize)];
+
+        let mut header = [0u8; PCAP_RECORD_HEADER_SIZE];
+        header[0..4].copy_from_slice(&((ts_ns / 1_000_000_000) as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&((ts_ns % 1_000_000_000) as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(captured.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.file.write_all(&header)?;
+        self.file.write_all(captured)?;
+
+        self.packets += 1;
+        Ok(())
+    }
+
+    /// number of records written so far
+    pub fn packets(&self) -> usize {
+        self.packets
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// writes ethernet frames to a pcapng file with nanosecond timestamps
+///
+/// the file has a single section with one ethernet interface, every frame is an enhanced packet
+/// block that can carry a comment. writes are buffered like PcapWriter's
+pub struct PcapngWriter {
+    file: BufWriter<File>,
+    packets: usize,
+    // enhanced packet block body, kept between packets
+    body: Vec<u8>,
+}
+
+impl PcapngWriter {
+    /// create or truncate `path` and write the section header and interface description blocks
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            packets: 0,
+            body: Vec::new(),
+        };
+
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // section length unknown
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.write_block(PCAPNG_SECTION_HEADER_BLOCK, &body)?;
+
+        let mut body = Vec::with_capacity(20);
+        body.extend_from_slice(&(LINKTYPE_ETHERNET as u16).to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&SNAPLEN.to_le_bytes());
+        // timestamps are in units of 10^-9 seconds
+        push_option(&mut body, PCAPNG_OPT_IF_TSRESOL, &[9]);
+        push_option(&mut body, PCAPNG_OPT_END, &[]);
+        writer.write_block(PCAPNG_INTERFACE_DESCRIPTION_BLOCK, &body)?;
+
+        Ok(writer)
+    }
+
+    /// append an enhanced packet block for `data` captured at `ts_ns` nanoseconds since the unix
+    /// epoch, frames longer than the snaplen are truncated
+    pub fn write_packet(
+        &mut self,
+        data: &[u8],
+        ts_ns: u64,
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        let captured = &data[..data.len().min(SNAPLEN as usize)];
+
+        let mut body = mem::take(&mut self.body);
+        body.clear();
+        // the only interface
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ts_ns >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts_ns as u32).to_le_bytes());
+        body.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(captured);
+        pad32(&mut body);
+        if let Some(comment) = comment {
+            push_option(&mut body, PCAPNG_OPT_COMMENT, comment.as_bytes());
+            push_option(&mut body, PCAPNG_OPT_END, &[]);
+        }
+        let written = self.write_block(PCAPNG_ENHANCED_PACKET_BLOCK, &body);
+        self.body = body;
+        written?;
+
+        self.packets += 1;
+        Ok(())
+    }
+
+    /// number of packet blocks written so far
+    pub fn packets(&self) -> usize {
+        self.packets
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(body.len() % 4, 0);
+        let len = ((PCAPNG_BLOCK_OVERHEAD + body.len()) as u32).to_le_bytes();
+        self.file.write_all(&block_type.to_le_bytes())?;
+        self.file.write_all(&len)?;
+        self.file.write_all(body)?;
+        self.file.write_all(&len)
+    }
+}
+
+/// pcap or pcapng output, picked from the file extension
+pub enum CaptureWriter {
+    Pcap(PcapWriter),
+    Pcapng(PcapngWriter),
+}
+
+impl CaptureWriter {
+    /// a pcapng writer for `.pcapng` paths, a pcap writer otherwise
+    pub fn new(path: &Path) -> io::Result<Self> {
+        if path.extension().is_some_and(|ext| ext == "pcapng") {
+            PcapngWriter::new(path).map(Self::Pcapng)
+        } else {
+            PcapWriter::new(path).map(Self::Pcap)
+        }
+    }
+
+    pub fn write_packet(&mut self, data: &[u8], ts_ns: u64) -> io::Result<()> {
+        match self {
+            Self::Pcap(writer) => writer.write_packet(data, ts_ns),
+            Self::Pcapng(writer) => writer.write_packet(data, ts_ns, None),
+        }
+    }
+
+    pub fn packets(&self) -> usize {
+        match self {
+            Self::Pcap(writer) => writer.packets(),
+            Self::Pcapng(writer) => writer.packets(),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Pcap(writer) => writer.flush(),
+            Self::Pcapng(writer) => writer.flush(),
+        }
+    }
+}
+
+/// writes captured frames on a thread of its own, so the relay never waits for the disk
+///
+/// frames are copied into one of `queue_len` buffers that the writer thread hands back once
+/// they're written. when it falls behind and every buffer is queued, frames are dropped instead
+/// of waited for, see dropped().
+pub struct CaptureThread {
+    queued: Sender<(Vec<u8>, u64)>,
+    free: Receiver<Vec<u8>>,
+    remaining: usize,
+    dropped: usize,
+    writer: JoinHandle<io::Result<usize>>,
+}
+
+impl CaptureThread {
+    pub const DEFAULT_QUEUE_LEN: usize = 1024;
+
+    /// start writing to `writer`, at most `max_packets` frames are captured
+    pub fn spawn(
+        mut writer: CaptureWriter,
+        max_packets: usize,
+        queue_len: usize,
+    ) -> io::Result<Self> {
+        let FrameQueue {
+            full_tx,
+            full_rx,
+            free_tx,
+            free_rx,
+        } = FrameQueue::new(queue_len);
+        let writer = thread::Builder::new()
+            .name("pcapWriter".to_string())
+            .spawn(move || {
+                for (data, ts_ns) in full_rx {
+                    let written = writer.write_packet(&data, ts_ns);
+                    if let Err(e) = written {
+                        log::error!("pcap write failed, capture stopped: {e}");
+                        return Err(e);
+                    }
+                    let _ = free_tx.send(data);
+                }
+                writer.flush()?;
+                Ok(writer.packets())
+            })?;
+        Ok(Self {
+            queued: full_tx,
+            free: free_rx,
+            remaining: max_packets,
+            dropped: 0,
+            writer,
+        })
+    }
+
+    /// queue `packet` with the current time, unless max_packets were captured already
+    #[inline]
+    pub fn capture(&mut self, packet: &[u8]) {
+        if self.remaining == 0 {
+            return;
+        }
+        let Ok(mut data) = self.free.try_recv() else {
+            self.dropped += 1;
+            return;
+        };
+        data.clear();
+        data.extend_from_slice(packet);
+        let ts_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |ts| ts.as_nanos() as u64);
+        match self.queued.try_send((data, ts_ns)) {
+            Ok(()) => self.remaining -= 1,
+            // the writer failed and exited
+            Err(TrySendError::Disconnected(_)) => self.remaining = 0,
+            // can't happen, there are no more buffers than queue slots
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+        }
+    }
+
+    /// frames not captured because the writer had fallen behind
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// wait for the queued frames to be written and flush the file, returns the number of
+    /// frames written
+    pub fn finish(self) -> io::Result<usize> {
+        drop(self.queued);
+        self.writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("pcap writer thread panicked")))
+    }
+}
+
+/// frames passed between two threads in a fixed set of buffers that go back and forth, so
+/// nothing is allocated per frame. one side takes free buffers and queues them full, the other
+/// takes the full ones and gives them back
+pub(crate) struct FrameQueue {
+    pub full_tx: Sender<(Vec<u8>, u64)>,
+    pub full_rx: Receiver<(Vec<u8>, u64)>,
+    pub free_tx: Sender<Vec<u8>>,
+    pub free_rx: Receiver<Vec<u8>>,
+}
+
+impl FrameQueue {
+    pub fn new(len: usize) -> Self {
+        let (full_tx, full_rx) = crossbeam_channel::bounded(len);
+        let (free_tx, free_rx) = crossbeam_channel::bounded(len);
+        for _ in 0..len {
+            free_tx
+                .send(Vec::with_capacity(FRAME_BUFFER_SIZE))
+                .expect("free queue has room");
+        }
+        Self {
+            full_tx,
+            full_rx,
+            free_tx,
+            free_rx,
+        }
+    }
+}
+
+/// reads frames back from a pcap or pcapng file, the format is detected from the file's magic
+///
+/// both byte orders and microsecond or nanosecond pcap timestamps are understood. pcapng files
+/// yield their enhanced packet blocks, timestamps are scaled with the interface's if_tsresol
+pub struct PcapReader {
+    file: BufReader<File>,
+    format: ReaderFormat,
+    error: Option<io::Error>,
+    // pcapng block body, kept between blocks
+    block: Vec<u8>,
+}
+
+enum ReaderFormat {
+    Pcap {
+        big_endian: bool,
+        // 1 for nanosecond timestamps, 1000 for microseconds
+        ns_per_unit: u64,
+    },
+    Pcapng {
+        big_endian: bool,
+        // timestamp units per second of each interface in the current section
+        interfaces: Vec<u64>,
+    },
+}
+
+impl PcapReader {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+
+        let format = if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER_BLOCK {
+            // the block type reads the same in both byte orders, the section header that
+            // follows sets it
+            ReaderFormat::Pcapng {
+                big_endian: false,
+                interfaces: Vec::new(),
+            }
+        } else {
+            let mut header = [0u8; PCAP_GLOBAL_HEADER_SIZE - 4];
+            file.read_exact(&mut header)?;
+            let (big_endian, ns_per_unit) = match magic {
+                [0xd4, 0xc3, 0xb2, 0xa1] => (false, 1_000),
+                [0xa1, 0xb2, 0xc3, 0xd4] => (true, 1_000),
+                [0x4d, 0x3c, 0xb2, 0xa1] => (false, 1),
+                [0xa1, 0xb2, 0x3c, 0x4d] => (true, 1),
+                _ => return Err(invalid_data("not a pcap or pcapng file")),
+            };
+            ReaderFormat::Pcap {
+                big_endian,
+                ns_per_unit,
+            }
+        };
+
+        let mut reader = Self {
+            file,
+            format,
+            error: None,
+            block: Vec::new(),
+        };
+        if let ReaderFormat::Pcapng { .. } = reader.format {
+            reader.read_section_header()?;
+        }
+        Ok(reader)
+    }
+
+    /// the next frame and its timestamp in nanoseconds since the unix epoch, None at the end of
+    /// the file or on the first malformed record, see take_error()
+    pub fn next_packet(&mut self) -> Option<(Vec<u8>, u64)> {
+        let mut data = Vec::new();
+        self.next_packet_into(&mut data).map(|ts_ns| (data, ts_ns))
+    }
+
+    /// like next_packet() but reads the frame into `data`, so one buffer can be reused for the
+    /// whole file
+    pub fn next_packet_into(&mut self, data: &mut Vec<u8>) -> Option<u64> {
+        if self.error.is_some() {
+            return None;
+        }
+        let next = match self.format {
+            ReaderFormat::Pcap { .. } => self.next_pcap_record(data),
+            ReaderFormat::Pcapng { .. } => self.next_pcapng_packet(data),
+        };
+        next.unwrap_or_else(|e| {
+            self.error = Some(e);
+            None
+        })
+    }
+
+    /// the error that ended next_packet() early, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn next_pcap_record(&mut self, data: &mut Vec<u8>) -> io::Result<Option<u64>> {
+        let ReaderFormat::Pcap {
+            big_endian,
+            ns_per_unit,
+        } = self.format
+        else {
+            unreachable!();
+        };
+        let mut header = [0u8; PCAP_RECORD_HEADER_SIZE];
+        if !read_exact_or_eof(&mut self.file, &mut header)? {
+            return Ok(None);
+        }
+        let ts_sec = u32_at(&header, 0, big_endian) as u64;
+        let ts_frac = u32_at(&header, 4, big_endian) as u64;
+        let captured = u32_at(&header, 8, big_endian);
+        if captured > MAX_RECORD_LEN {
+            return Err(invalid_data("pcap record too long"));
+        }
+
+        data.clear();
+        data.resize(captured as usize, 0);
+        self.file.read_exact(data)?;
+        Ok(Some(ts_sec * 1_000_000_000 + ts_frac * ns_per_unit))
+    }
+
+    fn next_pcapng_packet(&mut self, data: &mut Vec<u8>) -> io::Result<Option<u64>> {
+        loop {
+            let mut block_type = [0u8; 4];
+            if !read_exact_or_eof(&mut self.file, &mut block_type)? {
+                return Ok(None);
+            }
+            if u32::from_le_bytes(block_type) == PCAPNG_SECTION_HEADER_BLOCK {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let Self {
+                file,
+                format:
+                    ReaderFormat::Pcapng {
+                        big_endian,
+                        interfaces,
+                    },
+                block: body,
+                ..
+            } = self
+            else {
+                unreachable!();
+            };
+            let big_endian = *big_endian;
+            let block_type = u32_at(&block_type, 0, big_endian);
+            read_block_body(file, big_endian, body)?;
+
+            match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION_BLOCK => {
+                    let options = body.get(8..).unwrap_or_default();
+                    interfaces.push(ts_units_per_sec(options, big_endian));
+                }
+                PCAPNG_ENHANCED_PACKET_BLOCK => {
+                    if body.len() < PCAPNG_EPB_HEADER_SIZE {
+                        return Err(invalid_data("truncated pcapng packet block"));
+                    }
+                    let interface = u32_at(body, 0, big_endian) as usize;
+                    let units_per_sec = *interfaces
+                        .get(interface)
+                        .ok_or_else(|| invalid_data("pcapng packet on an unknown interface"))?;
+                    let ts = (u32_at(body, 4, big_endian) as u64) << 32
+                        | u32_at(body, 8, big_endian) as u64;
+                    let captured = u32_at(body, 12, big_endian) as usize;
+                    let packet = body
+                        .get(PCAPNG_EPB_HEADER_SIZE..PCAPNG_EPB_HEADER_SIZE + captured)
+                        .ok_or_else(|| invalid_data("truncated pcapng packet block"))?;
+                    data.clear();
+                    data.extend_from_slice(packet);
+                    let ts_ns = (ts as u128 * 1_000_000_000 / units_per_sec as u128) as u64;
+                    return Ok(Some(ts_ns));
+                }
+                // statistics, name resolution, simple packets without a timestamp...
+                _ => {}
+            }
+        }
+    }
+
+    // called with the block type already read, starts a new section
+    fn read_section_header(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 8];
+        self.file.read_exact(&mut header)?;
+        let big_endian = match u32::from_le_bytes(header[4..8].try_into().unwrap()) {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(invalid_data("bad pcapng byte order magic")),
+        };
+        let len = u32_at(&header, 0, big_endian) as usize;
+        // the rest of the body after the magic, and the trailing length
+        let rest = len
+            .checked_sub(PCAPNG_BLOCK_OVERHEAD + 4)
+            .ok_or_else(|| invalid_data("bad pcapng section header length"))?;
+        io::copy(&mut (&mut self.file).take(rest as u64 + 4), &mut io::sink())?;
+
+        self.format = ReaderFormat::Pcapng {
+            big_endian,
+            interfaces: Vec::new(),
+        };
+        Ok(())
+    }
+}
+
+/// read a pcapng block after its type into `body`, without the lengths
+fn read_block_body(file: &mut impl Read, big_endian: bool, body: &mut Vec<u8>) -> io::Result<()> {
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len)?;
+    let len = u32_at(&len, 0, big_endian);
+    if len > MAX_RECORD_LEN || (len as usize) < PCAPNG_BLOCK_OVERHEAD || !len.is_multiple_of(4) {
+        return Err(invalid_data("bad pcapng block length"));
+    }
+    body.clear();
+    body.resize(len as usize - 8, 0);
+    file.read_exact(body)?;
+    body.truncate(len as usize - PCAPNG_BLOCK_OVERHEAD);
+    Ok(())
+}
+
+/// timestamp units per second from an interface description block's options, microseconds
+/// unless if_tsresol says otherwise
+fn ts_units_per_sec(mut options: &[u8], big_endian: bool) -> u64 {
+    while options.len() >= 4 {
+        let code = u16_at(options, 0, big_endian);
+        let len = u16_at(options, 2, big_endian) as usize;
+        if code == PCAPNG_OPT_END {
+            break;
+        }
+        if code == PCAPNG_OPT_IF_TSRESOL && len == 1 && options.len() > 4 {
+            let resolution = options[4];
+            let exponent = u32::from(resolution & 0x7f);
+            // the high bit selects a power of 2 instead of 10
+            let base: u64 = if resolution & 0x80 != 0 { 2 } else { 10 };
+            return base.checked_pow(exponent).unwrap_or(u64::MAX);
+        }
+        options = options
+            .get(4 + len.next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    1_000_000
+}
+
+/// false if the reader was already at the end, a partial read is an error
+fn read_exact_or_eof(file: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn u32_at(bytes: &[u8], offset: usize, big_endian: bool) -> u32 {
+    let bytes = bytes[offset..offset + 4].try_into().unwrap();
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize, big_endian: bool) -> u16 {
+    let bytes = bytes[offset..offset + 2].try_into().unwrap();
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// append a pcapng option, the value is padded to 32 bits
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad32(body);
+}
+
+fn pad32(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    #[test]
+    fn test_pcap_writer() {
+        let path = std::env::temp_dir().join(format!("axdp-pcap-{}.pcap", std::process::id()));
+        let frame = [0xabu8; 60];
+
+        let mut writer = PcapWriter::new(&path).unwrap();
+        writer
+            .write_packet(&frame, 1_700_000_000_123_456_789)
+            .unwrap();
+        writer.write_packet(&frame[..14], 0).unwrap();
+        assert_eq!(writer.packets(), 2);
+        drop(writer);
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            data.len(),
+            PCAP_GLOBAL_HEADER_SIZE + 2 * PCAP_RECORD_HEADER_SIZE + 60 + 14
+        );
+        assert_eq!(data[0..4], PCAP_MAGIC_NS.to_le_bytes());
+        assert_eq!(data[20..24], LINKTYPE_ETHERNET.to_le_bytes());
+
+        let record = &data[PCAP_GLOBAL_HEADER_SIZE..];
+        assert_eq!(record[0..4], 1_700_000_000u32.to_le_bytes());
+        assert_eq!(record[4..8], 123_456_789u32.to_le_bytes());
+        assert_eq!(record[8..12], 60u32.to_le_bytes());
+        assert_eq!(record[12..16], 60u32.to_le_bytes());
+        assert_eq!(record[16..76], frame);
+    }
+
+    #[test]
+    fn test_pcapng_writer() {
+        let path = std::env::temp_dir().join(format!("axdp-pcapng-{}.pcapng", std::process::id()));
+        let frame = [0xabu8; 61];
+        let ts_ns = 1_700_000_000_123_456_789u64;
+
+        let mut capture = CaptureWriter::new(&path).unwrap();
+        capture.write_packet(&frame, ts_ns).unwrap();
+        let CaptureWriter::Pcapng(writer) = &mut capture else {
+            panic!("expected a pcapng writer for a .pcapng path");
+        };
+        writer.write_packet(&frame[..14], 0, Some("hi")).unwrap();
+        assert_eq!(capture.packets(), 2);
+        drop(capture);
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        // section header and interface description
+        assert_eq!(u32_at(0), PCAPNG_SECTION_HEADER_BLOCK);
+        assert_eq!(u32_at(4), 28);
+        assert_eq!(u32_at(8), PCAPNG_BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(28), PCAPNG_INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(u32_at(32), 32);
+        assert_eq!(data[44..49], [9, 0, 1, 0, 9]);
+
+        // packet data is padded from 61 to 64 bytes
+        let epb = 60;
+        assert_eq!(u32_at(epb), PCAPNG_ENHANCED_PACKET_BLOCK);
+        assert_eq!(u32_at(epb + 4), 96);
+        assert_eq!(u32_at(epb + 12), (ts_ns >> 32) as u32);
+        assert_eq!(u32_at(epb + 16), ts_ns as u32);
+        assert_eq!(u32_at(epb + 20), 61);
+        assert_eq!(data[epb + 28..epb + 89], frame);
+        assert_eq!(u32_at(epb + 92), 96);
+
+        // 14 bytes of data padded to 16, the comment option and the end of options
+        let epb = epb + 96;
+        assert_eq!(u32_at(epb + 4), 12 + 20 + 16 + 8 + 4);
+        assert_eq!(data[epb + 44..epb + 50], [1, 0, 2, 0, b'h', b'i']);
+        assert_eq!(data.len(), epb + 60);
+    }
+
+    #[test]
+    fn test_pcap_reader() {
+        let frame: Vec<u8> = (0..61).collect();
+        let ts_ns = 1_700_000_000_123_456_789u64;
+
+        for ext in ["pcap", "pcapng"] {
+            let path =
+                std::env::temp_dir().join(format!("axdp-pcap-reader-{}.{ext}", std::process::id()));
+            let mut writer = CaptureWriter::new(&path).unwrap();
+            writer.write_packet(&frame, ts_ns).unwrap();
+            writer.write_packet(&frame[..14], ts_ns + 1).unwrap();
+            drop(writer);
+
+            let mut reader = PcapReader::new(&path).unwrap();
+            assert_eq!(reader.next_packet(), Some((frame.clone(), ts_ns)));
+            assert_eq!(
+                reader.next_packet(),
+                Some((frame[..14].to_vec(), ts_ns + 1))
+            );
+            assert_eq!(reader.next_packet(), None);
+            assert!(reader.take_error().is_none());
+
+            // a record cut short ends the stream with an error
+            let data = fs::read(&path).unwrap();
+            fs::write(&path, &data[..data.len() - 6]).unwrap();
+            let mut reader = PcapReader::new(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert!(reader.next_packet().is_some());
+            assert_eq!(reader.next_packet(), None);
+            assert!(reader.take_error().is_some());
+        }
+    }
+
+    #[test]
+    fn test_capture_thread() {
+        let path = std::env::temp_dir().join(format!("axdp-capture-{}.pcap", std::process::id()));
+        let writer = CaptureWriter::new(&path).unwrap();
+        let mut capture = CaptureThread::spawn(writer, 3, 8).unwrap();
+        for len in 14..19 {
+            let frame: Vec<u8> = (0..len).collect();
+            capture.capture(&frame);
+        }
+        assert_eq!(capture.dropped(), 0);
+        // frames past max_packets are ignored
+        assert_eq!(capture.finish().unwrap(), 3);
+
+        let mut reader = PcapReader::new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut data = Vec::new();
+        for len in 14..17 {
+            assert!(reader.next_packet_into(&mut data).is_some());
+            assert_eq!(data, (0..len).collect::<Vec<u8>>());
+        }
+        assert_eq!(reader.next_packet_into(&mut data), None);
+        assert!(reader.take_error().is_none());
+    }
+}
@@ -1,16 +1,171 @@
 #![allow(clippy::arithmetic_side_effects)]
 
-use aya::{programs::Xdp, Ebpf, include_bytes_aligned};
-use aya::maps::XskMap;
-// use std::os::fd::AsRawFd;
+use aya::{programs::Xdp, Btf, Ebpf, EbpfError, EbpfLoader, Pod, include_bytes_aligned};
+use aya::maps::{
+    Array, CpuMap, DevMap, HashMap, Map, MapData, PerCpuArray, ProgramArray, RingBuf, XskMap,
+};
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::programs::xdp::XdpLinkId;
+use crate::Error;
+use ipnet::Ipv4Net;
+use std::net::Ipv4Addr;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
 
-pub fn load_xdp_program(if_index: u32) -> Result<Ebpf, Box<dyn std::error::Error>> {
+/// an XDP program attached to an interface by one of the load functions, detached when dropped
+///
+/// derefs to the loaded Ebpf object so the map helpers below take it directly. it remembers the
+/// XDP link and the sockets put into XSKS_MAP, which replace_xdp_program() carries over.
+pub struct XdpProgram {
+    ebpf: Ebpf,
+    if_index: u32,
+    program_name: &'static str,
+    link_id: Option<XdpLinkId>,
+    xsks: Vec<(u32, RawFd)>,
+}
+
+impl XdpProgram {
+    fn new(ebpf: Ebpf, if_index: u32, program_name: &'static str, link_id: XdpLinkId) -> Self {
+        Self {
+            ebpf,
+            if_index,
+            program_name,
+            link_id: Some(link_id),
+            xsks: Vec::new(),
+        }
+    }
+
+    pub fn if_index(&self) -> u32 {
+        self.if_index
+    }
+}
+
+impl Deref for XdpProgram {
+    type Target = Ebpf;
+
+    fn deref(&self) -> &Ebpf {
+        &self.ebpf
+    }
+}
+
+impl DerefMut for XdpProgram {
+    fn deref_mut(&mut self) -> &mut Ebpf {
+        &mut self.ebpf
+    }
+}
+
+pub fn load_xdp_program(if_index: u32) -> Result<XdpProgram, Error> {
     // load the compiled eBPF bytecode with proper alignment
     // the include_bytes_aligned! macro ensures the bytes are properly aligned for eBPF loading
-    let mut ebpf = Ebpf::load(include_bytes_aligned!(
+    let mut ebpf = load_ebpf(include_bytes_aligned!(
         "../target/bpf/xdp-redirect"
     ))?;
 
+    let program_name = xdp_program_name(&ebpf)?;
+    eprintln!("using XDP program: {}", program_name);
+    let p: &mut Xdp = ebpf
+        .program_mut(program_name)
+        .ok_or(Error::MissingProgram(program_name))?
+        .try_into()?;
+    p.load()?;
+
+    let link_id = attach(p, if_index)?;
+
+    Ok(XdpProgram::new(ebpf, if_index, program_name, link_id))
+}
+
+/// swap the program attached by load_xdp_program() for the one in `new_bytecode` without
+/// dropping packets or touching the sockets
+///
+/// the sockets `old` has in XSKS_MAP, the IP and port filters, LOCAL_IP and the rate limit are
+/// copied into the new object before its program atomically replaces the old one in the XDP
+/// link, so the sockets keep receiving the same traffic. stats start over. `old` no longer owns
+/// the link afterwards, dropping it unloads the old program.
+pub fn replace_xdp_program(
+    old: &mut XdpProgram,
+    new_bytecode: &[u8],
+) -> Result<XdpProgram, Error> {
+    let if_index = old.if_index;
+    let mut ebpf = load_ebpf(new_bytecode)?;
+
+    for &(index, socket_fd) in &old.xsks {
+        set_xskmap(&mut ebpf, index, socket_fd)?;
+    }
+    copy_config(&old.ebpf, &mut ebpf)?;
+
+    let program_name = xdp_program_name(&ebpf)?;
+    let p: &mut Xdp = ebpf
+        .program_mut(program_name)
+        .ok_or(Error::MissingProgram(program_name))?
+        .try_into()?;
+    p.load()?;
+
+    let link_id = old.link_id.take().ok_or(Error::NotAttached(if_index))?;
+    let old_p: &mut Xdp = old
+        .ebpf
+        .program_mut(old.program_name)
+        .ok_or(Error::MissingProgram(old.program_name))?
+        .try_into()?;
+    let link = old_p.take_link(link_id)?;
+
+    let link_id = p.attach_to_link(link)?;
+
+    eprintln!("replaced XDP program on if_index {}", if_index);
+
+    let mut program = XdpProgram::new(ebpf, if_index, program_name, link_id);
+    program.xsks = old.xsks.clone();
+    Ok(program)
+}
+
+// maps holding configuration rather than state, see replace_xdp_program()
+const CONFIG_ARRAYS: [&str; 4] = [
+    "LOCAL_IP",
+    "IP_WHITELIST_LEN",
+    "UDP_PORT_FILTER_LEN",
+    "RATE_LIMIT_PPS",
+];
+
+// maps the new object doesn't have are skipped
+fn copy_config(old: &Ebpf, new: &mut Ebpf) -> Result<(), Error> {
+    for name in CONFIG_ARRAYS {
+        let (Some(from), Some(to)) = (old.map(name), new.map_mut(name)) else {
+            continue;
+        };
+        let from: Array<_, u32> = from.try_into()?;
+        let mut to: Array<_, u32> = to.try_into()?;
+        to.set(0, from.get(&0, 0)?, 0)?;
+    }
+
+    copy_hash_map::<u32>(old, new, "IP_WHITELIST")?;
+    copy_hash_map::<u16>(old, new, "UDP_PORT_FILTER")?;
+
+    let routes = (old.map("IP_ROUTING_TABLE"), new.map_mut("IP_ROUTING_TABLE"));
+    if let (Some(from), Some(to)) = routes {
+        let from: LpmTrie<_, [u8; 4], u32> = from.try_into()?;
+        let mut to: LpmTrie<_, [u8; 4], u32> = to.try_into()?;
+        for entry in from.iter() {
+            let (key, action) = entry?;
+            to.insert(&key, action, 0)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_hash_map<K: Pod>(old: &Ebpf, new: &mut Ebpf, name: &str) -> Result<(), Error> {
+    let (Some(from), Some(to)) = (old.map(name), new.map_mut(name)) else {
+        return Ok(());
+    };
+    let from: HashMap<_, K, u8> = from.try_into()?;
+    let mut to: HashMap<_, K, u8> = to.try_into()?;
+    for entry in from.iter() {
+        let (key, value) = entry?;
+        to.insert(key, value, 0)?;
+    }
+    Ok(())
+}
+
+fn xdp_program_name(ebpf: &Ebpf) -> Result<&'static str, Error> {
     // debug: print all program names
     eprintln!("available programs in eBPF object:");
     for (name, _) in ebpf.programs() {
@@ -18,55 +173,688 @@ pub fn load_xdp_program(if_index: u32) -> Result<Ebpf, Box<dyn std::error::Error
     }
 
     // debug: get the XDP program - try different possible names
-    let program_name = if ebpf.program("xdp_redirect").is_some() {
-        "xdp_redirect"
+    if ebpf.program("xdp_redirect").is_some() {
+        Ok("xdp_redirect")
     } else if ebpf.program("xdp").is_some() {
-        "xdp"
+        Ok("xdp")
     } else {
-        return Err("no XDP program found in eBPF object".into());
+        Err(Error::MissingProgram("xdp_redirect or xdp"))
+    }
+}
+
+/// load the cpumap variant of the XDP program, which spreads packets over `cpus` by flow hash
+/// instead of relying on the NIC's queues. each CPU hands its packets to the socket stored at
+/// its CPU id in XSKS_MAP, see CpuMapRelay.
+pub fn load_xdp_cpumap_program(
+    if_index: u32,
+    cpus: &[u32],
+) -> Result<XdpProgram, Error> {
+    // max entries of CPU_MAP, CPU_IDS and XSKS_MAP
+    if cpus.is_empty() || cpus.len() > 64 {
+        return Err(Error::InvalidCpuCount(cpus.len() as u32));
+    }
+    if let Some(&cpu) = cpus.iter().find(|&&cpu| cpu >= 64) {
+        return Err(Error::InvalidCpuId(cpu));
+    }
+
+    let mut ebpf = load_ebpf(include_bytes_aligned!(
+        "../target/bpf/xdp-redirect"
+    ))?;
+
+    // second stage, runs on the target CPU and must be loaded before it's put into CPU_MAP
+    let p: &mut Xdp = ebpf.program_mut("xdp_cpumap_xsk")
+        .ok_or(Error::MissingProgram("xdp_cpumap_xsk"))?
+        .try_into()?;
+    p.load()?;
+    let xsk_program = p.fd()?.try_clone()?;
+
+    {
+        // size of the per CPU queue the kernel uses to hand packets to the target CPU
+        const CPUMAP_QUEUE_SIZE: u32 = 2048;
+
+        let map = ebpf.map_mut("CPU_MAP")
+            .ok_or(Error::MissingMap("CPU_MAP"))?;
+        let mut cpu_map: CpuMap<_> = map.try_into()?;
+        for &cpu in cpus {
+            cpu_map.set(cpu, CPUMAP_QUEUE_SIZE, Some(&xsk_program), 0)?;
+        }
+    }
+
+    {
+        let map = ebpf.map_mut("CPU_IDS")
+            .ok_or(Error::MissingMap("CPU_IDS"))?;
+        let mut cpu_ids: Array<_, u32> = map.try_into()?;
+        for (index, &cpu) in cpus.iter().enumerate() {
+            cpu_ids.set(index as u32, cpu, 0)?;
+        }
+    }
+
+    let map = ebpf.map_mut("CPU_COUNT")
+        .ok_or(Error::MissingMap("CPU_COUNT"))?;
+    let mut cpu_count: Array<_, u32> = map.try_into()?;
+    cpu_count.set(0, cpus.len() as u32, 0)?;
+
+    let p: &mut Xdp = ebpf.program_mut("xdp_cpumap")
+        .ok_or(Error::MissingProgram("xdp_cpumap"))?
+        .try_into()?;
+    p.load()?;
+    let link_id = attach(p, if_index)?;
+
+    eprintln!("XDP cpumap program spreading packets over CPUs {:?}", cpus);
+
+    Ok(XdpProgram::new(ebpf, if_index, "xdp_cpumap", link_id))
+}
+
+/// load the routing variant of the XDP program, which passes, drops or redirects packets by
+/// IPv4 source prefix as set with add_route(). packets matching no route go through the same
+/// filters as with load_xdp_program()
+pub fn load_xdp_routing_program(if_index: u32) -> Result<XdpProgram, Error> {
+    let mut ebpf = load_ebpf(include_bytes_aligned!(
+        "../target/bpf/xdp-redirect"
+    ))?;
+
+    let p: &mut Xdp = ebpf.program_mut("xdp_lpm_route")
+        .ok_or(Error::MissingProgram("xdp_lpm_route"))?
+        .try_into()?;
+    p.load()?;
+    let link_id = attach(p, if_index)?;
+
+    Ok(XdpProgram::new(ebpf, if_index, "xdp_lpm_route", link_id))
+}
+
+/// STAGES slot of the dispatcher's IP_WHITELIST and UDP_PORT_FILTER stage
+pub const STAGE_FILTER: u32 = 0;
+/// STAGES slot of the dispatcher's rate limit stage, see set_rate_limit()
+pub const STAGE_RATE_LIMIT: u32 = 1;
+/// STAGES slot of the dispatcher's AF_XDP redirect stage
+pub const STAGE_REDIRECT: u32 = 2;
+
+// max entries of STAGES, must match the eBPF program
+const STAGE_COUNT: u32 = 8;
+
+/// load the dispatcher variant of the XDP program, which parses the headers once and runs the
+/// programs in the STAGES program array by tail calls, in slot order
+///
+/// STAGE_FILTER, STAGE_RATE_LIMIT and STAGE_REDIRECT get the built-in stages, which together do
+/// what load_xdp_program() does plus rate limiting. replace or add stages with
+/// insert_tail_program(). packets that get past every stage go to the kernel.
+pub fn load_xdp_dispatcher(if_index: u32) -> Result<XdpProgram, Error> {
+    let mut ebpf = load_ebpf(include_bytes_aligned!(
+        "../target/bpf/xdp-redirect"
+    ))?;
+
+    for (slot, name) in [
+        (STAGE_FILTER, "xdp_stage_filter"),
+        (STAGE_RATE_LIMIT, "xdp_stage_rate_limit"),
+        (STAGE_REDIRECT, "xdp_stage_redirect"),
+    ] {
+        let p: &mut Xdp = ebpf.program_mut(name)
+            .ok_or(Error::MissingProgram(name))?
+            .try_into()?;
+        p.load()?;
+        let prog_fd = p.fd()?.as_fd().as_raw_fd();
+        insert_tail_program(&mut ebpf, slot, prog_fd)?;
+    }
+
+    let p: &mut Xdp = ebpf.program_mut("xdp_dispatch")
+        .ok_or(Error::MissingProgram("xdp_dispatch"))?
+        .try_into()?;
+    p.load()?;
+    let link_id = attach(p, if_index)?;
+
+    Ok(XdpProgram::new(ebpf, if_index, "xdp_dispatch", link_id))
+}
+
+/// put the XDP program `prog_fd` into `slot` of the dispatcher's STAGES, replacing the stage
+/// that was there. takes effect for the next packet, see load_xdp_dispatcher()
+///
+/// the program can come from any object, the map keeps it loaded so `prog_fd` may be closed
+/// afterwards. a stage ends with an XDP action or by tail calling the next slot.
+pub fn insert_tail_program(ebpf: &mut Ebpf, slot: u32, prog_fd: RawFd) -> Result<(), Error> {
+    // matches union bpf_attr for BPF_MAP_UPDATE_ELEM
+    #[repr(C)]
+    struct MapUpdateAttr {
+        map_fd: u32,
+        _pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+    const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+
+    if slot >= STAGE_COUNT {
+        return Err(Error::InvalidStageSlot(slot));
+    }
+    let Some(Map::ProgramArray(stages)) = ebpf.map("STAGES") else {
+        return Err(Error::MissingMap("STAGES"));
     };
 
-    eprintln!("using XDP program: {}", program_name);
-    let p: &mut Xdp = ebpf.program_mut(program_name).unwrap().try_into()?;
+    // aya's ProgramArray only takes programs it loaded itself, so update the map directly
+    let value = prog_fd as u32;
+    let attr = MapUpdateAttr {
+        map_fd: stages.fd().as_fd().as_raw_fd() as u32,
+        _pad: 0,
+        key: &slot as *const u32 as u64,
+        value: &value as *const u32 as u64,
+        flags: 0,
+    };
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_UPDATE_ELEM,
+            &attr as *const MapUpdateAttr,
+            std::mem::size_of::<MapUpdateAttr>() as u32,
+        )
+    };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    eprintln!("inserted program fd {} into STAGES slot {}", prog_fd, slot);
+
+    Ok(())
+}
+
+/// empty `slot` of the dispatcher's STAGES, packets skip to the next stage
+pub fn remove_tail_program(ebpf: &mut Ebpf, slot: u32) -> Result<(), Error> {
+    let map = ebpf.map_mut("STAGES")
+        .ok_or(Error::MissingMap("STAGES"))?;
+    let mut stages: ProgramArray<_> = map.try_into()?;
+    stages.clear_index(&slot)?;
+
+    eprintln!("removed the program in STAGES slot {}", slot);
+
+    Ok(())
+}
+
+/// let at most `pps` packets per second per CPU through the dispatcher's rate limit stage,
+/// dropping the rest. 0 turns the limit off, which is the default
+pub fn set_rate_limit(ebpf: &mut Ebpf, pps: u32) -> Result<(), Error> {
+    let map = ebpf.map_mut("RATE_LIMIT_PPS")
+        .ok_or(Error::MissingMap("RATE_LIMIT_PPS"))?;
+    let mut rate_limit: Array<_, u32> = map.try_into()?;
+    rate_limit.set(0, pps, 0)?;
+
+    eprintln!("rate limiting to {} packets per second per CPU", pps);
+
+    Ok(())
+}
+
+/// load the forwarding variant of the XDP program on `src_if_index`, which sends the UDP packets
+/// it receives out of `dst_if_index` in the kernel, bypassing AF_XDP and the network stack
+///
+/// the same filters as the redirect program apply, add_udp_port() and add_ip_to_whitelist()
+/// narrow down what's forwarded and everything else is passed to the kernel. frames go out
+/// unmodified, including the ethernet header. some drivers only transmit
+/// redirected frames when the destination device has an XDP program attached as well.
+pub fn load_xdp_devmap_forwarder(src_if_index: u32, dst_if_index: u32) -> Result<XdpProgram, Error> {
+    let mut ebpf = load_ebpf(include_bytes_aligned!(
+        "../target/bpf/xdp-redirect"
+    ))?;
+
+    {
+        let map = ebpf.map_mut("FORWARD_MAP")
+            .ok_or(Error::MissingMap("FORWARD_MAP"))?;
+        let mut forward_map: DevMap<_> = map.try_into()?;
+        forward_map.set(0, dst_if_index, None, 0)?;
+    }
+
+    let p: &mut Xdp = ebpf.program_mut("xdp_devmap_forward")
+        .ok_or(Error::MissingProgram("xdp_devmap_forward"))?
+        .try_into()?;
     p.load()?;
+    let link_id = attach(p, src_if_index)?;
+
+    eprintln!("XDP forwarding packets from if_index {} to if_index {}", src_if_index, dst_if_index);
 
+    Ok(XdpProgram::new(ebpf, src_if_index, "xdp_devmap_forward", link_id))
+}
+
+/// load the sampling variant of the XDP program, which only publishes packet metadata (see
+/// open_packet_event_stream()) and passes every packet to the kernel
+pub fn load_xdp_sample_program(if_index: u32) -> Result<XdpProgram, Error> {
+    let mut ebpf = load_ebpf(include_bytes_aligned!(
+        "../target/bpf/xdp-redirect"
+    ))?;
+
+    let p: &mut Xdp = ebpf.program_mut("xdp_sample")
+        .ok_or(Error::MissingProgram("xdp_sample"))?
+        .try_into()?;
+    p.load()?;
+    let link_id = attach(p, if_index)?;
+
+    Ok(XdpProgram::new(ebpf, if_index, "xdp_sample", link_id))
+}
+
+fn attach(p: &mut Xdp, if_index: u32) -> Result<XdpLinkId, Error> {
     // try native mode first, fall back to SKB mode if it fails
-    match p.attach_to_if_index(if_index, aya::programs::xdp::XdpFlags::DRV_MODE) {
-        Ok(_) => {
+    let link_id = match p.attach_to_if_index(if_index, aya::programs::xdp::XdpFlags::DRV_MODE) {
+        Ok(link_id) => {
             eprintln!("XDP program loaded and attached to if_index {} in DRV mode (native)", if_index);
+            link_id
         }
         Err(e) => {
             eprintln!("failed to attach in DRV mode: {}, trying SKB mode", e);
-            p.attach_to_if_index(if_index, aya::programs::xdp::XdpFlags::SKB_MODE)?;
+            let link_id = p.attach_to_if_index(if_index, aya::programs::xdp::XdpFlags::SKB_MODE)?;
             eprintln!("XDP program loaded and attached to if_index {} in SKB mode (generic)", if_index);
+            link_id
         }
-    }
+    };
 
-    Ok(ebpf)
+    Ok(link_id)
+}
+
+/// load an eBPF object with the kernel's BTF so CO-RE field accesses get relocated for the
+/// running kernel. the object is built with embedded BTF (see xdp-ebpf/build_ebpf.sh)
+fn load_ebpf(bytecode: &[u8]) -> Result<Ebpf, Error> {
+    let btf = match Btf::from_sys_fs() {
+        Ok(btf) => Some(btf),
+        Err(e) => {
+            eprintln!("kernel BTF not available ({}), loading without relocations", e);
+            None
+        }
+    };
+
+    EbpfLoader::new()
+        .btf(btf.as_ref())
+        .load(bytecode)
+        .map_err(|e| match e {
+            EbpfError::BtfError(_) => Error::InvalidBtf(e),
+            EbpfError::BtfRelocationError(_) => Error::BtfRelocation(e),
+            e => Error::XdpLoad(e),
+        })
 }
 
 /// insert AF_XDP socket file descriptor into XSKMAP
 /// this enables XDP_REDIRECT to route packets to the AF_XDP socket
+///
+/// the socket must outlive `program`, replace_xdp_program() inserts it into the new program too
 pub fn insert_socket_into_xskmap(
-    ebpf: &mut Ebpf,
+    program: &mut XdpProgram,
     queue_id: u32,
     socket_fd: i32,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Error> {
     // debug: print all map names
     eprintln!("available maps in eBPF object:");
-    for (name, _) in ebpf.maps() {
+    for (name, _) in program.maps() {
         eprintln!("  - {}", name);
     }
 
+    set_xskmap(program, queue_id, socket_fd)?;
+    program.xsks.retain(|&(index, _)| index != queue_id);
+    program.xsks.push((queue_id, socket_fd));
+
+    eprintln!("inserted socket FD {} into XSKS_MAP at queue {}", socket_fd, queue_id);
+
+    Ok(())
+}
+
+fn set_xskmap(ebpf: &mut Ebpf, queue_id: u32, socket_fd: RawFd) -> Result<(), Error> {
     // get the XSKS_MAP from the eBPF program
     let map = ebpf.map_mut("XSKS_MAP")
-        .ok_or("XSKS_MAP not found in XDP program")?;
+        .ok_or(Error::MissingMap("XSKS_MAP"))?;
     let mut xskmap: XskMap<_> = map.try_into()?;
 
     // insert the socket FD into the map at the queue index
-    xskmap.set(queue_id, socket_fd, 0)?;
+    xskmap.set(queue_id, socket_fd, 0).map_err(Error::XskMapInsert)?;
+    Ok(())
+}
 
-    eprintln!("inserted socket FD {} into XSKS_MAP at queue {}", socket_fd, queue_id);
+/// write the interface IPv4 address into LOCAL_IP
+/// the XDP program answers ICMP echo requests for this address with XDP_TX
+pub fn set_local_ipv4(ebpf: &mut Ebpf, ip: Ipv4Addr) -> Result<(), Error> {
+    let map = ebpf.map_mut("LOCAL_IP")
+        .ok_or(Error::MissingMap("LOCAL_IP"))?;
+    let mut local_ip: Array<_, u32> = map.try_into()?;
+
+    // stored in network byte order, same as the IP header
+    local_ip.set(0, u32::from_ne_bytes(ip.octets()), 0)?;
+
+    eprintln!("set LOCAL_IP to {} for ICMP echo replies", ip);
+
+    Ok(())
+}
+
+/// allow packets from `ip` through to the socket
+/// once the whitelist has an entry, packets from other sources are passed to the kernel
+pub fn add_ip_to_whitelist(ebpf: &mut Ebpf, ip: Ipv4Addr) -> Result<(), Error> {
+    let len = {
+        let map = ebpf.map_mut("IP_WHITELIST")
+            .ok_or(Error::MissingMap("IP_WHITELIST"))?;
+        let mut whitelist: HashMap<_, u32, u8> = map.try_into()?;
+        whitelist.insert(u32::from_ne_bytes(ip.octets()), 1, 0)?;
+        whitelist.keys().count() as u32
+    };
+    set_ip_whitelist_len(ebpf, len)?;
+
+    eprintln!("added {} to IP_WHITELIST ({} entries)", ip, len);
+
+    Ok(())
+}
+
+/// removing the last entry allows all sources again
+pub fn remove_ip_from_whitelist(ebpf: &mut Ebpf, ip: Ipv4Addr) -> Result<(), Error> {
+    let len = {
+        let map = ebpf.map_mut("IP_WHITELIST")
+            .ok_or(Error::MissingMap("IP_WHITELIST"))?;
+        let mut whitelist: HashMap<_, u32, u8> = map.try_into()?;
+        whitelist.remove(&u32::from_ne_bytes(ip.octets()))?;
+        whitelist.keys().count() as u32
+    };
+    set_ip_whitelist_len(ebpf, len)?;
+
+    eprintln!("removed {} from IP_WHITELIST ({} entries)", ip, len);
+
+    Ok(())
+}
+
+fn set_ip_whitelist_len(ebpf: &mut Ebpf, len: u32) -> Result<(), Error> {
+    let map = ebpf.map_mut("IP_WHITELIST_LEN")
+        .ok_or(Error::MissingMap("IP_WHITELIST_LEN"))?;
+    let mut whitelist_len: Array<_, u32> = map.try_into()?;
+    whitelist_len.set(0, len, 0)?;
+    Ok(())
+}
+
+/// redirect UDP packets to `port` to the socket
+/// once the filter has an entry, all other traffic is passed to the kernel
+pub fn add_udp_port(ebpf: &mut Ebpf, port: u16) -> Result<(), Error> {
+    let len = {
+        let map = ebpf.map_mut("UDP_PORT_FILTER")
+            .ok_or(Error::MissingMap("UDP_PORT_FILTER"))?;
+        let mut ports: HashMap<_, u16, u8> = map.try_into()?;
+        ports.insert(port.to_be(), 1, 0)?;
+        ports.keys().count() as u32
+    };
+
+    let map = ebpf.map_mut("UDP_PORT_FILTER_LEN")
+        .ok_or(Error::MissingMap("UDP_PORT_FILTER_LEN"))?;
+    let mut ports_len: Array<_, u32> = map.try_into()?;
+    ports_len.set(0, len, 0)?;
+
+    eprintln!("added UDP port {} to UDP_PORT_FILTER ({} entries)", port, len);
+
+    Ok(())
+}
+
+/// what the routing program does with packets from a prefix in IP_ROUTING_TABLE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteAction {
+    /// redirect to the socket at this XSKS_MAP index. the kernel only delivers to sockets bound
+    /// to the receiving queue, so this is the queue itself or a socket sharing it (see
+    /// Socket::new_shared_queue()), packets for sockets on other queues are dropped
+    RedirectQueue(u32),
+    /// hand the packet to the kernel
+    Pass,
+    Drop,
+}
+
+impl RouteAction {
+    // must match ROUTE_PASS and ROUTE_DROP in the eBPF program
+    fn code(self) -> u32 {
+        match self {
+            Self::RedirectQueue(index) => index,
+            Self::Pass => u32::MAX,
+            Self::Drop => u32::MAX - 1,
+        }
+    }
+}
+
+/// route packets with an IPv4 source in `prefix`, see load_xdp_routing_program()
+/// the longest matching prefix wins, adding an existing prefix replaces its action
+pub fn add_route(ebpf: &mut Ebpf, prefix: Ipv4Net, action: RouteAction) -> Result<(), Error> {
+    // max entries of XSKS_MAP
+    if let RouteAction::RedirectQueue(index @ 64..) = action {
+        return Err(Error::InvalidXskIndex(index));
+    }
+
+    let map = ebpf.map_mut("IP_ROUTING_TABLE")
+        .ok_or(Error::MissingMap("IP_ROUTING_TABLE"))?;
+    let mut routes: LpmTrie<_, [u8; 4], u32> = map.try_into()?;
+    routes.insert(&route_key(prefix), action.code(), 0)?;
+
+    eprintln!("added route {} -> {:?} to IP_ROUTING_TABLE", prefix, action);
+
+    Ok(())
+}
+
+pub fn remove_route(ebpf: &mut Ebpf, prefix: Ipv4Net) -> Result<(), Error> {
+    let map = ebpf.map_mut("IP_ROUTING_TABLE")
+        .ok_or(Error::MissingMap("IP_ROUTING_TABLE"))?;
+    let mut routes: LpmTrie<_, [u8; 4], u32> = map.try_into()?;
+    routes.remove(&route_key(prefix))?;
+
+    eprintln!("removed route {} from IP_ROUTING_TABLE", prefix);
 
     Ok(())
 }
+
+// the trie matches the address bytes from the most significant bit, so network byte order
+fn route_key(prefix: Ipv4Net) -> Key<[u8; 4]> {
+    Key::new(prefix.prefix_len() as u32, prefix.network().octets())
+}
+
+/// publish the metadata of 1 in `rate` IPv4 packets to the stream returned by
+/// open_packet_event_stream(), 0 turns sampling off. the cpumap and forwarding variants don't
+/// sample, sampling is off until this is called
+pub fn set_packet_sample_rate(ebpf: &mut Ebpf, rate: u32) -> Result<(), Error> {
+    let map = ebpf.map_mut("PACKET_SAMPLE_RATE")
+        .ok_or(Error::MissingMap("PACKET_SAMPLE_RATE"))?;
+    let mut sample_rate: Array<_, u32> = map.try_into()?;
+    sample_rate.set(0, rate, 0)?;
+
+    eprintln!("sampling 1 in {} packets to PACKET_EVENTS", rate);
+
+    Ok(())
+}
+
+/// metadata of a packet sampled by the XDP program, see set_packet_sample_rate()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketEvent {
+    /// CLOCK_MONOTONIC time the program saw the packet
+    pub timestamp_ns: u64,
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+    /// 0 for protocols other than UDP and TCP
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// frame length including the ethernet header
+    pub len: u32,
+}
+
+// layout of a PACKET_EVENTS entry, must match PacketSample in the eBPF program
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PacketEventValue {
+    timestamp_ns: u64,
+    saddr: u32,
+    daddr: u32,
+    source: u16,
+    dest: u16,
+    len: u32,
+}
+
+/// samples read from PACKET_EVENTS, see open_packet_event_stream()
+pub struct PacketEventStream {
+    ring: RingBuf<MapData>,
+}
+
+impl PacketEventStream {
+    /// the next sample if one is queued, doesn't block
+    pub fn try_next(&mut self) -> Option<PacketEvent> {
+        let item = self.ring.next()?;
+        if item.len() < std::mem::size_of::<PacketEventValue>() {
+            return None;
+        }
+        // Safety: checked the length above and PacketEventValue is plain data
+        let value = unsafe { std::ptr::read_unaligned(item.as_ptr() as *const PacketEventValue) };
+        Some(PacketEvent {
+            timestamp_ns: value.timestamp_ns,
+            src_ip: Ipv4Addr::from(value.saddr.to_ne_bytes()),
+            dst_ip: Ipv4Addr::from(value.daddr.to_ne_bytes()),
+            src_port: u16::from_be(value.source),
+            dst_port: u16::from_be(value.dest),
+            len: value.len,
+        })
+    }
+}
+
+impl Iterator for PacketEventStream {
+    type Item = PacketEvent;
+
+    /// blocks until the program publishes a sample
+    fn next(&mut self) -> Option<PacketEvent> {
+        loop {
+            if let Some(event) = self.try_next() {
+                return Some(event);
+            }
+            let mut pollfd = libc::pollfd {
+                fd: self.ring.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0
+                && std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+            {
+                return None;
+            }
+        }
+    }
+}
+
+/// stream the packets sampled by the XDP program, see set_packet_sample_rate()
+///
+/// takes PACKET_EVENTS out of `ebpf` so the stream can be moved to another thread, it can only
+/// be opened once per program.
+pub fn open_packet_event_stream(ebpf: &mut Ebpf) -> Result<PacketEventStream, Error> {
+    let map = ebpf.take_map("PACKET_EVENTS")
+        .ok_or(Error::MissingMap("PACKET_EVENTS"))?;
+    Ok(PacketEventStream {
+        ring: RingBuf::try_from(map)?,
+    })
+}
+
+/// XDP_STATS counters for one queue, summed over all CPUs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct XdpQueueStats {
+    pub queue_id: u32,
+    /// packets redirected to the AF_XDP socket
+    pub redirected: u64,
+    /// packets passed to the kernel by the filters
+    pub passed: u64,
+    /// redirects that failed, usually no socket in XSKS_MAP for the queue
+    pub errors: u64,
+}
+
+// layout of an XDP_STATS entry, must match XdpStats in the eBPF program
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpStatsValue {
+    redirected: u64,
+    passed: u64,
+    errors: u64,
+}
+
+unsafe impl aya::Pod for XdpStatsValue {}
+
+/// read XDP_STATS, only queues that saw traffic are returned
+pub fn read_xdp_stats(ebpf: &Ebpf) -> Vec<XdpQueueStats> {
+    let Some(map) = ebpf.map("XDP_STATS") else {
+        eprintln!("XDP_STATS not found in XDP program");
+        return Vec::new();
+    };
+    let array: PerCpuArray<_, XdpStatsValue> = match map.try_into() {
+        Ok(array) => array,
+        Err(e) => {
+            eprintln!("failed to open XDP_STATS: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut stats = Vec::new();
+    for queue_id in 0..array.len() {
+        let Ok(values) = array.get(&queue_id, 0) else {
+            continue;
+        };
+        let queue = values.iter().fold(
+            XdpQueueStats {
+                queue_id,
+                ..Default::default()
+            },
+            |mut acc, v| {
+                acc.redirected += v.redirected;
+                acc.passed += v.passed;
+                acc.errors += v.errors;
+                acc
+            },
+        );
+        if queue.redirected + queue.passed + queue.errors > 0 {
+            stats.push(queue);
+        }
+    }
+    stats
+}
+
+// layout of the metadata the eBPF program writes in front of redirected packets, must match
+// XdpRxMeta in the eBPF program
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpRxMeta {
+    xdp_timestamp: u64,
+    flow_hash: u32,
+    magic: u32,
+}
+
+const RX_META_MAGIC: u32 = 0x6873_6878;
+
+/// flow hash the XDP program stored in front of the packet at `addr` in `umem`
+///
+/// this is the program's software hash of the IPv4 addresses and ports (the same one the cpumap
+/// variant spreads flows with), not the NIC's RSS hash. returns None if the driver didn't support
+/// the metadata area or the packet wasn't IPv4.
+pub fn read_flow_hash(umem: &[u8], addr: usize) -> Option<u32> {
+    // non-IPv4 packets get a zero hash, same as the cpumap variant
+    read_rx_meta(umem, addr)
+        .map(|meta| meta.flow_hash)
+        .filter(|&flow_hash| flow_hash != 0)
+}
+
+/// CLOCK_MONOTONIC time in nanoseconds at which the XDP program redirected the packet at `addr`
+/// in `umem`, compare with clock_gettime(CLOCK_MONOTONIC) for the time spent in the rx ring
+///
+/// this is bpf_ktime_get_ns() at the time the program ran, not a NIC hardware timestamp: reading
+/// the NIC's needs the bpf_xdp_metadata_rx_timestamp kfunc from a device bound program, which
+/// the aya loader can't load. returns None if the driver didn't support the metadata area or the
+/// packet wasn't IPv4.
+pub fn read_xdp_timestamp(umem: &[u8], addr: usize) -> Option<u64> {
+    read_rx_meta(umem, addr).map(|meta| meta.xdp_timestamp)
+}
+
+fn read_rx_meta(umem: &[u8], addr: usize) -> Option<XdpRxMeta> {
+    let start = addr.checked_sub(std::mem::size_of::<XdpRxMeta>())?;
+    let bytes = umem.get(start..addr)?;
+    // Safety: bytes is exactly size_of::<XdpRxMeta>() long and XdpRxMeta is plain data
+    let meta = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const XdpRxMeta) };
+    (meta.magic == RX_META_MAGIC).then_some(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_rx_meta() {
+        let mut frame = vec![0u8; 64];
+        frame[16..24].copy_from_slice(&123_456_789u64.to_ne_bytes());
+        frame[24..28].copy_from_slice(&0xdead_beefu32.to_ne_bytes());
+        frame[28..32].copy_from_slice(&RX_META_MAGIC.to_ne_bytes());
+
+        assert_eq!(read_xdp_timestamp(&frame, 32), Some(123_456_789));
+        assert_eq!(read_flow_hash(&frame, 32), Some(0xdead_beef));
+
+        // no magic, the driver dropped the metadata
+        assert_eq!(read_xdp_timestamp(&frame, 36), None);
+        // not enough headroom
+        assert_eq!(read_flow_hash(&frame, 8), None);
+    }
+}
@@ -4,127 +4,480 @@
 
 use {
     crate::{
+        caps_check::capability_preflight,
+        error::Error,
+        disruptor::{Consumer, Producer},
         load_xdp_program,
-        program::insert_socket_into_xskmap,
+        packet_event::PacketEventZeroCopy,
+        packet_filter::PacketFilter,
+        pcap::{CaptureThread, CaptureWriter},
+        program::{
+            add_udp_port, insert_socket_into_xskmap, read_flow_hash, read_xdp_timestamp,
+            read_xdp_stats, set_local_ipv4,
+        },
         // shred_worker::{create_single_worker, publish_shred_zerocopy},
-        device::{NetworkDevice, QueueId, RingSizes},
-        netlink::MacAddress,
+        device::{NetworkDevice, QueueId, RingSizes, RxFillRing},
+        flow::{FlowKey, FlowTable},
+        latency::LatencyHistogram,
+        netlink::{netlink_get_xdp_socket, MacAddress},
         packet::{
-            write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE, IP_HEADER_SIZE,
-            UDP_HEADER_SIZE,
+            parse_ethertype, parse_ipv6_header, parse_tcp_header, parse_vxlan, send_arp_request,
+            write_eth_header, write_ip_header_with_protocol, write_tcp_header_with_options,
+            write_udp_header, TcpHeader, ETH_HEADER_SIZE, IPPROTO_TCP, IPPROTO_UDP, IP_HEADER_SIZE,
+            TCP_HEADER_SIZE, UDP_HEADER_SIZE, VXLAN_PORT,
         },
+        raw_socket::RawSocket,
         route::Router,
+        rx_loop::RxStats,
         set_cpu_affinity,
         // shred_processor::{parse_shred_type, ShredStats},
-        socket::{Socket, Rx, Tx},
-        umem::{Frame as _, FrameOffset, PageAlignedMemory, SliceUmem, SliceUmemFrame, Umem as _},
+        socket::{RingFull, Rx, Socket, Tx, TxRing},
+        tx_loop::TxStats,
+        umem::{
+            check_frame_fits_page, Frame, FrameOffset, PageAlignedMemory, PageSize, SliceUmem,
+            SliceUmemFrame, Umem, UmemRefs,
+        },
     },
     caps::{
         CapSet,
         Capability::{CAP_NET_ADMIN, CAP_NET_RAW, CAP_SYS_NICE},
     },
-    libc::{sysconf, _SC_PAGESIZE},
+    libc::{sysconf, ETH_P_IP, ETH_P_IPV6, _SC_PAGESIZE},
     std::{
-        io,
-        net::{IpAddr, Ipv4Addr},
+        collections::VecDeque,
+        fmt, io, mem,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
         os::fd::{AsFd, AsRawFd},
-        // sync::Arc,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant, SystemTime},
         // time::SystemTime,
     },
 };
+#[cfg(feature = "checksum-validation")]
+use crate::packet::{verify_ip_checksum, verify_udp_checksum};
 
+/// rx and tx counters of one relay loop, displayed as a one line summary
+#[derive(Clone, Debug)]
+pub struct IoStats {
+    pub rx: Arc<RxStats>,
+    pub tx: Arc<TxStats>,
+}
+
+impl fmt::Display for IoStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rx packets={} bytes={} tx packets={} bytes={} drops={} completions={}",
+            self.rx.rx_packets.load(Ordering::Relaxed),
+            self.rx.rx_bytes.load(Ordering::Relaxed),
+            self.tx.tx_packets.load(Ordering::Relaxed),
+            self.tx.tx_bytes.load(Ordering::Relaxed),
+            self.tx.tx_drops.load(Ordering::Relaxed),
+            self.tx.tx_completions.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// what to drop when a fanned out packet doesn't fit in the tx queue
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// drop all copies of the packet that didn't fit
+    #[default]
+    DropNewest,
+    /// drop the oldest queued copies to make room for the new packet
+    DropOldest,
+}
+
+/// forward every packet to a list of destinations instead of a single one
+///
+/// each destination gets its own copy of the packet in a freshly reserved UMEM frame, so the UMEM
+/// is sized for `destinations.len() * tx_size` tx frames.
+#[derive(Clone, Debug, Default)]
+pub struct MultiDestRelayConfig {
+    pub destinations: Vec<(Ipv4Addr, u16, MacAddress)>,
+    pub overflow: OverflowPolicy,
+    /// limit applied to each destination separately, so one slow consumer can't take over the
+    /// tx ring
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// byte rate allowed per fanout destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    /// bytes a destination can send in a burst after being idle
+    pub burst_bytes: u64,
+}
+
+/// optional relay_loop behaviour, the default relays with none of it enabled
+#[derive(Default)]
+pub struct RelayConfig {
+    /// scheduling policy of the relay thread, SCHED_FIFO at the highest priority when None
+    pub sched_policy: Option<SchedPolicy>,
+    /// NUMA node to allocate the UMEM on, the NIC's node when None
+    pub numa_node: Option<u32>,
+    /// busy-poll the NIC for up to this many microseconds per syscall, 0 disables it
+    pub busy_poll_micros: Option<u32>,
+    /// refill the fill ring mid-poll when the kernel has fewer frames than this, a quarter of
+    /// the rx ring when None
+    pub fill_low_watermark: Option<usize>,
+    /// restart the relay when a tx frame hasn't completed after this long, 1s when None
+    pub tx_timeout: Option<Duration>,
+    /// decapsulate VXLAN and relay the inner packet
+    pub vxlan_strip: bool,
+    pub multi_dest: Option<MultiDestRelayConfig>,
+    pub filter: Option<Box<dyn PacketFilter + Send>>,
+    /// capture received frames to this file, at most the given number of them. pcapng for
+    /// `.pcapng` paths, pcap otherwise
+    pub pcap: Option<(PathBuf, usize)>,
+    pub flows: Option<Arc<Mutex<FlowTable>>>,
+}
+
+/// slots in the ring between the relay loop and the decoder threads
+pub const PACKET_EVENT_RING_SIZE: usize = 16384;
+pub type PacketEventProducer = Producer<PacketEventZeroCopy, PACKET_EVENT_RING_SIZE>;
+pub type PacketEventConsumer = Consumer<PacketEventZeroCopy, PACKET_EVENT_RING_SIZE>;
+
+/// run the relay until `exit` is set
+///
+/// with `interface_down` (see NetworkMonitor) the relay tears down its socket, UMEM and XDP
+/// program when the flag stays raised and the device reports it isn't running, waits for the
+/// interface to be running again and sets everything up from scratch, so it survives cable
+/// pulls and driver resets. if the first setup fails the error is returned, setting up again
+/// after a restart is retried with backoff until `exit` is set.
 #[inline(never)]
+#[allow(clippy::too_many_arguments)]
 pub fn relay_loop(
     cpu_id: usize,
     dev: &NetworkDevice,
     queue_id: QueueId,
     zero_copy: bool,
-    dest_ip: Option<Ipv4Addr>,
+    dest_ip: Option<IpAddr>,
     dest_port: Option<u16>,
     dest_mac_override: Option<MacAddress>,
+    config: &RelayConfig,
+    mut events: Option<PacketEventProducer>,
+    stats: Arc<RxStats>,
+    tx_stats: Arc<TxStats>,
+    interface_down: Option<Arc<AtomicBool>>,
+    exit: Arc<AtomicBool>,
     // decoder_cpu: Option<usize>,
-) {
+) -> Result<(), Error> {
+    // spawned before the session pins itself and goes SCHED_FIFO, so the writer thread
+    // inherits neither and it outlives restarts
+    let mut capture = config
+        .pcap
+        .as_ref()
+        .map(|(path, max_packets)| {
+            CaptureWriter::new(path).and_then(|writer| {
+                CaptureThread::spawn(writer, *max_packets, CaptureThread::DEFAULT_QUEUE_LEN)
+            })
+        })
+        .transpose()?;
+
+    let run_session = || {
+        relay_session(
+            cpu_id,
+            dev,
+            queue_id,
+            zero_copy,
+            dest_ip,
+            dest_port,
+            dest_mac_override,
+            config,
+            &mut capture,
+            &mut events,
+            Arc::clone(&stats),
+            Arc::clone(&tx_stats),
+            interface_down.as_deref(),
+            Arc::clone(&exit),
+        )
+    };
+    let result = supervise_sessions(dev, interface_down.as_deref(), &stats, &exit, run_session);
+
+    if let Some(capture) = capture {
+        let dropped = capture.dropped();
+        match capture.finish() {
+            Ok(packets) => eprintln!(
+                "captured {packets} packets, dropped {dropped} the writer couldn't keep up with"
+            ),
+            Err(e) => eprintln!("failed to write pcap output: {e}"),
+        }
+    }
+    result
+}
+
+/// run sessions until one ends for good, restarting them after tx stalls and when the
+/// interface comes back up
+fn supervise_sessions(
+    dev: &NetworkDevice,
+    interface_down: Option<&AtomicBool>,
+    stats: &RxStats,
+    exit: &AtomicBool,
+    mut run_session: impl FnMut() -> Result<SessionEnd, Error>,
+) -> Result<(), Error> {
+    // a broken setup is the caller's problem, after a restart it's most likely the link
+    // flapping again and worth retrying
+    let mut end = run_session()?;
+    loop {
+        match (end, interface_down) {
+            (SessionEnd::TxStalled, _) => {
+                eprintln!("tx queue of {} stalled, restarting the relay", dev.name());
+            }
+            (SessionEnd::InterfaceDown, Some(down)) => {
+                eprintln!("{} went down, waiting for it to come back", dev.name());
+                if !wait_until_running(dev, down, stats, exit) {
+                    return Ok(());
+                }
+                eprintln!("{} is running again, restarting the relay", dev.name());
+            }
+            _ => return Ok(()),
+        }
+
+        let mut backoff = RESTART_BACKOFF_MIN;
+        end = loop {
+            let session = run_session();
+            match session {
+                Ok(end) => break end,
+                Err(e) => {
+                    log::error!(
+                        "failed to restart the relay on {}: {e}, retrying in {backoff:?}",
+                        dev.name()
+                    );
+                    if !idle_for(backoff, stats, exit) {
+                        return Ok(());
+                    }
+                    backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+                }
+            }
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionEnd {
+    Exit,
+    InterfaceDown,
+    TxStalled,
+}
+
+/// poll until the interface is up and running, false if `exit` was set first
+fn wait_until_running(
+    dev: &NetworkDevice,
+    interface_down: &AtomicBool,
+    stats: &RxStats,
+    exit: &AtomicBool,
+) -> bool {
+    while !exit.load(Ordering::Relaxed) {
+        // still alive as far as RelayWatchdog is concerned, a down link just looks idle
+        stats.loop_iterations.fetch_add(1, Ordering::Relaxed);
+        match dev.is_running() {
+            Ok(true) => {
+                interface_down.store(false, Ordering::Relaxed);
+                return true;
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("failed to read flags of {}: {e}", dev.name()),
+        }
+        thread::sleep(LINK_POLL_INTERVAL);
+    }
+    false
+}
+
+/// sleep for `duration` in LINK_POLL_INTERVAL steps, false if `exit` was set first
+fn idle_for(duration: Duration, stats: &RxStats, exit: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while !exit.load(Ordering::Relaxed) {
+        stats.loop_iterations.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        thread::sleep(LINK_POLL_INTERVAL.min(deadline - now));
+    }
+    false
+}
+
+/// one run of the relay, from creating the socket until `exit` or `interface_down` is set or
+/// the tx queue stalls
+#[allow(clippy::too_many_arguments)]
+fn relay_session(
+    cpu_id: usize,
+    dev: &NetworkDevice,
+    queue_id: QueueId,
+    zero_copy: bool,
+    dest_ip: Option<IpAddr>,
+    dest_port: Option<u16>,
+    dest_mac_override: Option<MacAddress>,
+    config: &RelayConfig,
+    capture: &mut Option<CaptureThread>,
+    events: &mut Option<PacketEventProducer>,
+    stats: Arc<RxStats>,
+    tx_stats: Arc<TxStats>,
+    interface_down: Option<&AtomicBool>,
+    exit: Arc<AtomicBool>,
+) -> Result<SessionEnd, Error> {
+    let RelayConfig {
+        sched_policy,
+        numa_node,
+        busy_poll_micros,
+        fill_low_watermark,
+        tx_timeout,
+        vxlan_strip,
+        ref multi_dest,
+        ref filter,
+        pcap: _,
+        ref flows,
+    } = *config;
     log::info!(
         "starting relay loop on {} queue {queue_id:?} cpu {cpu_id}",
         dev.name()
     );
 
-    // pin to CPU core
-    set_cpu_affinity([cpu_id]).unwrap();
+    capability_preflight()?;
 
-    let src_mac = dev.mac_addr().expect("device must have a MAC address");
-    let src_ip = dev.ipv4_addr().expect("device must have an IPv4 address");
+    // raise caps for socket creation and the scheduling policy
+    for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_SYS_NICE] {
+        caps::raise(None, CapSet::Effective, cap)?;
+    }
 
-    let frame_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+    // default to the highest SCHED_FIFO priority
+    let sched_policy = match sched_policy {
+        Some(sched_policy) => sched_policy,
+        None => SchedPolicy::Fifo {
+            priority: fifo_priority_bounds()?.1,
+        },
+    };
+    // before pinning, SCHED_DEADLINE is refused with EPERM for threads pinned to a single CPU
+    sched_policy.apply()?;
 
-    let queue = dev
-        .open_queue(queue_id)
-        .expect("failed to open queue for AF_XDP socket");
+    // pin to CPU core. deadline threads can only be pinned inside an exclusive cpuset, anywhere
+    // else the kernel refuses and they stay where the deadline scheduler puts them
+    let pinned = set_cpu_affinity([cpu_id]);
+    match pinned {
+        Err(e) if matches!(sched_policy, SchedPolicy::Deadline { .. }) => {
+            log::warn!("failed to pin the SCHED_DEADLINE relay thread to cpu {cpu_id}: {e}")
+        }
+        pinned => pinned?,
+    }
+
+    let src_mac = dev.mac_addr()?;
+    let src_ip = dev.ipv4_addr()?;
+
+    // frames must fit a full MTU sized packet, with jumbo frames this is more than a page which
+    // the kernel only accepts for hugepage backed UMEM
+    let frame_size = match dev.mtu() {
+        Ok(mtu) => (mtu as usize + ETH_HEADER_SIZE).next_power_of_two(),
+        Err(e) => {
+            eprintln!("failed to read MTU for {}: {}", dev.name(), e);
+            0
+        }
+    }
+    .max(unsafe { sysconf(_SC_PAGESIZE) } as usize);
+    eprintln!("using UMEM frame size {}", frame_size);
+
+    let queue = dev.open_queue(queue_id)?;
     let RingSizes {
         rx: rx_size,
         tx: tx_size,
     } = queue.ring_sizes().unwrap_or_default();
 
-    // allocate UMEM for both rx and tx
-    let frame_count = (rx_size + tx_size) * 2;
-
-    // allocate huge pages for UMEM
-    const HUGE_2MB: usize = 2 * 1024 * 1024;
-    let mut memory =
-        PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, HUGE_2MB, true)
-            .or_else(|_| PageAlignedMemory::alloc(frame_size, frame_count))
-            .unwrap();
-    let umem = SliceUmem::new(&mut memory, frame_size as u32).unwrap();
-
-    // raise caps for socket creation
-    for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_SYS_NICE] {
-        caps::raise(None, CapSet::Effective, cap).unwrap();
-    }
+    // allocate UMEM for both rx and tx, with fanout every destination needs its own tx frames.
+    // PageAlignedMemory takes a power of two frame count, two destinations would make it 6x
+    let fanout = multi_dest
+        .as_ref()
+        .map_or(1, |multi| multi.destinations.len().max(1));
+    let frame_count = ((rx_size + tx_size * fanout) * 2).next_power_of_two();
 
-    let (_min, max) = fifo_priority_bounds().unwrap();
-    set_current_thread_sched_fifo(max).unwrap();
+    // allocate huge pages for UMEM, prefer 1 GB pages when the system has them reserved
+    let page_size = [PageSize::Huge1G, PageSize::Huge2M]
+        .into_iter()
+        .find(|page_size| page_size.is_available(frame_size * frame_count))
+        .unwrap_or(PageSize::Regular);
+    // keep UMEM on the NIC's NUMA node unless told otherwise
+    let numa_node = numa_node.or_else(|| dev.numa_node());
+    eprintln!("allocating UMEM with {:?} pages on NUMA node {:?}", page_size, numa_node);
+    let bound = numa_node.map(|node| {
+        PageAlignedMemory::alloc_on_numa_node_with_page_size(
+            frame_size,
+            frame_count,
+            page_size,
+            node,
+        )
+        .inspect_err(|e| eprintln!("failed to bind UMEM to NUMA node {}: {}", node, e))
+    });
+    // without the binding keep the page size, only fall back to regular pages if that fails too
+    let mut memory = match bound {
+        Some(Ok(memory)) => memory,
+        _ => PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, page_size)
+            .or_else(|_| PageAlignedMemory::alloc_prefaulted(frame_size, frame_count))
+            .map_err(Error::UmemAlloc)?,
+    };
+    // the hugepage allocation may have fallen back to regular pages, which can't hold jumbo
+    // frames
+    check_frame_fits_page(frame_size as u32, memory.page_size())?;
+    let umem = SliceUmem::new(&mut memory, frame_size as u32)?;
 
     // load XDP program with XSKMAP for zero-copy redirection
     eprintln!("loading XDP_REDIRECT program on interface {} (if_index: {})", dev.name(), dev.if_index());
-    let mut xdp_program = match load_xdp_program(dev.if_index()) {
-        Ok(prog) => {
-            eprintln!("XDP program loaded successfully");
-            prog
-        },
-        Err(e) => {
-            eprintln!("failed to load XDP program: {}", e);
-            eprintln!(" make sure you have CAP_BPF and CAP_NET_ADMIN capabilities");
-            eprintln!(" try running with: sudo -E cargo run --example relay -- <args>");
-            panic!("cannot continue without XDP program");
+    let mut xdp_program = load_xdp_program(dev.if_index())?;
+    eprintln!("XDP program loaded successfully");
+
+    // let the XDP program answer pings for our address without involving the socket
+    if let Err(e) = set_local_ipv4(&mut xdp_program, src_ip) {
+        eprintln!("failed to set LOCAL_IP, ICMP echo replies disabled: {}", e);
+    }
+
+    // only steal traffic for the relayed ports, everything else goes to the kernel
+    let fanout_ports = multi_dest
+        .iter()
+        .flat_map(|multi| multi.destinations.iter().map(|(_, port, _)| *port));
+    let vxlan_port = vxlan_strip.then_some(VXLAN_PORT);
+    for port in dest_port.into_iter().chain(fanout_ports).chain(vxlan_port) {
+        let added = add_udp_port(&mut xdp_program, port);
+        if let Err(e) = added {
+            eprintln!("failed to add UDP port {} to filter: {}", port, e);
         }
-    };
+    }
 
     // create bidirectional AF_XDP socket for both RX and TX
     eprintln!("creating bidirectional AF_XDP socket on queue {}", queue_id.0);
-    let Ok((mut socket, rx, tx)) = Socket::new(
-        queue,
-        umem,
-        zero_copy,
-        rx_size,     // rx fill ring size
-        rx_size,     // rx ring size
-        tx_size * 2, // tx completion ring size
-        tx_size,     // tx ring size
-    ) else {
-        panic!("failed to create bidirectional AF_XDP socket on queue {queue_id:?}");
-    };
-    eprintln!("AF_XDP socket created successfully");
+    let (mut socket, rx, tx) = Socket::builder(umem)
+        .zero_copy(zero_copy)
+        .fill_ring_size(rx_size as u32)
+        .rx_ring_size(rx_size as u32)
+        .completion_ring_size(tx_size as u32 * 2)
+        .tx_ring_size(tx_size as u32)
+        .build(queue)
+        .map_err(Error::SocketCreate)?;
+    eprintln!(
+        "AF_XDP socket created in {} mode",
+        if socket.zero_copy_supported() { "zero-copy" } else { "copy" }
+    );
+
+    // with busy-polling the kernel processes the queue on our syscalls instead of waiting for
+    // the NIC interrupt
+    if let Some(micros) = busy_poll_micros {
+        match socket
+            .set_busy_poll(micros)
+            .and_then(|()| socket.set_prefer_busy_poll(micros > 0))
+        {
+            Ok(()) => eprintln!("busy-polling for up to {}us", micros),
+            Err(e) => eprintln!("failed to set busy-poll timeout: {}", e),
+        }
+    }
 
     // get socket file descriptor and insert into XSKMAP
     // this binds the AF_XDP socket to this queue for XDP_REDIRECT
     let socket_fd = socket.as_fd().as_raw_fd();
     eprintln!("inserting socket FD {} into XSKMAP for queue {}", socket_fd, queue_id.0);
-    match insert_socket_into_xskmap(&mut xdp_program, queue_id.0 as u32, socket_fd) {
-        Ok(()) => eprintln!("socket successfully bound to XDP program via XSKMAP"),
-        Err(e) => {
-            eprintln!("failed to insert socket into XSKMAP: {}", e);
-            panic!("cannot redirect packets without XSKMAP binding");
-        }
-    };
+    insert_socket_into_xskmap(&mut xdp_program, queue_id.0 as u32, socket_fd)?;
+    eprintln!("socket successfully bound to XDP program via XSKMAP");
 
     let umem = socket.umem();
 
@@ -137,22 +490,43 @@ pub fn relay_loop(
     let Tx { mut completion, ring: tx_ring } = tx;
     let mut tx_ring = tx_ring.expect("TX ring must exist");
 
-    let router = Router::new().expect("failed to create router");
+    let router = Router::new()?;
 
-    // drop caps after socket creation
-    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
-        caps::drop(None, CapSet::Effective, cap).unwrap();
+    // only IPv4 headers are written on the way out, an IPv6 destination is routed and resolved
+    // but nothing is forwarded to it
+    if let Some(ip @ IpAddr::V6(_)) = dest_ip {
+        log::warn!("not forwarding to {ip}, the relay only forwards to IPv4 destinations");
     }
-
     let dest_mac = if let Some(ip) = dest_ip {
         dest_mac_override.or_else(|| {
-            let next_hop = router.route(IpAddr::V4(ip)).ok()?;
-            next_hop.mac_addr
+            let next_hop = router.route(ip).ok()?;
+            next_hop.mac_addr.or_else(|| {
+                // cold ARP cache, poke the next hop ourselves and wait for the kernel to resolve
+                // it. this needs CAP_NET_RAW so it happens before the caps are dropped. IPv6 next
+                // hops are only taken from the kernel's neighbour table
+                let IpAddr::V4(next_hop_ip) = next_hop.ip_addr else {
+                    return None;
+                };
+                if let Err(e) = RawSocket::new_send_only(dev.if_index())
+                    .and_then(|socket| send_arp_request(&socket, src_ip, &src_mac, next_hop_ip))
+                {
+                    log::warn!("failed to send ARP request for {next_hop_ip}: {e}");
+                }
+                router
+                    .resolve_mac_within(next_hop_ip, next_hop.if_index, ARP_WAIT)
+                    .map_err(|e| log::warn!("failed to resolve MAC of {next_hop_ip}: {e}"))
+                    .ok()
+            })
         })
     } else {
         None
     };
 
+    // drop caps after socket creation
+    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
+        caps::drop(None, CapSet::Effective, cap)?;
+    }
+
     // pre-fill rx fill ring with frames for the kernel to use
     // the fill ring needs to have frames available for incoming packets
     fill.sync(false);
@@ -172,6 +546,16 @@ pub fn relay_loop(
     fill.commit();
     eprintln!("added {} frames to fill ring", added);
 
+    // rx frames published to the decoder threads are reference counted, whoever drops the last
+    // reference recycles the frame. frames released by a decoder come back on `recycled`
+    let (frame_refs, recycled) = match &events {
+        Some(_) => {
+            let (refs, recycled) = UmemRefs::new(umem);
+            (Some(refs), Some(recycled))
+        }
+        None => (None, None),
+    };
+
     // create single shred worker with UMEM access
     // let stats = Arc::new(ShredStats::new());
     // let mut shred_producer = if let Some(decoder_cpu) = decoder_cpu {
@@ -187,9 +571,37 @@ pub fn relay_loop(
     // };
 
     // main loop
-    const BATCH_SIZE: usize = 32;
+    let mut batch = AdaptiveBatch::new();
     let mut batch_count = 0;
     let mut total_packets = 0usize;
+    let mut loop_iterations = 0usize;
+    let mut refill = Vec::with_capacity(rx_size);
+    // refill mid-poll when the kernel has fewer frames than this left to receive into
+    let fill_low_watermark = fill_low_watermark.unwrap_or(rx_size / 4);
+    // fanout copies waiting for room in the tx ring
+    let mut pending_tx = VecDeque::with_capacity(tx_size);
+    let mut fanout_dropped = 0usize;
+    let mut events_dropped = 0usize;
+    // counted here and merged into `flows` whenever its lock is free, so the stats thread never
+    // holds up the loop
+    let mut flow_table = flows.as_ref().map(|_| FlowTable::new());
+    // one bucket per fanout destination, in the same order
+    let mut rate_limiters: Vec<TokenBucket> = match &multi_dest {
+        Some(MultiDestRelayConfig {
+            destinations,
+            rate_limit: Some(limit),
+            ..
+        }) => destinations
+            .iter()
+            .map(|_| TokenBucket::from_rate_limit(limit))
+            .collect(),
+        _ => Vec::new(),
+    };
+    // time a packet spends in the relay, from reading its rx descriptor until its tx frame is
+    // handed to the tx ring
+    let latency = LatencyHistogram::new();
+    let mut watchdog = TxCompletionWatchdog::new(tx_timeout.unwrap_or(DEFAULT_TX_TIMEOUT));
+    let mut last_latency_report = Instant::now();
     // let mut total_shreds = 0usize;
 
     eprintln!("waiting for packets on {} queue {}...", dev.name(), queue_id.0);
@@ -203,7 +615,33 @@ pub fn relay_loop(
 
     // let mut debug_counter = 0u64;
 
-    loop {
+    // attaching the program and binding the socket reset the link on some drivers, whatever
+    // the monitor saw until now is about the old socket
+    if let Some(down) = interface_down {
+        down.store(!dev.is_running().unwrap_or(false), Ordering::Relaxed);
+    }
+    // when interface_down was first seen raised
+    let mut down_since = None;
+
+    let mut end = SessionEnd::Exit;
+    while !exit.load(Ordering::Relaxed) {
+        // the socket is dead once the interface goes down. the flag has to stay raised for
+        // LINK_DOWN_DEBOUNCE and the device has to agree, a short flap or a stale event doesn't
+        // cost a restart
+        if let Some(down) = interface_down {
+            if !down.load(Ordering::Relaxed) {
+                down_since = None;
+            } else if down_since.get_or_insert_with(Instant::now).elapsed() >= LINK_DOWN_DEBOUNCE {
+                down_since = None;
+                if dev.is_running().unwrap_or(false) {
+                    down.store(false, Ordering::Relaxed);
+                } else {
+                    end = SessionEnd::InterfaceDown;
+                    break;
+                }
+            }
+        }
+
         // sync rings
         rx_ring.sync(false);
         tx_ring.sync(false);
@@ -219,26 +657,79 @@ pub fn relay_loop(
 
         // process completed tx frames
         while let Some(frame_offset) = completion.read() {
-            umem.release(frame_offset);
+            tx_stats.tx_completions.fetch_add(1, Ordering::Relaxed);
+            if watchdog.complete(frame_offset) && release_frame(frame_refs.as_deref(), frame_offset)
+            {
+                umem.release(frame_offset);
+            }
+        }
+        // a stalled tx queue never completes and its frames still belong to the kernel, only
+        // closing the socket gets them back
+        if watchdog.stalled(Instant::now()) {
+            log::warn!(
+                "{} tx frames in flight, oldest not completed within {:?}",
+                watchdog.in_flight(),
+                watchdog.timeout()
+            );
+            tx_stats.tx_timeout_count.fetch_add(1, Ordering::Relaxed);
+            end = SessionEnd::TxStalled;
+            break;
+        }
+        flush_pending_tx(&mut pending_tx, &mut tx_ring, &mut watchdog, &tx_stats);
+
+        // drop the frame references of events every decoder is done with, frames nobody else
+        // holds anymore come back through `recycled`
+        if let Some(producer) = events.as_mut() {
+            producer.reclaim(PacketEventZeroCopy::reset);
+        }
+        if let Some(recycled) = &recycled {
+            for frame in recycled.try_iter() {
+                umem.release(frame);
+            }
         }
 
         // process received packets (zero-copy)
+        let mut received = 0;
         while let Some(desc) = rx_ring.read() {
+            // latency is measured from when we see the frame, the XDP program's timestamp is
+            // missing on drivers without metadata support
+            let rx_time = Instant::now();
+            received += 1;
             let umem_offset = desc.addr as usize;
             let packet_len = desc.len as usize;
 
             total_packets += 1;
+            stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+            stats.rx_bytes.fetch_add(packet_len, Ordering::Relaxed);
 
             // debug logging every 1000 packets. add total_shreds
             if total_packets % 1000 == 0 {
-                eprintln!(" received {} packets", total_packets);
+                eprintln!(" received {} packets, umem {:?}", total_packets, umem.stats());
+            }
+
+            // let timestamp = SystemTime::now();
+
+            // parse packet headers directly in UMEM (zero-copy)
+            let packet_ptr = unsafe { umem_base.add(umem_offset) };
+            let packet = unsafe { std::slice::from_raw_parts(packet_ptr, packet_len) };
+
+            // capture the frame as received, the file is written on the capture thread
+            if let Some(capture) = capture.as_mut() {
+                capture.capture(packet);
             }
 
-            const HEADER_SIZE: usize = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+            // with vxlan_strip everything below works on the encapsulated frame, the outer
+            // headers stay in the UMEM frame in front of it
+            let inner_offset = if vxlan_strip {
+                parse_vxlan(packet).map_or(0, |(_vni, offset)| offset)
+            } else {
+                0
+            };
+            let packet_ptr = unsafe { packet_ptr.add(inner_offset) };
+            let packet_len = packet_len - inner_offset;
+            let packet = &packet[inner_offset..];
 
-            // filter small packets before processing. this will not work, since every shred is 1245 bytes big. we need to decode the tx size to determine if thats a vote. relevant for trading?
-            const VOTE_SIZE_THRESHOLD: usize = 400;
-            if packet_len < HEADER_SIZE + VOTE_SIZE_THRESHOLD {
+            if filter.as_ref().is_some_and(|filter| !filter.accept(packet)) {
                 // return frame to fill ring immediately
                 let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
                 if fill.write(frame).is_err() {
@@ -247,17 +738,72 @@ pub fn relay_loop(
                 continue;
             }
 
-            // let timestamp = SystemTime::now();
+            // the real ethertype of VLAN tagged frames comes after the tags. the forwarded frame
+            // is written untagged in front of the payload either way. ARP, LLDP and anything
+            // else that isn't IP goes straight back to the fill ring
+            let (l3_ethertype, l3_offset) = match parse_ethertype(packet) {
+                Some((l3_ethertype, l3_offset))
+                    if matches!(i32::from(l3_ethertype), ETH_P_IP | ETH_P_IPV6) =>
+                {
+                    (l3_ethertype, l3_offset)
+                }
+                _ => {
+                    let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
+                    if fill.write(frame).is_err() {
+                        umem.release(FrameOffset(umem_offset));
+                    }
+                    continue;
+                }
+            };
 
-            // parse packet headers directly in UMEM (zero-copy)
-            let packet_ptr = unsafe { umem_base.add(umem_offset) };
-            let packet = unsafe { std::slice::from_raw_parts(packet_ptr, packet_len) };
+            // without a filter nothing has checked the length yet, runts fall through as (0, 0)
+            let ip_header = packet.get(l3_offset..).unwrap_or_default();
 
-            let ip_header = &packet[ETH_HEADER_SIZE..];
+            // find the L4 protocol and the offset of the L4 header based on the ethertype
+            let (ip_proto, l4_offset) = match i32::from(l3_ethertype) {
+                // IHL counts 32 bit words and includes any IP options
+                ETH_P_IP => match (ip_header.first(), ip_header.get(9)) {
+                    (Some(&version_ihl), Some(&proto))
+                        if (version_ihl & 0x0f) as usize * 4 >= IP_HEADER_SIZE =>
+                    {
+                        (proto, l3_offset + (version_ihl & 0x0f) as usize * 4)
+                    }
+                    _ => (0, 0),
+                },
+                ETH_P_IPV6 => match parse_ipv6_header(ip_header) {
+                    Some(ipv6) => (ipv6.next_header, l3_offset + ipv6.payload_offset),
+                    None => (0, 0),
+                },
+                _ => (0, 0),
+            };
 
-            // check for UDP (protocol 17)
-            const IPPROTO_UDP: u8 = 17;
-            if ip_header[9] != IPPROTO_UDP {
+            // only UDP and TCP are forwarded
+            let tcp_header = if ip_proto == IPPROTO_TCP {
+                packet.get(l4_offset..).and_then(parse_tcp_header)
+            } else {
+                None
+            };
+            let payload_offset = match (ip_proto, tcp_header) {
+                (IPPROTO_UDP, _) => l4_offset + UDP_HEADER_SIZE,
+                (IPPROTO_TCP, Some(tcp)) => l4_offset + tcp.payload_offset,
+                _ => usize::MAX,
+            };
+
+            // catches frames corrupted by buggy NICs or test harnesses, too slow to leave on
+            #[cfg(feature = "checksum-validation")]
+            if i32::from(l3_ethertype) == ETH_P_IP
+                && (!verify_ip_checksum(ip_header)
+                    || (ip_proto == IPPROTO_UDP && !verify_udp_checksum(ip_header)))
+            {
+                stats.checksum_errors.fetch_add(1, Ordering::Relaxed);
+                let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
+                if fill.write(frame).is_err() {
+                    umem.release(FrameOffset(umem_offset));
+                }
+                continue;
+            }
+
+            if payload_offset > packet_len {
                 // return frame to fill ring
                 let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
                 if fill.write(frame).is_err() {
@@ -273,8 +819,62 @@ pub fn relay_loop(
             // let src_port = u16::from_be_bytes([udp_header[0], udp_header[1]]);
             // let dst_port = u16::from_be_bytes([udp_header[2], udp_header[3]]);
 
-            // let payload_offset = HEADER_SIZE;
-            let payload_len = packet_len - HEADER_SIZE;
+            let payload_len = packet_len - payload_offset;
+
+            if let Some(flow_table) = flow_table.as_mut() {
+                if i32::from(l3_ethertype) == ETH_P_IP && ip_header.len() >= IP_HEADER_SIZE {
+                    let ports = &packet[l4_offset..l4_offset + 4];
+                    let key = FlowKey {
+                        src_ip: ip_header[12..16].try_into().unwrap(),
+                        dst_ip: ip_header[16..20].try_into().unwrap(),
+                        src_port: u16::from_be_bytes([ports[0], ports[1]]),
+                        dst_port: u16::from_be_bytes([ports[2], ports[3]]),
+                        proto: ip_proto,
+                    };
+                    flow_table.record(key, packet_len, rx_time);
+                }
+            }
+
+            // hand UDP packets to the decoder threads. the event holds a reference to the frame,
+            // which keeps it out of the fill ring until the decoders are done reading the payload
+            if let (Some(producer), Some(refs), IPPROTO_UDP) =
+                (events.as_mut(), &frame_refs, ip_proto)
+            {
+                if let Some((src_ip, dst_ip)) = ip_addrs(l3_ethertype, ip_header) {
+                    let ports = &packet[l4_offset..l4_offset + 4];
+                    // the XDP program's metadata is in the frame's headroom, right in front of
+                    // the packet
+                    let frame_start = umem_offset & !(frame_size - 1);
+                    let headroom = unsafe {
+                        std::slice::from_raw_parts(
+                            umem_base.add(frame_start),
+                            umem_offset - frame_start,
+                        )
+                    };
+                    let flow_hash = read_flow_hash(headroom, headroom.len()).unwrap_or(0);
+                    let xdp_timestamp = read_xdp_timestamp(headroom, headroom.len());
+                    let published = producer.try_publish(|event| {
+                        event.set_from_umem(
+                            refs.share(FrameOffset(umem_offset)),
+                            umem_offset + inner_offset,
+                            payload_offset,
+                            payload_len,
+                            packet_len,
+                            src_ip,
+                            u16::from_be_bytes([ports[0], ports[1]]),
+                            dst_ip,
+                            u16::from_be_bytes([ports[2], ports[3]]),
+                            flow_hash,
+                            SystemTime::now(),
+                            xdp_timestamp,
+                            None,
+                        )
+                    });
+                    if !published {
+                        events_dropped += 1;
+                    }
+                }
+            }
             // let udp_payload = &packet[payload_offset..]; // packets
 
             // let src_ip_arr: [u8; 4] = src_ip_bytes.try_into().unwrap();
@@ -323,54 +923,140 @@ pub fn relay_loop(
             //     // }
             // }
 
-            // forward packet if configured (reuse same UMEM frame)
-            if let (Some(dest_ip), Some(dest_port), Some(dest_mac)) = (dest_ip, dest_port, dest_mac) {
-                // modify headers in-place (zero-copy)
-                // safety: we have exclusive access to this UMEM frame
-                let packet_mut = unsafe { std::slice::from_raw_parts_mut(packet_ptr as *mut u8, packet_len) };
+            // TCP options are forwarded along with the header, they're already in place in
+            // front of the payload
+            let l4_header_size = match &tcp_header {
+                Some(tcp) => tcp.payload_offset,
+                None => UDP_HEADER_SIZE,
+            };
+            let header_len = ETH_HEADER_SIZE + IP_HEADER_SIZE + l4_header_size;
 
-                // Update Ethernet header
-                write_eth_header(packet_mut, &src_mac.0, &dest_mac.0);
+            if let Some(multi) = &multi_dest {
+                // fan out, every destination gets its own copy in a fresh frame and the rx frame
+                // goes straight back to the fill ring
+                let options_len = tcp_header.map_or(0, |tcp| tcp.payload_offset - TCP_HEADER_SIZE);
+                let payload = &packet[payload_offset - options_len..];
+                let tx_len = header_len + payload_len;
+                for (i, (dest_ip, dest_port, dest_mac)) in multi.destinations.iter().enumerate() {
+                    if rate_limiters
+                        .get_mut(i)
+                        .is_some_and(|bucket| !bucket.consume_at(tx_len as u64, rx_time))
+                    {
+                        tx_stats.rate_limited_drops.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    // a None is counted in umem.stats().exhaustion_count
+                    let Some(mut tx_frame) = umem.reserve() else {
+                        break;
+                    };
+                    // safety: the frame was just reserved so we have exclusive access to it, and
+                    // tx_len is never larger than the rx packet so it fits the frame
+                    let frame_mut = unsafe {
+                        std::slice::from_raw_parts_mut(
+                            umem_base.add(tx_frame.offset().0) as *mut u8,
+                            tx_len,
+                        )
+                    };
+                    frame_mut[header_len - options_len..].copy_from_slice(payload);
+                    write_forward_headers(
+                        frame_mut,
+                        &src_mac,
+                        &src_ip,
+                        dest_mac,
+                        dest_ip,
+                        *dest_port,
+                        tcp_header.as_ref(),
+                        payload_len,
+                    );
+                    tx_frame.set_len(tx_len);
+                    pending_tx.push_back(tx_frame);
+                }
 
-                // update IP header
-                write_ip_header(
-                    &mut packet_mut[ETH_HEADER_SIZE..],
-                    &src_ip,
-                    &dest_ip,
-                    (UDP_HEADER_SIZE + payload_len) as u16,
-                );
+                flush_pending_tx(&mut pending_tx, &mut tx_ring, &mut watchdog, &tx_stats);
+                latency.record(rx_time.elapsed());
+                // whatever the tx ring can't take waits in pending_tx, bounded by the ring size
+                let excess = pending_tx.len().saturating_sub(tx_size);
+                for _ in 0..excess {
+                    let dropped = match multi.overflow {
+                        OverflowPolicy::DropNewest => pending_tx.pop_back(),
+                        OverflowPolicy::DropOldest => pending_tx.pop_front(),
+                    };
+                    if let Some(frame) = dropped {
+                        umem.release(frame.offset());
+                    }
+                }
+                fanout_dropped += excess;
+                tx_stats.tx_drops.fetch_add(excess, Ordering::Relaxed);
 
-                // update UDP header
-                write_udp_header(
-                    &mut packet_mut[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+                if release_frame(frame_refs.as_deref(), FrameOffset(umem_offset)) {
+                    let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
+                    if fill.write(frame).is_err() {
+                        umem.release(FrameOffset(umem_offset));
+                    }
+                }
+            } else if let (Some(IpAddr::V4(dest_ip)), Some(dest_port), Some(dest_mac)) =
+                (dest_ip, dest_port, dest_mac)
+            {
+                // modify headers in-place (zero-copy). the outgoing IPv4 headers are written so
+                // that they end right where the L4 payload starts, for IPv6 input (or IPv4 with
+                // options) this means the tx frame starts a few bytes into the rx frame.
+                // safety: we have exclusive access to this UMEM frame, decoders holding a reference
+                // to it only read the payload behind the headers we write
+                let frame_start = payload_offset - header_len;
+                let tx_len = packet_len - frame_start;
+                let packet_mut = unsafe {
+                    std::slice::from_raw_parts_mut(packet_ptr.add(frame_start) as *mut u8, tx_len)
+                };
+
+                write_forward_headers(
+                    packet_mut,
+                    &src_mac,
                     &src_ip,
-                    12345,
+                    &dest_mac,
                     &dest_ip,
                     dest_port,
-                    payload_len as u16,
-                    false,
+                    tcp_header.as_ref(),
+                    payload_len,
                 );
 
                 // queue same frame for tx (zero-copy forwarding)
-                let tx_frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), packet_len);
-                if tx_ring.write(tx_frame, 0).is_err() {
+                let tx_offset = FrameOffset(umem_offset + inner_offset + frame_start);
+                let tx_frame = SliceUmemFrame::from_offset(tx_offset, tx_len);
+                if tx_ring.write(tx_frame, 0).is_ok() {
+                    tx_stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+                    tx_stats.tx_bytes.fetch_add(tx_len, Ordering::Relaxed);
+                    watchdog.submit(tx_offset, rx_time);
+                    latency.record(rx_time.elapsed());
+                } else {
                     // tx ring full, return to fill ring
-                    let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
-                    if fill.write(frame).is_err() {
-                        umem.release(FrameOffset(umem_offset));
+                    tx_stats.tx_drops.fetch_add(1, Ordering::Relaxed);
+                    if release_frame(frame_refs.as_deref(), FrameOffset(umem_offset)) {
+                        let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
+                        if fill.write(frame).is_err() {
+                            umem.release(FrameOffset(umem_offset));
+                        }
                     }
                 }
             } else {
                 // not forwarding, return frame to fill ring
-                let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
-                if fill.write(frame).is_err() {
-                    umem.release(FrameOffset(umem_offset));
+                if release_frame(frame_refs.as_deref(), FrameOffset(umem_offset)) {
+                    let frame = SliceUmemFrame::from_offset(FrameOffset(umem_offset), 0);
+                    if fill.write(frame).is_err() {
+                        umem.release(FrameOffset(umem_offset));
+                    }
                 }
             }
 
             // batch commit
             batch_count += 1;
-            if batch_count >= BATCH_SIZE {
+            if batch_count >= batch.size() {
+                // under load the fill ring can run dry before the refill after this poll, top it
+                // up now so the kernel doesn't drop packets for lack of frames
+                fill.sync(false);
+                if fill.capacity() - fill.available() < fill_low_watermark {
+                    stats.fill_low_watermark_hits.fetch_add(1, Ordering::Relaxed);
+                    refill_fill_ring(&mut fill, umem, &mut refill);
+                }
                 rx_ring.commit();
                 tx_ring.commit();
                 fill.commit();
@@ -380,20 +1066,22 @@ pub fn relay_loop(
                 batch_count = 0;
             }
         }
-
-        // refill rx ring
-        while fill.available() > 0 {
-            if let Some(frame) = umem.reserve() {
-                let offset = frame.offset();
-                if fill.write(frame).is_err() {
-                    umem.release(offset);
-                    break;
-                }
-            } else {
-                break;
+        if let (Some(flows), Some(flow_table)) = (&flows, flow_table.as_mut()) {
+            let locked = if flow_table.is_empty() { None } else { flows.try_lock().ok() };
+            if let Some(mut flows) = locked {
+                flows.merge(flow_table);
             }
         }
 
+        // refill rx ring
+        refill_fill_ring(&mut fill, umem, &mut refill);
+        // progress for RelayWatchdog
+        loop_iterations += 1;
+        stats.loop_iterations.store(loop_iterations, Ordering::Relaxed);
+        stats
+            .fill_ring_frames
+            .store(fill.capacity() - fill.available(), Ordering::Relaxed);
+
         // final commits if needed
         if batch_count > 0 {
             rx_ring.commit();
@@ -401,9 +1089,391 @@ pub fn relay_loop(
             fill.commit();
             batch_count = 0;
         }
+
+        // empty polls say nothing about the burst size
+        if received > 0 {
+            batch.update(received);
+        }
+
+        if last_latency_report.elapsed() >= LATENCY_REPORT_INTERVAL {
+            if latency.count() > 0 {
+                log::info!("relay latency {}", latency.report());
+            }
+            latency.reset();
+            last_latency_report = Instant::now();
+        }
+    }
+
+    if let (Some(flows), Some(flow_table)) = (&flows, flow_table.as_mut()) {
+        flows.lock().unwrap().merge(flow_table);
+    }
+
+    eprintln!("relay loop exiting after {} packets, umem {:?}", total_packets, umem.stats());
+    let io_stats = IoStats {
+        rx: Arc::clone(&stats),
+        tx: Arc::clone(&tx_stats),
+    };
+    eprintln!("  {}", io_stats);
+    eprintln!(
+        "  fill ring low watermark hit {} times",
+        stats.fill_low_watermark_hits.load(Ordering::Relaxed)
+    );
+    #[cfg(feature = "checksum-validation")]
+    eprintln!(
+        "  dropped {} packets with bad checksums",
+        stats.checksum_errors.load(Ordering::Relaxed)
+    );
+    let duplicate_drops = stats.duplicate_drops.load(Ordering::Relaxed);
+    if duplicate_drops > 0 {
+        eprintln!("  dropped {} duplicate shreds", duplicate_drops);
+    }
+    let rp_filter_drops = stats.rp_filter_drops.load(Ordering::Relaxed);
+    if rp_filter_drops > 0 {
+        eprintln!("  reverse path filter dropped {} packets", rp_filter_drops);
+    }
+    if let Some(producer) = events.as_mut() {
+        eprintln!(
+            "  published {} packet events, dropped {} on a full ring",
+            producer.published(),
+            events_dropped
+        );
+        // the decoders read from UMEM, which is unmapped when we return
+        producer.wait_until_consumed();
+        producer.reclaim(PacketEventZeroCopy::reset);
+    }
+    if multi_dest.is_some() {
+        eprintln!("  fanout dropped {} copies on tx overflow", fanout_dropped);
+        eprintln!(
+            "  fanout dropped {} copies over the rate limit",
+            tx_stats.rate_limited_drops.load(Ordering::Relaxed)
+        );
+    }
+    for stats in read_xdp_stats(&xdp_program) {
+        eprintln!(
+            "  xdp queue {}: redirected {} passed {} errors {}",
+            stats.queue_id, stats.redirected, stats.passed, stats.errors
+        );
+    }
+    // packets the kernel dropped never show up in total_packets, a high rx_dropped or
+    // rx_fill_ring_empty_descs means we weren't returning frames to the fill ring fast enough
+    match socket.statistics() {
+        Ok(socket_stats) => {
+            eprintln!("  socket: {:?}", socket_stats);
+            if socket_stats.rx_dropped > 0 {
+                let seen = total_packets as u64 + socket_stats.rx_dropped;
+                eprintln!(
+                    "  kernel dropped {} of {} packets ({:.2}%)",
+                    socket_stats.rx_dropped,
+                    seen,
+                    socket_stats.rx_dropped as f64 * 100.0 / seen as f64
+                );
+            }
+            stats
+                .rx_drops
+                .store(socket_stats.rx_dropped as usize, Ordering::Relaxed);
+            stats
+                .rx_errors
+                .store(socket_stats.rx_invalid_descs as usize, Ordering::Relaxed);
+        }
+        Err(e) => eprintln!("  failed to read socket statistics: {}", e),
+    }
+    // the kernel's view of the socket, rx_ring_full with a small rx ring is a sizing problem,
+    // with a large one the loop isn't keeping up
+    match socket.inode().and_then(netlink_get_xdp_socket) {
+        Ok(Some(diag)) => {
+            eprintln!(
+                "  rings: rx {:?} tx {:?} fill {:?} completion {:?}",
+                diag.rx_ring, diag.tx_ring, diag.fill_ring, diag.completion_ring
+            );
+            if let Some(umem) = diag.umem {
+                eprintln!(
+                    "  umem {}: {} bytes in {} pages, frame size {}, zero copy {}",
+                    umem.id, umem.size, umem.pages, umem.frame_size, umem.zero_copy
+                );
+            }
+        }
+        Ok(None) => eprintln!("  socket not found in sock_diag dump"),
+        Err(e) => eprintln!("  failed to read sock_diag: {}", e),
+    }
+    Ok(end)
+}
+
+/// give up the relay loop's reference to an rx frame, true if no decoder holds it either and the
+/// frame can be recycled
+#[inline]
+fn release_frame(refs: Option<&UmemRefs>, frame: FrameOffset) -> bool {
+    refs.is_none_or(|refs| refs.release(frame))
+}
+
+/// source and destination address of an IPv4 or IPv6 header
+fn ip_addrs(ethertype: u16, ip_header: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match i32::from(ethertype) {
+        ETH_P_IP => {
+            let addrs: [u8; 8] = ip_header.get(12..20)?.try_into().ok()?;
+            let src = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let dst = Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+            Some((src.into(), dst.into()))
+        }
+        ETH_P_IPV6 => {
+            let src: [u8; 16] = ip_header.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip_header.get(24..40)?.try_into().ok()?;
+            Some((Ipv6Addr::from(src).into(), Ipv6Addr::from(dst).into()))
+        }
+        _ => None,
+    }
+}
+
+/// rewrite the eth, IPv4 and L4 headers at the start of `frame` for `dest_ip:dest_port`, TCP
+/// sequence state and options are passed through untouched
+#[inline]
+#[allow(clippy::too_many_arguments)]
+fn write_forward_headers(
+    frame: &mut [u8],
+    src_mac: &MacAddress,
+    src_ip: &Ipv4Addr,
+    dest_mac: &MacAddress,
+    dest_ip: &Ipv4Addr,
+    dest_port: u16,
+    tcp_header: Option<&TcpHeader>,
+    payload_len: usize,
+) {
+    let (ip_proto, l4_header_size) = if let Some(tcp) = tcp_header {
+        (IPPROTO_TCP, tcp.payload_offset)
+    } else {
+        (IPPROTO_UDP, UDP_HEADER_SIZE)
+    };
+
+    write_eth_header(frame, &src_mac.0, &dest_mac.0);
+    write_ip_header_with_protocol(
+        &mut frame[ETH_HEADER_SIZE..],
+        src_ip,
+        dest_ip,
+        (l4_header_size + payload_len) as u16,
+        ip_proto,
+    );
+
+    let l4 = &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..];
+    match tcp_header {
+        Some(tcp) => write_tcp_header_with_options(
+            l4,
+            tcp.payload_offset,
+            src_ip,
+            tcp.src_port,
+            dest_ip,
+            dest_port,
+            tcp.seq,
+            tcp.ack,
+            tcp.flags,
+            tcp.window,
+            payload_len as u16,
+        ),
+        None => write_udp_header(
+            l4,
+            src_ip,
+            12345,
+            dest_ip,
+            dest_port,
+            payload_len as u16,
+            false,
+        ),
+    }
+}
+
+/// top the fill ring up with free UMEM frames. a None from reserve() is counted in
+/// umem.stats().exhaustion_count
+#[inline]
+fn refill_fill_ring<U: Umem>(
+    fill: &mut RxFillRing<U::Frame>,
+    umem: &mut U,
+    refill: &mut Vec<U::Frame>,
+) {
+    refill.extend((0..fill.available()).map_while(|_| umem.reserve()));
+    let written = fill.write_batch(refill);
+    for frame in refill.drain(..).skip(written) {
+        umem.release(frame.offset());
+    }
+}
+
+/// move queued fanout copies into the tx ring until it's full
+#[inline]
+fn flush_pending_tx<F: Frame>(
+    pending: &mut VecDeque<F>,
+    tx_ring: &mut TxRing<F>,
+    watchdog: &mut TxCompletionWatchdog,
+    stats: &TxStats,
+) {
+    if pending.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    loop {
+        let Some(frame) = pending.pop_front() else {
+            break;
+        };
+        let offset = frame.offset();
+        let len = frame.len();
+        if let Err(RingFull(frame)) = tx_ring.write(frame, 0) {
+            pending.push_front(frame);
+            break;
+        }
+        stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+        stats.tx_bytes.fetch_add(len, Ordering::Relaxed);
+        watchdog.submit(offset, now);
+    }
+}
+
+/// AIMD controller for how many packets are processed between ring commits
+///
+/// grows by one while every poll fills the batch and halves when a poll brings less than a
+/// quarter of it, so it settles around the burst size the NIC hands us per poll. small batches
+/// get frames to the tx ring sooner at low rates, large ones amortize the commits at high rates.
+struct AdaptiveBatch {
+    size: usize,
+}
+
+impl AdaptiveBatch {
+    const MIN: usize = 1;
+    const MAX: usize = 256;
+    const INITIAL: usize = 32;
+
+    fn new() -> Self {
+        Self {
+            size: Self::INITIAL,
+        }
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// feed the number of packets processed in one poll of the rx ring
+    #[inline]
+    fn update(&mut self, processed: usize) {
+        if processed >= self.size {
+            self.size = (self.size + 1).min(Self::MAX);
+        } else if processed.saturating_mul(4) < self.size {
+            self.size = (self.size / 2).max(Self::MIN);
+        }
+    }
+}
+
+// how long startup waits for the next hop to answer ARP
+const ARP_WAIT: Duration = Duration::from_millis(500);
+
+const DEFAULT_TX_TIMEOUT: Duration = Duration::from_secs(1);
+
+// how often a relay whose interface went down checks whether it's back
+const LINK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// how long the interface has to be reported down before the relay tears down its socket
+const LINK_DOWN_DEBOUNCE: Duration = Duration::from_millis(200);
+
+// wait between attempts to set the relay up again after a restart failed, doubling up to the max
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// tracks tx frames from tx_ring.write until their completion so a stalled tx queue can't
+/// hold on to UMEM frames forever
+///
+/// AF_XDP completes frames in the order they were submitted, so in-flight frames are kept in
+/// a FIFO and only the oldest one needs checking. a frame that doesn't complete in time is
+/// still owned by the kernel and can't be recycled, the queue is stalled and the socket has to
+/// be torn down to get its frames back.
+pub struct TxCompletionWatchdog {
+    in_flight: VecDeque<(FrameOffset, Instant)>,
+    timeout: Duration,
+}
+
+impl TxCompletionWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            in_flight: VecDeque::new(),
+            timeout,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// record a frame written to the tx ring at `now`
+    #[inline]
+    pub fn submit(&mut self, frame: FrameOffset, now: Instant) {
+        self.in_flight.push_back((frame, now));
+    }
+
+    /// record a completion, returns false if the frame isn't the oldest one in flight
+    #[inline]
+    pub fn complete(&mut self, frame: FrameOffset) -> bool {
+        match self.in_flight.front() {
+            Some((oldest, _)) if oldest.0 == frame.0 => {
+                self.in_flight.pop_front();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// true if the oldest frame in flight has been pending for longer than the timeout
+    #[inline]
+    pub fn stalled(&self, now: Instant) -> bool {
+        self.in_flight
+            .front()
+            .is_some_and(|&(_, submitted)| now.saturating_duration_since(submitted) >= self.timeout)
+    }
+}
+
+/// token bucket over bytes, starts full
+pub struct TokenBucket {
+    capacity: u64,
+    tokens: f64,
+    // bytes per nanosecond
+    refill_rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u64, bytes_per_sec: u64) -> Self {
+        Self {
+            capacity,
+            tokens: capacity as f64,
+            refill_rate: bytes_per_sec as f64 / 1e9,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn from_rate_limit(limit: &RateLimit) -> Self {
+        Self::new(limit.burst_bytes, limit.bytes_per_sec)
+    }
+
+    /// take `bytes` tokens, returns false and takes nothing if there aren't enough
+    #[inline]
+    pub fn consume(&mut self, bytes: u64) -> bool {
+        self.consume_at(bytes, Instant::now())
+    }
+
+    #[inline]
+    pub fn consume_at(&mut self, bytes: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_nanos() as f64 * self.refill_rate)
+            .min(self.capacity as f64);
+        self.last_refill = self.last_refill.max(now);
+
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
     }
 }
 
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
 fn fifo_priority_bounds() -> io::Result<(i32, i32)> {
     unsafe {
         let min = libc::sched_get_priority_min(libc::SCHED_FIFO);
@@ -427,3 +1497,159 @@ pub fn set_current_thread_sched_fifo(priority: i32) -> io::Result<()> {
     }
     Ok(())
 }
+
+/// real-time scheduling policy for the relay thread
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    Fifo {
+        priority: i32,
+    },
+    /// run for up to `runtime_ns` every `period_ns`, finishing within `deadline_ns` of the start
+    /// of each period. see set_current_thread_sched_deadline() for the affinity caveat.
+    Deadline {
+        runtime_ns: u64,
+        deadline_ns: u64,
+        period_ns: u64,
+    },
+}
+
+impl SchedPolicy {
+    /// apply the policy to the calling thread
+    pub fn apply(&self) -> io::Result<()> {
+        match *self {
+            SchedPolicy::Fifo { priority } => set_current_thread_sched_fifo(priority),
+            SchedPolicy::Deadline {
+                runtime_ns,
+                deadline_ns,
+                period_ns,
+            } => set_current_thread_sched_deadline(runtime_ns, deadline_ns, period_ns),
+        }
+    }
+}
+
+// not in libc
+const SCHED_DEADLINE: u32 = 6;
+
+/// struct sched_attr from include/uapi/linux/sched/types.h, SCHED_ATTR_SIZE_VER0
+#[repr(C)]
+#[derive(Default)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+/// switch the calling thread to SCHED_DEADLINE
+///
+/// the kernel requires runtime <= deadline <= period and rejects budgets its admission control
+/// can't guarantee. deadline tasks must be allowed to run on every CPU of their root domain, so
+/// pinning with set_cpu_affinity() first fails with EPERM unless the CPU sits in an exclusive
+/// cpuset.
+pub fn set_current_thread_sched_deadline(
+    runtime_ns: u64,
+    deadline_ns: u64,
+    period_ns: u64,
+) -> io::Result<()> {
+    let attr = SchedAttr {
+        size: mem::size_of::<SchedAttr>() as u32,
+        sched_policy: SCHED_DEADLINE,
+        sched_runtime: runtime_ns,
+        sched_deadline: deadline_ns,
+        sched_period: period_ns,
+        ..SchedAttr::default()
+    };
+    let rc = unsafe { libc::syscall(libc::SYS_sched_setattr, 0, &attr as *const SchedAttr, 0) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_batch_converges() {
+        let mut batch = AdaptiveBatch::new();
+
+        // steady bursts of 64 grow the batch until a burst no longer fills it
+        for _ in 0..100 {
+            batch.update(64);
+        }
+        assert_eq!(batch.size(), 65);
+
+        // a trickle shrinks it back down
+        for _ in 0..5 {
+            batch.update(1);
+        }
+        assert_eq!(batch.size(), 4);
+
+        // and it never leaves its bounds
+        for _ in 0..10 {
+            batch.update(0);
+        }
+        assert_eq!(batch.size(), AdaptiveBatch::MIN);
+        for _ in 0..1000 {
+            batch.update(usize::MAX);
+        }
+        assert_eq!(batch.size(), AdaptiveBatch::MAX);
+    }
+
+    #[test]
+    fn test_tx_completion_watchdog() {
+        let mut watchdog = TxCompletionWatchdog::new(Duration::from_millis(100));
+        let start = Instant::now();
+        for offset in [0, 4096, 8192] {
+            watchdog.submit(FrameOffset(offset), start);
+        }
+
+        // completions arrive in submission order
+        assert!(watchdog.complete(FrameOffset(0)));
+        assert_eq!(watchdog.in_flight(), 2);
+
+        // not stalled before the timeout
+        assert!(!watchdog.stalled(start));
+
+        // stalled frames stay in flight, the kernel still owns them
+        let late = start + Duration::from_millis(100);
+        watchdog.submit(FrameOffset(12288), late);
+        assert!(watchdog.stalled(late));
+        assert_eq!(watchdog.in_flight(), 3);
+
+        // completions out of order aren't taken
+        assert!(!watchdog.complete(FrameOffset(8192)));
+        assert!(watchdog.complete(FrameOffset(4096)));
+        assert!(watchdog.complete(FrameOffset(8192)));
+        assert!(!watchdog.stalled(late));
+        assert!(watchdog.complete(FrameOffset(12288)));
+        assert_eq!(watchdog.in_flight(), 0);
+        assert!(!watchdog.stalled(late + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000, 1000);
+        let start = Instant::now();
+
+        // starts full, a refused consume takes nothing
+        assert!(bucket.consume_at(600, start));
+        assert!(!bucket.consume_at(600, start));
+        assert!(bucket.consume_at(400, start));
+
+        // refills at the configured rate
+        let later = start + Duration::from_millis(500);
+        assert!(!bucket.consume_at(600, later));
+        assert!(bucket.consume_at(500, later));
+
+        // but never past its capacity
+        let idle = later + Duration::from_secs(10);
+        assert!(bucket.consume_at(1000, idle));
+        assert!(!bucket.consume_at(1, idle));
+    }
+}
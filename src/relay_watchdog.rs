@@ -0,0 +1,200 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// watches a relay loop's RxStats from a separate thread and reports when it stops making progress
+
+use {
+    crate::rx_loop::RxStats,
+    std::{
+        fmt, io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    },
+};
+
+/// why the relay loop is considered stalled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stall {
+    /// the loop itself stopped going around, it's blocked or descheduled
+    LoopStuck,
+    /// the loop is polling but the kernel has no frames left to receive into
+    FillRingEmpty,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LoopStuck => write!(f, "relay loop stopped polling"),
+            Self::FillRingEmpty => write!(f, "fill ring empty, UMEM exhausted"),
+        }
+    }
+}
+
+/// calls `on_stall` when the relay loop stops receiving packets for `timeout`
+///
+/// no new packets alone is fine, the link may just be quiet. it's a stall when the loop stopped
+/// polling as well (RxStats::loop_iterations) or the fill ring is empty (RxStats::fill_ring_frames)
+/// so nothing could be received even if traffic arrived. a NIC that silently stops delivering
+/// looks like a quiet link. `on_stall` is called once per stall and again only after the loop
+/// has received packets in between. the thread stops when the watchdog is dropped.
+pub struct RelayWatchdog {
+    exit: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RelayWatchdog {
+    pub fn spawn(
+        stats: Arc<RxStats>,
+        timeout: Duration,
+        on_stall: Box<dyn Fn() + Send>,
+    ) -> io::Result<Self> {
+        let exit = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("relayWatchdog".to_string())
+            .spawn({
+                let exit = Arc::clone(&exit);
+                move || {
+                    // check a few times per timeout so a stall is reported soon after it hits
+                    let interval = timeout / 4;
+                    let mut detector = StallDetector::new(timeout, Progress::read(&stats));
+                    while !exit.load(Ordering::Relaxed) {
+                        thread::park_timeout(interval);
+                        if let Some(stall) = detector.check(Progress::read(&stats), Instant::now())
+                        {
+                            log::error!("no packets received for {timeout:?}: {stall}");
+                            on_stall();
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            exit,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for RelayWatchdog {
+    fn drop(&mut self) {
+        self.exit.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Progress {
+    rx_packets: usize,
+    loop_iterations: usize,
+    fill_ring_frames: usize,
+}
+
+impl Progress {
+    fn read(stats: &RxStats) -> Self {
+        Self {
+            rx_packets: stats.rx_packets.load(Ordering::Relaxed),
+            loop_iterations: stats.loop_iterations.load(Ordering::Relaxed),
+            fill_ring_frames: stats.fill_ring_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct StallDetector {
+    timeout: Duration,
+    last: Progress,
+    last_rx: Instant,
+    last_iteration: Instant,
+    // set until packets flow again
+    idle_logged: bool,
+    stall_reported: bool,
+}
+
+impl StallDetector {
+    fn new(timeout: Duration, progress: Progress) -> Self {
+        let now = Instant::now();
+        Self {
+            timeout,
+            last: progress,
+            last_rx: now,
+            last_iteration: now,
+            idle_logged: false,
+            stall_reported: false,
+        }
+    }
+
+    fn check(&mut self, progress: Progress, now: Instant) -> Option<Stall> {
+        if progress.rx_packets != self.last.rx_packets {
+            self.last_rx = now;
+            self.idle_logged = false;
+            self.stall_reported = false;
+        }
+        if progress.loop_iterations != self.last.loop_iterations {
+            self.last_iteration = now;
+        }
+        self.last = progress;
+
+        if self.stall_reported || now.duration_since(self.last_rx) < self.timeout {
+            return None;
+        }
+        let stall = if now.duration_since(self.last_iteration) >= self.timeout {
+            Stall::LoopStuck
+        } else if progress.fill_ring_frames == 0 {
+            Stall::FillRingEmpty
+        } else {
+            if !self.idle_logged {
+                log::info!("no packets received for {:?}, link idle", self.timeout);
+                self.idle_logged = true;
+            }
+            return None;
+        };
+        self.stall_reported = true;
+        Some(stall)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall_detector() {
+        let timeout = Duration::from_secs(1);
+        let progress = |rx_packets, loop_iterations, fill_ring_frames| Progress {
+            rx_packets,
+            loop_iterations,
+            fill_ring_frames,
+        };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut detector = StallDetector::new(timeout, progress(0, 0, 64));
+        detector.last_rx = start;
+        detector.last_iteration = start;
+
+        // a quiet link with the loop still polling is a lull
+        assert_eq!(detector.check(progress(0, 10, 64), at(500)), None);
+        assert_eq!(detector.check(progress(0, 20, 64), at(1500)), None);
+
+        // traffic resumes, then the UMEM runs dry
+        assert_eq!(detector.check(progress(5, 30, 64), at(1600)), None);
+        assert_eq!(detector.check(progress(5, 40, 0), at(2000)), None);
+        assert_eq!(
+            detector.check(progress(5, 50, 0), at(2600)),
+            Some(Stall::FillRingEmpty)
+        );
+        // reported once
+        assert_eq!(detector.check(progress(5, 60, 0), at(3000)), None);
+
+        // traffic resumes, then the loop stops
+        assert_eq!(detector.check(progress(6, 70, 64), at(3100)), None);
+        assert_eq!(
+            detector.check(progress(6, 70, 64), at(4200)),
+            Some(Stall::LoopStuck)
+        );
+    }
+}
@@ -4,8 +4,12 @@ use {
     },
     libc::{AF_INET, AF_INET6},
     std::{
+        collections::HashMap,
         io,
-        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket},
+        sync::Mutex,
+        thread,
+        time::{Duration, Instant},
     },
     thiserror::Error,
 };
@@ -22,6 +26,13 @@ pub enum RouteError {
     MacResolutionError,
 }
 
+// how long a resolved MAC is trusted before asking the kernel again
+const NEIGHBOR_TTL: Duration = Duration::from_secs(30);
+
+// neighbor table queries after triggering ARP, and the wait before each of them
+const ARP_RETRIES: usize = 3;
+const ARP_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug)]
 pub struct NextHop {
     pub mac_addr: Option<MacAddress>,
@@ -118,9 +129,12 @@ pub struct Router {
 
 impl Router {
     pub fn new() -> Result<Self, io::Error> {
+        // IPv4 first, default() picks the first default route
+        let mut routes = netlink_get_routes(AF_INET as u8)?;
+        routes.extend(netlink_get_routes(AF_INET6 as u8)?);
         Ok(Self {
             arp_table: ArpTable::new()?,
-            routes: netlink_get_routes(AF_INET as u8)?,
+            routes,
         })
     }
 
@@ -140,7 +154,7 @@ impl Router {
             None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
         };
 
-        let mac_addr = self.arp_table.lookup(next_hop_ip).cloned();
+        let mac_addr = self.arp_table.lookup(next_hop_ip, if_index);
 
         Ok(NextHop {
             ip_addr: next_hop_ip,
@@ -149,6 +163,7 @@ impl Router {
         })
     }
 
+    /// next hop for `dest_ip`, IPv4 destinations use the ARP cache and IPv6 ones the NDP cache
     pub fn route(&self, dest_ip: IpAddr) -> Result<NextHop, RouteError> {
         let route = lookup_route(&self.routes, dest_ip).ok_or(RouteError::NoRouteFound(dest_ip))?;
 
@@ -161,7 +176,7 @@ impl Router {
             None => dest_ip,
         };
 
-        let mac_addr = self.arp_table.lookup(next_hop_ip).cloned();
+        let mac_addr = self.arp_table.lookup(next_hop_ip, if_index);
 
         Ok(NextHop {
             ip_addr: next_hop_ip,
@@ -169,26 +184,142 @@ impl Router {
             if_index,
         })
     }
+
+    /// interface the route to `dest_ip` goes out of, without resolving the next hop
+    pub fn out_if_index(&self, dest_ip: IpAddr) -> Option<u32> {
+        lookup_route(&self.routes, dest_ip)?
+            .out_if_index
+            .map(|if_index| if_index as u32)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_routes(routes: Vec<RouteEntry>) -> Self {
+        Self {
+            arp_table: ArpTable {
+                cache: Mutex::default(),
+            },
+            routes,
+        }
+    }
+
+    /// MAC of `ip` on interface `if_index`, making the kernel send an ARP request if it doesn't
+    /// know it yet. blocks for up to ARP_RETRIES * ARP_RETRY_INTERVAL, so keep it out of the per
+    /// packet path
+    pub fn resolve_mac(&self, ip: Ipv4Addr, if_index: u32) -> io::Result<MacAddress> {
+        self.resolve_mac_within(ip, if_index, ARP_RETRY_INTERVAL * ARP_RETRIES as u32)
+    }
+
+    /// resolve_mac() giving up after `timeout`
+    pub fn resolve_mac_within(
+        &self,
+        ip: Ipv4Addr,
+        if_index: u32,
+        timeout: Duration,
+    ) -> io::Result<MacAddress> {
+        let ip_addr = IpAddr::V4(ip);
+        if let Some(mac) = self.arp_table.cached(ip_addr, if_index) {
+            return Ok(mac);
+        }
+        if let Some(mac) = self.arp_table.refresh(ip_addr, if_index)? {
+            return Ok(mac);
+        }
+
+        // the kernel resolves the address before it can send anything to it. the datagram goes
+        // to the discard port and is dropped if ARP fails
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.send_to(&[], (ip, 9))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::sleep(ARP_RETRY_INTERVAL.min(deadline - now));
+            if let Some(mac) = self.arp_table.refresh(ip_addr, if_index)? {
+                return Ok(mac);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no ARP reply from {ip}"),
+        ))
+    }
+
+    /// forget the cached MAC for `ip` on interface `if_index`, the next route() to it asks the
+    /// kernel
+    pub fn invalidate(&self, ip: IpAddr, if_index: u32) {
+        self.arp_table.cache.lock().unwrap().remove(&(ip, if_index));
+    }
 }
 
+/// ARP/NDP entries with the time they were read from the kernel
+///
+/// entries are per interface, the same address can be a different neighbor on another link
+/// (fe80::1 exists on every link, RFC 1918 addresses get reused across VLANs)
 struct ArpTable {
-    neighbors: Vec<NeighborEntry>,
+    cache: Mutex<HashMap<(IpAddr, u32), (MacAddress, Instant)>>,
 }
 
 impl ArpTable {
     pub fn new() -> Result<Self, io::Error> {
-        let neighbors = netlink_get_neighbors(None, AF_INET as u8)?;
-        Ok(Self { neighbors })
+        let mut neighbors = netlink_get_neighbors(None, AF_INET as u8)?;
+        neighbors.extend(netlink_get_neighbors(None, AF_INET6 as u8)?);
+
+        let now = Instant::now();
+        let cache = neighbors
+            .into_iter()
+            .filter_map(|n| Some(((n.destination?, n.ifindex as u32), (n.lladdr?, now))))
+            .collect();
+        Ok(Self {
+            cache: Mutex::new(cache),
+        })
     }
 
-    pub fn lookup(&self, ip: IpAddr) -> Option<&MacAddress> {
-        self.neighbors
-            .iter()
-            .find(|n| n.destination == Some(ip))
-            .and_then(|n| n.lladdr.as_ref())
+    /// cached MAC for `ip` on `if_index` if it's fresh, otherwise re-read the neighbors of
+    /// `if_index`
+    pub fn lookup(&self, ip: IpAddr, if_index: u32) -> Option<MacAddress> {
+        self.cached(ip, if_index)
+            .or_else(|| self.refresh(ip, if_index).ok()?)
+    }
+
+    fn cached(&self, ip: IpAddr, if_index: u32) -> Option<MacAddress> {
+        let cache = self.cache.lock().unwrap();
+        let (mac, updated) = cache.get(&(ip, if_index))?;
+        (updated.elapsed() < NEIGHBOR_TTL).then_some(*mac)
+    }
+
+    /// query the kernel's neighbor table of `if_index` for `ip` and update the cache
+    fn refresh(&self, ip: IpAddr, if_index: u32) -> io::Result<Option<MacAddress>> {
+        let family = match ip {
+            IpAddr::V4(_) => AF_INET,
+            IpAddr::V6(_) => AF_INET6,
+        };
+        let neighbors = netlink_get_neighbors(Some(if_index as i32), family as u8)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        Ok(match find_neighbor(&neighbors, ip, if_index) {
+            Some(mac) => {
+                cache.insert((ip, if_index), (mac, Instant::now()));
+                Some(mac)
+            }
+            None => {
+                cache.remove(&(ip, if_index));
+                None
+            }
+        })
     }
 }
 
+// older kernels ignore the interface of a neighbor dump request, check it here too
+fn find_neighbor(neighbors: &[NeighborEntry], ip: IpAddr, if_index: u32) -> Option<MacAddress> {
+    neighbors
+        .iter()
+        .find(|n| n.destination == Some(ip) && n.ifindex as u32 == if_index)
+        .and_then(|n| n.lladdr)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -243,6 +374,61 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_lookup_route_ipv6() {
+        let route = |destination: Option<&str>, dst_len, family, out_if_index| RouteEntry {
+            destination: destination.map(|d| d.parse().unwrap()),
+            gateway: None,
+            pref_src: None,
+            out_if_index: Some(out_if_index),
+            in_if_index: None,
+            priority: None,
+            table: None,
+            protocol: 0,
+            scope: 0,
+            type_: 0,
+            family: family as u8,
+            dst_len,
+        };
+        let routes = [
+            route(None, 0, AF_INET, 1),
+            route(Some("10.0.0.0"), 8, AF_INET, 2),
+            route(None, 0, AF_INET6, 3),
+            route(Some("2001:db8::"), 32, AF_INET6, 4),
+        ];
+
+        let lookup = |dest: &str| {
+            lookup_route(&routes, dest.parse().unwrap())
+                .unwrap()
+                .out_if_index
+        };
+        assert_eq!(lookup("10.1.2.3"), Some(2));
+        assert_eq!(lookup("1.1.1.1"), Some(1));
+        assert_eq!(lookup("2001:db8::1"), Some(4));
+        assert_eq!(lookup("2606:4700::1111"), Some(3));
+    }
+
+    #[test]
+    fn test_find_neighbor() {
+        let gateway: IpAddr = "fe80::1".parse().unwrap();
+        let neighbor = |ifindex, mac| NeighborEntry {
+            destination: Some(gateway),
+            lladdr: Some(MacAddress([mac; 6])),
+            ifindex,
+            state: 0,
+        };
+        let neighbors = [neighbor(2, 0xaa), neighbor(3, 0xbb)];
+        assert_eq!(
+            find_neighbor(&neighbors, gateway, 3),
+            Some(MacAddress([0xbb; 6]))
+        );
+        assert_eq!(
+            find_neighbor(&neighbors, gateway, 2),
+            Some(MacAddress([0xaa; 6]))
+        );
+        assert_eq!(find_neighbor(&neighbors, gateway, 4), None);
+    }
+
     #[test]
     fn test_router() {
         let router = Router::new().unwrap();
@@ -2,18 +2,23 @@
 
 use {
     crate::{
-        device::{NetworkDevice, QueueId, RingSizes},
+        caps_check::capability_preflight,
+        device::{NetworkDevice, QueueId, RingSizes, XdpDesc},
+        error::Error,
         set_cpu_affinity,
         socket::Socket,
-        umem::{Frame as _, PageAlignedMemory, SliceUmem, Umem as _},
+        umem::{Frame as _, FrameOffset, PageAlignedMemory, PageSize, SliceUmem, Umem as _},
     },
     caps::{
         CapSet,
         Capability::{CAP_NET_ADMIN, CAP_NET_RAW, CAP_SYS_NICE},
     },
+    crossbeam_channel::Sender,
     libc::{sysconf, _SC_PAGESIZE},
     std::{
         io,
+        ops::{Deref, DerefMut},
+        slice,
         sync::{
             atomic::{AtomicBool, AtomicUsize, Ordering},
             Arc,
@@ -21,11 +26,109 @@ use {
     },
 };
 
+/// aligns `T` to its own cache line, so counters written by different CPUs don't invalidate
+/// each other's lines
+#[derive(Debug, Default)]
+#[repr(align(64))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct RxStats {
-    pub rx_packets: AtomicUsize,
-    pub rx_bytes: AtomicUsize,
+    pub rx_packets: CachePadded<AtomicUsize>,
+    pub rx_bytes: CachePadded<AtomicUsize>,
+    /// packets the kernel dropped for lack of rx ring space, from XDP_STATISTICS. set when
+    /// rx_loop exits
+    pub rx_drops: CachePadded<AtomicUsize>,
+    /// invalid rx descriptors, from XDP_STATISTICS. set when rx_loop exits
+    pub rx_errors: CachePadded<AtomicUsize>,
+    /// times the relay loop found the fill ring below its low watermark mid-poll and refilled it
+    /// early
+    pub fill_low_watermark_hits: CachePadded<AtomicUsize>,
+    /// packets the relay loop dropped for a bad IPv4 or UDP checksum, only counted with the
+    /// checksum-validation feature
+    pub checksum_errors: CachePadded<AtomicUsize>,
+    /// packets StrictRpfFilter dropped because their source routes out of another interface
+    pub rp_filter_drops: CachePadded<AtomicUsize>,
+    /// shreds DeduplicatorFilter dropped because they were already received
+    pub duplicate_drops: CachePadded<AtomicUsize>,
+    /// times the relay loop went around its poll loop, tells a quiet link from a stuck loop
+    pub loop_iterations: CachePadded<AtomicUsize>,
+    /// frames the kernel had to receive into after the relay loop's last refill, 0 means the UMEM
+    /// ran dry
+    pub fill_ring_frames: CachePadded<AtomicUsize>,
+}
+
+impl RxStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// a received packet, still in the UMEM frame the kernel wrote it to
+pub struct UmemSlice<'a> {
+    base: *const u8,
+    desc: XdpDesc,
+    recycler: &'a Sender<FrameOffset>,
+}
+
+impl UmemSlice<'_> {
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        // Safety: the kernel wrote desc.len bytes at desc.addr, and the frame isn't handed back
+        // to the fill ring before the callback returns
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    /// start of the packet, for reading a kept frame after the callback returned. valid until
+    /// the frame is recycled or rx_loop_with_callback returns and unmaps the UMEM
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        unsafe { self.base.add(self.desc.addr as usize) }
+    }
+
+    /// offset of the packet in the UMEM, not necessarily frame aligned
+    #[inline]
+    pub fn offset(&self) -> FrameOffset {
+        FrameOffset(self.desc.addr as usize)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.desc.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.desc.len == 0
+    }
+
+    #[inline]
+    pub fn desc(&self) -> &XdpDesc {
+        &self.desc
+    }
+
+    /// where kept frames go once the caller is done with them, send offset() to recycle the
+    /// frame. it can be cloned and used from any thread
+    pub fn recycler(&self) -> &Sender<FrameOffset> {
+        self.recycler
+    }
 }
 
+/// receive and count packets, dropping them right away
 #[inline(never)]
 pub fn rx_loop(
     cpu_id: usize,
@@ -34,21 +137,41 @@ pub fn rx_loop(
     zero_copy: bool,
     stats: Arc<RxStats>,
     exit: Arc<AtomicBool>,
-) {
+) -> Result<(), Error> {
+    rx_loop_with_callback(cpu_id, dev, queue_id, zero_copy, stats, exit, |_| false)
+}
+
+/// receive packets and hand each one to `callback` without copying it out of the UMEM
+///
+/// the callback returns false to give the frame straight back to the fill ring, or true to keep
+/// it. a kept frame stays out of circulation until its offset is sent to
+/// UmemSlice::recycler(), so a caller that never recycles runs the UMEM dry.
+///
+/// returns an error if the socket can't be set up, e.g. for lack of capabilities.
+#[inline(never)]
+pub fn rx_loop_with_callback(
+    cpu_id: usize,
+    dev: &NetworkDevice,
+    queue_id: QueueId,
+    zero_copy: bool,
+    stats: Arc<RxStats>,
+    exit: Arc<AtomicBool>,
+    mut callback: impl FnMut(UmemSlice) -> bool,
+) -> Result<(), Error> {
     log::info!(
         "starting xdp rx loop on {} queue {queue_id:?} cpu {cpu_id}",
         dev.name()
     );
 
+    capability_preflight()?;
+
     // bind to CPU core
-    set_cpu_affinity([cpu_id]).unwrap();
+    set_cpu_affinity([cpu_id])?;
 
     // some drivers require frame_size=page_size
     let frame_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
 
-    let queue = dev
-        .open_queue(queue_id)
-        .expect("failed to open queue for AF_XDP socket");
+    let queue = dev.open_queue(queue_id)?;
 
     let RingSizes { rx: rx_size, .. } = queue.ring_sizes().unwrap_or_else(|| {
         log::info!(
@@ -61,35 +184,41 @@ pub fn rx_loop(
     let frame_count = rx_size * 2; // double for rx
 
     // try to allocate huge pages first, then fall back to regular pages
-    const HUGE_2MB: usize = 2 * 1024 * 1024;
     let mut memory =
-        PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, HUGE_2MB, true)
+        PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, PageSize::Huge2M)
             .or_else(|_| {
                 log::warn!("huge page alloc failed, falling back to regular page size");
-                PageAlignedMemory::alloc(frame_size, frame_count)
+                PageAlignedMemory::alloc_prefaulted(frame_size, frame_count)
             })
-            .unwrap();
-    let umem = SliceUmem::new(&mut memory, frame_size as u32).unwrap();
+            .map_err(Error::UmemAlloc)?;
+    let umem = SliceUmem::new(&mut memory, frame_size as u32)?;
 
     // we need NET_ADMIN and NET_RAW for the socket
     for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_SYS_NICE] {
-        caps::raise(None, CapSet::Effective, cap).unwrap();
+        caps::raise(None, CapSet::Effective, cap)?;
     }
 
-    let (_min, max) = fifo_priority_bounds().unwrap();
-    set_current_thread_sched_fifo(max).unwrap();
+    let (_min, max) = fifo_priority_bounds()?;
+    set_current_thread_sched_fifo(max)?;
 
-    let Ok((mut socket, rx)) = Socket::rx(queue, umem, zero_copy, rx_size, rx_size) else {
-        panic!("failed to create AF_XDP socket on queue {queue_id:?}");
-    };
+    // rx only, without a tx ring
+    let (mut socket, rx, _tx) = Socket::builder(umem)
+        .zero_copy(zero_copy)
+        .fill_ring_size(rx_size as u32)
+        .rx_ring_size(rx_size as u32)
+        .tx_ring_size(0)
+        .build(queue)
+        .map_err(Error::SocketCreate)?;
 
     let umem = socket.umem();
+    let umem_base = umem.as_ptr();
+    let (recycler, recycled) = crossbeam_channel::unbounded();
     let mut fill = rx.fill;
     let mut rx_ring = rx.ring.unwrap();
 
     // we dont need higher caps?
     for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
-        caps::drop(None, CapSet::Effective, cap).unwrap();
+        caps::drop(None, CapSet::Effective, cap)?;
     }
 
     // pre-fill the RX ring
@@ -112,6 +241,11 @@ pub fn rx_loop(
             break;
         }
 
+        // frames the caller kept and is done with
+        for frame in recycled.try_iter() {
+            umem.release(frame);
+        }
+
         // sync rx ring
         rx_ring.sync(false);
 
@@ -123,8 +257,15 @@ pub fn rx_loop(
             stats.rx_packets.fetch_add(1, Ordering::Relaxed);
             stats.rx_bytes.fetch_add(packet_len, Ordering::Relaxed);
 
-            // release frame back to fill ring
-            umem.release(crate::umem::FrameOffset(desc.addr as usize));
+            let packet = UmemSlice {
+                base: umem_base,
+                desc,
+                recycler: &recycler,
+            };
+            if !callback(packet) {
+                // release frame back to fill ring
+                umem.release(FrameOffset(desc.addr as usize));
+            }
         }
 
         // commit RX ring
@@ -145,6 +286,20 @@ pub fn rx_loop(
         }
         fill.commit();
     }
+
+    match socket.statistics() {
+        Ok(socket_stats) => {
+            stats
+                .rx_drops
+                .store(socket_stats.rx_dropped as usize, Ordering::Relaxed);
+            stats
+                .rx_errors
+                .store(socket_stats.rx_invalid_descs as usize, Ordering::Relaxed);
+        }
+        Err(e) => log::warn!("failed to read socket statistics: {e}"),
+    }
+
+    Ok(())
 }
 
 /// return min/max valid priorities for SCHED_FIFO on this system.
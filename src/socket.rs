@@ -4,13 +4,15 @@ use {
             mmap_ring, DeviceQueue, RingConsumer, RingMmap, RingProducer, RxFillRing,
             TxCompletionRing, XdpDesc,
         },
-        umem::{Frame, Umem},
+        sys::{check_and_raise_memlock, UmemRegistration},
+        umem::{Frame, FrameOffset, ScatterFrame, Umem},
     },
     libc::{
         bind, getsockopt, sa_family_t, sendto, setsockopt, sockaddr, sockaddr_xdp, socket,
-        socklen_t, xdp_mmap_offsets, xdp_umem_reg, AF_XDP, SOCK_RAW, SOL_SOCKET, SOL_XDP, XDP_COPY,
-        XDP_MMAP_OFFSETS, XDP_PGOFF_RX_RING, XDP_PGOFF_TX_RING, XDP_RING_NEED_WAKEUP, XDP_RX_RING,
-        XDP_TX_RING, XDP_UMEM_COMPLETION_RING, XDP_UMEM_FILL_RING, XDP_UMEM_PGOFF_COMPLETION_RING,
+        socklen_t, xdp_mmap_offsets, xdp_statistics, xdp_umem_reg, AF_XDP, SOCK_RAW, SOL_SOCKET,
+        SOL_XDP, XDP_COPY, XDP_MMAP_OFFSETS, XDP_PGOFF_RX_RING, XDP_PGOFF_TX_RING,
+        XDP_RING_NEED_WAKEUP, XDP_RX_RING, XDP_SHARED_UMEM, XDP_STATISTICS, XDP_TX_RING,
+        XDP_UMEM_COMPLETION_RING, XDP_UMEM_FILL_RING, XDP_UMEM_PGOFF_COMPLETION_RING,
         XDP_UMEM_PGOFF_FILL_RING, XDP_USE_NEED_WAKEUP, XDP_ZEROCOPY,
     },
     std::{
@@ -21,6 +23,7 @@ use {
         ptr,
         sync::atomic::Ordering,
     },
+    thiserror::Error,
 };
 
 // socket options for busy-polling:
@@ -31,23 +34,135 @@ const SO_BUSY_POLL: i32 = 46;
 const SO_BUSY_POLL_BUDGET: i32 = 70;
 const SO_PREFER_BUSY_POLL: i32 = 69;
 
+// bind flag for multi-buffer packets, linux 6.6+
+const XDP_USE_SG: u16 = 1 << 4;
+
+/// tx descriptor option, the packet continues in the next descriptor
+pub const XDP_PKT_CONTD: u32 = 1 << 0;
+
+/// per socket counters kept by the kernel, see Socket::statistics()
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XdpSocketStats {
+    /// dropped for reasons other than invalid descriptors, mostly a full rx ring
+    pub rx_dropped: u64,
+    pub rx_invalid_descs: u64,
+    pub tx_invalid_descs: u64,
+    /// the rx ring was full when a packet arrived
+    pub rx_ring_full: u64,
+    /// the fill ring was empty when a packet arrived, ie the UMEM ran out of frames
+    pub rx_fill_ring_empty_descs: u64,
+    /// tx was kicked with nothing on the tx ring
+    pub tx_ring_empty_descs: u64,
+}
+
 pub struct Socket<U: Umem> {
     fd: OwnedFd,
     dev_queue: DeviceQueue,
     umem: U,
+    zero_copy: bool,
+    // set on the socket that registered the UMEM, sockets sharing it don't pin it again
+    _registration: Option<UmemRegistration>,
 }
 
 impl<U: Umem> Socket<U> {
     #[allow(clippy::type_complexity)]
     pub fn new(
         dev_queue: DeviceQueue,
-        mut umem: U,
+        umem: U,
+        zero_copy: bool,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+        Self::create(
+            dev_queue,
+            umem,
+            zero_copy,
+            rx_fill_ring_size,
+            rx_ring_size,
+            tx_completion_ring_size,
+            tx_ring_size,
+            true,
+            false,
+            None,
+        )
+    }
+
+    /// create a socket that shares the UMEM registered by `primary` (XDP_SHARED_UMEM)
+    ///
+    /// `umem` must be backed by the same memory region as the primary's UMEM, usually obtained
+    /// with SliceUmem::split_off(). the socket must be bound to a different queue or device
+    /// than the primary as it gets its own fill and completion rings, see new_shared_queue() for
+    /// the same queue.
+    ///
+    /// the kernel doesn't take a copy/zero-copy mode for sockets sharing a UMEM, they always run
+    /// in the primary's mode. `zero_copy` works like in new(): it's only a request and copy mode
+    /// is used if the primary couldn't get zero copy, but asking for copy mode fails when the
+    /// primary is in zero copy mode.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    pub fn new_shared_umem(
+        primary: &Socket<U>,
+        dev_queue: DeviceQueue,
+        umem: U,
         zero_copy: bool,
         rx_fill_ring_size: usize,
         rx_ring_size: usize,
         tx_completion_ring_size: usize,
         tx_ring_size: usize,
     ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+        if !ptr::eq(umem.as_ptr(), primary.umem.as_ptr()) || umem.len() != primary.umem.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shared UMEM must use the same memory region as the primary socket",
+            ));
+        }
+
+        if !zero_copy && primary.zero_copy {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "copy mode requested but the primary socket shares its UMEM in zero copy mode",
+            ));
+        }
+
+        // the primary's mode also decides whether the fill ring gets pre-populated
+        Self::create(
+            dev_queue,
+            umem,
+            primary.zero_copy,
+            rx_fill_ring_size,
+            rx_ring_size,
+            tx_completion_ring_size,
+            tx_ring_size,
+            true,
+            false,
+            Some(primary.fd.as_raw_fd()),
+        )
+    }
+
+    /// create a socket bound to the same device and queue as `primary`, sharing its UMEM
+    ///
+    /// sockets on the same queue share the primary's fill and completion rings, so this one only
+    /// gets an rx ring. frames read from it must be returned through the primary's fill ring.
+    /// `umem` must be backed by the same memory region as the primary's UMEM.
+    pub fn new_shared_queue(
+        primary: &Socket<U>,
+        umem: U,
+        rx_ring_size: usize,
+    ) -> Result<(Self, RxRing), io::Error> {
+        if !ptr::eq(umem.as_ptr(), primary.umem.as_ptr()) || umem.len() != primary.umem.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shared UMEM must use the same memory region as the primary socket",
+            ));
+        }
+
+        let dev_queue = DeviceQueue::new(
+            primary.dev_queue.if_index(),
+            primary.dev_queue.id(),
+            primary.dev_queue.ring_sizes(),
+        );
+
         unsafe {
             let fd = socket(AF_XDP, SOCK_RAW, 0);
             if fd < 0 {
@@ -55,34 +170,196 @@ impl<U: Umem> Socket<U> {
             }
             let fd = OwnedFd::from_raw_fd(fd);
 
-            let reg = xdp_umem_reg {
-                addr: umem.as_ptr() as u64,
-                len: umem.len() as u64,
-                chunk_size: umem.frame_size() as u32,
-                headroom: 0,
-                flags: 0,
-                tx_metadata_len: 0,
+            if setsockopt(
+                fd.as_raw_fd(),
+                SOL_XDP,
+                XDP_RX_RING,
+                &rx_ring_size as *const _ as *const libc::c_void,
+                mem::size_of::<u32>() as socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut offsets: xdp_mmap_offsets = mem::zeroed();
+            let mut optlen = mem::size_of::<xdp_mmap_offsets>() as socklen_t;
+            if getsockopt(
+                fd.as_raw_fd(),
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut _ as *mut libc::c_void,
+                &mut optlen,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            let rx_ring = RxRing::new(
+                mmap_ring(
+                    fd.as_raw_fd(),
+                    rx_ring_size.saturating_mul(mem::size_of::<XdpDesc>()),
+                    &offsets.rx,
+                    XDP_PGOFF_RX_RING as u64,
+                )?,
+                rx_ring_size as u32,
+                fd.as_raw_fd(),
+            );
+
+            let sxdp = sockaddr_xdp {
+                sxdp_family: AF_XDP as sa_family_t,
+                sxdp_flags: XDP_SHARED_UMEM,
+                sxdp_ifindex: dev_queue.if_index(),
+                sxdp_queue_id: dev_queue.id().0 as u32,
+                sxdp_shared_umem_fd: primary.fd.as_raw_fd() as u32,
             };
 
-            if setsockopt(
+            if bind(
                 fd.as_raw_fd(),
-                libc::SOL_XDP,
-                libc::XDP_UMEM_REG,
-                &reg as *const _ as *const libc::c_void,
-                mem::size_of::<xdp_umem_reg>() as libc::socklen_t,
+                &sxdp as *const _ as *const sockaddr,
+                mem::size_of::<sockaddr_xdp>() as socklen_t,
             ) < 0
             {
                 return Err(io::Error::last_os_error());
             }
 
+            Ok((
+                Self {
+                    fd,
+                    dev_queue,
+                    umem,
+                    zero_copy: primary.zero_copy,
+                    _registration: None,
+                },
+                rx_ring,
+            ))
+        }
+    }
+
+    /// with `zero_copy` the socket is created in zero copy mode if the driver supports it and in
+    /// copy mode otherwise, see zero_copy_supported()
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn create(
+        dev_queue: DeviceQueue,
+        mut umem: U,
+        zero_copy: bool,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+        need_wakeup: bool,
+        scatter_gather: bool,
+        shared_umem_fd: Option<RawFd>,
+    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+        // registering the UMEM pins it, CAP_IPC_LOCK skips the limit so don't give up here
+        let registers_umem = shared_umem_fd.is_none();
+        if registers_umem {
+            if let Err(e) = check_and_raise_memlock(umem.len()) {
+                log::warn!("{e}");
+            }
+        }
+
+        let mut create = |zero_copy| {
+            Self::create_mode(
+                &dev_queue,
+                &mut umem,
+                zero_copy,
+                rx_fill_ring_size,
+                rx_ring_size,
+                tx_completion_ring_size,
+                tx_ring_size,
+                need_wakeup,
+                scatter_gather,
+                shared_umem_fd,
+            )
+        };
+
+        // sockets sharing a UMEM inherit the mode, they can't fall back on their own. ENOTSUP
+        // is the same errno as EOPNOTSUPP on linux
+        let result = match create(zero_copy) {
+            Err(e)
+                if zero_copy
+                    && shared_umem_fd.is_none()
+                    && e.raw_os_error() == Some(libc::EOPNOTSUPP) =>
+            {
+                log::warn!(
+                    "zero copy not supported on if_index {} queue {}: {e}, falling back to copy \
+                     mode",
+                    dev_queue.if_index(),
+                    dev_queue.id().0,
+                );
+                create(false).map(|created| (created, false))
+            }
+            result => result.map(|created| (created, zero_copy)),
+        };
+        let ((fd, rx, tx), zero_copy) = result?;
+        let registration = registers_umem.then(|| UmemRegistration::new(umem.len()));
+
+        Ok((
+            Self {
+                fd,
+                dev_queue,
+                umem,
+                zero_copy,
+                _registration: registration,
+            },
+            rx,
+            tx,
+        ))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn create_mode(
+        dev_queue: &DeviceQueue,
+        umem: &mut U,
+        zero_copy: bool,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+        need_wakeup: bool,
+        scatter_gather: bool,
+        shared_umem_fd: Option<RawFd>,
+    ) -> Result<(OwnedFd, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+        // bind fails without a completion ring next to the fill ring, even on rx only sockets
+        let tx_completion_ring_size = tx_completion_ring_size.max(1);
+        unsafe {
+            let fd = socket(AF_XDP, SOCK_RAW, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+
+            // with a shared UMEM the region is already registered on the primary socket
+            if shared_umem_fd.is_none() {
+                let reg = xdp_umem_reg {
+                    addr: umem.as_ptr() as u64,
+                    len: umem.len() as u64,
+                    chunk_size: umem.frame_size() as u32,
+                    headroom: 0,
+                    flags: 0,
+                    tx_metadata_len: 0,
+                };
+
+                if setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_XDP,
+                    libc::XDP_UMEM_REG,
+                    &reg as *const _ as *const libc::c_void,
+                    mem::size_of::<xdp_umem_reg>() as libc::socklen_t,
+                ) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
             for (ring, size) in [
                 (XDP_UMEM_COMPLETION_RING, tx_completion_ring_size),
                 (XDP_UMEM_FILL_RING, rx_fill_ring_size),
                 (XDP_TX_RING, tx_ring_size),
                 (XDP_RX_RING, rx_ring_size),
             ] {
-                if ring == XDP_RX_RING && size == 0 {
-                    // tx only
+                if (ring == XDP_RX_RING || ring == XDP_TX_RING) && size == 0 {
+                    // tx or rx only
                     continue;
                 }
 
@@ -173,28 +450,39 @@ impl<U: Umem> Socket<U> {
                 fd.as_raw_fd(),
             );
 
+            // handed back to the UMEM if the socket isn't created, so a retry in copy mode
+            // finds them
+            let mut prefilled = PrefilledFrames {
+                umem,
+                frames: Vec::new(),
+            };
             if zero_copy {
                 // most drivers (intel) are buggy if ZC is enabled and the fill ring is not
                 // pre-populated before calling bind()
                 for _ in 0..rx_fill_ring_size {
-                    let Some(frame) = umem.reserve() else {
+                    let Some(frame) = prefilled.umem.reserve() else {
                         return Err(io::Error::other("Failed to reserve frame for RX fill ring"));
                     };
+                    prefilled.frames.push(frame.offset());
                     rx_fill_ring.write(frame)?;
                 }
                 rx_fill_ring.commit();
             }
 
-            let tx_ring = Some(TxRing::new(
-                mmap_ring(
+            let tx_ring = if tx_ring_size > 0 {
+                Some(TxRing::new(
+                    mmap_ring(
+                        fd.as_raw_fd(),
+                        tx_ring_size.saturating_mul(mem::size_of::<XdpDesc>()),
+                        &offsets.tx,
+                        XDP_PGOFF_TX_RING as u64,
+                    )?,
+                    tx_ring_size as u32,
                     fd.as_raw_fd(),
-                    tx_ring_size.saturating_mul(mem::size_of::<XdpDesc>()),
-                    &offsets.tx,
-                    XDP_PGOFF_TX_RING as u64,
-                )?,
-                tx_ring_size as u32,
-                fd.as_raw_fd(),
-            ));
+                ))
+            } else {
+                None
+            };
 
             let rx_ring = if rx_ring_size > 0 {
                 Some(RxRing::new(
@@ -211,13 +499,26 @@ impl<U: Umem> Socket<U> {
                 None
             };
 
-            let sxdp = sockaddr_xdp {
-                sxdp_family: AF_XDP as sa_family_t,
-                // do NEED_WAKEUP and don't do zero copy for now for maximum compatibility
-                sxdp_flags: XDP_USE_NEED_WAKEUP | if zero_copy { XDP_ZEROCOPY } else { XDP_COPY },
-                sxdp_ifindex: dev_queue.if_index(),
-                sxdp_queue_id: dev_queue.id().0 as u32,
-                sxdp_shared_umem_fd: 0,
+            let sxdp = match shared_umem_fd {
+                // the kernel rejects mode, wakeup and sg flags together with XDP_SHARED_UMEM,
+                // they are inherited from the primary socket
+                Some(shared_fd) => sockaddr_xdp {
+                    sxdp_family: AF_XDP as sa_family_t,
+                    sxdp_flags: XDP_SHARED_UMEM,
+                    sxdp_ifindex: dev_queue.if_index(),
+                    sxdp_queue_id: dev_queue.id().0 as u32,
+                    sxdp_shared_umem_fd: shared_fd as u32,
+                },
+                None => sockaddr_xdp {
+                    sxdp_family: AF_XDP as sa_family_t,
+                    // do NEED_WAKEUP and don't do zero copy for now for maximum compatibility
+                    sxdp_flags: if need_wakeup { XDP_USE_NEED_WAKEUP } else { 0 }
+                        | if zero_copy { XDP_ZEROCOPY } else { XDP_COPY }
+                        | if scatter_gather { XDP_USE_SG } else { 0 },
+                    sxdp_ifindex: dev_queue.if_index(),
+                    sxdp_queue_id: dev_queue.id().0 as u32,
+                    sxdp_shared_umem_fd: 0,
+                },
             };
 
             if bind(
@@ -229,6 +530,9 @@ impl<U: Umem> Socket<U> {
                 return Err(io::Error::last_os_error());
             }
 
+            // the frames belong to the fill ring now
+            prefilled.frames.clear();
+
             let tx = Tx {
                 completion: tx_completion_ring,
                 ring: tx_ring,
@@ -237,15 +541,7 @@ impl<U: Umem> Socket<U> {
                 fill: rx_fill_ring,
                 ring: rx_ring,
             };
-            Ok((
-                Self {
-                    fd,
-                    dev_queue,
-                    umem,
-                },
-                rx,
-                tx,
-            ))
+            Ok((fd, rx, tx))
         }
     }
 
@@ -290,6 +586,10 @@ impl<U: Umem> Socket<U> {
         Ok((socket, rx))
     }
 
+    pub fn builder(umem: U) -> SocketBuilder<U> {
+        SocketBuilder::new(umem)
+    }
+
     pub fn queue(&self) -> &DeviceQueue {
         &self.dev_queue
     }
@@ -297,6 +597,195 @@ impl<U: Umem> Socket<U> {
     pub fn umem(&mut self) -> &mut U {
         &mut self.umem
     }
+
+    /// true if the socket runs in zero copy mode. false when zero copy wasn't requested or the
+    /// driver doesn't support it and the socket fell back to copy mode
+    pub fn zero_copy_supported(&self) -> bool {
+        self.zero_copy
+    }
+
+    /// busy-poll the NIC for up to `micros` before blocking, 0 disables busy-polling
+    pub fn set_busy_poll(&self, micros: u32) -> io::Result<()> {
+        self.set_socket_option(SO_BUSY_POLL, micros.min(i32::MAX as u32) as i32)
+    }
+
+    /// prefer busy-polling over interrupt driven processing
+    pub fn set_prefer_busy_poll(&self, enable: bool) -> io::Result<()> {
+        self.set_socket_option(SO_PREFER_BUSY_POLL, enable as i32)
+    }
+
+    /// read the socket's XDP_STATISTICS counters
+    pub fn statistics(&self) -> io::Result<XdpSocketStats> {
+        unsafe {
+            let mut stats: xdp_statistics = mem::zeroed();
+            let mut optlen = mem::size_of::<xdp_statistics>() as socklen_t;
+            if getsockopt(
+                self.fd.as_raw_fd(),
+                SOL_XDP,
+                XDP_STATISTICS,
+                &mut stats as *mut _ as *mut libc::c_void,
+                &mut optlen,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            // kernels before 5.9 only fill in the first three counters and shrink optlen, the
+            // rest stays zeroed
+            Ok(XdpSocketStats {
+                rx_dropped: stats.rx_dropped,
+                rx_invalid_descs: stats.rx_invalid_descs,
+                tx_invalid_descs: stats.tx_invalid_descs,
+                rx_ring_full: stats.rx_ring_full,
+                rx_fill_ring_empty_descs: stats.rx_fill_ring_empty_descs,
+                tx_ring_empty_descs: stats.tx_ring_empty_descs,
+            })
+        }
+    }
+
+    /// the socket's inode, identifies it in netlink_get_xdp_sockets() and ss --xdp
+    pub fn inode(&self) -> io::Result<u32> {
+        unsafe {
+            let mut stat: libc::stat = mem::zeroed();
+            if libc::fstat(self.fd.as_raw_fd(), &mut stat) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // sockfs inode numbers are 32 bit
+            Ok(stat.st_ino as u32)
+        }
+    }
+
+    fn set_socket_option(&self, name: i32, value: i32) -> io::Result<()> {
+        unsafe {
+            if setsockopt(
+                self.fd.as_raw_fd(),
+                SOL_SOCKET,
+                name,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of::<i32>() as socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// builds a Socket, ring sizes that aren't set default to the queue's ring sizes (see
+/// DeviceQueue::ring_sizes()) with a completion ring twice the size of the tx ring
+pub struct SocketBuilder<U: Umem> {
+    umem: U,
+    zero_copy: bool,
+    need_wakeup: bool,
+    scatter_gather: bool,
+    busy_poll_us: Option<u32>,
+    fill_ring_size: Option<u32>,
+    rx_ring_size: Option<u32>,
+    completion_ring_size: Option<u32>,
+    tx_ring_size: Option<u32>,
+}
+
+impl<U: Umem> SocketBuilder<U> {
+    pub fn new(umem: U) -> Self {
+        Self {
+            umem,
+            zero_copy: false,
+            need_wakeup: true,
+            scatter_gather: false,
+            busy_poll_us: None,
+            fill_ring_size: None,
+            rx_ring_size: None,
+            completion_ring_size: None,
+            tx_ring_size: None,
+        }
+    }
+
+    pub fn rx_ring_size(mut self, size: u32) -> Self {
+        self.rx_ring_size = Some(size);
+        self
+    }
+
+    /// 0 leaves out the tx ring, the completion ring then defaults to the smallest one the kernel
+    /// takes
+    pub fn tx_ring_size(mut self, size: u32) -> Self {
+        self.tx_ring_size = Some(size);
+        self
+    }
+
+    pub fn fill_ring_size(mut self, size: u32) -> Self {
+        self.fill_ring_size = Some(size);
+        self
+    }
+
+    pub fn completion_ring_size(mut self, size: u32) -> Self {
+        self.completion_ring_size = Some(size);
+        self
+    }
+
+    pub fn zero_copy(mut self, zero_copy: bool) -> Self {
+        self.zero_copy = zero_copy;
+        self
+    }
+
+    /// set XDP_USE_NEED_WAKEUP, on by default
+    pub fn need_wakeup(mut self, need_wakeup: bool) -> Self {
+        self.need_wakeup = need_wakeup;
+        self
+    }
+
+    /// set XDP_USE_SG to send packets larger than a frame with TxRing::write_scatter(). bind
+    /// fails with EOPNOTSUPP on kernels and zero copy drivers without multi-buffer support
+    pub fn scatter_gather(mut self, scatter_gather: bool) -> Self {
+        self.scatter_gather = scatter_gather;
+        self
+    }
+
+    /// busy-poll timeout in microseconds, see Socket::set_busy_poll(). 0 turns busy-polling off
+    pub fn busy_poll_us(mut self, micros: u32) -> Self {
+        self.busy_poll_us = Some(micros);
+        self
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn build(
+        self,
+        queue: DeviceQueue,
+    ) -> Result<(Socket<U>, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+        let ring_sizes = queue.ring_sizes().unwrap_or_default();
+        let rx_ring_size = self
+            .rx_ring_size
+            .map_or(ring_sizes.rx, |size| size as usize);
+        let fill_ring_size = self
+            .fill_ring_size
+            .map_or(rx_ring_size, |size| size as usize);
+        let tx_ring_size = self
+            .tx_ring_size
+            .map_or(ring_sizes.tx, |size| size as usize);
+        let completion_ring_size = self
+            .completion_ring_size
+            .map_or(tx_ring_size.saturating_mul(2), |size| size as usize);
+
+        let (socket, rx, tx) = Socket::create(
+            queue,
+            self.umem,
+            self.zero_copy,
+            fill_ring_size,
+            rx_ring_size,
+            completion_ring_size,
+            tx_ring_size,
+            self.need_wakeup,
+            self.scatter_gather,
+            None,
+        )?;
+
+        if let Some(micros) = self.busy_poll_us {
+            socket.set_busy_poll(micros)?;
+            socket.set_prefer_busy_poll(micros > 0)?;
+        }
+
+        Ok((socket, rx, tx))
+    }
 }
 
 impl<U: Umem> AsFd for Socket<U> {
@@ -326,6 +815,29 @@ pub struct TxRing<F: Frame> {
 #[derive(Debug)]
 pub struct RingFull<F: Frame>(pub F);
 
+// frames reserved for the fill ring while creating a socket, released on drop
+struct PrefilledFrames<'a, U: Umem> {
+    umem: &'a mut U,
+    frames: Vec<FrameOffset>,
+}
+
+impl<U: Umem> Drop for PrefilledFrames<'_, U> {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            self.umem.release(frame);
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TxError {
+    #[error("tx ring full, {needed} descriptors needed, {available} available")]
+    RingFull { needed: usize, available: usize },
+
+    #[error("empty scatter frame")]
+    EmptyFrame,
+}
+
 impl<F: Frame> TxRing<F> {
     fn new(mmap: RingMmap<XdpDesc>, size: u32, fd: RawFd) -> Self {
         debug_assert!(size.is_power_of_two());
@@ -343,16 +855,70 @@ impl<F: Frame> TxRing<F> {
         let Some(index) = self.producer.produce() else {
             return Err(RingFull(frame));
         };
+        self.write_desc(index, frame.offset(), frame.len(), options);
+        Ok(())
+    }
+
+    /// write as many of `frames` as fit with the same options, returns how many were written.
+    /// the frames after that are still owned by the caller.
+    #[inline]
+    pub fn write_batch(&mut self, frames: &[F], options: u32) -> usize {
+        let (start, count) = self
+            .producer
+            .produce_batch(frames.len().min(u32::MAX as usize) as u32);
+        for (i, frame) in frames[..count as usize].iter().enumerate() {
+            self.write_desc(
+                start.wrapping_add(i as u32),
+                frame.offset(),
+                frame.len(),
+                options,
+            );
+        }
+        count as usize
+    }
+
+    /// write multi-buffer packets, one descriptor per fragment with XDP_PKT_CONTD set on all
+    /// but the last fragment of each packet. the socket must be bound with XDP_USE_SG, see
+    /// SocketBuilder::scatter_gather().
+    ///
+    /// writes all of `frames` or nothing, so a packet is never left half written.
+    pub fn write_scatter(&mut self, frames: &[ScatterFrame]) -> Result<(), TxError> {
+        if frames.iter().any(|frame| frame.frames().is_empty()) {
+            return Err(TxError::EmptyFrame);
+        }
+        let needed = frames
+            .iter()
+            .map(|frame| frame.frames().len())
+            .sum::<usize>();
+        let available = self.available();
+        if needed > available {
+            return Err(TxError::RingFull { needed, available });
+        }
+
+        let (mut index, _) = self.producer.produce_batch(needed as u32);
+        for frame in frames {
+            let last = frame.frames().len().saturating_sub(1);
+            for (i, (offset, len)) in frame.fragments().enumerate() {
+                let options = if i < last { XDP_PKT_CONTD } else { 0 };
+                self.write_desc(index, offset, len, options);
+                index = index.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// fill in the descriptor at producer `index`, which must have been produced
+    #[inline]
+    fn write_desc(&mut self, index: u32, offset: FrameOffset, len: usize, options: u32) {
         let index = index & self.size.saturating_sub(1);
+        // Safety: index is within the ring so the pointer is valid
         unsafe {
-            let desc = self.mmap.desc.add(index as usize);
-            desc.write(XdpDesc {
-                addr: frame.offset().0 as u64,
-                len: frame.len() as u32,
+            self.mmap.desc.add(index as usize).write(XdpDesc {
+                addr: offset.0 as u64,
+                len: len as u32,
                 options,
             });
         }
-        Ok(())
     }
 
     #[inline]
@@ -431,4 +997,11 @@ impl RxRing {
         let desc = unsafe { *self.mmap.desc.add(index as usize) };
         Some(desc)
     }
+
+    /// the descriptor read() would return next, without consuming it. the kernel doesn't reuse
+    /// the slot until it has been read and committed.
+    pub fn peek(&self) -> Option<&XdpDesc> {
+        let index = self.consumer.peek()? & self.size.saturating_sub(1);
+        Some(unsafe { &*self.mmap.desc.add(index as usize) })
+    }
 }
@@ -0,0 +1,123 @@
+use {
+    libc::{getrlimit, rlimit, setrlimit, RLIMIT_MEMLOCK, RLIM_INFINITY},
+    std::{
+        io,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+};
+
+// UMEM bytes registered by sockets of this process that are still open. the kernel charges them
+// to the user's locked_vm, which userspace can't read
+static REGISTERED_UMEM: AtomicUsize = AtomicUsize::new(0);
+
+/// UMEM registered with XDP_UMEM_REG, counted against RLIMIT_MEMLOCK until dropped
+pub(crate) struct UmemRegistration(usize);
+
+impl UmemRegistration {
+    pub(crate) fn new(bytes: usize) -> Self {
+        REGISTERED_UMEM.fetch_add(bytes, Ordering::Relaxed);
+        Self(bytes)
+    }
+}
+
+impl Drop for UmemRegistration {
+    fn drop(&mut self) {
+        REGISTERED_UMEM.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
+
+/// make sure RLIMIT_MEMLOCK has room to pin another `additional_bytes`, raising the soft limit
+/// if needed
+///
+/// UMEM pages are pinned when the socket registers them and count against RLIMIT_MEMLOCK unless
+/// the process has CAP_IPC_LOCK, together with the UMEM registered before. the hard limit is only
+/// raised if we're allowed to (CAP_SYS_RESOURCE), otherwise this fails with a message saying
+/// what to change.
+pub fn check_and_raise_memlock(additional_bytes: usize) -> io::Result<()> {
+    let required_bytes = REGISTERED_UMEM
+        .load(Ordering::Relaxed)
+        .saturating_add(additional_bytes);
+    let required = required_bytes as libc::rlim_t;
+
+    let mut limit = rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: libc wrapper
+    if unsafe { getrlimit(RLIMIT_MEMLOCK, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if limit.rlim_cur == RLIM_INFINITY || limit.rlim_cur >= required {
+        return Ok(());
+    }
+
+    let raised = rlimit {
+        rlim_cur: required,
+        rlim_max: limit.rlim_max.max(required),
+    };
+    // Safety: libc wrapper
+    if unsafe { setrlimit(RLIMIT_MEMLOCK, &raised) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if limit.rlim_max >= required {
+        return Err(err);
+    }
+
+    // not allowed to raise the hard limit, take what we can get
+    let capped = rlimit {
+        rlim_cur: limit.rlim_max,
+        rlim_max: limit.rlim_max,
+    };
+    // Safety: libc wrapper
+    unsafe { setrlimit(RLIMIT_MEMLOCK, &capped) };
+    Err(io::Error::new(
+        err.kind(),
+        format!(
+            "RLIMIT_MEMLOCK hard limit is {} bytes but {required_bytes} bytes are needed, raise \
+             it with `ulimit -l unlimited` or run with CAP_IPC_LOCK",
+            limit.rlim_max
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn memlock_limit() -> rlimit {
+        let mut limit = rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(unsafe { getrlimit(RLIMIT_MEMLOCK, &mut limit) }, 0);
+        limit
+    }
+
+    #[test]
+    fn test_memlock_raised_for_registered_umem() {
+        const UMEM_SIZE: usize = 1 << 20;
+
+        // lowering the soft limit needs no privileges, raising it again needs the hard limit to
+        // allow it
+        let original = memlock_limit();
+        if original.rlim_max != RLIM_INFINITY && original.rlim_max < 4 * UMEM_SIZE as libc::rlim_t {
+            return;
+        }
+        let lowered = rlimit {
+            rlim_cur: UMEM_SIZE as libc::rlim_t,
+            rlim_max: original.rlim_max,
+        };
+        assert_eq!(unsafe { setrlimit(RLIMIT_MEMLOCK, &lowered) }, 0);
+
+        // the first UMEM fits, the second one needs room for both
+        check_and_raise_memlock(UMEM_SIZE).unwrap();
+        assert_eq!(memlock_limit().rlim_cur, UMEM_SIZE as libc::rlim_t);
+        let registered = UmemRegistration::new(UMEM_SIZE);
+        check_and_raise_memlock(UMEM_SIZE).unwrap();
+        assert_eq!(memlock_limit().rlim_cur, 2 * UMEM_SIZE as libc::rlim_t);
+
+        drop(registered);
+        assert_eq!(unsafe { setrlimit(RLIMIT_MEMLOCK, &original) }, 0);
+    }
+}
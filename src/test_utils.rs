@@ -0,0 +1,473 @@
+#![allow(clippy::arithmetic_side_effects)]
+
+// synthetic and recorded traffic for exercising the rings without a live cluster
+
+use {
+    crate::{
+        device::TxCompletionRing,
+        netlink::MacAddress,
+        packet::{
+            write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE, IP_HEADER_SIZE,
+            UDP_HEADER_SIZE,
+        },
+        pcap::{FrameQueue, PcapReader},
+        socket::TxRing,
+        umem::{Frame, SliceUmem, SliceUmemFrame, Umem},
+    },
+    std::{
+        hint, io,
+        net::Ipv4Addr,
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+/// UDP payload length of the generated shreds
+pub const SHRED_PAYLOAD_SIZE: usize = 1245;
+/// variant byte of a chained merkle data shred
+pub const SHRED_VARIANT_MERKLE_DATA: u8 = 0x90;
+
+const SHRED_VARIANT_OFFSET: usize = 0x40;
+const SHRED_SLOT_OFFSET: usize = 0x41;
+const SHRED_INDEX_OFFSET: usize = 0x49;
+const SRC_PORT: u16 = 8001;
+// replay_pcap spins instead of sleeping when a frame is due this soon
+const REPLAY_SPIN: Duration = Duration::from_millis(1);
+// frames replay_pcap reads ahead of the one being paced
+const REPLAY_READ_AHEAD: usize = 256;
+
+/// total frame length of a generated packet
+pub const GENERATED_PACKET_SIZE: usize =
+    ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + SHRED_PAYLOAD_SIZE;
+
+/// writes UDP packets shaped like solana data shreds into UMEM frames and queues them on a tx ring
+///
+/// the signature and payload are pseudo random, the variant byte is a merkle data shred and the
+/// slot and shred index are set so the packets pass the shred type and slot parsers. shreds are
+/// numbered 0.. within a slot and the slot advances every `SHREDS_PER_SLOT` packets.
+pub struct PacketGenerator<'a, 'b> {
+    umem: &'b mut SliceUmem<'a>,
+    tx_ring: &'b mut TxRing<SliceUmemFrame<'a>>,
+    dest_mac: MacAddress,
+    src_mac: MacAddress,
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    dst_port: u16,
+    slot: u64,
+    index: u32,
+    rng: u64,
+}
+
+impl<'a, 'b> PacketGenerator<'a, 'b> {
+    pub const SHREDS_PER_SLOT: u32 = 1024;
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        umem: &'b mut SliceUmem<'a>,
+        tx_ring: &'b mut TxRing<SliceUmemFrame<'a>>,
+        dest_mac: MacAddress,
+        src_mac: MacAddress,
+        src_ip: Ipv4Addr,
+        dst_ip: Ipv4Addr,
+        dst_port: u16,
+    ) -> Self {
+        assert!(umem.frame_size() >= GENERATED_PACKET_SIZE);
+        Self {
+            umem,
+            tx_ring,
+            dest_mac,
+            src_mac,
+            src_ip,
+            dst_ip,
+            dst_port,
+            slot: 1,
+            index: 0,
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    /// slot and shred index of the next generated packet
+    pub fn next_shred(&self) -> (u64, u32) {
+        (self.slot, self.index)
+    }
+
+    /// generate up to `n` packets and commit them to the tx ring, returns how many were queued.
+    /// generation stops early when the UMEM or the tx ring runs out of room.
+    pub fn generate_burst(&mut self, n: usize) -> usize {
+        self.tx_ring.sync(false);
+        let mut queued = 0;
+        while queued < n {
+            let Some(mut frame) = self.umem.reserve() else {
+                break;
+            };
+            frame.set_len(GENERATED_PACKET_SIZE);
+            self.write_packet(&frame);
+
+            if let Err(full) = self.tx_ring.write(frame, 0) {
+                self.umem.release(full.0.offset());
+                break;
+            }
+            queued += 1;
+            self.advance();
+        }
+        self.tx_ring.commit();
+        if queued > 0 && self.tx_ring.needs_wakeup() {
+            let _ = self.tx_ring.wake();
+        }
+        queued
+    }
+
+    fn write_packet(&mut self, frame: &SliceUmemFrame<'a>) {
+        let (slot, index) = self.next_shred();
+        let mut rng = self.rng;
+        let packet = self.umem.map_frame_mut(frame);
+
+        write_eth_header(packet, &self.src_mac.0, &self.dest_mac.0);
+        write_ip_header(
+            &mut packet[ETH_HEADER_SIZE..],
+            &self.src_ip,
+            &self.dst_ip,
+            (UDP_HEADER_SIZE + SHRED_PAYLOAD_SIZE) as u16,
+        );
+        write_udp_header(
+            &mut packet[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &self.src_ip,
+            SRC_PORT,
+            &self.dst_ip,
+            self.dst_port,
+            SHRED_PAYLOAD_SIZE as u16,
+            false,
+        );
+
+        let shred = &mut packet[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..];
+        for chunk in shred.chunks_mut(8) {
+            rng = xorshift64(rng);
+            chunk.copy_from_slice(&rng.to_le_bytes()[..chunk.len()]);
+        }
+        shred[SHRED_VARIANT_OFFSET] = SHRED_VARIANT_MERKLE_DATA;
+        shred[SHRED_SLOT_OFFSET..SHRED_SLOT_OFFSET + 8].copy_from_slice(&slot.to_le_bytes());
+        shred[SHRED_INDEX_OFFSET..SHRED_INDEX_OFFSET + 4].copy_from_slice(&index.to_le_bytes());
+
+        self.rng = rng;
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+        if self.index == Self::SHREDS_PER_SLOT {
+            self.index = 0;
+            self.slot += 1;
+        }
+    }
+}
+
+/// queue the frames of a capture on `tx_ring`, spaced like they were recorded
+///
+/// the gap between two frames is the recorded gap divided by `replay_rate`, None replays in real
+/// time and `f64::INFINITY` as fast as the ring takes them. frames that don't fit a UMEM frame are
+/// skipped. completed frames are taken back from `completion` whenever the UMEM or the ring run
+/// out. the file is read on a separate thread, so pacing never waits for the disk. returns the
+/// number of frames queued, or the error that cut the capture short.
+pub fn replay_pcap<'a>(
+    reader: &mut PcapReader,
+    tx_ring: &mut TxRing<SliceUmemFrame<'a>>,
+    completion: &mut TxCompletionRing,
+    umem: &mut SliceUmem<'a>,
+    replay_rate: Option<f64>,
+) -> io::Result<u64> {
+    let replay_rate = replay_rate.unwrap_or(1.0);
+    assert!(replay_rate > 0.0, "replay rate must be positive");
+
+    let FrameQueue {
+        full_tx,
+        full_rx,
+        free_tx,
+        free_rx,
+    } = FrameQueue::new(REPLAY_READ_AHEAD);
+    let read_ahead = &mut *reader;
+    let queued = thread::scope(|scope| {
+        scope.spawn(move || {
+            while let Ok(mut data) = free_rx.recv() {
+                let Some(ts_ns) = read_ahead.next_packet_into(&mut data) else {
+                    break;
+                };
+                if full_tx.send((data, ts_ns)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let mut first_ts = None;
+        let mut queued = 0;
+        for (packet, ts_ns) in full_rx {
+            if packet.len() > umem.frame_size() {
+                let _ = free_tx.send(packet);
+                continue;
+            }
+
+            let first_ts = *first_ts.get_or_insert(ts_ns);
+            let due =
+                start + Duration::from_nanos(ts_ns.saturating_sub(first_ts)).div_f64(replay_rate);
+            // sleep through most of the gap and spin the rest, sleeps overshoot by tens of us
+            loop {
+                let now = Instant::now();
+                if now >= due {
+                    break;
+                }
+                match (due - now).checked_sub(REPLAY_SPIN) {
+                    Some(sleep) if !sleep.is_zero() => thread::sleep(sleep),
+                    _ => hint::spin_loop(),
+                }
+            }
+
+            while tx_ring.available() == 0 || umem.available() == 0 {
+                completion.sync(true);
+                tx_ring.sync(false);
+                while let Some(frame_offset) = completion.read() {
+                    umem.release(frame_offset);
+                }
+                if tx_ring.available() > 0 && umem.available() > 0 {
+                    break;
+                }
+                if tx_ring.needs_wakeup() {
+                    let _ = tx_ring.wake();
+                }
+            }
+
+            let mut frame = umem.reserve().expect("UMEM has a free frame");
+            frame.set_len(packet.len());
+            umem.map_frame_mut(&frame).copy_from_slice(&packet);
+            if tx_ring.write(frame, 0).is_err() {
+                unreachable!("tx ring has room");
+            }
+            tx_ring.commit();
+            if tx_ring.needs_wakeup() {
+                let _ = tx_ring.wake();
+            }
+            queued += 1;
+            let _ = free_tx.send(packet);
+        }
+        queued
+    });
+
+    match reader.take_error() {
+        Some(e) => Err(e),
+        None => Ok(queued),
+    }
+}
+
+#[inline]
+fn xorshift64(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            add_udp_port,
+            device::{NetworkDevice, QueueId},
+            insert_socket_into_xskmap, load_xdp_program,
+            pcap::CaptureWriter,
+            socket::{Rx, Socket, Tx},
+            umem::PageAlignedMemory,
+        },
+        std::{
+            fs,
+            os::fd::{AsFd, AsRawFd},
+            thread,
+            time::{Duration, Instant},
+        },
+    };
+
+    // tx on a copy mode socket bound to lo goes through loopback_xmit and comes back in on the
+    // same queue, where the XDP program redirects it into the socket's own rx ring
+    #[test]
+    #[ignore = "needs root and the eBPF object built by xdp-ebpf/build_ebpf.sh"]
+    fn test_generated_shreds_round_trip_on_loopback() {
+        const FRAME_SIZE: usize = 2048;
+        const RING_SIZE: usize = 64;
+        const BURST: usize = 32;
+        const DST_PORT: u16 = 18001;
+
+        let dev = NetworkDevice::new("lo").unwrap();
+        let mut xdp_program = load_xdp_program(dev.if_index()).unwrap();
+        add_udp_port(&mut xdp_program, DST_PORT).unwrap();
+
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, RING_SIZE * 2).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (mut socket, rx, tx) = Socket::builder(umem)
+            .fill_ring_size(RING_SIZE as u32)
+            .rx_ring_size(RING_SIZE as u32)
+            .completion_ring_size(RING_SIZE as u32)
+            .tx_ring_size(RING_SIZE as u32)
+            .build(dev.open_queue(QueueId(0)).unwrap())
+            .unwrap();
+        insert_socket_into_xskmap(&mut xdp_program, 0, socket.as_fd().as_raw_fd()).unwrap();
+
+        let Rx { mut fill, ring } = rx;
+        let mut rx_ring = ring.unwrap();
+        let Tx { ring, .. } = tx;
+        let mut tx_ring = ring.unwrap();
+
+        // half the frames receive, the other half transmit
+        let umem = socket.umem();
+        for _ in 0..RING_SIZE {
+            fill.write(umem.reserve().unwrap()).unwrap();
+        }
+        fill.commit();
+
+        let localhost = Ipv4Addr::LOCALHOST;
+        let mut generator = PacketGenerator::new(
+            umem,
+            &mut tx_ring,
+            MacAddress([0; 6]),
+            MacAddress([0; 6]),
+            localhost,
+            localhost,
+            DST_PORT,
+        );
+        assert_eq!(generator.generate_burst(BURST), BURST);
+        assert_eq!(generator.next_shred(), (1, BURST as u32));
+
+        let payload_offset = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while received.len() < BURST && Instant::now() < deadline {
+            rx_ring.sync(false);
+            while let Some(desc) = rx_ring.read() {
+                assert_eq!(desc.len as usize, GENERATED_PACKET_SIZE);
+                let packet = unsafe {
+                    std::slice::from_raw_parts(
+                        socket.umem().as_ptr().add(desc.addr as usize),
+                        desc.len as usize,
+                    )
+                };
+                let shred = &packet[payload_offset..];
+                assert_eq!(shred[SHRED_VARIANT_OFFSET], SHRED_VARIANT_MERKLE_DATA);
+                assert_eq!(
+                    shred[SHRED_SLOT_OFFSET..SHRED_SLOT_OFFSET + 8],
+                    1u64.to_le_bytes()
+                );
+                let index = &shred[SHRED_INDEX_OFFSET..SHRED_INDEX_OFFSET + 4];
+                received.push(u32::from_le_bytes(index.try_into().unwrap()));
+            }
+            rx_ring.commit();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        received.sort_unstable();
+        assert_eq!(received, (0..BURST as u32).collect::<Vec<_>>());
+    }
+
+    // frames written with CaptureWriter and replayed onto lo come back in unchanged, through
+    // the same loopback path as test_generated_shreds_round_trip_on_loopback
+    #[test]
+    #[ignore = "needs root and the eBPF object built by xdp-ebpf/build_ebpf.sh"]
+    fn test_replay_pcap_round_trip_on_loopback() {
+        const FRAME_SIZE: usize = 2048;
+        const RING_SIZE: usize = 64;
+        const FRAMES: usize = 32;
+        const PAYLOAD_SIZE: usize = 100;
+        const FRAME_LEN: usize = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + PAYLOAD_SIZE;
+        const DST_PORT: u16 = 18002;
+
+        // distinct payloads so every frame can be told apart
+        let localhost = Ipv4Addr::LOCALHOST;
+        let frames: Vec<Vec<u8>> = (0..FRAMES)
+            .map(|i| {
+                let mut frame = vec![i as u8; FRAME_LEN];
+                write_eth_header(&mut frame, &[0; 6], &[0; 6]);
+                write_ip_header(
+                    &mut frame[ETH_HEADER_SIZE..],
+                    &localhost,
+                    &localhost,
+                    (UDP_HEADER_SIZE + PAYLOAD_SIZE) as u16,
+                );
+                write_udp_header(
+                    &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+                    &localhost,
+                    SRC_PORT,
+                    &localhost,
+                    DST_PORT,
+                    PAYLOAD_SIZE as u16,
+                    false,
+                );
+                frame
+            })
+            .collect();
+
+        let path = std::env::temp_dir().join(format!("axdp-replay-{}.pcap", std::process::id()));
+        let mut writer = CaptureWriter::new(&path).unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            writer.write_packet(frame, 1_000_000 * i as u64).unwrap();
+        }
+        drop(writer);
+        let mut reader = PcapReader::new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let dev = NetworkDevice::new("lo").unwrap();
+        let mut xdp_program = load_xdp_program(dev.if_index()).unwrap();
+        add_udp_port(&mut xdp_program, DST_PORT).unwrap();
+
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, RING_SIZE * 2).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (mut socket, rx, tx) = Socket::builder(umem)
+            .fill_ring_size(RING_SIZE as u32)
+            .rx_ring_size(RING_SIZE as u32)
+            .completion_ring_size(RING_SIZE as u32)
+            .tx_ring_size(RING_SIZE as u32)
+            .build(dev.open_queue(QueueId(0)).unwrap())
+            .unwrap();
+        insert_socket_into_xskmap(&mut xdp_program, 0, socket.as_fd().as_raw_fd()).unwrap();
+
+        let Rx { mut fill, ring } = rx;
+        let mut rx_ring = ring.unwrap();
+        let Tx {
+            mut completion,
+            ring,
+        } = tx;
+        let mut tx_ring = ring.unwrap();
+
+        // half the frames receive, the other half transmit
+        let umem = socket.umem();
+        for _ in 0..RING_SIZE {
+            fill.write(umem.reserve().unwrap()).unwrap();
+        }
+        fill.commit();
+
+        let queued = replay_pcap(
+            &mut reader,
+            &mut tx_ring,
+            &mut completion,
+            umem,
+            Some(f64::INFINITY),
+        )
+        .unwrap();
+        assert_eq!(queued, FRAMES as u64);
+
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while received.len() < FRAMES && Instant::now() < deadline {
+            rx_ring.sync(false);
+            while let Some(desc) = rx_ring.read() {
+                let packet = unsafe {
+                    std::slice::from_raw_parts(
+                        socket.umem().as_ptr().add(desc.addr as usize),
+                        desc.len as usize,
+                    )
+                };
+                received.push(packet.to_vec());
+            }
+            rx_ring.commit();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        received.sort_unstable_by_key(|frame| frame[frame.len() - 1]);
+        assert_eq!(received, frames);
+    }
+}
@@ -9,9 +9,10 @@ use {
             UDP_HEADER_SIZE,
         },
         route::Router,
+        rx_loop::CachePadded,
         set_cpu_affinity,
         socket::{Socket, Tx, TxRing},
-        umem::{Frame as _, PageAlignedMemory, SliceUmem, SliceUmemFrame, Umem as _},
+        umem::{Frame as _, PageAlignedMemory, PageSize, SliceUmem, SliceUmemFrame, Umem as _},
     },
     caps::{
         CapSet,
@@ -22,11 +23,33 @@ use {
     std::{
         io,
         net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::atomic::AtomicUsize,
         thread,
         time::Duration,
     },
 };
 
+#[derive(Debug, Default)]
+pub struct TxStats {
+    /// frames written to the tx ring
+    pub tx_packets: CachePadded<AtomicUsize>,
+    pub tx_bytes: CachePadded<AtomicUsize>,
+    /// frames dropped because the tx ring or the queue in front of it was full
+    pub tx_drops: CachePadded<AtomicUsize>,
+    /// frames the kernel reported sent through the completion ring
+    pub tx_completions: CachePadded<AtomicUsize>,
+    /// times the relay loop restarted because a tx frame wasn't completed in time
+    pub tx_timeout_count: CachePadded<AtomicUsize>,
+    /// fanout copies the relay loop dropped because their destination was over its rate limit
+    pub rate_limited_drops: CachePadded<AtomicUsize>,
+}
+
+impl TxStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
 #[inline(never)]
 #[allow(clippy::too_many_arguments)]
 pub fn tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
@@ -80,9 +103,8 @@ pub fn tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
     let frame_count = rx_size + tx_size;
 
     // try to allocate huge pages first, then fall back to regular pages
-    const HUGE_2MB: usize = 2 * 1024 * 1024;
     let mut memory =
-        PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, HUGE_2MB, true)
+        PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, PageSize::Huge2M)
             .or_else(|_| {
                 log::warn!("huge page alloc failed, falling back to regular page size");
                 PageAlignedMemory::alloc(frame_size, frame_count)
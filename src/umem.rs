@@ -1,6 +1,7 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 use {
+    crossbeam_channel::{Receiver, Sender},
     libc::{munmap, sysconf, _SC_PAGESIZE},
     std::{
         ffi::c_void,
@@ -9,12 +10,87 @@ use {
         ops::{Deref, DerefMut},
         ptr, slice,
         iter::FromIterator,
+        sync::{
+            atomic::{fence, AtomicI32, Ordering},
+            Arc,
+        },
     },
+    thiserror::Error,
 };
 
+// smallest chunk the kernel accepts in XDP_UMEM_REG
+const MIN_FRAME_SIZE: u32 = 2048;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum UmemError {
+    #[error("invalid UMEM frame size {got}, must be {requirement}")]
+    InvalidFrameSize { got: u32, requirement: &'static str },
+
+    #[error("UMEM of {frame_count} frames of {frame_size} bytes overflows usize")]
+    SizeOverflow {
+        frame_size: usize,
+        frame_count: usize,
+    },
+
+    #[error(
+        "UMEM frame size {frame_size} is larger than the {page_size} byte pages backing it, \
+         frames larger than a page need hugepage backed UMEM"
+    )]
+    FrameExceedsPage { frame_size: u32, page_size: usize },
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct FrameOffset(pub(crate) usize);
 
+/// a packet spread over several UMEM frames, sent with TxRing::write_scatter()
+///
+/// the packet fills every frame but the last. the kernel completes each frame separately so
+/// they're released one by one from the completion ring like single frame packets.
+#[derive(Clone, Debug)]
+pub struct ScatterFrame {
+    frames: Vec<FrameOffset>,
+    frame_size: usize,
+    len: usize,
+}
+
+impl ScatterFrame {
+    /// `len` bytes laid out over `frames` in order, `len` must need all of them
+    pub fn new(frames: Vec<FrameOffset>, frame_size: usize, len: usize) -> Self {
+        assert_eq!(
+            frames.len(),
+            len.div_ceil(frame_size),
+            "{len} bytes don't fill {} frames of {frame_size} bytes",
+            frames.len()
+        );
+        Self {
+            frames,
+            frame_size,
+            len,
+        }
+    }
+
+    pub fn frames(&self) -> &[FrameOffset] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// (frame, bytes in that frame) for every fragment of the packet
+    pub fn fragments(&self) -> impl Iterator<Item = (FrameOffset, usize)> + '_ {
+        let frame_size = self.frame_size;
+        self.frames
+            .iter()
+            .enumerate()
+            .map(move |(i, &frame)| (frame, (self.len - i * frame_size).min(frame_size)))
+    }
+}
+
 pub trait Frame {
     fn offset(&self) -> FrameOffset;
     fn len(&self) -> usize;
@@ -74,22 +150,114 @@ impl Frame for SliceUmemFrame<'_> {
     }
 }
 
+/// snapshot of UMEM frame usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UmemStats {
+    /// frames reserved and not released yet, including the ones out on the fill, rx, tx and
+    /// completion rings
+    pub frames_used: usize,
+    /// frames available for reserve()
+    pub frames_free: usize,
+    /// highest frames_used seen
+    pub frames_peak: usize,
+    /// number of reserve() calls that found no free frame
+    pub exhaustion_count: usize,
+}
+
 pub struct SliceUmem<'a> {
-    buffer: &'a mut [u8],
+    ptr: *mut u8,
+    len: usize,
     frame_size: u32,
     available_frames: Vec<u64>,
     capacity: usize,
+    frames_used: usize,
+    frames_peak: usize,
+    exhaustion_count: usize,
+    // debug builds check every reserve and release against it to catch frames released twice
+    #[cfg(debug_assertions)]
+    free_shadow: FreeShadow,
+    // set when the memory is shared between sockets (XDP_SHARED_UMEM). the region is unmapped
+    // when the last SliceUmem referencing it is dropped
+    _shared: Option<Arc<PageAlignedMemory>>,
+    _buf: PhantomData<&'a mut [u8]>,
 }
 
+// Safety: SliceUmem holds an exclusive borrow of the mapping, either the &'a mut [u8] it was
+// built from or, for shared regions, the frames it was handed by split_off() which no other
+// SliceUmem hands out. moving it to another thread moves that exclusive access with it.
+unsafe impl Send for SliceUmem<'_> {}
+
 impl<'a> SliceUmem<'a> {
-    pub fn new(buffer: &'a mut [u8], frame_size: u32) -> Result<Self, io::Error> {
-        debug_assert!(frame_size.is_power_of_two());
+    pub fn new(buffer: &'a mut [u8], frame_size: u32) -> Result<Self, UmemError> {
+        check_frame_size(frame_size)?;
         let capacity = buffer.len() / frame_size as usize;
         Ok(Self {
             available_frames: Vec::from_iter(0..capacity as u64),
             capacity,
             frame_size,
-            buffer,
+            ptr: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            frames_used: 0,
+            frames_peak: 0,
+            exhaustion_count: 0,
+            #[cfg(debug_assertions)]
+            free_shadow: FreeShadow::new(capacity, 0..capacity as u64),
+            _shared: None,
+            _buf: PhantomData,
+        })
+    }
+
+    /// create a UMEM that keeps `memory` alive, use split_off() to hand frames to other sockets
+    /// sharing the same region
+    pub fn new_shared(
+        memory: Arc<PageAlignedMemory>,
+        frame_size: u32,
+    ) -> Result<SliceUmem<'static>, UmemError> {
+        check_frame_size(frame_size)?;
+        check_frame_fits_page(frame_size, memory.page_size())?;
+        let capacity = memory.len() / frame_size as usize;
+        Ok(SliceUmem {
+            available_frames: Vec::from_iter(0..capacity as u64),
+            capacity,
+            frame_size,
+            ptr: memory.ptr,
+            len: memory.len,
+            frames_used: 0,
+            frames_peak: 0,
+            exhaustion_count: 0,
+            #[cfg(debug_assertions)]
+            free_shadow: FreeShadow::new(capacity, 0..capacity as u64),
+            _shared: Some(memory),
+            _buf: PhantomData,
+        })
+    }
+
+    /// move `frame_count` available frames into a new UMEM over the same memory region
+    /// returns None if not enough frames are available
+    pub fn split_off(&mut self, frame_count: usize) -> Option<SliceUmem<'a>> {
+        let available = self.available_frames.len();
+        if frame_count > available {
+            return None;
+        }
+
+        let available_frames = self.available_frames.split_off(available - frame_count);
+        #[cfg(debug_assertions)]
+        for &index in &available_frames {
+            self.free_shadow.take(index as usize, self.frame_size);
+        }
+        Some(SliceUmem {
+            #[cfg(debug_assertions)]
+            free_shadow: FreeShadow::new(self.capacity, available_frames.iter().copied()),
+            available_frames,
+            capacity: self.capacity,
+            frame_size: self.frame_size,
+            ptr: self.ptr,
+            len: self.len,
+            frames_used: 0,
+            frames_peak: 0,
+            exhaustion_count: 0,
+            _shared: self._shared.clone(),
+            _buf: PhantomData,
         })
     }
 
@@ -100,21 +268,55 @@ impl<'a> SliceUmem<'a> {
     pub fn available(&self) -> usize {
         self.available_frames.len()
     }
+
+    /// reserve `n` frames at once for a multi-buffer packet, returns None and reserves nothing
+    /// if fewer are available
+    ///
+    /// the frames aren't adjacent in memory, each fragment gets its own tx descriptor so they
+    /// don't need to be.
+    pub fn reserve_n(&mut self, n: usize) -> Option<Vec<FrameOffset>> {
+        if n > self.available_frames.len() {
+            self.exhaustion_count += 1;
+            return None;
+        }
+        Some((0..n).map(|_| self.reserve().unwrap().offset()).collect())
+    }
+
+    /// reserve as many frames as `data` needs and copy it into them
+    pub fn reserve_scatter(&mut self, data: &[u8]) -> Option<ScatterFrame> {
+        let frame_size = self.frame_size as usize;
+        let frames = self.reserve_n(data.len().div_ceil(frame_size))?;
+        for (frame, chunk) in frames.iter().zip(data.chunks(frame_size)) {
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), self.ptr.add(frame.0), chunk.len());
+            }
+        }
+        Some(ScatterFrame::new(frames, frame_size, data.len()))
+    }
+
+    pub fn stats(&self) -> UmemStats {
+        UmemStats {
+            frames_used: self.frames_used,
+            frames_free: self.available_frames.len(),
+            frames_peak: self.frames_peak,
+            exhaustion_count: self.exhaustion_count,
+        }
+    }
 }
 
 impl<'a> Umem for SliceUmem<'a> {
     type Frame = SliceUmemFrame<'a>;
 
     fn as_ptr(&self) -> *const u8 {
-        self.buffer.as_ptr()
+        self.ptr
     }
 
     fn as_mut_ptr(&mut self) -> *mut u8 {
-        self.buffer.as_mut_ptr()
+        self.ptr
     }
 
     fn len(&self) -> usize {
-        self.buffer.len()
+        self.len
     }
 
     fn frame_size(&self) -> usize {
@@ -122,7 +324,15 @@ impl<'a> Umem for SliceUmem<'a> {
     }
 
     fn reserve(&mut self) -> Option<SliceUmemFrame<'a>> {
-        let index = self.available_frames.pop()?;
+        let Some(index) = self.available_frames.pop() else {
+            self.exhaustion_count += 1;
+            return None;
+        };
+        #[cfg(debug_assertions)]
+        self.free_shadow.take(index as usize, self.frame_size);
+
+        self.frames_used += 1;
+        self.frames_peak = self.frames_peak.max(self.frames_used);
 
         Some(SliceUmemFrame {
             offset: index as usize * self.frame_size as usize,
@@ -133,40 +343,320 @@ impl<'a> Umem for SliceUmem<'a> {
 
     fn release(&mut self, frame: FrameOffset) {
         let index = frame.0 / self.frame_size as usize;
+        #[cfg(debug_assertions)]
+        self.free_shadow.put(index, self.frame_size);
         self.available_frames.push(index as u64);
+        debug_assert!(self.frames_used > 0, "released a frame that was never reserved");
+        self.frames_used = self.frames_used.saturating_sub(1);
+    }
+}
+
+/// the kernel rejects other frame sizes with a bare EINVAL from XDP_UMEM_REG
+fn check_frame_size(frame_size: u32) -> Result<(), UmemError> {
+    let requirement = if !frame_size.is_power_of_two() {
+        "a power of two"
+    } else if frame_size < MIN_FRAME_SIZE {
+        "at least 2048 bytes"
+    } else {
+        return Ok(());
+    };
+    Err(UmemError::InvalidFrameSize {
+        got: frame_size,
+        requirement,
+    })
+}
+
+/// the kernel only accepts frames larger than a page when the UMEM is backed by hugepages,
+/// otherwise XDP_UMEM_REG fails with EINVAL
+pub(crate) fn check_frame_fits_page(frame_size: u32, page_size: PageSize) -> Result<(), UmemError> {
+    let page_size = page_size.bytes();
+    if frame_size as usize > page_size {
+        return Err(UmemError::FrameExceedsPage {
+            frame_size,
+            page_size,
+        });
+    }
+    Ok(())
+}
+
+/// one bit per frame, set while the frame is in SliceUmem::available_frames
+#[cfg(debug_assertions)]
+struct FreeShadow(Vec<u64>);
+
+#[cfg(debug_assertions)]
+impl FreeShadow {
+    fn new(capacity: usize, free: impl Iterator<Item = u64>) -> Self {
+        let mut shadow = Self(vec![0; capacity.div_ceil(64)]);
+        for index in free {
+            shadow.0[index as usize / 64] |= 1 << (index % 64);
+        }
+        shadow
+    }
+
+    fn take(&mut self, index: usize, frame_size: u32) {
+        let (word, bit) = (index / 64, 1 << (index % 64));
+        assert!(
+            self.0[word] & bit != 0,
+            "UMEM frame at offset {} handed out while in use\n{}",
+            index * frame_size as usize,
+            std::backtrace::Backtrace::force_capture()
+        );
+        self.0[word] &= !bit;
+    }
+
+    fn put(&mut self, index: usize, frame_size: u32) {
+        let offset = index * frame_size as usize;
+        let Some(word) = self.0.get_mut(index / 64) else {
+            panic!(
+                "released UMEM frame at offset {offset} is outside the UMEM\n{}",
+                std::backtrace::Backtrace::force_capture()
+            );
+        };
+        let bit = 1 << (index % 64);
+        assert!(
+            *word & bit == 0,
+            "UMEM frame at offset {offset} released twice\n{}",
+            std::backtrace::Backtrace::force_capture()
+        );
+        *word |= bit;
+    }
+}
+
+/// reference counts of RX frames that are shared with other threads, see UmemRc
+///
+/// a count of 0 means the frame isn't shared and its owner recycles it as usual. SliceUmem isn't
+/// Sync, so frames whose last UmemRc is dropped on another thread come back through a channel the
+/// owner drains into the UMEM.
+pub struct UmemRefs {
+    base: usize,
+    frame_size: usize,
+    counts: Box<[AtomicI32]>,
+    recycled: Sender<FrameOffset>,
+}
+
+impl UmemRefs {
+    /// returns the table and the receiver frames come back on once nobody references them
+    pub fn new(umem: &impl Umem) -> (Arc<Self>, Receiver<FrameOffset>) {
+        let (recycled, receiver) = crossbeam_channel::unbounded();
+        let refs = Self {
+            base: umem.as_ptr() as usize,
+            frame_size: umem.frame_size(),
+            counts: (0..umem.len() / umem.frame_size())
+                .map(|_| AtomicI32::new(0))
+                .collect(),
+            recycled,
+        };
+        (Arc::new(refs), receiver)
+    }
+
+    /// share `frame` with another thread. the caller keeps its own reference and gives it up
+    /// with release()
+    #[inline]
+    pub fn share(self: &Arc<Self>, frame: FrameOffset) -> UmemRc {
+        let count = self.count(frame);
+        // the first share starts counting the caller's reference too
+        if count.load(Ordering::Relaxed) == 0 {
+            count.store(1, Ordering::Relaxed);
+        }
+        count.fetch_add(1, Ordering::Relaxed);
+        UmemRc {
+            frame,
+            refs: Arc::clone(self),
+        }
+    }
+
+    /// give up the caller's reference to `frame`, returns true if it was the last one and the
+    /// caller should recycle the frame. frames that were never shared always return true
+    #[inline]
+    pub fn release(&self, frame: FrameOffset) -> bool {
+        let count = self.count(frame);
+        if count.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+        count.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+
+    #[inline]
+    fn count(&self, frame: FrameOffset) -> &AtomicI32 {
+        &self.counts[frame.0 / self.frame_size]
+    }
+}
+
+/// counted reference to an RX frame, the frame is handed back to its owner once every clone and
+/// the owner's own reference (UmemRefs::release) are gone
+pub struct UmemRc {
+    frame: FrameOffset,
+    refs: Arc<UmemRefs>,
+}
+
+impl UmemRc {
+    /// offset the frame was shared with, not necessarily frame aligned
+    pub fn offset(&self) -> FrameOffset {
+        self.frame
+    }
+
+    /// start of the UMEM the frame belongs to
+    pub fn umem_base(&self) -> *const u8 {
+        self.refs.base as *const u8
+    }
+}
+
+impl Clone for UmemRc {
+    fn clone(&self) -> Self {
+        self.refs.count(self.frame).fetch_add(1, Ordering::Relaxed);
+        Self {
+            frame: self.frame,
+            refs: Arc::clone(&self.refs),
+        }
+    }
+}
+
+impl Drop for UmemRc {
+    fn drop(&mut self) {
+        if self.refs.count(self.frame).fetch_sub(1, Ordering::Release) == 1 {
+            // order everyone's reads of the frame before it's reused
+            fence(Ordering::Acquire);
+            // the owner is gone if the receiver is, and so is the UMEM
+            let _ = self.refs.recycled.send(self.frame);
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct AllocError;
 
+/// page size backing a PageAlignedMemory allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// system page size, usually 4 KB
+    Regular,
+    /// 2 MB hugepages
+    Huge2M,
+    /// 1 GB hugepages, must be reserved at boot or via nr_hugepages
+    Huge1G,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> usize {
+        match self {
+            // Safety: just a libc wrapper
+            PageSize::Regular => unsafe { sysconf(_SC_PAGESIZE) as usize },
+            PageSize::Huge2M => 1 << 21,
+            PageSize::Huge1G => 1 << 30,
+        }
+    }
+
+    /// number of free hugepages of this size, None for regular pages or if the size isn't
+    /// configured on this system
+    pub fn free_hugepages(&self) -> Option<usize> {
+        let kb = match self {
+            PageSize::Regular => return None,
+            PageSize::Huge2M => 2048,
+            PageSize::Huge1G => 1048576,
+        };
+        std::fs::read_to_string(format!(
+            "/sys/kernel/mm/hugepages/hugepages-{kb}kB/free_hugepages"
+        ))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+    }
+
+    /// true if an allocation of `size` bytes can be satisfied with this page size
+    pub fn is_available(&self, size: usize) -> bool {
+        match self {
+            PageSize::Regular => true,
+            _ => self
+                .free_hugepages()
+                .is_some_and(|free| free * self.bytes() >= size),
+        }
+    }
+
+    fn mmap_flags(&self) -> i32 {
+        match self {
+            PageSize::Regular => 0,
+            PageSize::Huge2M => libc::MAP_HUGETLB | (21 << libc::MAP_HUGE_SHIFT),
+            PageSize::Huge1G => libc::MAP_HUGETLB | (30 << libc::MAP_HUGE_SHIFT),
+        }
+    }
+}
+
 pub struct PageAlignedMemory {
     ptr: *mut u8,
     len: usize,
+    page_size: PageSize,
 }
 
 impl PageAlignedMemory {
     pub fn alloc(frame_size: usize, frame_count: usize) -> Result<Self, AllocError> {
-        Self::alloc_with_page_size(
-            frame_size,
-            frame_count,
-            // Safety: just a libc wrapper
-            unsafe { sysconf(_SC_PAGESIZE) as usize },
-            false,
-        )
+        Self::alloc_with_page_size(frame_size, frame_count, PageSize::Regular)
+    }
+
+    /// like alloc() but returns why the allocation failed. every allocation has its pages
+    /// faulted in before it's returned (see prefault()), so the first packets don't pay for it
+    pub fn alloc_prefaulted(frame_size: usize, frame_count: usize) -> io::Result<Self> {
+        Self::map(frame_size, frame_count, PageSize::Regular, None)
     }
 
     pub fn alloc_with_page_size(
         frame_size: usize,
         frame_count: usize,
-        page_size: usize,
-        huge: bool,
+        page_size: PageSize,
     ) -> Result<Self, AllocError> {
+        Self::map(frame_size, frame_count, page_size, None).map_err(|_| AllocError)
+    }
+
+    /// allocate memory bound to NUMA node `node`, see alloc_on_numa_node_with_page_size()
+    pub fn alloc_on_numa_node(
+        frame_size: usize,
+        frame_count: usize,
+        node: u32,
+    ) -> io::Result<Self> {
+        Self::alloc_on_numa_node_with_page_size(frame_size, frame_count, PageSize::Regular, node)
+    }
+
+    /// allocate memory whose pages are bound to NUMA node `node` with MPOL_BIND. Put the UMEM on
+    /// the NIC's node (see NetworkDevice::numa_node()) so DMA doesn't cross the interconnect.
+    pub fn alloc_on_numa_node_with_page_size(
+        frame_size: usize,
+        frame_count: usize,
+        page_size: PageSize,
+        node: u32,
+    ) -> io::Result<Self> {
+        Self::map(frame_size, frame_count, page_size, Some(node))
+    }
+
+    fn map(
+        frame_size: usize,
+        frame_count: usize,
+        page_size: PageSize,
+        numa_node: Option<u32>,
+    ) -> io::Result<Self> {
+        let page_bytes = page_size.bytes();
         debug_assert!(frame_size.is_power_of_two());
         debug_assert!(frame_count.is_power_of_two());
-        debug_assert!(page_size.is_power_of_two());
-        let memory_size = frame_count * frame_size;
-        let aligned_size = (memory_size + page_size - 1) & !(page_size - 1);
+        debug_assert!(page_bytes.is_power_of_two());
+        let aligned_size = frame_count
+            .checked_mul(frame_size)
+            .and_then(|size| size.checked_next_multiple_of(page_bytes))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    UmemError::SizeOverflow {
+                        frame_size,
+                        frame_count,
+                    },
+                )
+            })?;
+
+        // don't bother calling mmap if the kernel has no hugepages of this size left
+        if page_size != PageSize::Regular && !page_size.is_available(aligned_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("not enough free {page_size:?} hugepages"),
+            ));
+        }
 
         // Safety:
         // doing an ANONYMOUS alloc. addr=NULL is ok, fd is not used.
@@ -175,28 +665,112 @@ impl PageAlignedMemory {
                 ptr::null_mut(),
                 aligned_size,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | if huge { libc::MAP_HUGETLB } else { 0 },
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | page_size.mmap_flags(),
                 -1,
                 0,
             )
         };
 
         if std::ptr::eq(ptr, libc::MAP_FAILED) {
-            return Err(AllocError);
-        }
-
-        // Safety: ptr is valid for aligned_size bytes
-        unsafe {
-            ptr::write_bytes(ptr as *mut u8, 0, aligned_size);
+            return Err(io::Error::last_os_error());
         }
 
-        Ok(Self {
+        // from here on the mapping is unmapped on drop
+        let mut memory = Self {
             ptr: ptr as *mut u8,
             len: aligned_size,
-        })
+            page_size,
+        };
+
+        // the policy must be set before the pages are touched, otherwise they're already faulted
+        // in on whatever node we're running on
+        if let Some(node) = numa_node {
+            mbind(memory.ptr, memory.len, node)?;
+        }
+
+        // fault the pages in now rather than on the first packets. with hugepages this also
+        // turns a shortage into an error instead of a SIGBUS on first touch
+        memory.prefault()?;
+
+        Ok(memory)
+    }
+
+    /// back every page of the mapping with physical memory, with MADV_POPULATE_WRITE or by
+    /// writing to each page on kernels older than 5.14. the contents are left as they are
+    pub fn prefault(&mut self) -> io::Result<()> {
+        // Safety: ptr/len describe a mapping we own
+        let rc = unsafe { libc::madvise(self.ptr as *mut c_void, self.len, MADV_POPULATE_WRITE) };
+        if rc == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        // EINVAL is an unknown advice, anything else is a real failure to populate
+        if e.raw_os_error() != Some(libc::EINVAL) {
+            return Err(e);
+        }
+
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+        for offset in (0..self.len).step_by(page_size) {
+            // Safety: offset is inside the mapping and &mut self means nobody else is using it
+            unsafe {
+                let byte = self.ptr.add(offset);
+                byte.write_volatile(byte.read_volatile());
+            }
+        }
+        Ok(())
+    }
+
+    /// size of the pages backing the mapping
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+}
+
+// from linux/mman.h, Linux 5.14+
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+// from linux/mempolicy.h, libc doesn't export these
+const MPOL_BIND: libc::c_ulong = 2;
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+const MAX_NUMA_NODES: usize = 1024;
+
+fn mbind(ptr: *mut u8, len: usize, node: u32) -> io::Result<()> {
+    let node = node as usize;
+    if node >= MAX_NUMA_NODES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid NUMA node {node}"),
+        ));
+    }
+
+    const BITS: usize = u64::BITS as usize;
+    let mut nodemask = [0u64; MAX_NUMA_NODES / BITS];
+    nodemask[node / BITS] |= 1 << (node % BITS);
+
+    // Safety: ptr/len describe a mapping we own and nodemask outlives the call. The kernel
+    // reads maxnode - 1 bits so we pass one more than the size of the mask.
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr as *mut c_void,
+            len,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            MAX_NUMA_NODES + 1,
+            MPOL_MF_STRICT,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
     }
+
+    Ok(())
 }
 
+// Safety: PageAlignedMemory exclusively owns its mapping
+unsafe impl Send for PageAlignedMemory {}
+unsafe impl Sync for PageAlignedMemory {}
+
 impl Drop for PageAlignedMemory {
     fn drop(&mut self) {
         // Safety:
@@ -220,3 +794,134 @@ impl DerefMut for PageAlignedMemory {
         unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slice_umem_frame_size() {
+        let mut buffer = vec![0u8; 16384];
+        for (frame_size, requirement) in [(3000, "a power of two"), (1024, "at least 2048 bytes")]
+        {
+            assert_eq!(
+                SliceUmem::new(&mut buffer, frame_size).err(),
+                Some(UmemError::InvalidFrameSize {
+                    got: frame_size,
+                    requirement
+                })
+            );
+        }
+        assert_eq!(SliceUmem::new(&mut buffer, 4096).unwrap().capacity(), 4);
+
+        // sockets are built on one thread and run on another
+        fn assert_send<T: Send>() {}
+        assert_send::<SliceUmem<'_>>();
+    }
+
+    #[test]
+    fn test_slice_umem_frame_exceeds_page() {
+        let page_size = PageSize::Regular.bytes();
+        let frame_size = (page_size * 2) as u32;
+        let memory = Arc::new(PageAlignedMemory::alloc(frame_size as usize, 4).unwrap());
+        assert_eq!(
+            SliceUmem::new_shared(memory, frame_size).err(),
+            Some(UmemError::FrameExceedsPage {
+                frame_size,
+                page_size
+            })
+        );
+        assert!(check_frame_fits_page(frame_size, PageSize::Huge2M).is_ok());
+    }
+
+    #[test]
+    fn test_slice_umem_reserve_scatter() {
+        const FRAME_SIZE: usize = 2048;
+        let mut buffer = vec![0u8; FRAME_SIZE * 4];
+        let mut umem = SliceUmem::new(&mut buffer, FRAME_SIZE as u32).unwrap();
+
+        // all or nothing
+        assert!(umem.reserve_n(5).is_none());
+        assert_eq!(umem.available(), 4);
+
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let packet = umem.reserve_scatter(&data).unwrap();
+        assert_eq!(umem.available(), 1);
+        assert_eq!(
+            umem.stats(),
+            UmemStats {
+                frames_used: 3,
+                frames_free: 1,
+                frames_peak: 3,
+                exhaustion_count: 1,
+            }
+        );
+        let fragments: Vec<_> = packet.fragments().collect();
+        assert_eq!(
+            fragments.iter().map(|&(_, len)| len).collect::<Vec<_>>(),
+            [FRAME_SIZE, FRAME_SIZE, 904]
+        );
+        let mut copied = Vec::new();
+        for (frame, len) in fragments {
+            copied.extend_from_slice(umem.map_frame(&SliceUmemFrame::from_offset(frame, len)));
+        }
+        assert_eq!(copied, data);
+
+        for &frame in packet.frames() {
+            umem.release(frame);
+        }
+        assert_eq!(umem.available(), 4);
+        let stats = umem.stats();
+        assert_eq!((stats.frames_used, stats.frames_peak), (0, 3));
+    }
+
+    #[test]
+    fn test_page_aligned_memory_prefault() {
+        let mut memory = PageAlignedMemory::alloc_prefaulted(2048, 8).unwrap();
+        assert!(memory.iter().all(|&byte| byte == 0));
+        memory[5000] = 0xab;
+        memory.prefault().unwrap();
+        assert_eq!(memory[5000], 0xab);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "released twice")]
+    fn test_slice_umem_double_release() {
+        const FRAME_SIZE: usize = 2048;
+        let mut buffer = vec![0u8; FRAME_SIZE * 4];
+        let mut umem = SliceUmem::new(&mut buffer, FRAME_SIZE as u32).unwrap();
+
+        let frame = umem.reserve().unwrap();
+        umem.release(frame.offset());
+        umem.release(frame.offset());
+    }
+
+    #[test]
+    fn test_umem_rc_recycles_after_last_reference() {
+        const FRAME_SIZE: usize = 2048;
+        let mut buffer = vec![0u8; FRAME_SIZE * 4];
+        let umem = SliceUmem::new(&mut buffer, FRAME_SIZE as u32).unwrap();
+        let (refs, recycled) = UmemRefs::new(&umem);
+
+        // never shared, the owner recycles it
+        assert!(refs.release(FrameOffset(FRAME_SIZE)));
+
+        // the owner lets go first, the last clone sends the frame back
+        let frame = FrameOffset(2 * FRAME_SIZE + 256);
+        let rc = refs.share(frame);
+        let clone = rc.clone();
+        assert!(!refs.release(frame));
+        drop(rc);
+        assert!(recycled.try_recv().is_err());
+        drop(clone);
+        assert_eq!(recycled.try_recv().unwrap().0, frame.0);
+
+        // the clones go first, the owner recycles it
+        let rc = refs.share(frame);
+        drop(rc);
+        assert!(recycled.try_recv().is_err());
+        assert!(refs.release(frame));
+        assert!(refs.release(frame));
+    }
+}
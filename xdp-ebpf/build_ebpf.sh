@@ -14,8 +14,8 @@ fi
 
 rustup toolchain install nightly --component rust-src
 
-# build the eBPF program
-cargo +nightly build -Z build-std --release
+# build the eBPF program with BTF embedded so the loader can do CO-RE relocations
+RUSTFLAGS="-C debuginfo=2 -C link-arg=--btf" cargo +nightly build -Z build-std --release
 
 # copy the compiled eBPF program to a known location
 mkdir -p ../target/bpf
@@ -1,11 +1,20 @@
 #![no_std]
 #![no_main]
 
-use aya_ebpf::{
-    bindings::xdp_action,
-    macros::{map, xdp},
-    maps::XskMap,
-    programs::XdpContext,
+use {
+    aya_ebpf::{
+        bindings::{xdp_action, BPF_F_NO_PREALLOC},
+        helpers::{
+            bpf_get_prandom_u32, bpf_get_smp_processor_id, bpf_ktime_get_ns, bpf_xdp_adjust_meta,
+        },
+        macros::{map, xdp},
+        maps::{
+            lpm_trie::Key, Array, CpuMap, DevMap, HashMap, LpmTrie, PerCpuArray, ProgramArray,
+            RingBuf, XskMap,
+        },
+        programs::XdpContext,
+    },
+    core::mem,
 };
 
 // XDP_REDIRECT is mutually exclusive - packet goes to AF_XDP or kernel
@@ -19,29 +28,744 @@ use aya_ebpf::{
 #[map]
 static XSKS_MAP: XskMap = XskMap::with_max_entries(64, 0);
 
+// IPv4 address of the interface in network byte order, written by userspace at startup.
+// 0 means unset and disables the ICMP echo responder
+#[map]
+static LOCAL_IP: Array<u32> = Array::with_max_entries(1, 0);
+
+// source IPv4 addresses (network byte order) allowed through to the socket. everything else is
+// passed to the kernel. an empty whitelist allows all traffic
+#[map]
+static IP_WHITELIST: HashMap<u32, u8> = HashMap::with_max_entries(4096, 0);
+
+// number of entries in IP_WHITELIST, maintained by userspace since the program can't cheaply
+// tell if a hash map is empty
+#[map]
+static IP_WHITELIST_LEN: Array<u32> = Array::with_max_entries(1, 0);
+
+// UDP destination ports (network byte order) redirected to the socket. when non-empty, only UDP
+// packets to these ports are redirected and everything else (including TCP) is passed to the
+// kernel, so services like RPC keep working on the same NIC
+#[map]
+static UDP_PORT_FILTER: HashMap<u16, u8> = HashMap::with_max_entries(64, 0);
+
+// number of entries in UDP_PORT_FILTER, maintained by userspace
+#[map]
+static UDP_PORT_FILTER_LEN: Array<u32> = Array::with_max_entries(1, 0);
+
+// per queue counters, indexed by rx_queue_index. read and summed across CPUs by userspace
+#[map]
+static XDP_STATS: PerCpuArray<XdpStats> = PerCpuArray::with_max_entries(64, 0);
+
+// CPUs the cpumap variant spreads packets over, keyed by CPU id. each entry runs
+// xdp_cpumap_xsk on its CPU
+#[map]
+static CPU_MAP: CpuMap = CpuMap::with_max_entries(64, 0);
+
+// number of CPUs in CPU_MAP, packets are hashed onto the first CPU_COUNT entries of CPU_IDS
+#[map]
+static CPU_COUNT: Array<u32> = Array::with_max_entries(1, 0);
+
+// ids of the CPUs in CPU_MAP, so userspace can pick CPUs close to the NIC
+#[map]
+static CPU_IDS: Array<u32> = Array::with_max_entries(64, 0);
+
+// IPv4 source prefixes for the routing variant, keyed by (prefix length, address in network byte
+// order). the value is a ROUTE_* action or the XSKS_MAP index to redirect to. the kernel
+// requires BPF_F_NO_PREALLOC for LPM tries
+#[map]
+static IP_ROUTING_TABLE: LpmTrie<[u8; 4], u32> =
+    LpmTrie::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+// IP_ROUTING_TABLE actions, must match RouteAction in program.rs. any other value is an
+// XSKS_MAP index
+const ROUTE_PASS: u32 = u32::MAX;
+const ROUTE_DROP: u32 = u32::MAX - 1;
+
+// device the forwarding variant sends every packet to, at index 0
+#[map]
+static FORWARD_MAP: DevMap = DevMap::with_max_entries(1, 0);
+
+// stages of the dispatcher variant, run in slot order by tail calls. empty slots are skipped.
+// written by userspace, see insert_tail_program() in program.rs
+#[map]
+static STAGES: ProgramArray = ProgramArray::with_max_entries(STAGE_COUNT, 0);
+
+// headers parsed by xdp_dispatch for the stages. a packet runs to completion on one CPU so a
+// per CPU slot is private to it
+#[map]
+static PARSED: PerCpuArray<ParsedHeaders> = PerCpuArray::with_max_entries(1, 0);
+
+// packets per second each CPU lets through the rate limit stage, 0 means unlimited
+#[map]
+static RATE_LIMIT_PPS: Array<u32> = Array::with_max_entries(1, 0);
+
+#[map]
+static RATE_LIMIT_STATE: PerCpuArray<TokenBucket> = PerCpuArray::with_max_entries(1, 0);
+
+// metadata of sampled packets, read by userspace with open_packet_event_stream()
+#[map]
+static PACKET_EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+// sample 1 in N packets into PACKET_EVENTS, written by userspace. 0 disables sampling
+#[map]
+static PACKET_SAMPLE_RATE: Array<u32> = Array::with_max_entries(1, 0);
+
+// must match XdpStatsValue in program.rs
+#[repr(C)]
+struct XdpStats {
+    redirected: u64,
+    passed: u64,
+    errors: u64,
+}
+
+// written into the metadata area in front of redirected packets, must match XdpRxMeta in
+// program.rs
+#[repr(C)]
+struct XdpRxMeta {
+    xdp_timestamp: u64,
+    flow_hash: u32,
+    magic: u32,
+}
+
+const RX_META_MAGIC: u32 = 0x6873_6878;
+
+// well-known STAGES slots, must match STAGE_* in program.rs
+const STAGE_FILTER: u32 = 0;
+const STAGE_RATE_LIMIT: u32 = 1;
+const STAGE_REDIRECT: u32 = 2;
+const STAGE_COUNT: u32 = 8;
+
+// packets the rate limit stage lets through back to back after an idle period
+const RATE_LIMIT_BURST: u64 = 64;
+
+#[repr(C)]
+struct ParsedHeaders {
+    // network byte order, after an 802.1Q tag
+    ether_type: u16,
+    // IPv4 protocol or IPv6 next header, 0 for non-IP packets
+    ip_proto: u8,
+    _pad: u8,
+    // UDP destination port in network byte order, 0 for other protocols
+    dest: u16,
+    _pad2: u16,
+    // IPv4 source address in network byte order, 0 for IPv6
+    saddr: u32,
+}
+
+#[repr(C)]
+struct TokenBucket {
+    // nanoseconds of sending time saved up, a packet costs 1s / RATE_LIMIT_PPS
+    credit_ns: u64,
+    last_ns: u64,
+}
+
+// a PACKET_EVENTS entry, must match PacketEventValue in program.rs
+#[repr(C)]
+struct PacketSample {
+    // CLOCK_MONOTONIC
+    timestamp_ns: u64,
+    // network byte order
+    saddr: u32,
+    daddr: u32,
+    // network byte order, 0 for protocols other than UDP and TCP
+    source: u16,
+    dest: u16,
+    len: u32,
+}
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86DD;
+const ETH_P_8021Q: u16 = 0x8100;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+
+#[repr(C)]
+struct EthHdr {
+    dst: [u8; 6],
+    src: [u8; 6],
+    // network byte order
+    ether_type: u16,
+}
+
+#[repr(C)]
+struct Ipv4Hdr {
+    version_ihl: u8,
+    tos: u8,
+    tot_len: u16,
+    id: u16,
+    frag_off: u16,
+    ttl: u8,
+    protocol: u8,
+    check: u16,
+    // network byte order
+    saddr: u32,
+    daddr: u32,
+}
+
+#[repr(C)]
+struct Ipv6Hdr {
+    version_tc_flow: u32,
+    payload_len: u16,
+    next_header: u8,
+    hop_limit: u8,
+    saddr: [u8; 16],
+    daddr: [u8; 16],
+}
+
+#[repr(C)]
+struct UdpHdr {
+    // network byte order
+    source: u16,
+    dest: u16,
+    len: u16,
+    check: u16,
+}
+
+#[repr(C)]
+struct IcmpHdr {
+    type_: u8,
+    code: u8,
+    checksum: u16,
+}
+
 #[xdp]
 pub fn xdp_redirect(ctx: XdpContext) -> u32 {
-    match try_xdp_redirect(ctx) {
+    // get the queue index from the context
+    // this tells us which hardware queue received the packet
+    let queue_id = rx_queue_index(&ctx);
+    sample_packet(&ctx);
+
+    match try_xdp_redirect(ctx, queue_id) {
         Ok(ret) => ret,
-        Err(_) => xdp_action::XDP_PASS,
+        Err(_) => {
+            count(queue_id, |stats| stats.passed += 1);
+            xdp_action::XDP_PASS
+        }
     }
 }
 
 #[inline(always)]
-fn try_xdp_redirect(ctx: XdpContext) -> Result<u32, ()> {
-    // get the queue index from the context
-    // this tells us which hardware queue received the packet
-    let queue_id = unsafe { (*ctx.ctx).rx_queue_index };
+fn try_xdp_redirect(ctx: XdpContext, queue_id: u32) -> Result<u32, ()> {
+    // answer pings for our own address directly from the NIC, they never reach the socket
+    if try_icmp_echo_reply(&ctx).unwrap_or(false) {
+        return Ok(xdp_action::XDP_TX);
+    }
+
+    if !source_allowed(&ctx)? || !udp_port_allowed(&ctx)? {
+        count(queue_id, |stats| stats.passed += 1);
+        return Ok(xdp_action::XDP_PASS);
+    }
+
+    store_rx_meta(&ctx);
 
     // redirect the packet to the AF_XDP socket bound to this queue
     // the XSKS_MAP contains socket FDs inserted by the userspace program
-    XSKS_MAP.redirect(queue_id, 0).map_err(|_| ())?;
+    if XSKS_MAP.redirect(queue_id, 0).is_err() {
+        // no socket bound to this queue, the packet goes to the kernel
+        count(queue_id, |stats| stats.errors += 1);
+        return Ok(xdp_action::XDP_PASS);
+    }
+    count(queue_id, |stats| stats.redirected += 1);
 
     // return XDP_REDIRECT to tell the kernel to redirect the packet
     Ok(xdp_action::XDP_REDIRECT)
 }
 
+/// dispatcher variant: parses the headers once and tail calls the programs in STAGES. the
+/// default stages (xdp_stage_filter, xdp_stage_rate_limit, xdp_stage_redirect) do what
+/// xdp_redirect does plus rate limiting, and each can be replaced at runtime
+#[xdp]
+pub fn xdp_dispatch(ctx: XdpContext) -> u32 {
+    sample_packet(&ctx);
+    let Some(parsed) = PARSED.get_ptr_mut(0) else {
+        return xdp_action::XDP_PASS;
+    };
+    match parse_headers(&ctx) {
+        // per CPU entry, no other writers
+        Ok(headers) => unsafe { *parsed = headers },
+        Err(_) => {
+            count(rx_queue_index(&ctx), |stats| stats.passed += 1);
+            return xdp_action::XDP_PASS;
+        }
+    }
+    run_stages(&ctx, STAGE_FILTER)
+}
+
+/// IP_WHITELIST and UDP_PORT_FILTER on the headers parsed by xdp_dispatch
+#[xdp]
+pub fn xdp_stage_filter(ctx: XdpContext) -> u32 {
+    let Some(parsed) = PARSED.get(0) else {
+        return xdp_action::XDP_PASS;
+    };
+
+    let source_allowed = match IP_WHITELIST_LEN.get(0) {
+        Some(len) if *len != 0 => {
+            parsed.ether_type == ETH_P_IP.to_be()
+                && unsafe { IP_WHITELIST.get(&parsed.saddr) }.is_some()
+        }
+        _ => true,
+    };
+    let port_allowed = match UDP_PORT_FILTER_LEN.get(0) {
+        Some(len) if *len != 0 => {
+            parsed.ip_proto == IPPROTO_UDP
+                && unsafe { UDP_PORT_FILTER.get(&parsed.dest) }.is_some()
+        }
+        _ => true,
+    };
+    if !source_allowed || !port_allowed {
+        count(rx_queue_index(&ctx), |stats| stats.passed += 1);
+        return xdp_action::XDP_PASS;
+    }
+
+    run_stages(&ctx, STAGE_FILTER + 1)
+}
+
+/// drop packets above RATE_LIMIT_PPS, a token bucket per CPU
+#[xdp]
+pub fn xdp_stage_rate_limit(ctx: XdpContext) -> u32 {
+    let pps = match RATE_LIMIT_PPS.get(0) {
+        Some(pps) if *pps != 0 => *pps as u64,
+        _ => return run_stages(&ctx, STAGE_RATE_LIMIT + 1),
+    };
+    let Some(bucket) = RATE_LIMIT_STATE.get_ptr_mut(0) else {
+        return run_stages(&ctx, STAGE_RATE_LIMIT + 1);
+    };
+
+    let cost_ns = 1_000_000_000 / pps;
+    let now = unsafe { bpf_ktime_get_ns() };
+    // per CPU entry, no other writers
+    let bucket = unsafe { &mut *bucket };
+    let credit_ns = bucket.credit_ns + now.saturating_sub(bucket.last_ns);
+    bucket.credit_ns = credit_ns.min(cost_ns * RATE_LIMIT_BURST);
+    bucket.last_ns = now;
+    if bucket.credit_ns < cost_ns {
+        return xdp_action::XDP_DROP;
+    }
+    bucket.credit_ns -= cost_ns;
+
+    run_stages(&ctx, STAGE_RATE_LIMIT + 1)
+}
+
+/// hand the packet to the AF_XDP socket bound to its queue
+#[xdp]
+pub fn xdp_stage_redirect(ctx: XdpContext) -> u32 {
+    let queue_id = rx_queue_index(&ctx);
+    store_rx_meta(&ctx);
+    if XSKS_MAP.redirect(queue_id, 0).is_err() {
+        count(queue_id, |stats| stats.errors += 1);
+        return xdp_action::XDP_PASS;
+    }
+    count(queue_id, |stats| stats.redirected += 1);
+    xdp_action::XDP_REDIRECT
+}
+
+/// tail call the first program in STAGES at or after `first`. only returns when all of those
+/// slots are empty, then the packet goes to the kernel
+#[inline(always)]
+fn run_stages(ctx: &XdpContext, first: u32) -> u32 {
+    let mut slot = first;
+    while slot < STAGE_COUNT {
+        // returns only if the slot is empty
+        let _ = unsafe { STAGES.tail_call(ctx, slot) };
+        slot += 1;
+    }
+    count(rx_queue_index(ctx), |stats| stats.passed += 1);
+    xdp_action::XDP_PASS
+}
+
+#[inline(always)]
+fn parse_headers(ctx: &XdpContext) -> Result<ParsedHeaders, ()> {
+    let (ether_type, l3_offset) = l3_header(ctx)?;
+    let mut headers = ParsedHeaders {
+        ether_type,
+        ip_proto: 0,
+        _pad: 0,
+        dest: 0,
+        _pad2: 0,
+        saddr: 0,
+    };
+
+    let udp_offset = if ether_type == ETH_P_IP.to_be() {
+        let ip = ptr_at::<Ipv4Hdr>(ctx, l3_offset)?;
+        headers.ip_proto = unsafe { (*ip).protocol };
+        headers.saddr = unsafe { (*ip).saddr };
+        let ihl = (unsafe { (*ip).version_ihl } & 0x0f) as usize;
+        l3_offset + ihl * 4
+    } else if ether_type == ETH_P_IPV6.to_be() {
+        let ip = ptr_at::<Ipv6Hdr>(ctx, l3_offset)?;
+        headers.ip_proto = unsafe { (*ip).next_header };
+        l3_offset + mem::size_of::<Ipv6Hdr>()
+    } else {
+        return Ok(headers);
+    };
+
+    if headers.ip_proto == IPPROTO_UDP {
+        let udp = ptr_at::<UdpHdr>(ctx, udp_offset)?;
+        headers.dest = unsafe { (*udp).dest };
+    }
+    Ok(headers)
+}
+
+/// forwarding variant: sends the UDP packets that pass IP_WHITELIST and UDP_PORT_FILTER out of
+/// the device in FORWARD_MAP as is, without going through userspace or the kernel's stack.
+/// everything else, ARP included, goes to the kernel
+#[xdp]
+pub fn xdp_devmap_forward(ctx: XdpContext) -> u32 {
+    let queue_id = rx_queue_index(&ctx);
+    if !forwardable(&ctx).unwrap_or(false) {
+        count(queue_id, |stats| stats.passed += 1);
+        return xdp_action::XDP_PASS;
+    }
+    match FORWARD_MAP.redirect(0, 0) {
+        Ok(action) => {
+            count(queue_id, |stats| stats.redirected += 1);
+            action
+        }
+        Err(_) => {
+            count(queue_id, |stats| stats.errors += 1);
+            xdp_action::XDP_PASS
+        }
+    }
+}
+
+/// UDP and allowed by the same filters as xdp_redirect
+#[inline(always)]
+fn forwardable(ctx: &XdpContext) -> Result<bool, ()> {
+    if parse_headers(ctx)?.ip_proto != IPPROTO_UDP {
+        return Ok(false);
+    }
+    Ok(source_allowed(ctx)? && udp_port_allowed(ctx)?)
+}
+
+/// sampling variant: only publishes sampled packet metadata to PACKET_EVENTS, every packet goes
+/// to the kernel
+#[xdp]
+pub fn xdp_sample(ctx: XdpContext) -> u32 {
+    sample_packet(&ctx);
+    xdp_action::XDP_PASS
+}
+
+/// publish the addresses, ports and length of 1 in PACKET_SAMPLE_RATE IPv4 packets to
+/// PACKET_EVENTS. samples are dropped while the ring buffer is full
+#[inline(always)]
+fn sample_packet(ctx: &XdpContext) {
+    let rate = match PACKET_SAMPLE_RATE.get(0) {
+        Some(rate) if *rate != 0 => *rate,
+        _ => return,
+    };
+    if rate > 1 && unsafe { bpf_get_prandom_u32() } % rate != 0 {
+        return;
+    }
+    if let Ok(sample) = packet_sample(ctx) {
+        let _ = PACKET_EVENTS.output(&sample, 0);
+    }
+}
+
+#[inline(always)]
+fn packet_sample(ctx: &XdpContext) -> Result<PacketSample, ()> {
+    let (ether_type, l3_offset) = l3_header(ctx)?;
+    if ether_type != ETH_P_IP.to_be() {
+        return Err(());
+    }
+
+    let ip = ptr_at::<Ipv4Hdr>(ctx, l3_offset)?;
+    let (source, dest) = match unsafe { (*ip).protocol } {
+        IPPROTO_UDP | IPPROTO_TCP => {
+            // both start with the source and destination ports
+            let ihl = (unsafe { (*ip).version_ihl } & 0x0f) as usize;
+            let ports = ptr_at::<[u16; 2]>(ctx, l3_offset + ihl * 4)?;
+            unsafe { ((*ports)[0], (*ports)[1]) }
+        }
+        _ => (0, 0),
+    };
+
+    Ok(PacketSample {
+        timestamp_ns: unsafe { bpf_ktime_get_ns() },
+        saddr: unsafe { (*ip).saddr },
+        daddr: unsafe { (*ip).daddr },
+        source,
+        dest,
+        len: (ctx.data_end() - ctx.data()) as u32,
+    })
+}
+
+/// routing variant: packets whose IPv4 source matches a prefix in IP_ROUTING_TABLE are passed,
+/// dropped or redirected as the longest matching entry says. everything else goes through
+/// xdp_redirect's filters
+#[xdp]
+pub fn xdp_lpm_route(ctx: XdpContext) -> u32 {
+    let queue_id = rx_queue_index(&ctx);
+    sample_packet(&ctx);
+
+    let action = match route_action(&ctx) {
+        Ok(Some(action)) => action,
+        _ => {
+            return try_xdp_redirect(ctx, queue_id).unwrap_or_else(|_| {
+                count(queue_id, |stats| stats.passed += 1);
+                xdp_action::XDP_PASS
+            })
+        }
+    };
+    match action {
+        ROUTE_PASS => {
+            count(queue_id, |stats| stats.passed += 1);
+            xdp_action::XDP_PASS
+        }
+        ROUTE_DROP => xdp_action::XDP_DROP,
+        // the kernel only delivers to sockets bound to the receiving queue, packets redirected
+        // to a socket on another queue are dropped after the program returns
+        index => match XSKS_MAP.redirect(index, 0) {
+            Ok(action) => {
+                count(queue_id, |stats| stats.redirected += 1);
+                action
+            }
+            Err(_) => {
+                count(queue_id, |stats| stats.errors += 1);
+                xdp_action::XDP_PASS
+            }
+        },
+    }
+}
+
+/// longest IP_ROUTING_TABLE match for the IPv4 source address
+#[inline(always)]
+fn route_action(ctx: &XdpContext) -> Result<Option<u32>, ()> {
+    let (ether_type, l3_offset) = l3_header(ctx)?;
+    if ether_type != ETH_P_IP.to_be() {
+        return Ok(None);
+    }
+
+    let ip = ptr_at::<Ipv4Hdr>(ctx, l3_offset)?;
+    // saddr is in network byte order so its bytes are the address octets
+    let saddr = unsafe { (*ip).saddr }.to_ne_bytes();
+    Ok(IP_ROUTING_TABLE.get(&Key::new(32, saddr)).copied())
+}
+
+/// cpumap variant: spread packets over CPUs by flow hash, like RSS does in hardware. works on
+/// NICs with a single queue
+#[xdp]
+pub fn xdp_cpumap(ctx: XdpContext) -> u32 {
+    let cpu_count = match CPU_COUNT.get(0) {
+        Some(count) if *count != 0 => *count,
+        _ => return xdp_action::XDP_PASS,
+    };
+    let index = flow_hash(&ctx).unwrap_or(0) % cpu_count;
+    let cpu = match CPU_IDS.get(index) {
+        Some(cpu) => *cpu,
+        None => return xdp_action::XDP_PASS,
+    };
+    CPU_MAP.redirect(cpu, 0).unwrap_or(xdp_action::XDP_PASS)
+}
+
+/// runs on the target CPU for packets redirected through CPU_MAP, hands them to the socket owned
+/// by this CPU's worker. the kernel reports queue 0 for these packets so all the sockets are
+/// bound to queue 0.
+#[xdp(map = "cpumap")]
+pub fn xdp_cpumap_xsk(_ctx: XdpContext) -> u32 {
+    let cpu = unsafe { bpf_get_smp_processor_id() };
+    XSKS_MAP.redirect(cpu, 0).unwrap_or(xdp_action::XDP_PASS)
+}
+
+/// hash of the IPv4 addresses and L4 ports, packets of the same flow land on the same CPU
+#[inline(always)]
+fn flow_hash(ctx: &XdpContext) -> Result<u32, ()> {
+    let eth = ptr_at::<EthHdr>(ctx, 0)?;
+    if unsafe { (*eth).ether_type } != ETH_P_IP.to_be() {
+        return Ok(0);
+    }
+
+    let ip = ptr_at::<Ipv4Hdr>(ctx, mem::size_of::<EthHdr>())?;
+    let mut hash = unsafe { (*ip).saddr ^ (*ip).daddr };
+    // ports are the first 4 bytes of both UDP and TCP, skip packets with IP options
+    if unsafe { (*ip).version_ihl } == 0x45 {
+        let ports = ptr_at::<u32>(ctx, mem::size_of::<EthHdr>() + mem::size_of::<Ipv4Hdr>())?;
+        hash ^= unsafe { *ports };
+    }
+
+    // murmur3 finalizer
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+    Ok(hash)
+}
+
+/// put the flow hash and the time we saw the packet in front of it so the socket doesn't have
+/// to recompute the hash or guess when the packet arrived.
+///
+/// xdp_md doesn't carry the NIC's RSS hash or rx timestamp, getting them needs the
+/// bpf_xdp_metadata_rx_* kfuncs and a device bound program, so this stores flow_hash() which is
+/// stable per flow just the same and bpf_ktime_get_ns(). drivers without metadata support fail
+/// bpf_xdp_adjust_meta and the packet goes out without it
+#[inline(always)]
+fn store_rx_meta(ctx: &XdpContext) {
+    let Ok(hash) = flow_hash(ctx) else {
+        return;
+    };
+    if unsafe { bpf_xdp_adjust_meta(ctx.ctx, -(mem::size_of::<XdpRxMeta>() as i32)) } != 0 {
+        return;
+    }
+
+    // the verifier wants data_meta + size checked against data after the adjust
+    let meta = ctx.metadata();
+    if meta + mem::size_of::<XdpRxMeta>() > ctx.data() {
+        return;
+    }
+    let meta = meta as *mut XdpRxMeta;
+    unsafe {
+        (*meta).xdp_timestamp = bpf_ktime_get_ns();
+        (*meta).flow_hash = hash;
+        (*meta).magic = RX_META_MAGIC;
+    }
+}
+
+/// xdp_md is UAPI, the verifier rewrites loads from it into loads from the kernel's xdp_buff
+/// so the offset is stable across kernels. read it volatile so it's always a single ctx load,
+/// which is what the verifier expects, instead of something the compiler might combine.
+#[inline(always)]
+fn rx_queue_index(ctx: &XdpContext) -> u32 {
+    unsafe { core::ptr::read_volatile(&(*ctx.ctx).rx_queue_index) }
+}
+
+#[inline(always)]
+fn count(queue_id: u32, f: impl FnOnce(&mut XdpStats)) {
+    if let Some(stats) = XDP_STATS.get_ptr_mut(queue_id) {
+        // per CPU entry, no other writers
+        f(unsafe { &mut *stats });
+    }
+}
+
+/// check the IPv4 source address against IP_WHITELIST
+/// with an active whitelist non-IPv4 packets are not allowed either
+#[inline(always)]
+fn source_allowed(ctx: &XdpContext) -> Result<bool, ()> {
+    match IP_WHITELIST_LEN.get(0) {
+        Some(len) if *len != 0 => {}
+        _ => return Ok(true),
+    }
+
+    let eth = ptr_at::<EthHdr>(ctx, 0)?;
+    if unsafe { (*eth).ether_type } != ETH_P_IP.to_be() {
+        return Ok(false);
+    }
+
+    let ip = ptr_at::<Ipv4Hdr>(ctx, mem::size_of::<EthHdr>())?;
+    let saddr = unsafe { (*ip).saddr };
+    Ok(unsafe { IP_WHITELIST.get(&saddr) }.is_some())
+}
+
+/// ethertype (network byte order) and offset of the L3 header, looking through one 802.1Q tag
+#[inline(always)]
+fn l3_header(ctx: &XdpContext) -> Result<(u16, usize), ()> {
+    let eth = ptr_at::<EthHdr>(ctx, 0)?;
+    let ether_type = unsafe { (*eth).ether_type };
+    if ether_type != ETH_P_8021Q.to_be() {
+        return Ok((ether_type, mem::size_of::<EthHdr>()));
+    }
+    // tag control info followed by the tagged frame's ethertype
+    let tag = ptr_at::<[u16; 2]>(ctx, mem::size_of::<EthHdr>())?;
+    Ok((unsafe { (*tag)[1] }, mem::size_of::<EthHdr>() + 4))
+}
+
+/// check the UDP destination port against UDP_PORT_FILTER
+/// IPv6 packets with extension headers don't match
+#[inline(always)]
+fn udp_port_allowed(ctx: &XdpContext) -> Result<bool, ()> {
+    match UDP_PORT_FILTER_LEN.get(0) {
+        Some(len) if *len != 0 => {}
+        _ => return Ok(true),
+    }
+
+    let (ether_type, l3_offset) = l3_header(ctx)?;
+    let udp_offset = if ether_type == ETH_P_IP.to_be() {
+        let ip = ptr_at::<Ipv4Hdr>(ctx, l3_offset)?;
+        if unsafe { (*ip).protocol } != IPPROTO_UDP {
+            return Ok(false);
+        }
+        let ihl = (unsafe { (*ip).version_ihl } & 0x0f) as usize;
+        l3_offset + ihl * 4
+    } else if ether_type == ETH_P_IPV6.to_be() {
+        let ip = ptr_at::<Ipv6Hdr>(ctx, l3_offset)?;
+        if unsafe { (*ip).next_header } != IPPROTO_UDP {
+            return Ok(false);
+        }
+        l3_offset + mem::size_of::<Ipv6Hdr>()
+    } else {
+        return Ok(false);
+    };
+
+    let udp = ptr_at::<UdpHdr>(ctx, udp_offset)?;
+    let dest = unsafe { (*udp).dest };
+    Ok(unsafe { UDP_PORT_FILTER.get(&dest) }.is_some())
+}
+
+/// turn an ICMP echo request for LOCAL_IP into an echo reply in place
+/// returns true if the packet was rewritten and should be sent back with XDP_TX
+#[inline(always)]
+fn try_icmp_echo_reply(ctx: &XdpContext) -> Result<bool, ()> {
+    let local_ip = match LOCAL_IP.get(0) {
+        Some(ip) if *ip != 0 => *ip,
+        _ => return Ok(false),
+    };
+
+    let eth = ptr_at::<EthHdr>(ctx, 0)?;
+    if unsafe { (*eth).ether_type } != ETH_P_IP.to_be() {
+        return Ok(false);
+    }
+
+    let ip = ptr_at::<Ipv4Hdr>(ctx, mem::size_of::<EthHdr>())?;
+    // no IP options, keeps the ICMP offset constant for the verifier
+    if unsafe { (*ip).version_ihl } != 0x45
+        || unsafe { (*ip).protocol } != IPPROTO_ICMP
+        || unsafe { (*ip).daddr } != local_ip
+    {
+        return Ok(false);
+    }
+
+    let icmp = ptr_at::<IcmpHdr>(ctx, mem::size_of::<EthHdr>() + mem::size_of::<Ipv4Hdr>())?;
+    if unsafe { (*icmp).type_ } != ICMP_ECHO_REQUEST || unsafe { (*icmp).code } != 0 {
+        return Ok(false);
+    }
+
+    unsafe {
+        // swap MACs
+        let src_mac = (*eth).src;
+        (*eth).src = (*eth).dst;
+        (*eth).dst = src_mac;
+
+        // swap IPs, the IP checksum doesn't change
+        let saddr = (*ip).saddr;
+        (*ip).saddr = (*ip).daddr;
+        (*ip).daddr = saddr;
+
+        // flip the type and incrementally update the checksum (RFC 1624). the type/code word
+        // goes from 0x0800 to 0x0000 so the checksum grows by 0x0800
+        (*icmp).type_ = ICMP_ECHO_REPLY;
+        let mut csum = u16::from_be((*icmp).checksum) as u32 + ((ICMP_ECHO_REQUEST as u32) << 8);
+        csum = (csum & 0xFFFF) + (csum >> 16);
+        (*icmp).checksum = (csum as u16).to_be();
+    }
+
+    Ok(true)
+}
+
+/// bounds checked pointer into the packet, required by the verifier
+#[inline(always)]
+fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*mut T, ()> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+    if start + offset + mem::size_of::<T>() > end {
+        return Err(());
+    }
+    Ok((start + offset) as *mut T)
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
-}
\ No newline at end of file
+}